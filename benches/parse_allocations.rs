@@ -0,0 +1,83 @@
+//! Compares how often the owned and the borrowed parser allocate for a typical response.
+//! Run with `cargo bench --bench parse_allocations`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use untitled::dns_server::dns_packet::dns_packet::{Answer, CLASS_IN, DnsPacket, QueryType, Record};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ROUNDS: usize = 10_000;
+
+fn response() -> (Vec<u8>, usize) {
+    let mut packet = DnsPacket::query(1, "www.example.com", QueryType::A);
+    packet.header.set_query_response(true);
+    for i in 0..8 {
+        packet.add_answer(Answer {
+            name: "www.example.com".to_string(),
+            query_type: QueryType::A,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 4,
+            record: Record::A(Ipv4Addr::new(10, 0, 0, i)),
+        });
+    }
+    for i in 0..4 {
+        packet.add_authority(Answer {
+            name: "example.com".to_string(),
+            query_type: QueryType::NS,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::NS(format!("ns{}.example.com", i)),
+        });
+    }
+    let (buf, len) = packet.to_buf().unwrap();
+    (buf.to_vec(), len)
+}
+
+/// Runs `parse` ROUNDS times and returns allocations per parse and the elapsed time.
+fn measure(buf: &[u8], parse: impl Fn(&[u8]) -> usize) -> (usize, f64) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let mut records = 0;
+    for _ in 0..ROUNDS {
+        records += parse(buf);
+    }
+    let elapsed = started.elapsed().as_secs_f64() * 1000.0;
+    assert_eq!(records, ROUNDS * 13);
+    ((ALLOCATIONS.load(Ordering::Relaxed) - before) / ROUNDS, elapsed)
+}
+
+fn main() {
+    let (buf, len) = response();
+    let buf = &buf[..len];
+    let (owned_allocations, owned_ms) = measure(buf, |buf| {
+        let packet = DnsPacket::from_buf(buf).unwrap();
+        packet.questions.len() + packet.answers.len() + packet.authorities.len()
+    });
+    let (borrowed_allocations, borrowed_ms) = measure(buf, |buf| {
+        let packet = DnsPacket::parse_borrowed(buf).unwrap();
+        packet.questions.len() + packet.answers.len() + packet.authorities.len()
+    });
+    println!("owned:    {} allocations per parse, {:.1}ms for {} parses", owned_allocations, owned_ms, ROUNDS);
+    println!("borrowed: {} allocations per parse, {:.1}ms for {} parses", borrowed_allocations, borrowed_ms, ROUNDS);
+}
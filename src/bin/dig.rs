@@ -0,0 +1,25 @@
+use std::process::ExitCode;
+use untitled::dig::dig::{dig, DigOptions};
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let options = match DigOptions::from_args(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: dig [@server[:port]] name [type] [+tcp]");
+            return ExitCode::FAILURE;
+        }
+    };
+    match dig(&options).await {
+        Ok(response) => {
+            print!("{}", response);
+            println!("\n;; SERVER: {}", options.server);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!(";; query to {} failed: {}", options.server, e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,70 @@
+pub mod dig {
+    use std::io::{self, Error, ErrorKind};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+    use crate::dns_server::dns_packet::dns_packet::{DnsPacket, QueryType};
+    use crate::dns_server::transport::transport::{query_tcp, query_udp};
+
+    const DEFAULT_PORT: u16 = 53;
+    const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A parsed `dig [@server[:port]] name [type] [+tcp]` command line.
+    #[derive(Debug, PartialEq, Eq)]
+    pub struct DigOptions {
+        pub server: SocketAddr,
+        pub name: String,
+        pub query_type: QueryType,
+        pub tcp: bool,
+    }
+
+    impl DigOptions {
+        /// Parses the arguments following the program name. The server defaults
+        /// to 127.0.0.1:53 and the type to A.
+        pub fn from_args(args: impl IntoIterator<Item = String>) -> io::Result<DigOptions> {
+            let mut server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), DEFAULT_PORT);
+            let mut name = None;
+            let mut query_type = None;
+            let mut tcp = false;
+            for arg in args {
+                if let Some(addr) = arg.strip_prefix('@') {
+                    server = parse_server(addr)?;
+                } else if arg == "+tcp" {
+                    tcp = true;
+                } else if arg.starts_with('+') {
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("unknown option {}", arg)));
+                } else if name.is_none() {
+                    name = Some(arg.trim_end_matches('.').to_lowercase());
+                } else if query_type.is_none() {
+                    query_type = Some(arg.parse()?);
+                } else {
+                    return Err(Error::new(ErrorKind::InvalidInput, format!("unexpected argument {}", arg)));
+                }
+            }
+            Ok(DigOptions {
+                server,
+                name: name.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no name given"))?,
+                query_type: query_type.unwrap_or(QueryType::A),
+                tcp,
+            })
+        }
+    }
+
+    fn parse_server(addr: &str) -> io::Result<SocketAddr> {
+        if let Ok(server) = addr.parse::<SocketAddr>() {
+            return Ok(server);
+        }
+        addr.parse::<IpAddr>()
+            .map(|ip| SocketAddr::new(ip, DEFAULT_PORT))
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid server address {}", addr)))
+    }
+
+    /// Sends the query described by `options` and returns the response.
+    pub async fn dig(options: &DigOptions) -> io::Result<DnsPacket> {
+        let query = DnsPacket::query(std::process::id() as u16, &options.name, options.query_type.clone());
+        if options.tcp {
+            query_tcp(options.server, &query, QUERY_TIMEOUT).await
+        } else {
+            query_udp(options.server, &query, QUERY_TIMEOUT).await
+        }
+    }
+}
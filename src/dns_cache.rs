@@ -1,116 +1,813 @@
 
 pub mod dns_cache
 {
-    use std::collections::{HashMap, HashSet};
+    use std::collections::{BTreeSet, HashMap, HashSet};
+    use std::borrow::Borrow;
+    use std::fmt;
     use std::hash::{Hash, Hasher};
-    use std::sync::Mutex;
-    use chrono::{Duration, Local, DateTime};
-    use crate::dns_server::dns_packet::dns_packet::{Answer, DnsPacket, QueryType};
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use chrono::{Duration, Local, DateTime, Utc};
+    use crate::dns_server::dns_packet::dns_packet::{Answer, CLASS_IN, DnsPacket, QueryType, Record, ResponseCode};
+    use crate::dns_server::names::names::{is_subdomain_of, names_equal};
+    use crate::dns_server::query_log::query_log::trace;
 
-    #[derive(Eq, Debug)]
+    /// how many CNAMEs a cache lookup follows before giving up
+    const MAX_CNAME_CHAIN: usize = 8;
+
+    #[derive(Clone, Debug)]
+    pub struct CachePolicy {
+        /// lower bound in seconds applied to every record ttl
+        pub min_ttl: u32,
+        /// upper bound in seconds applied to every record ttl
+        pub max_ttl: u32,
+        /// how long negative answers are remembered in seconds, 0 disables negative caching
+        pub negative_ttl: u32,
+        /// how long in seconds an expired record may still be served
+        pub serve_stale: u32,
+        /// remaining ttl in seconds below which a record should be refreshed, 0 disables prefetching
+        pub prefetch_threshold: u32,
+        /// approximate bytes of names and records after which the least recently
+        /// used names are evicted, 0 disables the limit
+        pub max_bytes: usize,
+        /// zone cuts kept at most, those expiring first are evicted beyond it
+        pub max_zone_cuts: usize,
+    }
+
+    impl Default for CachePolicy {
+        fn default() -> Self {
+            CachePolicy {
+                min_ttl: 0,
+                max_ttl: u32::MAX,
+                negative_ttl: 0,
+                serve_stale: 0,
+                prefetch_threshold: 0,
+                max_bytes: 0,
+                max_zone_cuts: 10_000,
+            }
+        }
+    }
+
+    impl CachePolicy {
+        pub fn clamp_ttl(&self, ttl: u32) -> u32 {
+            ttl.max(self.min_ttl).min(self.max_ttl)
+        }
+    }
+
+    /// Source of the current time for expiry decisions.
+    pub trait Clock: fmt::Debug + Send + Sync {
+        fn now(&self) -> DateTime<Local>;
+    }
+
+    /// The wall clock, used unless a cache is created with another one.
+    #[derive(Debug, Default)]
+    pub struct SystemClock;
+
+    impl Clock for SystemClock {
+        fn now(&self) -> DateTime<Local> {
+            Local::now()
+        }
+    }
+
+    /// A clock that only moves when told to, for testing expiry without sleeping.
+    #[derive(Debug)]
+    pub struct MockClock {
+        now: Mutex<DateTime<Local>>,
+    }
+
+    impl MockClock {
+        pub fn new(now: DateTime<Local>) -> Self {
+            MockClock { now: Mutex::new(now) }
+        }
+
+        pub fn advance(&self, by: Duration) {
+            *self.now.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> DateTime<Local> {
+            *self.now.lock().unwrap()
+        }
+    }
+
+    /// `time` moved `seconds` ahead, saturating a day short of the latest time chrono
+    /// can represent. TTLs come from upstream, so the sum must never panic.
+    fn add_seconds(time: DateTime<Local>, seconds: u32) -> DateTime<Local> {
+        time.checked_add_signed(Duration::seconds(seconds as i64))
+            .unwrap_or_else(|| (DateTime::<Utc>::MAX_UTC - Duration::days(1)).with_timezone(&Local))
+    }
+
+    #[derive(Eq, Debug, Clone)]
     pub struct RecordEntry {
         pub record: Answer,
         pub expires_in: DateTime<Local>,
     }
 
     impl RecordEntry {
-        pub fn new(record: Answer) -> Self {
-            let ttl = record.ttl as i64;
+        pub fn new(record: Answer, policy: &CachePolicy, clock: &dyn Clock) -> Self {
+            let ttl = policy.clamp_ttl(record.ttl);
             RecordEntry {
                 record,
-                expires_in: Local::now() + Duration::seconds(ttl),
+                expires_in: add_seconds(clock.now(), ttl),
             }
         }
 
-        pub fn is_expired(&self) -> bool {
-            self.expires_in < Local::now()
+        pub fn is_expired(&self, clock: &dyn Clock) -> bool {
+            self.expires_in < clock.now()
+        }
+
+        /// Whether the record may still be served, allowing `grace` seconds past expiry.
+        pub fn is_usable(&self, grace: u32, clock: &dyn Clock) -> bool {
+            add_seconds(self.expires_in, grace) >= clock.now()
         }
     }
 
+    // entries are identified by their data alone so a refreshed record
+    // with a new ttl replaces the old one instead of sitting next to it
     impl Hash for RecordEntry {
         fn hash<H: Hasher>(&self, state: &mut H) {
-            self.record.hash(state);
+            self.record.name.hash(state);
+            self.record.query_type.hash(state);
+            self.record.class.hash(state);
+            self.record.record.hash(state);
         }
     }
     impl PartialEq for RecordEntry {
         fn eq(&self, other: &Self) -> bool {
-            self.record == other.record
+            self.record.name == other.record.name
+                && self.record.query_type == other.record.query_type
+                && self.record.class == other.record.class
+                && self.record.record == other.record.record
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct CacheEntry {
         pub domain: String,
-        pub record_types: HashMap<QueryType, HashSet<RecordEntry>>
+        /// records keyed by type and class so classes never alias
+        pub record_types: HashMap<(QueryType, u16), HashSet<RecordEntry>>,
+        /// approximate bytes held by the name and its records
+        pub size: usize,
+        /// value of the cache's access counter when the name was last inserted or read
+        pub last_used: u64,
+    }
+
+    /// Rough bytes a cached record takes: its name, the fixed record fields and the rdata.
+    fn approximate_size(answer: &Answer) -> usize {
+        let rdata = match &answer.record {
+            Record::A(_) => 4,
+            Record::AAAA(_) => 16,
+            Record::NS(name) | Record::CNAME(name) | Record::PTR(name) => name.len() + 2,
+            Record::SOA { mname, rname, .. } => mname.len() + rname.len() + 24,
+            Record::HINFO { cpu, os } => cpu.len() + os.len() + 2,
+            Record::MX { host, .. } => host.len() + 4,
+            Record::TXT(strings) => strings.iter().map(|string| string.len() + 1).sum(),
+            Record::OPT(options) => options.iter().map(|option| option.data.len() + 4).sum(),
+            Record::DS(data) | Record::RRSIG(data) | Record::DNSKEY(data) => data.len(),
+            Record::NSEC { next_domain, types } => next_domain.len() + 2 + types.len() * 2,
+            Record::NSEC3 { salt, next_hashed_owner, types, .. } => salt.len() + next_hashed_owner.len() + 6 + types.len() * 2,
+            Record::URI { target, .. } => target.len() + 4,
+            Record::UNKOWN(_) => 0,
+        };
+        answer.name.len() + 10 + rdata
+    }
+
+    /// A cached NXDOMAIN or NODATA answer together with the SOA record proving it.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct NegativeEntry {
+        pub response_code: ResponseCode,
+        pub soa: Option<Answer>,
+        pub expires_in: DateTime<Local>,
+    }
+
+    /// A zone cut learned from a referral, its nameservers and their known addresses.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Delegation {
+        pub zone: String,
+        pub name_servers: Vec<String>,
+        pub addresses: Vec<IpAddr>,
+        pub expires_in: DateTime<Local>,
+    }
+
+    /// A domain name in canonical form, lowercase and without a trailing dot,
+    /// so names differing only in case share one cache key.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+    pub struct DomainName(String);
+
+    impl DomainName {
+        pub fn new(name: &str) -> Self {
+            DomainName(name.trim_end_matches('.').to_ascii_lowercase())
+        }
+
+        pub fn as_str(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl From<&str> for DomainName {
+        fn from(name: &str) -> Self {
+            DomainName::new(name)
+        }
+    }
+
+    impl Borrow<str> for DomainName {
+        fn borrow(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl fmt::Display for DomainName {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    /// Finds the usable records for the query, following CNAMEs within `cache`.
+    /// Returns the CNAMEs of the chain and the records at its end.
+    fn follow_chain(cache: &HashMap<DomainName, CacheEntry>, query_name: &str, query_type: &QueryType, class: u16,
+                    grace: u32, clock: &dyn Clock) -> Option<(Vec<Answer>, Vec<Answer>)> {
+        let mut chain = Vec::new();
+        let mut name = DomainName::new(query_name);
+        for _ in 0..MAX_CNAME_CHAIN {
+            if let Some(answers) = usable_records(cache, &name, query_type, class, grace, clock) {
+                return Some((chain, answers));
+            }
+            if *query_type == QueryType::CNAME {
+                return None;
+            }
+            let cname = usable_records(cache, &name, &QueryType::CNAME, class, grace, clock)?
+                .into_iter()
+                .next()?;
+            name = match &cname.record {
+                Record::CNAME(target) => DomainName::new(target),
+                _ => return None,
+            };
+            chain.push(cname);
+        }
+        None
+    }
+
+    fn usable_records(cache: &HashMap<DomainName, CacheEntry>, query_name: &DomainName, query_type: &QueryType, class: u16,
+                      grace: u32, clock: &dyn Clock) -> Option<Vec<Answer>> {
+        cache.get(query_name)
+            .and_then(|x| x.record_types.get(&(query_type.clone(), class)))
+            .and_then(|x| {
+                let answers: Vec<Answer> = x.iter()
+                    .filter(|entry| entry.is_usable(grace, clock))
+                    .map(|entry| entry.record.clone())
+                    .collect();
+                if answers.is_empty() {
+                    None
+                } else {
+                    Some(answers)
+                }
+            })
+    }
+
+    /// The time a snapshot was taken, which never moves.
+    #[derive(Debug, Clone, Copy)]
+    struct FrozenClock(DateTime<Local>);
+
+    impl Clock for FrozenClock {
+        fn now(&self) -> DateTime<Local> {
+            self.0
+        }
+    }
+
+    /// An immutable copy of a cache's records, answering lookups as the cache did when
+    /// the snapshot was taken. Records are returned in canonical order instead of shuffled.
+    #[derive(Debug, Clone)]
+    pub struct CacheSnapshot {
+        entries: Arc<HashMap<DomainName, CacheEntry>>,
+        serve_stale: u32,
+        taken_at: FrozenClock,
+    }
+
+    impl CacheSnapshot {
+        pub fn get(&self, query_name: &str, query_type: &QueryType) -> Option<Vec<Answer>> {
+            self.get_with_class(query_name, query_type, CLASS_IN)
+        }
+
+        pub fn get_with_class(&self, query_name: &str, query_type: &QueryType, class: u16) -> Option<Vec<Answer>> {
+            let (mut chain, mut answers) = follow_chain(&self.entries, query_name, query_type, class, self.serve_stale, &self.taken_at)?;
+            answers.sort_by_cached_key(|answer| answer.record.to_string());
+            chain.extend(answers);
+            Some(chain)
+        }
+
+        pub fn taken_at(&self) -> DateTime<Local> {
+            self.taken_at.0
+        }
+    }
+
+    /// Rough bytes a zone cut takes: its zone, nameserver names and addresses.
+    fn delegation_size(delegation: &Delegation) -> usize {
+        delegation.zone.len()
+            + delegation.name_servers.iter().map(|server| server.len()).sum::<usize>()
+            + delegation.addresses.len() * 16
+    }
+
+    #[derive(Debug, Default)]
+    struct ZoneCuts {
+        by_zone: HashMap<DomainName, Delegation>,
+        /// every cut ordered by expiry, the first ones are evicted when full
+        by_expiry: BTreeSet<(DateTime<Local>, DomainName)>,
+        /// sum of the `delegation_size` of all cuts
+        bytes: usize,
+    }
+
+    impl ZoneCuts {
+        fn insert(&mut self, zone: DomainName, delegation: Delegation) {
+            self.remove(&zone);
+            self.bytes += delegation_size(&delegation);
+            self.by_expiry.insert((delegation.expires_in, zone.clone()));
+            self.by_zone.insert(zone, delegation);
+        }
+
+        fn remove(&mut self, zone: &DomainName) {
+            if let Some(removed) = self.by_zone.remove(zone) {
+                self.bytes -= delegation_size(&removed);
+                self.by_expiry.remove(&(removed.expires_in, zone.clone()));
+            }
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct CachedNames {
+        by_name: HashMap<DomainName, CacheEntry>,
+        /// every name ordered by `last_used`, the first ones are evicted when full
+        by_use: BTreeSet<(u64, DomainName)>,
+    }
+
+    impl CachedNames {
+        fn insert(&mut self, name: DomainName, entry: CacheEntry) {
+            self.remove(&name);
+            self.by_use.insert((entry.last_used, name.clone()));
+            self.by_name.insert(name, entry);
+        }
+
+        fn remove(&mut self, name: &DomainName) -> Option<CacheEntry> {
+            let removed = self.by_name.remove(name)?;
+            self.by_use.remove(&(removed.last_used, name.clone()));
+            Some(removed)
+        }
+
+        /// Moves `name` to the back of the eviction order.
+        fn touch(&mut self, name: &DomainName, last_used: u64) {
+            if let Some(entry) = self.by_name.get_mut(name) {
+                self.by_use.remove(&(entry.last_used, name.clone()));
+                entry.last_used = last_used;
+                self.by_use.insert((last_used, name.clone()));
+            }
+        }
+    }
+
+    /// Zone cuts learned from referrals, keyed by zone and kept apart from the records
+    /// so the deepest cut above a name is found with one lookup per label.
+    #[derive(Debug)]
+    pub struct ZoneCutCache {
+        cuts: Mutex<ZoneCuts>,
+        policy: CachePolicy,
+        clock: Arc<dyn Clock>,
+    }
+
+    impl ZoneCutCache {
+        pub fn new(policy: CachePolicy, clock: Arc<dyn Clock>) -> Self {
+            ZoneCutCache { cuts: Mutex::default(), policy, clock }
+        }
+
+        /// Records every zone with NS records in the authority section as a
+        /// zone cut, together with the glue for its nameservers. Beyond
+        /// `max_zone_cuts` the cuts expiring first are evicted.
+        pub fn insert(&self, packet: &DnsPacket) {
+            let mut zones: HashMap<&str, (Vec<String>, u32)> = HashMap::new();
+            for auth in &packet.authorities {
+                if let Record::NS(server) = &auth.record {
+                    let (name_servers, ttl) = zones.entry(&auth.name).or_insert((Vec::new(), u32::MAX));
+                    name_servers.push(server.clone());
+                    *ttl = (*ttl).min(auth.ttl);
+                }
+            }
+            let mut cuts = self.cuts.lock().unwrap();
+            for (zone, (name_servers, ttl)) in zones {
+                let addresses = packet.additional.iter()
+                    .filter(|additional| name_servers.iter().any(|server| names_equal(server, &additional.name)))
+                    .filter_map(|additional| match &additional.record {
+                        Record::A(ip) => Some(IpAddr::V4(*ip)),
+                        Record::AAAA(ip) => Some(IpAddr::V6(*ip)),
+                        _ => None,
+                    })
+                    .collect();
+                let expires_in = add_seconds(self.clock.now(), self.policy.clamp_ttl(ttl));
+                cuts.insert(DomainName::new(zone), Delegation { zone: zone.to_string(), name_servers, addresses, expires_in });
+            }
+            while cuts.by_zone.len() > self.policy.max_zone_cuts {
+                match cuts.by_expiry.first().cloned() {
+                    Some((_, zone)) => cuts.remove(&zone),
+                    None => break,
+                }
+            }
+        }
+
+        /// The unexpired cut of exactly `zone`. An expired one is dropped.
+        pub fn get(&self, zone: &str) -> Option<Delegation> {
+            let zone = DomainName::new(zone);
+            let mut cuts = self.cuts.lock().unwrap();
+            match cuts.by_zone.get(&zone) {
+                Some(cut) if cut.expires_in >= self.clock.now() => Some(cut.clone()),
+                Some(_) => {
+                    cuts.remove(&zone);
+                    None
+                }
+                None => None,
+            }
+        }
+
+        /// The deepest unexpired cut enclosing `name`, whether or not the
+        /// addresses of its nameservers are known.
+        pub fn deepest_cut(&self, name: &str) -> Option<Delegation> {
+            self.find_cut(name, Some)
+        }
+
+        /// The first value `found` returns for the unexpired cuts enclosing
+        /// `name`, trying them from the deepest one up to the root.
+        fn find_cut<T>(&self, name: &str, mut found: impl FnMut(Delegation) -> Option<T>) -> Option<T> {
+            let name = DomainName::new(name);
+            let labels: Vec<&str> = name.as_str().split('.').collect();
+            (0..labels.len()).map(|label_idx| labels[label_idx..].join("."))
+                .chain((!name.as_str().is_empty()).then(String::new))
+                .find_map(|zone| self.get(&zone).and_then(&mut found))
+        }
+
+        /// Approximate bytes held by the cuts.
+        pub fn memory_usage(&self) -> usize {
+            self.cuts.lock().unwrap().bytes
+        }
+
+        pub fn len(&self) -> usize {
+            self.cuts.lock().unwrap().by_zone.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
     }
 
     #[derive(Debug)]
     pub struct DnsCache {
-        cache: Mutex<HashMap<String, CacheEntry>>
+        cache: Mutex<CachedNames>,
+        zone_cuts: ZoneCutCache,
+        /// NODATA keyed by the type asked for, NXDOMAIN by `QueryType::ANY` as it covers every type
+        negative: Mutex<HashMap<(DomainName, QueryType), NegativeEntry>>,
+        policy: CachePolicy,
+        /// xorshift state used to shuffle the records of every answer
+        rng_state: AtomicU64,
+        /// incremented on every insert and read, orders names for eviction
+        access_counter: AtomicU64,
+        /// sum of the `size` of all cache entries, only changed with `cache` locked
+        memory_usage: AtomicUsize,
+        clock: Arc<dyn Clock>,
+    }
+
+    impl Default for DnsCache {
+        fn default() -> Self {
+            DnsCache::new()
+        }
     }
 
     impl DnsCache {
         pub fn new() -> Self {
+            DnsCache::with_policy(CachePolicy::default())
+        }
+
+        pub fn with_policy(policy: CachePolicy) -> Self {
+            DnsCache::with_seed(policy, None)
+        }
+
+        /// Creates a cache whose answer order is derived from `seed`, so two
+        /// caches with the same seed and records return them in the same order.
+        /// Without a seed the order is randomized per process.
+        pub fn with_seed(policy: CachePolicy, seed: Option<u64>) -> Self {
+            let seed = seed.unwrap_or_else(|| {
+                let nanos = Local::now().timestamp_nanos() as u64;
+                nanos ^ (std::process::id() as u64) << 32
+            });
             DnsCache {
                 cache: Mutex::new(Default::default()),
+                zone_cuts: ZoneCutCache::new(policy.clone(), Arc::new(SystemClock)),
+                negative: Mutex::new(Default::default()),
+                policy,
+                // xorshift never leaves the all zero state
+                rng_state: AtomicU64::new(seed.max(1)),
+                access_counter: AtomicU64::new(0),
+                memory_usage: AtomicUsize::new(0),
+                clock: Arc::new(SystemClock),
             }
         }
+
+        /// Creates a cache that reads the time from `clock` instead of the system clock.
+        pub fn with_clock(policy: CachePolicy, clock: Arc<dyn Clock>) -> Self {
+            DnsCache {
+                zone_cuts: ZoneCutCache::new(policy.clone(), Arc::clone(&clock)),
+                clock,
+                ..DnsCache::with_policy(policy)
+            }
+        }
+
+        /// Approximate bytes held by cached names, records and zone cuts,
+        /// negative entries are not counted.
+        pub fn memory_usage(&self) -> usize {
+            self.memory_usage.load(Ordering::Relaxed) + self.zone_cuts.memory_usage()
+        }
+
+        fn next_access(&self) -> u64 {
+            self.access_counter.fetch_add(1, Ordering::Relaxed)
+        }
+
+        fn next_random(&self) -> u64 {
+            let mut x = self.rng_state.load(Ordering::Relaxed);
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.rng_state.store(x, Ordering::Relaxed);
+            x
+        }
+
+        /// Sorts `answers` into a canonical order, then shuffles them with the cache rng.
+        fn shuffle(&self, answers: &mut [Answer]) {
+            answers.sort_by_cached_key(|answer| answer.record.to_string());
+            for i in (1..answers.len()).rev() {
+                let j = (self.next_random() % (i as u64 + 1)) as usize;
+                answers.swap(i, j);
+            }
+        }
+
         pub fn get(&self, query_name: &str, query_type: &QueryType) -> Option<Vec<Answer>> {
-            let cache = self.cache.lock().unwrap();
-            cache.get(query_name)
-                .and_then(|x| x.record_types.get(query_type))
-                .and_then(|x| {
-                    let answers: Vec<Answer> = x.iter().filter(|entry| !entry.is_expired())
-                        .map(|entry| entry.record.clone())
-                        .collect();
-                    if answers.is_empty() {
-                        None
-                    } else {
-                        Some(answers)
-                    }
-                })
+            self.get_with_class(query_name, query_type, CLASS_IN)
+        }
+
+        /// Returns the usable records for the query. When only a CNAME is cached
+        /// for the name, the chain is followed within the cache and the CNAMEs are
+        /// returned followed by the target's records, or None if the target is missing.
+        pub fn get_with_class(&self, query_name: &str, query_type: &QueryType, class: u16) -> Option<Vec<Answer>> {
+            let mut cache = self.cache.lock().unwrap();
+            let (mut chain, mut answers) = follow_chain(&cache.by_name, query_name, query_type, class, self.policy.serve_stale, self.clock.as_ref())?;
+            let last_used = self.next_access();
+            for cached in chain.iter().chain(answers.first()).map(|answer| DomainName::new(&answer.name)) {
+                cache.touch(&cached, last_used);
+            }
+            self.shuffle(&mut answers);
+            chain.extend(answers);
+            Some(chain)
+        }
+
+        /// Copies the cached records into a snapshot that answers as of now, however
+        /// the cache changes afterwards. The cache stays locked only while copying.
+        pub fn snapshot(&self) -> CacheSnapshot {
+            CacheSnapshot {
+                entries: Arc::new(self.cache.lock().unwrap().by_name.clone()),
+                serve_stale: self.policy.serve_stale,
+                taken_at: FrozenClock(self.clock.now()),
+            }
         }
 
+        /// Every unexpired record set as its owner name, type, records and the seconds
+        /// left until the first of them expires, sorted by name and type. Meant for
+        /// dumping the cache, which stays locked only while the records are copied.
+        pub fn entries(&self) -> Vec<(String, QueryType, Vec<Answer>, u32)> {
+            let now = self.clock.now();
+            let mut entries = Vec::new();
+            for entry in self.cache.lock().unwrap().by_name.values() {
+                for ((query_type, _), records) in &entry.record_types {
+                    let live: Vec<&RecordEntry> = records.iter().filter(|record| record.expires_in >= now).collect();
+                    let remaining_ttl = match live.iter().map(|record| record.expires_in).min() {
+                        Some(expires_in) => (expires_in - now).num_seconds().clamp(0, u32::MAX as i64) as u32,
+                        None => continue,
+                    };
+                    let mut answers: Vec<Answer> = live.into_iter().map(|record| record.record.clone()).collect();
+                    answers.sort_by_cached_key(|answer| answer.record.to_string());
+                    entries.push((entry.domain.clone(), query_type.clone(), answers, remaining_ttl));
+                }
+            }
+            entries.sort_by_cached_key(|(name, query_type, _, _)| (DomainName::new(name), query_type.to_u16()));
+            entries
+        }
+
+        /// `entries` as a JSON array with the name, type, remaining ttl and records in
+        /// presentation format of each record set, rendered after the cache is unlocked.
+        pub fn entries_json(&self) -> String {
+            let entries = self.entries().into_iter()
+                .map(|(name, query_type, answers, remaining_ttl)| serde_json::json!({
+                    "name": name,
+                    "type": format!("{:?}", query_type),
+                    "ttl": remaining_ttl,
+                    "records": answers.iter().map(|answer| answer.record.to_string()).collect::<Vec<_>>(),
+                }))
+                .collect();
+            serde_json::Value::Array(entries).to_string()
+        }
+
+        /// Caches every answer under its own name, creating or updating one
+        /// `CacheEntry` per distinct name. Records already cached get the new ttl and expiry.
+        /// Afterwards the least recently used names are evicted until `max_bytes` is met.
         pub fn insert(&self, answers: Vec<Answer>) {
             if answers.is_empty() {
                 return;
             }
-            if let Some(()) = self.update(&answers) {
+            let mut cache = self.cache.lock().unwrap();
+            let last_used = self.next_access();
+            for answer in answers {
+                let added = approximate_size(&answer);
+                let name = DomainName::new(&answer.name);
+                if cache.by_name.contains_key(&name) {
+                    cache.touch(&name, last_used);
+                } else {
+                    self.memory_usage.fetch_add(answer.name.len(), Ordering::Relaxed);
+                    cache.insert(name.clone(), CacheEntry {
+                        domain: answer.name.clone(),
+                        record_types: HashMap::new(),
+                        size: answer.name.len(),
+                        last_used,
+                    });
+                }
+                let entry = match cache.by_name.get_mut(&name) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let replaced = entry.record_types.entry((answer.query_type.clone(), answer.class))
+                    .or_default()
+                    .replace(RecordEntry::new(answer, &self.policy, self.clock.as_ref()));
+                let removed = replaced.map_or(0, |replaced| approximate_size(&replaced.record));
+                entry.size = entry.size + added - removed;
+                self.memory_usage.fetch_add(added, Ordering::Relaxed);
+                self.memory_usage.fetch_sub(removed, Ordering::Relaxed);
+            }
+            self.evict(&mut cache);
+        }
+
+        fn evict(&self, cache: &mut CachedNames) {
+            if self.policy.max_bytes == 0 {
+                return;
+            }
+            // zone cuts are bounded by `max_zone_cuts` instead
+            while self.memory_usage.load(Ordering::Relaxed) > self.policy.max_bytes {
+                let oldest = match cache.by_use.first() {
+                    Some((_, name)) => name.clone(),
+                    None => return,
+                };
+                if let Some(entry) = cache.remove(&oldest) {
+                    trace(format_args!("evicting {} from the cache", entry.domain));
+                    self.memory_usage.fetch_sub(entry.size, Ordering::Relaxed);
+                }
+            }
+        }
+
+        /// Remembers that `query_name` has no records of `query_type` for `negative_ttl` seconds.
+        pub fn insert_negative(&self, query_name: &str, query_type: &QueryType) {
+            self.insert_negative_entry(query_name, query_type, ResponseCode::NOERROR, None, self.policy.negative_ttl);
+        }
+
+        fn insert_negative_entry(&self, query_name: &str, query_type: &QueryType, response_code: ResponseCode, soa: Option<Answer>, ttl: u32) {
+            let ttl = ttl.min(self.policy.negative_ttl);
+            if ttl == 0 {
+                return;
+            }
+            let expires_in = add_seconds(self.clock.now(), ttl);
+            let mut negative = self.negative.lock().unwrap();
+            negative.insert((DomainName::new(query_name), query_type.clone()), NegativeEntry { response_code, soa, expires_in });
+        }
+
+        pub fn is_negative(&self, query_name: &str, query_type: &QueryType) -> bool {
+            self.get_negative(query_name, query_type).is_some()
+        }
+
+        /// The unexpired negative answer for the query, NXDOMAIN of the name taking precedence.
+        pub fn get_negative(&self, query_name: &str, query_type: &QueryType) -> Option<NegativeEntry> {
+            let negative = self.negative.lock().unwrap();
+            let name = DomainName::new(query_name);
+            let now = self.clock.now();
+            let mut entry = [QueryType::ANY, query_type.clone()].into_iter()
+                .filter_map(|query_type| negative.get(&(name.clone(), query_type)))
+                .find(|entry| entry.expires_in >= now)
+                .cloned()?;
+            // the SOA tells clients how long they may cache the answer, that is what is left
+            if let Some(soa) = &mut entry.soa {
+                soa.ttl = (entry.expires_in - now).num_seconds().clamp(0, u32::MAX as i64) as u32;
+            }
+            Some(entry)
+        }
+
+        /// Caches an upstream response according to its response code. NOERROR and NXDOMAIN
+        /// responses have their records cached via `insert_all`, and NXDOMAIN as well as NODATA
+        /// are remembered negatively for the SOA minimum, at most `negative_ttl`. Any other
+        /// response code, SERVFAIL and REFUSED in particular, is transient and nothing is cached.
+        pub fn insert_response(&self, packet: &DnsPacket, bailiwick: &str) {
+            let response_code = packet.header.get_response_code();
+            if response_code != ResponseCode::NOERROR && response_code != ResponseCode::NXDOMAIN {
+                return;
+            }
+            self.insert_all(packet, bailiwick);
+            let (question, soa) = match (packet.question(), packet.negative_soa()) {
+                (Some(question), Some(soa)) => (question, soa),
+                _ => return,
+            };
+            // a CNAME chain leading nowhere makes its target the name that does not exist
+            let name = packet.unresolved_cname().unwrap_or(&question.name);
+            if !is_subdomain_of(&soa.name, bailiwick) || !is_subdomain_of(name, &soa.name) {
+                trace(format_args!("not caching the negative answer for {} proven by {}", name, soa.name));
                 return;
             }
-            let mut entry = CacheEntry {
-                domain: answers.first().unwrap().name.to_string(),
-                record_types: HashMap::new(),
+            let ttl = match packet.negative_ttl() {
+                Some(ttl) => ttl,
+                None => return,
             };
+            let query_type = if response_code == ResponseCode::NXDOMAIN { QueryType::ANY } else { question.query_type.clone() };
+            self.insert_negative_entry(name, &query_type, response_code, Some(soa.clone()), ttl);
+        }
 
-            for answer in answers {
-                entry.record_types.entry(answer.query_type.clone())
-                    .or_insert_with(HashSet::new)
-                    .insert(RecordEntry::new(answer));
+        /// Whether a cached record for the query is about to expire and should be refreshed.
+        pub fn needs_prefetch(&self, query_name: &str, query_type: &QueryType) -> bool {
+            if self.policy.prefetch_threshold == 0 {
+                return false;
             }
-            let mut cache = self.cache.lock().unwrap();
-            cache.insert(entry.domain.clone(), entry);
+            let threshold = add_seconds(self.clock.now(), self.policy.prefetch_threshold);
+            let cache = self.cache.lock().unwrap();
+            cache.by_name.get(&DomainName::new(query_name))
+                .and_then(|x| x.record_types.get(&(query_type.clone(), CLASS_IN)))
+                .is_some_and(|x| x.iter()
+                    .any(|entry| !entry.is_expired(self.clock.as_ref()) && entry.expires_in < threshold))
         }
 
-        pub fn insert_all(&self, packet: &DnsPacket) {
-            self.insert(packet.answers.clone());
-            self.insert(packet.authorities.clone());
-            self.insert(packet.additional.clone());
+        /// Caches the records of every section that lie within `bailiwick`, the zone the
+        /// answering server is authoritative for. Anything else it sent is discarded,
+        /// as a server could otherwise plant records for names it has no say over.
+        pub fn insert_all(&self, packet: &DnsPacket, bailiwick: &str) {
+            let mut trusted = DnsPacket::new(packet.header);
+            let mut discarded = 0;
+            let mut within = |records: &[Answer]| -> Vec<Answer> {
+                let kept: Vec<Answer> = records.iter()
+                    .filter(|record| is_subdomain_of(&record.name, bailiwick))
+                    .cloned()
+                    .collect();
+                discarded += records.len() - kept.len();
+                kept
+            };
+            trusted.set_answers(within(&packet.answers));
+            trusted.set_authorities(within(&packet.authorities));
+            trusted.set_additional(within(&packet.additional));
+            if discarded > 0 {
+                trace(format_args!("not caching {} records outside of {:?}", discarded, bailiwick));
+            }
+            // one insert for all sections takes the cache lock only once per response
+            self.insert(trusted.answers.iter()
+                .chain(&trusted.authorities)
+                .chain(&trusted.additional)
+                .cloned()
+                .collect());
+            self.insert_delegations(&trusted);
         }
 
-        pub fn update(&self, answers: &Vec<Answer>) -> Option<()> {
-            let mut cache = self.cache.lock().unwrap();
+        /// Records every zone with NS records in the authority section as a zone cut.
+        pub fn insert_delegations(&self, packet: &DnsPacket) {
+            self.zone_cuts.insert(packet);
+        }
+
+        /// The zone cuts learned from referrals.
+        pub fn zone_cuts(&self) -> &ZoneCutCache {
+            &self.zone_cuts
+        }
+
+        /// Finds the deepest unexpired delegation enclosing `name` for which a
+        /// nameserver address is known, from glue or from cached A records.
+        pub fn closest_delegation(&self, name: &str) -> Option<Delegation> {
+            // the root is no delegation, clients know its servers already
+            self.zone_cuts.find_cut(name, |delegation| Some(self.with_cached_addresses(delegation))
+                .filter(|delegation| !delegation.zone.is_empty() && !delegation.addresses.is_empty()))
+        }
 
-            answers.first().map(|q| &q.name)
-                .and_then(|qname| cache.get_mut(qname))
-                .and_then(|entry|
-                    Some(for answer in answers {
-                        entry.record_types.entry(answer.query_type.clone())
-                            .or_insert_with(HashSet::new)
-                            .insert(RecordEntry::new(answer.clone()));
-                    }))
+        /// The unexpired delegation of exactly `zone`, with the addresses of its
+        /// nameservers from glue and cached A records, None if no address is known.
+        pub fn delegation(&self, zone: &str) -> Option<Delegation> {
+            Some(self.with_cached_addresses(self.zone_cuts.get(zone)?))
+                .filter(|delegation| !delegation.addresses.is_empty())
         }
 
+        /// `delegation` with the cached A records of its nameservers added to its glue.
+        pub fn with_cached_addresses(&self, mut delegation: Delegation) -> Delegation {
+            for server in &delegation.name_servers {
+                let cached = self.get(server, &QueryType::A).unwrap_or_default();
+                for answer in cached {
+                    if let Record::A(ip) = answer.record {
+                        if !delegation.addresses.contains(&IpAddr::V4(ip)) {
+                            delegation.addresses.push(IpAddr::V4(ip));
+                        }
+                    }
+                }
+            }
+            delegation
+        }
     }
 
 
@@ -118,7 +815,7 @@ pub mod dns_cache
     mod tests {
         use std::thread;
         use super::*;
-        use crate::dns_server::dns_packet::dns_packet::{Answer, Record, Question, Header, ResponseCode};
+        use crate::dns_server::dns_packet::dns_packet::{Answer, Record, Question, Header, ResponseCode, CLASS_CH};
         use std::net::Ipv4Addr;
         use std::str::FromStr;
         use std::net::Ipv6Addr;
@@ -127,27 +824,27 @@ pub mod dns_cache
 
         #[test]
         fn test_dns_cache() {
-            let mut dns_cache = DnsCache::new();
+            let dns_cache = DnsCache::new();
             let question = Question {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
             };
 
             let answer = Answer {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
                 ttl: 1,
                 len: 4,
                 record: Record::A(Ipv4Addr::from_str("127.0.0.1").unwrap()),
             };
 
-            let mut packet = DnsPacket::new(Header::new(42,true,true, ResponseCode::NOERROR));
+            let mut packet = DnsPacket::new(Header::new(42, true, false, true, ResponseCode::NOERROR));
             packet.add_question(question);
             packet.add_answer(answer.clone());
 
-            dns_cache.insert_all(&packet);
+            dns_cache.insert_all(&packet, "");
             let cache_result = dns_cache.get("example.com", &QueryType::A);
 
             assert_eq!(cache_result.unwrap(), vec![answer]);
@@ -158,24 +855,24 @@ pub mod dns_cache
             let question = Question {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
             };
 
             let answer = Answer {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
                 ttl: 1,
                 len: 4,
                 record: Record::A(Ipv4Addr::from_str("127.0.0.1").unwrap()),
             };
 
-            let mut packet = DnsPacket::new(Header::new(42, true, true, ResponseCode::NOERROR));
+            let mut packet = DnsPacket::new(Header::new(42, true, false, true, ResponseCode::NOERROR));
             packet.add_question(question);
             packet.add_answer(answer);
 
-            dns_cache.insert_all(&packet);
-            thread::sleep(Duration::from_secs(2));
+            dns_cache.insert_all(&packet, "");
+            thread::sleep(std::time::Duration::from_secs(2));
             let cache_result = dns_cache.get("example.com", &QueryType::A);
             assert_eq!(cache_result, None);
         }
@@ -186,19 +883,19 @@ pub mod dns_cache
             let question_a = Question {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
             };
 
             let question_aaaa = Question {
                 name: "example.com".to_string(),
                 query_type: QueryType::AAAA,
-                class: 1,
+                class: CLASS_IN,
             };
 
             let answer_a = Answer {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
                 ttl: 300,
                 len: 4,
                 record: Record::A(Ipv4Addr::from_str("127.0.0.1").unwrap()),
@@ -207,22 +904,22 @@ pub mod dns_cache
             let answer_aaaa = Answer {
                 name: "example.com".to_string(),
                 query_type: QueryType::AAAA,
-                class: 1,
+                class: CLASS_IN,
                 ttl: 300,
                 len: 16,
                 record: Record::AAAA(Ipv6Addr::from_str("2001:db8::1").unwrap()),
             };
 
-            let mut packet_a = DnsPacket::new(Header::new(42, true, true, ResponseCode::NOERROR));
+            let mut packet_a = DnsPacket::new(Header::new(42, true, false, true, ResponseCode::NOERROR));
             packet_a.add_question(question_a);
             packet_a.add_answer(answer_a.clone());
 
-            let mut packet_aaaa = DnsPacket::new(Header::new(43, true, true, ResponseCode::NOERROR));
+            let mut packet_aaaa = DnsPacket::new(Header::new(43, true, false, true, ResponseCode::NOERROR));
             packet_aaaa.add_question(question_aaaa);
             packet_aaaa.add_answer(answer_aaaa.clone());
 
-            dns_cache.insert_all(&packet_a);
-            dns_cache.insert_all(&packet_aaaa);
+            dns_cache.insert_all(&packet_a, "");
+            dns_cache.insert_all(&packet_aaaa, "");
             println!("{:#?}", dns_cache);
             let cache_result_a = dns_cache.get("example.com", &QueryType::A);
             assert_eq!(cache_result_a.unwrap(), vec![answer_a]);
@@ -236,29 +933,436 @@ pub mod dns_cache
             let question_a = Question {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
             };
 
             let answer_a = Answer {
                 name: "example.com".to_string(),
                 query_type: QueryType::A,
-                class: 1,
+                class: CLASS_IN,
                 ttl: 300,
                 len: 4,
                 record: Record::A(Ipv4Addr::from_str("127.0.0.1").unwrap()),
             };
 
-            let mut packet_a = DnsPacket::new(Header::new(42, true, true, ResponseCode::NOERROR));
+            let mut packet_a = DnsPacket::new(Header::new(42, true, false, true, ResponseCode::NOERROR));
             packet_a.add_question(question_a);
             packet_a.add_answer(answer_a.clone());
 
-            let mut packet_b = packet_a.clone();
-            dns_cache.insert_all(&packet_a);
-            dns_cache.insert_all(&packet_b);
+            let packet_b = packet_a.clone();
+            dns_cache.insert_all(&packet_a, "");
+            dns_cache.insert_all(&packet_b, "");
             let cache_result = dns_cache.get("example.com", &QueryType::A);
 
             println!("{:#?}", dns_cache);
             assert_eq!(cache_result.clone().unwrap().len(), 1);
         }
+
+        fn a_answer(ttl: u32) -> Answer {
+            Answer {
+                name: "example.com".to_string(),
+                query_type: QueryType::A,
+                class: CLASS_IN,
+                ttl,
+                len: 4,
+                record: Record::A(Ipv4Addr::from_str("127.0.0.1").unwrap()),
+            }
+        }
+
+        #[test]
+        fn test_dns_cache_policy_knobs() {
+            let policy = CachePolicy {
+                min_ttl: 60,
+                max_ttl: 3600,
+                negative_ttl: 30,
+                serve_stale: 10,
+                prefetch_threshold: 120,
+                max_bytes: 0,
+                max_zone_cuts: 10_000,
+            };
+
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let clamped_up = DnsCache::with_clock(policy.clone(), clock.clone());
+            clamped_up.insert(vec![a_answer(1)]);
+            clock.advance(Duration::milliseconds(1100));
+            assert!(clamped_up.get("example.com", &QueryType::A).is_some());
+            assert!(clamped_up.needs_prefetch("example.com", &QueryType::A));
+
+            let clamped_down = DnsCache::with_policy(policy.clone());
+            clamped_down.insert(vec![a_answer(86400)]);
+            let entry_expiry = {
+                let cache = clamped_down.cache.lock().unwrap();
+                let entries = &cache.by_name["example.com"].record_types[&(QueryType::A, CLASS_IN)];
+                entries.iter().next().unwrap().expires_in
+            };
+            assert!(entry_expiry <= Local::now() + Duration::seconds(3600));
+            assert!(!clamped_down.needs_prefetch("example.com", &QueryType::A));
+
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let stale = DnsCache::with_clock(CachePolicy { min_ttl: 0, ..policy.clone() }, clock.clone());
+            stale.insert(vec![a_answer(0)]);
+            clock.advance(Duration::milliseconds(1100));
+            assert_eq!(stale.get("example.com", &QueryType::A).unwrap(), vec![a_answer(0)]);
+            assert_eq!(DnsCache::new().get("missing.com", &QueryType::A), None);
+
+            let negative = DnsCache::with_policy(policy);
+            negative.insert_negative("missing.com", &QueryType::A);
+            assert!(negative.is_negative("missing.com", &QueryType::A));
+            assert!(!negative.is_negative("missing.com", &QueryType::AAAA));
+            let disabled = DnsCache::new();
+            disabled.insert_negative("missing.com", &QueryType::A);
+            assert!(!disabled.is_negative("missing.com", &QueryType::A));
+        }
+
+        #[test]
+        fn test_dns_cache_separates_classes() {
+            let dns_cache = DnsCache::new();
+            let answer_in = a_answer(300);
+            let answer_ch = Answer {
+                class: CLASS_CH,
+                record: Record::A(Ipv4Addr::from_str("127.0.0.2").unwrap()),
+                ..a_answer(300)
+            };
+            dns_cache.insert(vec![answer_in.clone()]);
+            dns_cache.insert(vec![answer_ch.clone()]);
+
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![answer_in]);
+            assert_eq!(dns_cache.get_with_class("example.com", &QueryType::A, CLASS_CH).unwrap(), vec![answer_ch]);
+        }
+
+        #[test]
+        fn test_dns_cache_insert_groups_by_name() {
+            let dns_cache = DnsCache::new();
+            let cname = Answer {
+                name: "www.example.com".to_string(),
+                query_type: QueryType::CNAME,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 0,
+                record: Record::CNAME("example.com".to_string()),
+            };
+            dns_cache.insert(vec![cname.clone(), a_answer(300)]);
+
+            assert_eq!(dns_cache.get("www.example.com", &QueryType::CNAME).unwrap(), vec![cname.clone()]);
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![a_answer(300)]);
+            assert_eq!(dns_cache.get("www.example.com", &QueryType::A).unwrap(), vec![cname, a_answer(300)]);
+        }
+
+        #[test]
+        fn test_dns_cache_insert_all_caches_every_section_at_once() {
+            let dns_cache = DnsCache::new();
+            let ns = Answer {
+                name: "example.com".to_string(),
+                query_type: QueryType::NS,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 0,
+                record: Record::NS("ns1.example.com".to_string()),
+            };
+            let glue = Answer { name: "ns1.example.com".to_string(), ..a_answer(300) };
+            let second_a = Answer { record: Record::A(Ipv4Addr::new(127, 0, 0, 2)), ..a_answer(300) };
+            let mut packet = DnsPacket::new(Header::new(1, true, false, true, ResponseCode::NOERROR));
+            packet.add_answer(a_answer(300));
+            packet.add_authority(ns.clone());
+            packet.add_additional(glue.clone());
+            packet.add_additional(second_a.clone());
+            dns_cache.insert_all(&packet, "example.com");
+            {
+                // all names were touched by the same pass over the cache
+                let cache = dns_cache.cache.lock().unwrap();
+                assert_eq!(cache.by_name.len(), 2);
+                assert!(cache.by_name.values().all(|entry| entry.last_used == cache.by_name[&DomainName::new("example.com")].last_used));
+            }
+
+            let mut a_records = dns_cache.get("example.com", &QueryType::A).unwrap();
+            a_records.sort_by_key(|answer| answer.to_string());
+            assert_eq!(a_records, vec![a_answer(300), second_a]);
+            assert_eq!(dns_cache.get("example.com", &QueryType::NS).unwrap(), vec![ns]);
+            assert_eq!(dns_cache.get("ns1.example.com", &QueryType::A).unwrap(), vec![glue]);
+        }
+
+        #[test]
+        fn test_dns_cache_seeded_order_is_reproducible() {
+            let answers: Vec<Answer> = (1..=8)
+                .map(|i| Answer { record: Record::A(Ipv4Addr::new(10, 0, 0, i)), ..a_answer(300) })
+                .collect();
+            let first = DnsCache::with_seed(CachePolicy::default(), Some(7));
+            let second = DnsCache::with_seed(CachePolicy::default(), Some(7));
+            first.insert(answers.clone());
+            second.insert(answers.into_iter().rev().collect());
+
+            for _ in 0..3 {
+                let order = first.get("example.com", &QueryType::A).unwrap();
+                assert_eq!(order.len(), 8);
+                assert_eq!(order, second.get("example.com", &QueryType::A).unwrap());
+            }
+        }
+
+        #[test]
+        fn test_dns_cache_refresh_extends_expiry() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let dns_cache = DnsCache::with_clock(CachePolicy::default(), clock.clone());
+            dns_cache.insert(vec![a_answer(1)]);
+            clock.advance(Duration::milliseconds(500));
+            dns_cache.insert(vec![a_answer(300)]);
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![a_answer(300)]);
+            clock.advance(Duration::milliseconds(1500));
+
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![a_answer(300)]);
+        }
+
+        #[test]
+        fn test_dns_cache_chases_cname() {
+            let dns_cache = DnsCache::new();
+            let cname = Answer {
+                name: "www.example.com".to_string(),
+                query_type: QueryType::CNAME,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 0,
+                record: Record::CNAME("example.com".to_string()),
+            };
+            dns_cache.insert(vec![cname.clone()]);
+            assert_eq!(dns_cache.get("www.example.com", &QueryType::A), None);
+
+            dns_cache.insert(vec![a_answer(300)]);
+            assert_eq!(dns_cache.get("www.example.com", &QueryType::A).unwrap(), vec![cname.clone(), a_answer(300)]);
+            assert_eq!(dns_cache.get("www.example.com", &QueryType::CNAME).unwrap(), vec![cname]);
+        }
+
+        #[test]
+        fn test_dns_cache_closest_delegation() {
+            let dns_cache = DnsCache::new();
+            let ns = |zone: &str, server: &str| Answer {
+                name: zone.to_string(),
+                query_type: QueryType::NS,
+                class: CLASS_IN,
+                ttl: 300,
+                len: 0,
+                record: Record::NS(server.to_string()),
+            };
+            let mut referral = DnsPacket::new(Header::new(1, false, false, true, ResponseCode::NOERROR));
+            referral.add_authority(ns("com", "a.gtld-servers.net"));
+            referral.add_authority(ns("example.com", "ns1.example.com"));
+            referral.add_additional(Answer { name: "ns1.example.com".to_string(), ..a_answer(300) });
+            dns_cache.insert_all(&referral, "");
+
+            let delegation = dns_cache.closest_delegation("www.example.com").unwrap();
+            assert_eq!(delegation.zone, "example.com");
+            assert_eq!(delegation.addresses, vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]);
+            // the com delegation has no known address and is skipped
+            assert_eq!(dns_cache.closest_delegation("www.other.com"), None);
+            assert_eq!(dns_cache.closest_delegation("www.example.org"), None);
+        }
+
+        #[test]
+        fn test_dns_cache_keys_ignore_case() {
+            let dns_cache = DnsCache::new();
+            let answer = Answer { name: "Example.COM".to_string(), ..a_answer(300) };
+            dns_cache.insert(vec![answer.clone()]);
+
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![answer.clone()]);
+            assert_eq!(dns_cache.get("EXAMPLE.com.", &QueryType::A).unwrap(), vec![answer]);
+            assert_eq!(DomainName::new("Example.COM."), DomainName::from("example.com"));
+        }
+
+        #[test]
+        fn test_dns_cache_expiry_with_mock_clock() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let dns_cache = DnsCache::with_clock(CachePolicy::default(), clock.clone());
+            dns_cache.insert(vec![a_answer(60)]);
+
+            clock.advance(Duration::seconds(59));
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![a_answer(60)]);
+            clock.advance(Duration::seconds(2));
+            assert_eq!(dns_cache.get("example.com", &QueryType::A), None);
+        }
+
+        #[test]
+        fn test_zone_cuts_deepest_cut_expires_with_ttl() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let dns_cache = DnsCache::with_clock(CachePolicy::default(), clock.clone());
+            let ns = |zone: &str, ttl: u32| Answer {
+                name: zone.to_string(),
+                query_type: QueryType::NS,
+                class: CLASS_IN,
+                ttl,
+                len: 0,
+                record: Record::NS(format!("ns.{}", zone)),
+            };
+            let mut referral = DnsPacket::new(Header::new(1, false, false, true, ResponseCode::NOERROR));
+            referral.add_authority(ns("com", 600));
+            referral.add_authority(ns("example.com", 60));
+            dns_cache.insert_delegations(&referral);
+
+            // unlike closest_delegation, a cut without known addresses is still found
+            let zone_cuts = dns_cache.zone_cuts();
+            assert_eq!(zone_cuts.deepest_cut("www.example.com").unwrap().zone, "example.com");
+            assert_eq!(zone_cuts.deepest_cut("www.other.com").unwrap().zone, "com");
+            assert_eq!(zone_cuts.deepest_cut("www.example.org"), None);
+            clock.advance(Duration::seconds(61));
+            assert_eq!(zone_cuts.deepest_cut("www.example.com").unwrap().zone, "com");
+        }
+
+        #[test]
+        fn test_negative_answer_soa_ttl_counts_down_with_the_cache_clock() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let dns_cache = DnsCache::with_clock(CachePolicy { negative_ttl: 300, ..CachePolicy::default() }, clock.clone());
+            let mut response = DnsPacket::new(Header::new(1, false, false, true, ResponseCode::NXDOMAIN));
+            response.set_questions(vec![Question { name: "missing.example.com".to_string(), query_type: QueryType::A, class: CLASS_IN }]);
+            response.add_authority(Answer {
+                name: "example.com".to_string(),
+                query_type: QueryType::SOA,
+                class: CLASS_IN,
+                ttl: 3600,
+                len: 0,
+                record: Record::SOA {
+                    mname: "ns1.example.com".to_string(),
+                    rname: "hostmaster.example.com".to_string(),
+                    serial: 1,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 86400,
+                    minimum: 60,
+                },
+            });
+            dns_cache.insert_response(&response, "example.com");
+
+            clock.advance(Duration::seconds(20));
+            let negative = dns_cache.get_negative("missing.example.com", &QueryType::A).unwrap();
+            assert_eq!(negative.response_code, ResponseCode::NXDOMAIN);
+            assert_eq!(negative.soa.unwrap().ttl, 40);
+            clock.advance(Duration::seconds(41));
+            assert_eq!(dns_cache.get_negative("missing.example.com", &QueryType::A), None);
+        }
+
+        #[test]
+        fn test_zone_cuts_evict_the_first_to_expire() {
+            let dns_cache = DnsCache::with_policy(CachePolicy { max_zone_cuts: 2, ..CachePolicy::default() });
+            let referral = |zone: &str, ttl: u32| {
+                let mut referral = DnsPacket::new(Header::new(1, false, false, true, ResponseCode::NOERROR));
+                referral.add_authority(Answer {
+                    name: zone.to_string(),
+                    query_type: QueryType::NS,
+                    class: CLASS_IN,
+                    ttl,
+                    len: 0,
+                    record: Record::NS(format!("ns.{}", zone)),
+                });
+                referral
+            };
+            dns_cache.insert_delegations(&referral("com", 600));
+            dns_cache.insert_delegations(&referral("org", 60));
+            let usage = dns_cache.memory_usage();
+            assert!(usage > 0);
+            dns_cache.insert_delegations(&referral("net", 300));
+
+            let zone_cuts = dns_cache.zone_cuts();
+            assert_eq!(zone_cuts.len(), 2);
+            assert_eq!(zone_cuts.deepest_cut("example.org"), None);
+            assert_eq!(zone_cuts.deepest_cut("example.com").unwrap().zone, "com");
+            assert_eq!(zone_cuts.deepest_cut("example.net").unwrap().zone, "net");
+            assert_eq!(dns_cache.memory_usage(), usage);
+        }
+    
+        fn txt_answer(name: &str, len: usize) -> Answer {
+            Answer {
+                name: name.to_string(),
+                query_type: QueryType::TXT,
+                class: CLASS_IN,
+                ttl: 300,
+                len: len as u16 + 1,
+                record: Record::TXT(vec!["x".repeat(len)]),
+            }
+        }
+
+        #[test]
+        fn test_dns_cache_evicts_by_memory_usage() {
+            let dns_cache = DnsCache::with_policy(CachePolicy { max_bytes: 1000, ..CachePolicy::default() });
+            dns_cache.insert(vec![a_answer(300)]);
+            let small = dns_cache.memory_usage();
+            assert!(small > 0 && small < 100);
+            // reinserting the same record replaces it without growing the estimate
+            dns_cache.insert(vec![a_answer(300)]);
+            assert_eq!(dns_cache.memory_usage(), small);
+
+            dns_cache.insert(vec![txt_answer("one.example.com", 400)]);
+            dns_cache.insert(vec![txt_answer("two.example.com", 400)]);
+            assert!(dns_cache.memory_usage() <= 1000);
+            assert!(dns_cache.get("example.com", &QueryType::A).is_some());
+
+            // example.com was read last, so one.example.com is the least recently used
+            dns_cache.insert(vec![txt_answer("three.example.com", 400)]);
+            assert!(dns_cache.memory_usage() <= 1000);
+            assert_eq!(dns_cache.get("one.example.com", &QueryType::TXT), None);
+            assert!(dns_cache.get("two.example.com", &QueryType::TXT).is_some());
+            assert!(dns_cache.get("three.example.com", &QueryType::TXT).is_some());
+            assert!(dns_cache.get("example.com", &QueryType::A).is_some());
+            // every read moved its name in the eviction order instead of adding another
+            let cache = dns_cache.cache.lock().unwrap();
+            assert_eq!(cache.by_use.len(), cache.by_name.len());
+            assert_eq!(cache.by_use.first().unwrap().1, DomainName::new("two.example.com"));
+        }
+    
+        #[test]
+        fn test_dns_cache_snapshot_is_frozen() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let dns_cache = DnsCache::with_clock(CachePolicy::default(), clock.clone());
+            dns_cache.insert(vec![a_answer(60)]);
+            let snapshot = dns_cache.snapshot();
+
+            dns_cache.insert(vec![txt_answer("new.example.com", 10)]);
+            dns_cache.insert(vec![Answer { record: Record::A(Ipv4Addr::new(10, 0, 0, 2)), ..a_answer(60) }]);
+            clock.advance(Duration::seconds(120));
+            assert_eq!(dns_cache.get("example.com", &QueryType::A), None);
+            assert!(dns_cache.get("new.example.com", &QueryType::TXT).is_some());
+
+            // the snapshot answers as of the moment it was taken
+            assert_eq!(snapshot.get("example.com", &QueryType::A).unwrap(), vec![a_answer(60)]);
+            assert_eq!(snapshot.get("new.example.com", &QueryType::TXT), None);
+        }
+    
+        #[test]
+        fn test_dns_cache_huge_ttl_saturates() {
+            let far_future = (DateTime::<Utc>::MAX_UTC - Duration::days(30)).with_timezone(&Local);
+            let clock = Arc::new(MockClock::new(far_future));
+            let policy = CachePolicy { serve_stale: u32::MAX, ..CachePolicy::default() };
+            let dns_cache = DnsCache::with_clock(policy, clock.clone());
+            dns_cache.insert(vec![a_answer(u32::MAX)]);
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![a_answer(u32::MAX)]);
+
+            let dns_cache = DnsCache::new();
+            dns_cache.insert(vec![a_answer(u32::MAX)]);
+            assert_eq!(dns_cache.get("example.com", &QueryType::A).unwrap(), vec![a_answer(u32::MAX)]);
+        }
+
+        #[test]
+        fn test_dns_cache_entries_lists_live_record_sets() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let dns_cache = DnsCache::with_clock(CachePolicy::default(), clock.clone());
+            let second = Answer { record: Record::A(Ipv4Addr::new(10, 0, 0, 2)), ..a_answer(60) };
+            dns_cache.insert(vec![a_answer(300), second.clone(), txt_answer("www.example.com", 5)]);
+            dns_cache.insert(vec![txt_answer("mail.example.com", 5)]);
+            clock.advance(Duration::seconds(20));
+            dns_cache.insert(vec![Answer { ttl: 10, ..txt_answer("brief.example.com", 5) }]);
+            clock.advance(Duration::seconds(15));
+
+            let entries = dns_cache.entries();
+            let listed: Vec<(&str, QueryType)> = entries.iter()
+                .map(|(name, query_type, _, _)| (name.as_str(), query_type.clone()))
+                .collect();
+            assert_eq!(listed, vec![("example.com", QueryType::A), ("mail.example.com", QueryType::TXT), ("www.example.com", QueryType::TXT)]);
+            // the set expires with its shortest lived record
+            assert_eq!(entries[0].2, vec![second.clone(), a_answer(300)]);
+            assert_eq!(entries[0].3, 25);
+            assert_eq!(entries[2].3, 265);
+
+            let json: serde_json::Value = serde_json::from_str(&dns_cache.entries_json()).unwrap();
+            let first = &json.as_array().unwrap()[0];
+            assert_eq!(first["name"], "example.com");
+            assert_eq!(first["type"], "A");
+            assert_eq!(first["ttl"], 25);
+            assert_eq!(first["records"], serde_json::json!([second.record.to_string(), a_answer(300).record.to_string()]));
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,89 +1,413 @@
 pub mod dns_packet;
+pub mod names;
+pub mod query_log;
+pub mod rate_limit;
+pub mod server_config;
+pub mod transport;
+pub mod upstream;
+pub mod zone;
 
 pub mod dns_server {
     use std::io;
-    use tokio::time::timeout;
-    use std::time::Duration;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::Poll;
+    use std::time::{Duration, Instant};
+    use chrono::Local;
+    use tokio::task::{JoinError, JoinSet};
+    use tokio::time::{timeout, timeout_at};
     use std::io::{Error, ErrorKind};
-    use std::net::{Ipv4Addr, SocketAddr};
-    use std::str::FromStr;
-    use std::sync::Arc;
-    use tokio::net::UdpSocket;
+    use std::collections::{HashMap, HashSet};
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tokio::sync::{mpsc, oneshot, Notify, Semaphore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream, UdpSocket};
     use async_recursion::async_recursion;
-    use crate::dns_cache::dns_cache::DnsCache;
-    use crate::dns_server::dns_packet::dns_packet::{DnsPacket, Header, QueryType, Question, Record, ResponseCode};
-
-    const ROOT_SERVER_STRS: [&str; 13] = ["198.41.0.4",
-                                        "199.9.14.201",
-                                        "192.33.4.12",
-                                        "199.7.91.13",
-                                        "192.203.230.10",
-                                        "192.5.5.241",
-                                        "192.112.36.4",
-                                        "198.97.190.53",
-                                        "192.36.148.17",
-                                        "192.58.128.30",
-                                        "193.0.14.129",
-                                        "199.7.83.42",
-                                        "202.12.27.33",
-                                        ];
+    use crate::dns_cache::dns_cache::{Delegation, DnsCache, DomainName};
+    use crate::dns_server::dns_packet::buffer::buffer::BufferBuilder;
+    use crate::dns_server::dns_packet::dns_packet::{Answer, BADVERS, ede, flags, CLASS_CH, CLASS_IN, ClientSubnet, DnsPacket, ExtendedError, Header, OperationCode, QueryType, Question, Record, ResponseCode};
+    use crate::dns_server::names::names::{is_subdomain_of, label_count, names_equal};
+    use crate::dns_server::query_log::query_log::{QueryLogEntry, RequestTrace, trace, traced};
+    use crate::dns_server::rate_limit::rate_limit::{ResponseRateLimiter, RrlAction};
+    use crate::dns_server::server_config::server_config::{AnyQueries, MinimalResponses, SecondaryZone, ServerConfig, SpecialUse, SpecialUseDomain};
+    use crate::dns_server::transport::transport::{bind_udp, exchange_tcp, query_tcp, transfer_zone};
+    use crate::dns_server::upstream::upstream::{CircuitBreaker, EdnsCapabilities, EdnsSupport, TransportPreference, UpstreamSelector};
+    use crate::dns_server::zone::zone::{serial_is_newer, Zone, ZoneAnswer};
+
+    /// how many nameserver names or CNAME targets may be resolved nested within one query
+    const MAX_RESOLUTION_DEPTH: usize = 4;
+    /// how long to wait before retrying a secondary zone that was never transferred
+    const FIRST_TRANSFER_RETRY: Duration = Duration::from_secs(30);
+
+    /// server, lowercased name and type of an upstream query
+    type MemoKey = (IpAddr, String, QueryType);
+
+    /// A zone with the IPv4 addresses of its nameservers.
+    pub type ZoneCut = (String, Vec<Ipv4Addr>);
+
+    /// State of one top-level resolution.
+    #[derive(Default)]
+    struct LookupMemo {
+        /// upstream answers received so far, None for failed lookups. Lets a
+        /// nameserver referenced several times be queried only once
+        answers: Mutex<HashMap<MemoKey, Option<DnsPacket>>>,
+        /// when tracing, every zone the resolution of the name itself was
+        /// delegated to with the IPv4 addresses of the nameservers tried
+        delegations: Option<Mutex<Vec<ZoneCut>>>,
+    }
+
+    impl LookupMemo {
+        fn tracing_delegations() -> Self {
+            LookupMemo { answers: Mutex::default(), delegations: Some(Mutex::default()) }
+        }
+
+        /// Records that the servers at `addresses` were asked on behalf of `zone`.
+        fn record_delegation(&self, zone: &str, addresses: &[IpAddr], depth: usize) {
+            // nameserver names and CNAME targets resolved on the way are not part of the chain
+            let delegations = match &self.delegations {
+                Some(delegations) if depth == 0 => delegations,
+                _ => return,
+            };
+            let v4 = addresses.iter().filter_map(|addr| match addr {
+                IpAddr::V4(ip) => Some(*ip),
+                IpAddr::V6(_) => None,
+            });
+            let mut delegations = delegations.lock().unwrap();
+            match delegations.last_mut() {
+                Some((last, known)) if names_equal(last, zone) => {
+                    for ip in v4 {
+                        if !known.contains(&ip) {
+                            known.push(ip);
+                        }
+                    }
+                }
+                _ => delegations.push((zone.to_string(), v4.collect())),
+            }
+        }
+    }
+
+    /// an upstream lookup running concurrently with others for the same resolution
+    type InFlightLookup<'a> = Pin<Box<dyn Future<Output = (IpAddr, io::Result<DnsPacket>)> + Send + 'a>>;
+
+    /// Waits for whichever of the non-empty `futures` finishes first and removes it.
+    async fn first_completed<T>(futures: &mut Vec<Pin<Box<dyn Future<Output = T> + Send + '_>>>) -> T {
+        std::future::poll_fn(|cx| {
+            for idx in 0..futures.len() {
+                if let Poll::Ready(output) = futures[idx].as_mut().poll(cx) {
+                    drop(futures.swap_remove(idx));
+                    return Poll::Ready(output);
+                }
+            }
+            Poll::Pending
+        }).await
+    }
+
+    /// How a client query reached the server.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum Transport {
+        Udp,
+        Tcp,
+    }
+
+    /// What a client query asked for beyond its question, gathered before resolution.
+    struct QueryContext {
+        /// None when the query did not come from the network
+        client: Option<IpAddr>,
+        /// internal id correlating the log lines of one client request, 0 outside of one
+        request_id: u64,
+        transport: Transport,
+        has_edns: bool,
+        dnssec_ok: bool,
+        client_subnet: Option<ClientSubnet>,
+        /// the query was padded, asking for a padded response
+        padding: bool,
+        /// the query reached us encrypted, through one of `tls_proxies`
+        encrypted: bool,
+        /// set once the answer was served from the cache
+        cache_hit: bool,
+        /// set when policy refused the query, the response is then a plain refusal
+        refused: bool,
+        /// why resolution failed, sent to clients that use EDNS
+        extended_error: Option<ExtendedError>,
+        /// when upstream lookups for this query give up, `resolve_timeout` after it arrived
+        deadline: tokio::time::Instant,
+    }
+
+    impl QueryContext {
+        fn new(query: &DnsPacket, resolve_timeout: Duration) -> Self {
+            QueryContext {
+                client: None,
+                request_id: 0,
+                transport: Transport::Udp,
+                has_edns: query.edns().is_some(),
+                dnssec_ok: query.dnssec_ok(),
+                client_subnet: query.client_subnet(),
+                padding: query.has_padding(),
+                encrypted: false,
+                cache_hit: false,
+                refused: false,
+                extended_error: None,
+                deadline: tokio::time::Instant::now() + resolve_timeout,
+            }
+        }
+    }
+
+    /// `ip` as the last 32 bits of the /96 `prefix`.
+    fn embed_ipv4(prefix: Ipv6Addr, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = prefix.octets();
+        octets[12..].copy_from_slice(&ip.octets());
+        Ipv6Addr::from(octets)
+    }
+
+    /// `query` with its OPT record removed, None if it has none.
+    fn without_edns(query: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let mut packet = DnsPacket::from_buf(query)?;
+        if packet.edns().is_none() {
+            return Ok(None);
+        }
+        packet.set_additional(packet.additional.iter()
+            .filter(|additional| additional.query_type != QueryType::OPT)
+            .cloned()
+            .collect());
+        let (buf, amt) = packet.to_buf()?;
+        Ok(Some(buf[..amt].to_vec()))
+    }
+
+    /// Whether `response` to a query with an OPT record shows the server does not
+    /// understand EDNS.
+    fn rejects_edns(response: &DnsPacket) -> bool {
+        response.extended_response_code() == BADVERS
+            || (response.edns().is_none()
+                && matches!(response.header.get_response_code(), ResponseCode::FORMERR | ResponseCode::NOTIMP))
+    }
+
+    /// What the policy layer decided to send a udp client, before anything is sent.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ResolutionOutcome {
+        Answer(DnsPacket),
+        /// the configured refusal policy decided on, echoing the question
+        Refuse(DnsPacket),
+        /// nothing at all, the client has to time out
+        Drop,
+    }
+
+    type PendingLookups = HashMap<(SocketAddr, u16), oneshot::Sender<Vec<u8>>>;
+    type PrefetchKey = (DomainName, QueryType);
+
+    /// How many prefetches may wait for `serve_until` to start them; more are dropped.
+    const PREFETCH_QUEUE_LEN: usize = 64;
+
+    /// Rejects a response that does not repeat the questions of `query` exactly, in
+    /// the same casing, since a spoofed answer may carry none or another one.
+    fn check_echoed_questions(query: &[u8], response: &DnsPacket, server: SocketAddr) -> io::Result<()> {
+        let sent = DnsPacket::parse_borrowed(query)?.questions;
+        let echoed = sent.len() == response.questions.len() && sent.iter().zip(&response.questions)
+            .all(|(sent, echoed)| sent.name.to_name().is_ok_and(|name| name == echoed.name)
+                && sent.query_type == echoed.query_type
+                && sent.class == echoed.class);
+        if !echoed {
+            trace(format_args!("rejecting response from {} whose question does not match the query", server));
+            return Err(Error::new(ErrorKind::InvalidData, "response question does not match the query"));
+        }
+        Ok(())
+    }
+
+    /// Unregisters a pending lookup when dropped, including when the lookup is
+    /// cancelled by an enclosing timeout.
+    struct PendingGuard<'a> {
+        pending_lookups: &'a Mutex<PendingLookups>,
+        key: (SocketAddr, u16),
+    }
+
+    impl Drop for PendingGuard<'_> {
+        fn drop(&mut self) {
+            self.pending_lookups.lock().unwrap().remove(&self.key);
+        }
+    }
 
     pub struct DnsServer {
-        client_socket: UdpSocket,
+        client_sockets: Vec<UdpSocket>,
+        /// listeners for clients over tcp, only bound when `serve_tcp` is set
+        tcp_listeners: Vec<TcpListener>,
+        /// one permit per tcp connection being served
+        tcp_connections: Arc<Semaphore>,
+        /// only bound when `debug_http_addr` is set
+        debug_listener: Option<TcpListener>,
         lookup_socket: UdpSocket,
+        lookup_socket_v6: Option<UdpSocket>,
+        /// upstream queries waiting for a response, keyed by server and query id
+        pending_lookups: Mutex<PendingLookups>,
+        /// secret per process, hashes `query_id_counter` into unpredictable upstream query ids
+        query_id_keys: RandomState,
+        query_id_counter: AtomicU64,
+        next_request_id: AtomicU64,
+        edns_support: EdnsCapabilities,
         cache: DnsCache,
-        root_server_ips: Vec<Ipv4Addr>,
+        /// cached answers about to expire, refreshed in the background by `serve_until`
+        prefetch_queue: mpsc::Sender<(String, QueryType)>,
+        prefetch_receiver: Mutex<Option<mpsc::Receiver<(String, QueryType)>>>,
+        /// prefetches queued or running, so a popular name is refreshed once
+        prefetching: Mutex<HashSet<PrefetchKey>>,
+        /// secondary zones served authoritatively, keyed by origin
+        zones: Mutex<HashMap<DomainName, Arc<Zone>>>,
+        /// wakes the maintenance of a secondary zone when its primary sends a NOTIFY
+        refresh_signals: HashMap<DomainName, Notify>,
+        forwarders: UpstreamSelector,
+        breaker: CircuitBreaker,
+        transports: TransportPreference,
+        rrl: ResponseRateLimiter,
+        config: ServerConfig,
     }
 
     impl DnsServer {
-        pub async fn new(addr: &str) -> io::Result<DnsServer> {
+        pub async fn new(addrs: &[&str]) -> io::Result<DnsServer> {
+            DnsServer::with_config(addrs, ServerConfig::default()).await
+        }
+
+        /// Binds one client socket per address in `addrs`, all served by the same resolver and cache.
+        pub async fn with_config(addrs: &[&str], config: ServerConfig) -> io::Result<DnsServer> {
+            if addrs.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "no listen address given"));
+            }
+            let (recv_buffer, send_buffer) = (config.udp_recv_buffer, config.udp_send_buffer);
+            let lookup_socket_v6 = match bind_udp(&config.lookup_addr_v6, recv_buffer, send_buffer).await {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    println!("ipv6 lookups disabled, could not bind {}: {}", config.lookup_addr_v6, e);
+                    None
+                }
+            };
+            let mut client_sockets = Vec::with_capacity(addrs.len());
+            for addr in addrs {
+                client_sockets.push(bind_udp(addr, recv_buffer, send_buffer).await?);
+            }
+            // tcp listens on the same addresses, ports picked for udp included
+            let mut tcp_listeners = Vec::new();
+            if config.serve_tcp {
+                for socket in &client_sockets {
+                    tcp_listeners.push(TcpListener::bind(socket.local_addr()?).await?);
+                }
+            }
+            let lookup_socket = bind_udp(&config.lookup_addr, recv_buffer, send_buffer).await?;
+            let debug_listener = match &config.debug_http_addr {
+                Some(addr) => Some(TcpListener::bind(addr).await?),
+                None => None,
+            };
+            let mut server = DnsServer::from_sockets(client_sockets, lookup_socket, lookup_socket_v6, config)?;
+            server.tcp_listeners = tcp_listeners;
+            server.debug_listener = debug_listener;
+            Ok(server)
+        }
+
+        /// Serves clients on sockets that are already bound, e.g. handed over by systemd
+        /// or bound to port 0 in tests. `lookup_addr` and `lookup_addr_v6` of `config` are ignored
+        /// and no tcp listeners are bound.
+        pub fn from_sockets(client_sockets: Vec<UdpSocket>, lookup_socket: UdpSocket, lookup_socket_v6: Option<UdpSocket>, config: ServerConfig) -> io::Result<DnsServer> {
+            if client_sockets.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidInput, "no client socket given"));
+            }
+            if config.edns_udp_payload_size < 512 {
+                return Err(Error::new(ErrorKind::InvalidInput, "edns udp payload size must be within 512 and 65535"));
+            }
+            let (prefetch_queue, prefetch_receiver) = mpsc::channel(PREFETCH_QUEUE_LEN);
             let server = DnsServer {
-                client_socket: UdpSocket::bind(addr).await?,
-                lookup_socket: UdpSocket::bind("0.0.0.0:3267").await?,
-                cache: DnsCache::new(),
-                root_server_ips: ROOT_SERVER_STRS
-                    .iter()
-                    .filter_map(|ip_str | match Ipv4Addr::from_str(ip_str) {
-                        Ok(ip) => Some(ip),
-                        _ => None,
-                    }).collect(),
+                client_sockets,
+                tcp_listeners: Vec::new(),
+                debug_listener: None,
+                tcp_connections: Arc::new(Semaphore::new(config.max_tcp_connections)),
+                lookup_socket,
+                lookup_socket_v6,
+                pending_lookups: Mutex::new(HashMap::new()),
+                query_id_keys: RandomState::new(),
+                query_id_counter: AtomicU64::new(0),
+                next_request_id: AtomicU64::new(1),
+                edns_support: EdnsCapabilities::default(),
+                cache: DnsCache::with_policy(config.cache_policy.clone()),
+                prefetch_queue,
+                prefetch_receiver: Mutex::new(Some(prefetch_receiver)),
+                prefetching: Mutex::new(HashSet::new()),
+                zones: Mutex::new(HashMap::new()),
+                refresh_signals: config.secondary_zones.iter()
+                    .map(|secondary| (DomainName::new(&secondary.origin), Notify::new()))
+                    .collect(),
+                forwarders: UpstreamSelector::new(config.upstream_strategy, config.forwarders.len()),
+                breaker: CircuitBreaker::new(config.circuit_breaker),
+                transports: TransportPreference::new(config.tcp_fallback),
+                rrl: ResponseRateLimiter::new(config.response_rate_limit),
+                config,
             };
             Ok(server)
         }
 
+        pub async fn recursive_lookup(&self, out_buf: &[u8], ips: impl Iterator<Item = IpAddr> + Send) -> io::Result<DnsPacket> {
+            self.recursive_lookup_at(out_buf, ips, "", &LookupMemo::default(), 0).await
+        }
+
+        /// `zone` is what the servers in `ips` are authoritative for, only records within it are cached.
+        /// `depth` counts how many nameserver names or CNAME targets are being resolved
+        /// on behalf of the original query, bounding mutual recursion between them.
         #[async_recursion]
-        pub async fn recursive_lookup<'a>(&self, out_buf: &[u8], ips: impl Iterator<Item = &'a Ipv4Addr> + Send + 'async_recursion) -> io::Result<DnsPacket> {
-            for addr in ips {
-                println!("looking up ip: {:#?}", addr);
-                let packet = self.lookup(addr, &out_buf).await?;
+        async fn recursive_lookup_at(&self, out_buf: &[u8], ips: impl Iterator<Item = IpAddr> + Send + 'async_recursion, zone: &str, memo: &LookupMemo, depth: usize) -> io::Result<DnsPacket> {
+            let question = DnsPacket::from_buf(out_buf).ok()
+                .and_then(|query| query.question().map(|q| (q.name.to_lowercase(), q.query_type.clone())));
+            let mut ips = ips.filter(|addr| {
+                let allowed = self.breaker.allow(addr);
+                if !allowed {
+                    trace(format_args!("skipping {} while its circuit is open", addr));
+                }
+                allowed
+            });
+            // up to `lookup_parallelism` servers are queried at once, the first answer is used
+            let mut in_flight: Vec<InFlightLookup> = Vec::new();
+            loop {
+                while in_flight.len() < self.config.lookup_parallelism.max(1) {
+                    let addr = match ips.next() {
+                        Some(addr) => addr,
+                        None => break,
+                    };
+                    trace(format_args!("looking up ip: {:#?}", addr));
+                    let key = question.clone().map(|(name, query_type)| (addr, name, query_type));
+                    in_flight.push(Box::pin(async move { (addr, self.memoized_lookup(&addr, out_buf, key, memo).await) }));
+                }
+                if in_flight.is_empty() {
+                    break;
+                }
+                let (addr, result) = first_completed(&mut in_flight).await;
+                let mut packet = match result {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        trace(format_args!("lookup at {} failed: {}", addr, e));
+                        continue;
+                    }
+                };
                 let res_code = packet.header.get_response_code();
-                if !packet.answers.is_empty() &&
-                   (res_code == ResponseCode::NOERROR || res_code == ResponseCode::NXDOMAIN) {
-                    self.cache.insert_all(&packet);
-                    return Ok(packet);
-                } else if packet.header.additional_count > 0 {
-                    println!("starting recursive lookup with additional");
-                    let ips = packet.get_resolved_ns(&packet.questions.first().expect("123").name);
-                    self.cache.insert_all(&packet);
-                    let res = self.recursive_lookup(&out_buf, ips).await?;
-                    return Ok(res);
+                if res_code != ResponseCode::NOERROR && res_code != ResponseCode::NXDOMAIN {
+                    trace(format_args!("{} answered {:?}", addr, res_code));
+                    continue;
                 }
-                else if packet.header.authoritiy_count > 0 {
-                    println!("starting recursive lookup without additional");
-                    self.cache.insert_all(&packet);
-                    let name_servers = packet.get_unresolved_ns(&packet.questions.first().expect("123").name);
-                    for (server_name, _) in name_servers {
-                        let mut packet  = DnsPacket::new(
-                            Header::new(1, true, false, ResponseCode::NOERROR));
-                        packet.add_question(Question{
-                            name: server_name.to_string(),
-                            query_type: QueryType::A,
-                            class: 1,
-                        });
-                        let (buf, amt) = packet.to_buf()?;
-                        let packet_ns = self.recursive_lookup(&buf[..amt], self.root_server_ips.iter()).await?;
-                        let ips = packet_ns.get_ipv4_iterator_answers();
-                        let res= self.recursive_lookup(&out_buf, ips).await?;
+                // the other lookups would only hold on to their pending entries and sockets
+                // while the delegation or CNAME is followed
+                in_flight.clear();
+                self.cache.insert_response(&packet, zone);
+                if !packet.answers.is_empty() {
+                    if res_code == ResponseCode::NOERROR {
+                        if packet.answer_cname_from_additional(zone) {
+                            trace(format_args!("cname target answered in the additional section"));
+                        }
+                        if let Some(target) = packet.unresolved_cname() {
+                            let target = target.to_string();
+                            return self.follow_cname(packet, &target, memo, depth).await;
+                        }
+                    }
+                    return Ok(packet);
+                } else if packet.negative_soa().is_some() {
+                    return Ok(packet);
+                } else if packet.header.authoritiy_count > 0 {
+                    if let Ok(res) = self.follow_delegation(out_buf, &packet, zone, memo, depth).await {
                         return Ok(res);
                     }
                 }
@@ -91,92 +415,1487 @@ pub mod dns_server {
                     return Err(Error::new(ErrorKind::InvalidInput, "packet contains nothing"));
                 }
             }
-            return Err(Error::new(ErrorKind::InvalidInput, "rec lookup error"));
+            Err(Error::new(ErrorKind::InvalidInput, "rec lookup error"))
+        }
+
+        /// Like `lookup`, but answers a query already sent to `addr` during this
+        /// resolution from `memo`, including its failure.
+        async fn memoized_lookup(&self, addr: &IpAddr, out_buf: &[u8], key: Option<MemoKey>, memo: &LookupMemo) -> io::Result<DnsPacket> {
+            let key = match key {
+                Some(key) => key,
+                None => return self.checked_lookup(addr, out_buf).await,
+            };
+            let memoized = memo.answers.lock().unwrap().get(&key).cloned();
+            let result = match memoized {
+                Some(Some(mut packet)) => {
+                    trace(format_args!("reusing answer of {} for {}", addr, key.1));
+                    packet.header.id = u16::from_be_bytes([out_buf[0], out_buf[1]]);
+                    return Ok(packet);
+                }
+                Some(None) => return Err(Error::new(ErrorKind::TimedOut, "upstream already failed to answer")),
+                None => self.checked_lookup(addr, out_buf).await,
+            };
+            memo.answers.lock().unwrap().insert(key, result.as_ref().ok().cloned());
+            result
+        }
+
+        /// Like `lookup`, reporting the outcome to the circuit breaker. SERVFAIL counts as a failure.
+        async fn checked_lookup(&self, addr: &IpAddr, out_buf: &[u8]) -> io::Result<DnsPacket> {
+            let result = self.lookup(addr, out_buf).await;
+            match &result {
+                Ok(packet) if packet.header.get_response_code() != ResponseCode::SERVFAIL => self.breaker.record_success(addr),
+                _ => self.breaker.record_failure(addr),
+            }
+            result
+        }
+
+        /// Resolves `target`, the end of the CNAME chain in `packet`, and appends
+        /// its answers so the packet answers the original question.
+        async fn follow_cname(&self, mut packet: DnsPacket, target: &str, memo: &LookupMemo, depth: usize) -> io::Result<DnsPacket> {
+            if depth >= MAX_RESOLUTION_DEPTH {
+                return Err(Error::new(ErrorKind::InvalidInput, "cname chain nested too deeply"));
+            }
+            let query_type = packet.question()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "response without a question"))?
+                .query_type.clone();
+            trace(format_args!("following cname to {}", target));
+            let (buf, amt) = self.outbound_query(DnsPacket::query(1, target, query_type)).to_buf()?;
+            let resolved = self.iterative_cache_resolve_at(target, &buf[..amt], memo, depth + 1).await?;
+            packet.header.set_response_code(resolved.header.get_response_code());
+            let mut answers = std::mem::take(&mut packet.answers);
+            answers.extend(resolved.answers);
+            packet.set_answers(answers);
+            Ok(packet)
+        }
+
+        /// Tries every nameserver of the closest enclosing zone in `referral`,
+        /// first through the glue records, then by resolving the remaining names
+        /// starting from the closest cached delegation.
+        #[async_recursion]
+        async fn follow_delegation(&self, out_buf: &[u8], referral: &DnsPacket, zone: &str, memo: &LookupMemo, depth: usize) -> io::Result<DnsPacket> {
+            let qname = &referral.question()
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "referral without a question"))?
+                .name;
+            // servers may only hand out zones below their own
+            let delegated = match referral.get_unresolved_ns(qname).next() {
+                Some((_, delegated)) if is_subdomain_of(delegated, zone) => delegated.to_string(),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "referral outside the server's zone")),
+            };
+            let mut glue: Vec<IpAddr> = referral.get_glue_addresses(qname).collect();
+            self.config.glue_preference.order(&mut glue);
+            if !glue.is_empty() {
+                trace(format_args!("starting recursive lookup with additional"));
+                memo.record_delegation(&delegated, &glue, depth);
+                if let Ok(res) = self.recursive_lookup_at(out_buf, glue.into_iter(), &delegated, memo, depth).await {
+                    return Ok(res);
+                }
+            }
+            let (in_bailiwick, unglued): (Vec<_>, Vec<_>) = referral.get_unresolved_ns(qname)
+                .filter(|(server_name, _)| referral.get_address_iterator_additional()
+                    .all(|(_, additional_name)| !names_equal(additional_name, server_name)))
+                .partition(|(server_name, zone)| is_subdomain_of(server_name, zone));
+            // a nameserver inside the zone it serves can only be found through that zone,
+            // so without glue it is usable only if its address is already cached
+            let cached: Vec<IpAddr> = in_bailiwick.iter()
+                .flat_map(|(server_name, zone)| {
+                    let cached = self.cache.get(server_name, &QueryType::A).unwrap_or_default();
+                    if cached.is_empty() {
+                        trace(format_args!("skipping {} inside {} as the referral has no glue for it", server_name, zone));
+                    }
+                    cached
+                })
+                .filter_map(|answer| match answer.record {
+                    Record::A(ip) => Some(IpAddr::V4(ip)),
+                    _ => None,
+                })
+                .collect();
+            if !cached.is_empty() {
+                memo.record_delegation(&delegated, &cached, depth);
+                if let Ok(res) = self.recursive_lookup_at(out_buf, cached.into_iter(), &delegated, memo, depth).await {
+                    return Ok(res);
+                }
+            }
+            if !unglued.is_empty() && depth >= MAX_RESOLUTION_DEPTH {
+                return Err(Error::new(ErrorKind::InvalidInput, "nameserver resolution nested too deeply"));
+            }
+            for (server_name, _) in unglued {
+                trace(format_args!("starting recursive lookup without additional for {}", server_name));
+                let packet = self.outbound_query(DnsPacket::query(1, server_name, QueryType::A));
+                let (buf, amt) = packet.to_buf()?;
+                let packet_ns = match self.iterative_cache_resolve_at(server_name, &buf[..amt], memo, depth + 1).await {
+                    Ok(packet_ns) => packet_ns,
+                    Err(_) => continue,
+                };
+                let ips: Vec<IpAddr> = packet_ns.get_ipv4_iterator_answers().map(|ip| IpAddr::V4(*ip)).collect();
+                memo.record_delegation(&delegated, &ips, depth);
+                if let Ok(res) = self.recursive_lookup_at(out_buf, ips.into_iter(), &delegated, memo, depth).await {
+                    return Ok(res);
+                }
+            }
+            Err(Error::new(ErrorKind::InvalidInput, "no reachable nameserver in delegation"))
+        }
+
+        /// Queries `addr`, falling back to a query without OPT record when the server
+        /// seems not to understand EDNS: it answers BADVERS, or FORMERR or NOTIMP without
+        /// an OPT record of its own, or it times out while it is known to answer queries
+        /// without EDNS. A timeout on first contact says nothing about EDNS and is not retried.
+        /// Servers found that way are queried without EDNS for a while.
+        pub async fn lookup(&self, addr: &IpAddr, out_buf: &[u8]) -> io::Result<DnsPacket> {
+            let plain = match without_edns(out_buf)? {
+                Some(plain) => plain,
+                None => return self.lookup_once(addr, out_buf).await,
+            };
+            let support = self.edns_support.get(addr);
+            if support == Some(EdnsSupport::Unsupported) {
+                return self.lookup_once(addr, &plain).await;
+            }
+            let result = self.lookup_once(addr, out_buf).await;
+            let edns_rejected = match &result {
+                Ok(packet) => rejects_edns(packet),
+                Err(e) => e.kind() == ErrorKind::TimedOut && support == Some(EdnsSupport::Ignored),
+            };
+            if !edns_rejected {
+                if let Ok(packet) = &result {
+                    let support = if packet.edns().is_some() { EdnsSupport::Supported } else { EdnsSupport::Ignored };
+                    self.edns_support.record(addr, support);
+                }
+                return result;
+            }
+            trace(format_args!("retrying {} without edns", addr));
+            let fallback = self.lookup_once(addr, &plain).await;
+            if fallback.as_ref().is_ok_and(|packet|
+                !matches!(packet.header.get_response_code(), ResponseCode::FORMERR | ResponseCode::NOTIMP)) {
+                trace(format_args!("{} does not support edns, querying it without for a while", addr));
+                self.edns_support.record(addr, EdnsSupport::Unsupported);
+                return fallback;
+            }
+            result
         }
 
-        pub async fn lookup(&self, addr: &Ipv4Addr, out_buf: &[u8]) -> io::Result<DnsPacket> {
-            self.lookup_socket.send_to(&out_buf, (*addr,53 as u16)).await?;
-            let mut buf =  [0u8;512];
-            let amt = timeout(Duration::from_secs(1),self.lookup_socket.recv(&mut buf)).await??;
-            Ok(DnsPacket::from_buf(&buf[..amt])?)
+        /// Queries `addr`, resending up to `lookup_retries` times with exponential backoff.
+        /// As the backoff never exceeds `lookup_timeout`, a server is given at most
+        /// (2 * retries + 1) * lookup_timeout in total.
+        async fn lookup_once(&self, addr: &IpAddr, out_buf: &[u8]) -> io::Result<DnsPacket> {
+            let socket = match addr {
+                IpAddr::V4(_) => &self.lookup_socket,
+                IpAddr::V6(_) => self.lookup_socket_v6.as_ref()
+                    .ok_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "no ipv6 lookup socket"))?,
+            };
+            if out_buf.len() < 2 {
+                return Err(Error::new(ErrorKind::InvalidInput, "query too short"));
+            }
+            let original_id = u16::from_be_bytes([out_buf[0], out_buf[1]]);
+            let server = SocketAddr::new(*addr, self.config.upstream_port);
+            let (tx, mut rx) = oneshot::channel();
+            // every in-flight query gets its own random id so concurrent lookups sharing
+            // the socket can tell their responses apart and spoofers can not guess it
+            let id = {
+                let mut pending_lookups = self.pending_lookups.lock().unwrap();
+                let id = loop {
+                    let id = self.random_query_id();
+                    if !pending_lookups.contains_key(&(server, id)) {
+                        break id;
+                    }
+                };
+                pending_lookups.insert((server, id), tx);
+                id
+            };
+            let guard = PendingGuard { pending_lookups: &self.pending_lookups, key: (server, id) };
+            let mut query = out_buf.to_vec();
+            query[..2].copy_from_slice(&id.to_be_bytes());
+
+            if self.transports.use_tcp(addr) {
+                trace(format_args!("sending lookup {} to {} over tcp", id, server));
+                let mut packet = self.lookup_tcp(server, &query, id).await?;
+                check_echoed_questions(out_buf, &packet, server)?;
+                packet.header.id = original_id;
+                return Ok(packet);
+            }
+            let res = async {
+                let mut backoff = self.config.lookup_backoff;
+                for attempt in 0..=self.config.lookup_retries {
+                    if attempt > 0 {
+                        trace(format_args!("retrying lookup {} at {}, attempt {}", id, server, attempt + 1));
+                        // a late answer to an earlier attempt is still accepted while backing off
+                        if let Ok(res) = timeout(backoff, self.receive_response(socket, &mut rx)).await {
+                            return res;
+                        }
+                        backoff = (backoff * 2).min(self.config.lookup_timeout);
+                    }
+                    if attempt == 0 {
+                        trace(format_args!("sending lookup {} to {}", id, server));
+                    }
+                    socket.send_to(&query, server).await?;
+                    if let Ok(res) = timeout(self.config.lookup_timeout, self.receive_response(socket, &mut rx)).await {
+                        return res;
+                    }
+                }
+                Err(Error::new(ErrorKind::TimedOut, "upstream did not answer"))
+            }.await;
+            drop(guard);
+
+            let mut packet = match res {
+                Ok(buf) => {
+                    self.transports.record_udp_answer(addr);
+                    match DnsPacket::from_buf(&buf) {
+                        Ok(packet) => packet,
+                        // a datagram cut short by a server ignoring our payload size may be complete over tcp,
+                        // anything else that fails to parse is malformed and not worth a second try
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                            trace(format_args!("response {} from {} ends early, retrying over tcp", id, server));
+                            self.lookup_tcp(server, &query, id).await?
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                // an upstream losing udp over and over is asked over tcp right away
+                Err(e) if e.kind() == ErrorKind::TimedOut && self.transports.record_udp_timeout(addr) => {
+                    trace(format_args!("lookup {} to {} timed out over udp, retrying over tcp", id, server));
+                    self.lookup_tcp(server, &query, id).await?
+                }
+                Err(e) => return Err(e),
+            };
+            check_echoed_questions(out_buf, &packet, server)?;
+            packet.header.id = original_id;
+            Ok(packet)
+        }
+
+        /// The next query id, the counter hashed with the secret keys of this process.
+        fn random_query_id(&self) -> u16 {
+            let mut hasher = self.query_id_keys.build_hasher();
+            hasher.write_u64(self.query_id_counter.fetch_add(1, Ordering::Relaxed));
+            hasher.finish() as u16
+        }
+
+        /// Sends `query`, carrying the lookup `id`, to `server` over tcp.
+        async fn lookup_tcp(&self, server: SocketAddr, query: &[u8], id: u16) -> io::Result<DnsPacket> {
+            let packet = DnsPacket::from_buf(&exchange_tcp(server, query, self.config.lookup_timeout).await?)?;
+            if packet.header.id != id {
+                return Err(Error::new(ErrorKind::InvalidData, "tcp response id does not match the query"));
+            }
+            Ok(packet)
+        }
+
+        /// Reads responses from `socket` and hands each to the lookup waiting for it
+        /// until the response for `rx` arrives.
+        async fn receive_response(&self, socket: &UdpSocket, rx: &mut oneshot::Receiver<Vec<u8>>) -> io::Result<Vec<u8>> {
+            let mut buf = vec![0u8; self.config.edns_udp_payload_size as usize];
+            loop {
+                tokio::select! {
+                    res = &mut *rx => {
+                        return res.map_err(|_| Error::other("lookup abandoned"));
+                    }
+                    res = socket.recv_from(&mut buf) => {
+                        let (amt, from) = res?;
+                        if amt < 2 {
+                            continue;
+                        }
+                        let id = u16::from_be_bytes([buf[0], buf[1]]);
+                        match self.pending_lookups.lock().unwrap().remove(&(from, id)) {
+                            Some(waiting) => {
+                                let _ = waiting.send(buf[..amt].to_vec());
+                            }
+                            None => trace(format_args!("dropping unexpected response {} from {}", id, from)),
+                        }
+                    }
+                }
+            }
+        }
+
+        pub async fn lookup_v4(&self, addr: &Ipv4Addr, out_buf: &[u8]) -> io::Result<DnsPacket> {
+            self.lookup(&IpAddr::V4(*addr), out_buf).await
+        }
+
+        /// The primed root nameservers while their delegation is cached, the root hints otherwise.
+        fn root_servers(&self) -> impl Iterator<Item = IpAddr> + Send {
+            match self.cache.delegation("") {
+                Some(root) => root.addresses,
+                None => self.root_hints().collect(),
+            }.into_iter()
+        }
+
+        fn root_hints(&self) -> impl Iterator<Item = IpAddr> + Send + '_ {
+            self.config.root_server_ips.iter().map(|ip| IpAddr::V4(*ip))
+        }
+
+        /// Asks the root hints one after another for the root NS set and caches it,
+        /// with its glue, as the delegation of the root zone. Resolutions start from
+        /// that delegation until it expires and fall back to the hints afterwards
+        /// or when priming fails.
+        pub async fn prime(&self) -> io::Result<()> {
+            let mut query = DnsPacket::query(0, "", QueryType::NS);
+            query.header.set_recursion_desired(false);
+            query.set_edns(self.config.edns_udp_payload_size, false);
+            let (buf, amt) = query.to_buf()?;
+            for hint in self.root_hints() {
+                let mut priming = match self.checked_lookup(&hint, &buf[..amt]).await {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        println!("could not prime from {}: {}", hint, e);
+                        continue;
+                    }
+                };
+                let root_ns: Vec<Answer> = priming.answers.iter()
+                    .filter(|answer| answer.name.is_empty() && matches!(answer.record, Record::NS(_)))
+                    .cloned()
+                    .collect();
+                if !priming.header.get_authoritative_answer() || root_ns.is_empty() {
+                    println!("ignoring priming response from {} without an authoritative root NS set", hint);
+                    continue;
+                }
+                // the root NS set is the delegation of the root zone, cached like any referral
+                priming.set_authorities(root_ns);
+                self.cache.insert_all(&priming, "");
+                if let Some(root) = self.cache.delegation("") {
+                    println!("primed {} root nameservers from {}", root.name_servers.len(), hint);
+                    return Ok(());
+                }
+                println!("priming response from {} carried no root nameserver addresses", hint);
+            }
+            Err(Error::new(ErrorKind::NotFound, "no root hint answered the priming query"))
+        }
+
+        /// Sends the query to the configured forwarders in the order picked by
+        /// `upstream_strategy`, falling back to the next one on failure.
+        pub async fn forward(&self, out_buf: &[u8]) -> io::Result<DnsPacket> {
+            if out_buf.len() < 3 {
+                return Err(Error::new(ErrorKind::InvalidInput, "query too short"));
+            }
+            // forwarders resolve on our behalf, so they are asked to recurse
+            let mut query = out_buf.to_vec();
+            query[2] |= flags::RECURSION_DESIRED;
+            let out_buf = &query[..];
+            // a forwarder failing to resolve is passed over like one that does not answer
+            let mut failed = None;
+            for idx in self.forwarders.order() {
+                let addr = self.config.forwarders[idx];
+                if !self.breaker.allow(&addr) {
+                    trace(format_args!("skipping forwarder {} while its circuit is open", addr));
+                    continue;
+                }
+                let started = std::time::Instant::now();
+                match self.checked_lookup(&addr, out_buf).await {
+                    Ok(packet) if matches!(packet.header.get_response_code(), ResponseCode::SERVFAIL | ResponseCode::REFUSED) => {
+                        trace(format_args!("forwarder {} answered {:?}", addr, packet.header.get_response_code()));
+                        // a quick failure must not make the forwarder look fast
+                        self.forwarders.record(idx, self.config.lookup_timeout);
+                        failed = Some(packet);
+                    }
+                    Ok(packet) => {
+                        self.forwarders.record(idx, started.elapsed());
+                        // forwarders resolve on our behalf and are trusted for every name
+                        self.cache.insert_response(&packet, "");
+                        return Ok(packet);
+                    }
+                    Err(e) => {
+                        trace(format_args!("forwarding to {} failed: {}", addr, e));
+                        self.forwarders.record(idx, self.config.lookup_timeout);
+                    }
+                }
+            }
+            failed.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no forwarder answered"))
         }
 
         pub async fn iterative_cache_resolve(&self, name: &str, out_buf: &[u8]) -> io::Result<DnsPacket> {
-            let labels: Vec<&str> = name.split('.').collect();
-            for label_idx in 0..labels.len() {
-                let domain = labels[label_idx..].join(".");
-                if let Some(nss) = self.cache.get(&domain, &QueryType::NS) {
-                    println!("found subdomain {} while resolving {}", domain, name);
-                    let ns_name_iter = nss.iter()
-                        .filter_map(|ans| match &ans.record {
-                            Record::NS(name)=> Some(name),
-                            _ => None,
-                        });
-                    for ns_name in ns_name_iter  {
-                        if let Some(resolved_ns) = self.cache.get(ns_name, &QueryType::A){
-                            let ips = resolved_ns.iter()
-                                .filter_map(|ans| match &ans.record {
-                                    Record::A(ip) => Some(ip),
-                                    _ => None,
-                                });
-                            return Ok(self.recursive_lookup(&out_buf, ips).await?);
+            self.iterative_cache_resolve_at(name, out_buf, &LookupMemo::default(), 0).await
+        }
+
+        #[async_recursion]
+        async fn iterative_cache_resolve_at(&self, name: &str, out_buf: &[u8], memo: &LookupMemo, depth: usize) -> io::Result<DnsPacket> {
+            if let Some(cut) = self.cache.zone_cuts().deepest_cut(name) {
+                trace(format_args!("starting at cached delegation {} while resolving {}", cut.zone, name));
+                let cut = self.cache.with_cached_addresses(cut);
+                if !cut.addresses.is_empty() {
+                    if let Ok(res) = self.recursive_lookup_at(out_buf, cut.addresses.into_iter(), &cut.zone, memo, depth).await {
+                        return Ok(res);
+                    }
+                } else if depth < MAX_RESOLUTION_DEPTH {
+                    // nameservers inside the zone can only be found through it
+                    for server_name in cut.name_servers.iter().filter(|server_name| !is_subdomain_of(server_name, &cut.zone)) {
+                        let packet = self.outbound_query(DnsPacket::query(1, server_name, QueryType::A));
+                        let (buf, amt) = packet.to_buf()?;
+                        let packet_ns = match self.iterative_cache_resolve_at(server_name, &buf[..amt], memo, depth + 1).await {
+                            Ok(packet_ns) => packet_ns,
+                            Err(_) => continue,
+                        };
+                        let ips = packet_ns.get_ipv4_iterator_answers().map(|ip| IpAddr::V4(*ip));
+                        if let Ok(res) = self.recursive_lookup_at(out_buf, ips, &cut.zone, memo, depth).await {
+                            return Ok(res);
                         }
                     }
-                } else {
-                    continue
                 }
             }
-            Ok(self.recursive_lookup(&out_buf, self.root_server_ips.iter()).await?)
+            self.recursive_lookup_at(out_buf, self.root_servers(), "", memo, depth).await
+        }
 
+        /// The zone cuts from the root down to the zone of `name`, each with the IPv4
+        /// addresses of its nameservers, as followed while resolving `name` from the root
+        /// servers. A delegation that cannot be followed ends the chain early, showing
+        /// where it breaks.
+        pub async fn trace_delegation(&self, name: &str) -> Vec<ZoneCut> {
+            let memo = LookupMemo::tracing_delegations();
+            let root: Vec<IpAddr> = self.root_servers().collect();
+            memo.record_delegation("", &root, 0);
+            let query = self.outbound_query(DnsPacket::query(1, name, QueryType::A));
+            match query.to_buf() {
+                Ok((buf, amt)) => {
+                    if let Err(e) = self.recursive_lookup_at(&buf[..amt], root.into_iter(), "", &memo, 0).await {
+                        trace(format_args!("could not resolve {} while tracing its delegation: {}", name, e));
+                    }
+                }
+                Err(e) => trace(format_args!("could not trace the delegation of {}: {}", name, e)),
+            }
+            memo.delegations.map(|delegations| delegations.into_inner().unwrap()).unwrap_or_default()
         }
 
-        pub async fn resolve_request(&self, client: SocketAddr, query: DnsPacket) {
-            let mut header = Header::new(query.header.id, true, true, ResponseCode::NOERROR);
-            header.set_recursion_available(true);
-            let mut response;
-            if query.questions.is_empty() {
-                header.set_response_code(ResponseCode::FORMERR);
-                response = DnsPacket::new(header);
-            } else if matches!(query.questions.first().unwrap().query_type, QueryType::UNKOWN(_)) {
-                header.set_response_code(ResponseCode::NOTIMP);
-                response = DnsPacket::new(header);
+        /// Addresses the client sockets are bound to, in the order they were given.
+        pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+            self.client_sockets.iter().map(|socket| socket.local_addr()).collect()
+        }
+
+        /// Addresses clients can reach the server at over tcp, empty unless `serve_tcp` is set.
+        pub fn local_tcp_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+            self.tcp_listeners.iter().map(|listener| listener.local_addr()).collect()
+        }
+
+        /// Address of the debug http endpoint, None unless `debug_http_addr` is set.
+        pub fn local_debug_addr(&self) -> Option<io::Result<SocketAddr>> {
+            self.debug_listener.as_ref().map(|listener| listener.local_addr())
+        }
+
+        /// Address upstream queries are sent from, with the port picked when `lookup_addr` asks for 0.
+        pub fn lookup_local_addr(&self) -> io::Result<SocketAddr> {
+            self.lookup_socket.local_addr()
+        }
+
+        /// Resolves `query` and answers `client` through the client socket at `socket_idx`.
+        pub async fn resolve_request(&self, socket_idx: usize, client: SocketAddr, query: DnsPacket) {
+            let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+            let request = RequestTrace { id: request_id, log: self.config.trace_log.clone() };
+            traced(request, async {
+                let started = Instant::now();
+                let id = query.header.id;
+                let question = query.question().cloned();
+                let mut context = QueryContext::new(&query, self.config.resolve_timeout);
+                context.client = Some(client.ip());
+                context.request_id = request_id;
+                trace(format_args!("received query {} for {} from {}", id,
+                                   question.as_ref().map_or("", |question| question.name.as_str()), client));
+                let mut echo = query.clone();
+                echo.dedup_questions();
+                let outcome = self.resolve_outcome(client, query, &mut context).await;
+                let response = match self.deliver(socket_idx, client, &echo, outcome).await {
+                    Some(response) => response,
+                    None => return,
+                };
+                trace(format_args!("answered {} with {:?} and {} answers", client,
+                                   response.header.get_response_code(), response.answers.len()));
+                self.log_query(client, question.as_ref(), &response, &context, started);
+            }).await
+        }
+
+        /// Resolves `query` and decides what to send for it. Udp responses are rate
+        /// limited as only udp can be spoofed, policy refusals become `Refuse`.
+        async fn resolve_outcome(&self, client: SocketAddr, query: DnsPacket, context: &mut QueryContext) -> ResolutionOutcome {
+            let mut response = self.checked_response(query, context).await;
+            self.cap_answers(&mut response);
+            match self.rrl.check(client.ip(), &response) {
+                RrlAction::Send if context.refused => ResolutionOutcome::Refuse(response),
+                RrlAction::Send => ResolutionOutcome::Answer(response),
+                RrlAction::Slip => {
+                    let mut slipped = self.error_response(&response, response.header.get_response_code());
+                    slipped.header.set_truncated_message(true);
+                    ResolutionOutcome::Answer(slipped)
+                }
+                RrlAction::Drop => ResolutionOutcome::Drop,
+            }
+        }
+
+        /// Keeps the first `max_answers` records of the asked type, which come rotated from
+        /// the cache, and every other record such as the CNAMEs leading to them.
+        fn cap_answers(&self, response: &mut DnsPacket) {
+            let max_answers = match self.config.max_answers {
+                Some(max_answers) => max_answers.max(1),
+                None => return,
+            };
+            let query_type = match response.question() {
+                Some(question) => question.query_type.clone(),
+                None => return,
+            };
+            let mut kept = 0;
+            let answers: Vec<Answer> = std::mem::take(&mut response.answers).into_iter()
+                .filter(|answer| {
+                    if answer.query_type != query_type {
+                        return true;
+                    }
+                    kept += 1;
+                    kept <= max_answers
+                })
+                .collect();
+            if kept > max_answers {
+                trace(format_args!("sending {} of {} answers", max_answers, kept));
+                if self.config.max_answers_truncates {
+                    response.header.set_truncated_message(true);
+                }
+            }
+            response.set_answers(answers);
+            // the padding was sized for the answers before capping
+            if let (true, Some(block)) = (response.has_padding(), self.config.padding_block_size) {
+                response.pad_to_block(block);
+            }
+        }
+
+        /// Sends what `outcome` asks for in answer to `query` and returns the response
+        /// sent, None if nothing was.
+        pub async fn deliver(&self, socket_idx: usize, client: SocketAddr, query: &DnsPacket, outcome: ResolutionOutcome) -> Option<DnsPacket> {
+            let id = query.header.id;
+            let response = match outcome {
+                ResolutionOutcome::Answer(response) | ResolutionOutcome::Refuse(response) => response,
+                ResolutionOutcome::Drop => {
+                    trace(format_args!("rate limiting response {} to {}", id, client));
+                    return None;
+                }
+            };
+            // what does not fit the payload size both sides accept is retried over tcp
+            let max_size = match query.edns() {
+                Some(_) => query.udp_payload_size().min(self.config.edns_udp_payload_size.max(512)),
+                None => 512,
+            };
+            let response = response.truncated_to(max_size as usize);
+            let buf = match response.to_vec() {
+                Ok(buf) => buf,
+                Err(e) => {
+                    trace(format_args!("could not serialize response {}: {}", id, e));
+                    return None;
+                }
+            };
+            if let Err(e) = self.client_sockets[socket_idx].send_to(&buf, client).await {
+                trace(format_args!("could not send response {} to {}: {}", id, client, e));
+            }
+            Some(response)
+        }
+
+        /// Builds the response to `query`, replacing a malformed one by SERVFAIL in debug builds.
+        async fn checked_response(&self, query: DnsPacket, context: &mut QueryContext) -> DnsPacket {
+            let id = query.header.id;
+            let mut echo = DnsPacket::new(query.header);
+            echo.set_questions(query.questions.clone());
+            let mut response = self.build_response_in(query, context).await;
+            if cfg!(debug_assertions) {
+                if let Err(e) = response.validate() {
+                    trace(format_args!("refusing to send malformed response {}: {}", id, e));
+                    response = self.error_response(&echo, ResponseCode::SERVFAIL);
+                    if context.has_edns {
+                        response.set_edns(self.config.edns_udp_payload_size, context.dnssec_ok);
+                        response.set_extended_error(&e.extended_error());
+                    }
+                }
+            }
+            response
+        }
+
+        fn log_query(&self, client: SocketAddr, question: Option<&Question>, response: &DnsPacket, context: &QueryContext, started: Instant) {
+            if let Some(query_log) = &self.config.query_log {
+                query_log.log(&QueryLogEntry {
+                    timestamp: Local::now(),
+                    request_id: context.request_id,
+                    client: client.ip(),
+                    qname: question.map(|question| question.name.clone()).unwrap_or_default(),
+                    qtype: question.map(|question| question.query_type.to_string()).unwrap_or_default(),
+                    rcode: format!("{:?}", response.header.get_response_code()),
+                    answers: response.answers.len(),
+                    cache_hit: context.cache_hit,
+                    latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                });
+            }
+        }
+
+        /// Accepts tcp clients on the listener at `listener_idx`, serving each connection in its own task.
+        async fn accept_tcp(self: Arc<Self>, listener_idx: usize) {
+            let listener = &self.tcp_listeners[listener_idx];
+            let mut connections = JoinSet::new();
+            loop {
+                tokio::select! {
+                    res = listener.accept() => match res {
+                        Ok((stream, client)) => {
+                            let permit = match Arc::clone(&self.tcp_connections).try_acquire_owned() {
+                                Ok(permit) => permit,
+                                Err(_) => {
+                                    println!("closing tcp connection from {}, too many connections", client);
+                                    continue;
+                                }
+                            };
+                            let self_clone = Arc::clone(&self);
+                            connections.spawn(async move {
+                                self_clone.serve_tcp_connection(stream, client).await;
+                                drop(permit);
+                            });
+                        }
+                        Err(e) => println!("could not accept tcp client: {}", e),
+                    },
+                    Some(res) = connections.join_next(), if !connections.is_empty() => {
+                        log_task_result(res);
+                    }
+                }
+            }
+        }
+
+        /// Answers http requests on the debug listener one connection at a time.
+        async fn serve_debug_http(&self) {
+            let listener = match &self.debug_listener {
+                Some(listener) => listener,
+                None => return,
+            };
+            loop {
+                match listener.accept().await {
+                    Ok((stream, client)) => {
+                        if let Err(e) = timeout(self.config.tcp_idle_timeout, self.answer_debug_request(stream)).await
+                            .unwrap_or_else(|_| Err(Error::new(ErrorKind::TimedOut, "request timed out"))) {
+                            println!("could not answer debug request from {}: {}", client, e);
+                        }
+                    }
+                    Err(e) => println!("could not accept debug client: {}", e),
+                }
+            }
+        }
+
+        /// Sends the cache contents for `GET /cache` and 404 for anything else.
+        async fn answer_debug_request(&self, mut stream: TcpStream) -> io::Result<()> {
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let amt = stream.read(&mut buf).await?;
+                if amt == 0 || request.len() > 8192 {
+                    return Err(Error::new(ErrorKind::InvalidData, "incomplete http request"));
+                }
+                request.extend_from_slice(&buf[..amt]);
+            }
+            let (status, body) = if request.starts_with(b"GET /cache ") {
+                ("200 OK", self.cache.entries_json())
             } else {
-                let question = query.questions.first().unwrap();
-                if let Some(cached) = self.cache.get(&question.name, &question.query_type) {
-                    println!("found in cache");
+                ("404 Not Found", String::new())
+            };
+            let response = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                   status, body.len(), body);
+            stream.write_all(response.as_bytes()).await?;
+            stream.shutdown().await
+        }
+
+        /// Answers length-prefixed queries on `stream` one after another until the client
+        /// closes it or stays idle for `tcp_idle_timeout`, which also bounds reading each
+        /// query. Clients sending the tcp keepalive option are told that timeout in every response.
+        async fn serve_tcp_connection(&self, mut stream: TcpStream, client: SocketAddr) {
+            let mut out_buf = vec![0u8; u16::MAX as usize];
+            loop {
+                let len = match timeout(self.config.tcp_idle_timeout, stream.read_u16()).await {
+                    Ok(Ok(len)) => len,
+                    Ok(Err(_)) => return,
+                    Err(_) => {
+                        println!("closing idle tcp connection from {}", client);
+                        return;
+                    }
+                };
+                let mut in_buf = vec![0u8; len as usize];
+                match timeout(self.config.tcp_idle_timeout, stream.read_exact(&mut in_buf)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        println!("could not read query from {}: {}", client, e);
+                        return;
+                    }
+                    Err(_) => {
+                        println!("closing tcp connection from {} sending a query too slowly", client);
+                        return;
+                    }
+                }
+                let query = match DnsPacket::from_buf(&in_buf) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        println!("closing tcp connection from {} after unparsable query: {}", client, e);
+                        return;
+                    }
+                };
+                if query.header.get_query_response() {
+                    println!("dropping response packet {} from {}", query.header.id, client);
+                    continue;
+                }
+                let started = Instant::now();
+                let id = query.header.id;
+                let question = query.question().cloned();
+                let keepalive = query.has_tcp_keepalive();
+                let mut context = QueryContext::new(&query, self.config.resolve_timeout);
+                context.client = Some(client.ip());
+                context.transport = Transport::Tcp;
+                context.encrypted = self.config.tls_proxies.contains(&client.ip());
+                context.request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+                let request = RequestTrace { id: context.request_id, log: self.config.trace_log.clone() };
+                let sent = traced(request, async {
+                    let mut response = self.checked_response(query, &mut context).await;
+                    if keepalive {
+                        response.set_tcp_keepalive(self.config.tcp_idle_timeout);
+                    }
+                    let mut builder = BufferBuilder::new(&mut out_buf);
+                    if let Err(e) = response.write_to_buf(&mut builder) {
+                        trace(format_args!("could not serialize response {}: {}", id, e));
+                        return None;
+                    }
+                    let amt = builder.get_pos();
+                    let sent = async {
+                        stream.write_all(&(amt as u16).to_be_bytes()).await?;
+                        stream.write_all(&out_buf[..amt]).await
+                    }.await;
+                    if let Err(e) = sent {
+                        trace(format_args!("could not send response {} to {}: {}", id, client, e));
+                        return None;
+                    }
+                    Some(response)
+                }).await;
+                let response = match sent {
+                    Some(response) => response,
+                    None => return,
+                };
+                self.log_query(client, question.as_ref(), &response, &context, started);
+            }
+        }
+
+        pub async fn build_response(&self, query: DnsPacket) -> DnsPacket {
+            let mut context = QueryContext::new(&query, self.config.resolve_timeout);
+            self.build_response_in(query, &mut context).await
+        }
+
+        async fn build_response_in(&self, mut query: DnsPacket, context: &mut QueryContext) -> DnsPacket {
+            // a repeated question is resolved, sent upstream and echoed only once
+            query.dedup_questions();
+            let mut scope_prefix_len = 0;
+            // set for answers from recursion or forwarders, the only ones rebinding protection filters
+            let mut from_upstream = false;
+            let recursion_available = !self.config.authoritative_only;
+            let mut header = Header::new(query.header.id, query.header.get_recursion_desired(),
+                                         recursion_available, true, ResponseCode::NOERROR);
+            let mut response;
+            // version 0 is the only one there is, anything newer gets BADVERS
+            let bad_version = query.edns_version().is_some_and(|version| version > 0);
+            if bad_version {
+                response = self.error_response(&query, ResponseCode::NOERROR);
+            } else if self.config.reject_z_bit && query.header.get_z() {
+                response = self.error_response(&query, ResponseCode::FORMERR);
+            } else if query.header.get_op_code() == OperationCode::Notify {
+                response = self.notify_response(&query, context);
+            } else if query.header.get_op_code() == OperationCode::Update {
+                // dynamic updates are not supported
+                response = self.refused_response(&query, context);
+            } else if query.header.get_op_code() != OperationCode::Query {
+                response = self.error_response(&query, ResponseCode::NOTIMP);
+            } else if let Some(question) = query.question() {
+                if matches!(question.query_type, QueryType::UNKOWN(_)) {
+                    response = self.error_response(&query, ResponseCode::NOTIMP);
+                } else if question.query_type.is_zone_transfer() {
+                    response = if self.may_transfer(context) {
+                        // allowed, but transfers are not implemented yet
+                        self.error_response(&query, ResponseCode::NOTIMP)
+                    } else {
+                        self.refused_response(&query, context)
+                    };
+                } else if question.class == CLASS_CH {
+                    response = self.chaos_response(&query, question, context);
+                } else if self.is_refused(question) {
+                    response = self.refused_response(&query, context);
+                } else if question.query_type == QueryType::ANY && self.config.any_queries == AnyQueries::Rfc8482 {
+                    response = self.minimal_any_response(&query, question);
+                } else if let Some(special_use) = self.special_use(&question.name) {
+                    response = self.special_use_response(&query, question, special_use);
+                } else if let Some(zone) = self.zone_for(&question.name) {
+                    response = self.zone_response(&query, &zone, question);
+                } else if let Some(cached) = self.cache.get_with_class(&question.name, &question.query_type, question.class) {
+                    trace(format_args!("found in cache"));
+                    context.cache_hit = true;
+                    if self.cache.needs_prefetch(&question.name, &question.query_type) {
+                        self.queue_prefetch(&question.name, &question.query_type);
+                    }
+                    from_upstream = true;
                     response = DnsPacket::new(header);
-                    response.set_questions(query.questions);
+                    response.set_questions(query.questions.clone());
                     response.set_answers(cached);
+                } else if let Some(negative) = self.cache.get_negative(&question.name, &question.query_type) {
+                    trace(format_args!("found negative answer in cache"));
+                    context.cache_hit = true;
+                    header.set_response_code(negative.response_code);
+                    response = DnsPacket::new(header);
+                    response.set_questions(query.questions.clone());
+                    if let Some(soa) = negative.soa {
+                        response.add_authority(soa);
+                    }
+                } else if self.config.authoritative_only {
+                    response = match self.cache.closest_delegation(&question.name) {
+                        Some(delegation) => self.referral_response(&query, &delegation),
+                        None => self.refused_response(&query, context),
+                    };
                 } else {
-                    let (buf, bytes_written) = query.to_buf().unwrap();
-                    if let Ok(packet) = self.iterative_cache_resolve(&question.name, &buf[..bytes_written]).await {
-                        response = packet;
-                    } else {
-                        header.set_response_code(ResponseCode::SERVFAIL);
-                        response = DnsPacket::new(header);
+                    match self.resolve_upstream(&query, &question.name, context).await {
+                        Ok(packet) => {
+                            from_upstream = true;
+                            if let Some(subnet) = packet.client_subnet() {
+                                scope_prefix_len = subnet.scope_prefix_len;
+                            }
+                            // build our own response instead of relaying the upstream header
+                            header.set_response_code(packet.header.get_response_code());
+                            response = DnsPacket::new(header);
+                            response.set_questions(query.questions.clone());
+                            response.set_answers(packet.answers);
+                            response.set_authorities(packet.authorities);
+                            response.set_additional(packet.additional.into_iter()
+                                .filter(|additional| additional.query_type != QueryType::OPT)
+                                .collect());
+                            response.retain_relevant_additional();
+                        }
+                        Err(e) => {
+                            context.extended_error = Some(ExtendedError::from_io_error(&e));
+                            response = self.error_response(&query, ResponseCode::SERVFAIL);
                         }
                     }
                 }
-            let (buf, amt) = response.to_buf().unwrap();
-            self.client_socket.send_to(&buf[..amt],client).await.unwrap();
+            } else {
+                response = self.error_response(&query, ResponseCode::FORMERR);
+            }
+            if let Some(prefix) = self.config.dns64_prefix {
+                self.synthesize_dns64(&query, &mut response, prefix, context).await;
+            }
+            // local data such as localhost and secondary zones is trusted
+            if self.config.rebinding_protection && from_upstream {
+                self.filter_private_answers(&mut response);
+            }
+            if !context.dnssec_ok {
+                response.strip_dnssec_records();
+            }
+            self.minimize(&mut response);
+            if let Some(ttl) = self.config.fixed_ttl {
+                response.set_all_ttls(ttl);
+            }
+            if context.has_edns {
+                response.set_edns(self.config.edns_udp_payload_size, context.dnssec_ok);
+            }
+            if bad_version {
+                response.set_extended_response_code(BADVERS);
+            }
+            if let Some(error) = &context.extended_error {
+                response.set_extended_error(error);
+            }
+            if let Some(mut subnet) = context.client_subnet.clone() {
+                subnet.scope_prefix_len = scope_prefix_len;
+                response.set_client_subnet(&subnet);
+            }
+            if context.padding && context.encrypted {
+                if let Some(block) = self.config.padding_block_size {
+                    response.pad_to_block(block);
+                }
+            }
+            response
+        }
+
+        /// Sends `query` to the forwarders or resolves it iteratively, giving up at the
+        /// deadline of `context`.
+        async fn resolve_upstream(&self, query: &DnsPacket, name: &str, context: &QueryContext) -> io::Result<DnsPacket> {
+            let mut outbound = self.outbound_query(query.clone());
+            let forwarding = !self.config.forwarders.is_empty();
+            if let (true, Some(subnet)) = (forwarding, &context.client_subnet) {
+                outbound.set_client_subnet(subnet);
+            }
+            let resolution = async {
+                match outbound.to_buf() {
+                    Ok((buf, bytes_written)) if !forwarding =>
+                        self.iterative_cache_resolve(name, &buf[..bytes_written]).await,
+                    Ok((buf, bytes_written)) => self.forward(&buf[..bytes_written]).await,
+                    Err(e) => Err(e),
+                }
+            };
+            match timeout_at(context.deadline, resolution).await {
+                Ok(resolved) => resolved,
+                Err(_) => {
+                    trace(format_args!("resolving {} exceeded {:?}", name, self.config.resolve_timeout));
+                    Err(Error::new(ErrorKind::TimedOut, "resolution deadline exceeded"))
+                }
+            }
+        }
+
+        /// The A records of `name` from a local zone, the cache or upstream, for DNS64.
+        /// Upstream lookups share the deadline of the AAAA query they are made for.
+        async fn dns64_a_records(&self, a_query: &DnsPacket, question: &Question, context: &QueryContext) -> Vec<Answer> {
+            if let Some(zone) = self.zone_for(&question.name) {
+                return self.zone_response(a_query, &zone, question).answers;
+            }
+            if let Some(cached) = self.cache.get_with_class(&question.name, &QueryType::A, CLASS_IN) {
+                return cached;
+            }
+            if self.config.authoritative_only || self.cache.get_negative(&question.name, &QueryType::A).is_some() {
+                return Vec::new();
+            }
+            match self.resolve_upstream(a_query, &question.name, context).await {
+                Ok(packet) if packet.header.get_response_code() == ResponseCode::NOERROR => packet.answers,
+                _ => Vec::new(),
+            }
+        }
+
+        /// Turns a NODATA answer to an AAAA query into AAAA records embedding the name's
+        /// A records in the NAT64 `prefix`. CNAMEs leading to the A records are kept.
+        /// IPv4-mapped AAAA records count as absent (RFC 6147 5.1.4) and validating
+        /// clients, sending DO and CD, get the answer untouched (5.5).
+        async fn synthesize_dns64(&self, query: &DnsPacket, response: &mut DnsPacket, prefix: Ipv6Addr, context: &QueryContext) {
+            let question = match query.question() {
+                Some(question) if question.query_type == QueryType::AAAA && question.class == CLASS_IN => question,
+                _ => return,
+            };
+            if query.dnssec_ok() && query.header.get_checking_disabled() {
+                return;
+            }
+            let usable_aaaa = response.answers.iter().any(|answer| match answer.record {
+                Record::AAAA(ip) => ip.to_ipv4_mapped().is_none(),
+                _ => false,
+            });
+            if response.header.get_response_code() != ResponseCode::NOERROR || usable_aaaa {
+                return;
+            }
+            let a_query = DnsPacket::query(query.header.id, &question.name, QueryType::A);
+            let a_question = match a_query.question() {
+                Some(a_question) => a_question,
+                None => return,
+            };
+            let a_answers = self.dns64_a_records(&a_query, a_question, context).await;
+            if !a_answers.iter().any(|answer| answer.query_type == QueryType::A) {
+                return;
+            }
+            // the synthesized records live no longer than the proof there is no AAAA record,
+            // 600s when there was no SOA to tell (5.1.7)
+            let max_ttl = response.negative_ttl().unwrap_or(600);
+            trace(format_args!("synthesizing AAAA records for {} in {}", question.name, prefix));
+            response.set_answers(a_answers.into_iter()
+                .map(|answer| match answer.record {
+                    Record::A(ip) => Answer {
+                        query_type: QueryType::AAAA,
+                        ttl: answer.ttl.min(max_ttl),
+                        len: 16,
+                        record: Record::AAAA(embed_ipv4(prefix, ip)),
+                        ..answer
+                    },
+                    _ => answer,
+                })
+                .collect());
+            // the SOA proving there is no AAAA record no longer fits the answer
+            response.set_authorities(Vec::new());
+        }
+
+        /// Leaves out the sections `minimal_responses` asks to drop. Only responses with
+        /// answers are touched, referrals keep their glue and negative answers their SOA.
+        fn minimize(&self, response: &mut DnsPacket) {
+            if response.answers.is_empty() {
+                return;
+            }
+            match self.config.minimal_responses {
+                MinimalResponses::Disabled => {}
+                MinimalResponses::Additional => response.set_additional(Vec::new()),
+                MinimalResponses::AdditionalAndAuthority => {
+                    response.set_additional(Vec::new());
+                    response.set_authorities(Vec::new());
+                }
+            }
+        }
+
+        /// Drops address records pointing into `private_ranges`.
+        fn filter_private_answers(&self, response: &mut DnsPacket) {
+            let answers = std::mem::take(&mut response.answers).into_iter()
+                .filter(|answer| {
+                    let ip = match answer.record {
+                        Record::A(ip) => IpAddr::V4(ip),
+                        Record::AAAA(ip) => IpAddr::V6(ip),
+                        _ => return true,
+                    };
+                    let private = self.config.private_ranges.iter().any(|range| range.contains(&ip));
+                    if private {
+                        trace(format_args!("dropping private address {} for {}", ip, answer.name));
+                    }
+                    !private
+                })
+                .collect();
+            response.set_answers(answers);
+        }
+
+        /// Prepares a query for upstream servers, advertising our edns payload size.
+        /// RD is cleared as authoritative servers are not asked to recurse, `forward`
+        /// sets it again for forwarders.
+        fn outbound_query(&self, mut query: DnsPacket) -> DnsPacket {
+            query.header.set_recursion_desired(false);
+            let dnssec_ok = query.dnssec_ok();
+            query.set_edns(self.config.edns_udp_payload_size, dnssec_ok);
+            query
+        }
+
+        pub fn cache(&self) -> &DnsCache {
+            &self.cache
+        }
+
+        /// Resolves `names` into the cache, running at most `warm_up_parallelism`
+        /// resolutions at a time. Failures are logged and skipped.
+        pub async fn warm_cache(self: &Arc<Self>, names: &[(String, QueryType)]) {
+            let mut tasks = JoinSet::new();
+            for (name, query_type) in names {
+                if tasks.len() >= self.config.warm_up_parallelism.max(1) {
+                    tasks.join_next().await;
+                }
+                if self.cache.get(name, query_type).is_some() {
+                    continue;
+                }
+                let server = Arc::clone(self);
+                let (name, query_type) = (name.clone(), query_type.clone());
+                tasks.spawn(async move {
+                    let (buf, amt) = match server.outbound_query(DnsPacket::query(1, &name, query_type)).to_buf() {
+                        Ok(res) => res,
+                        Err(e) => {
+                            println!("could not warm {}: {}", name, e);
+                            return;
+                        }
+                    };
+                    if let Err(e) = server.iterative_cache_resolve(&name, &buf[..amt]).await {
+                        println!("could not warm {}: {}", name, e);
+                    }
+                });
+            }
+            while tasks.join_next().await.is_some() {}
         }
 
-        pub async fn start(self: Arc<Self>) {
+        /// Asks `serve_until` to resolve `name` again before its cached answer expires.
+        fn queue_prefetch(&self, name: &str, query_type: &QueryType) {
+            let key = (DomainName::new(name), query_type.clone());
+            if !self.prefetching.lock().unwrap().insert(key.clone()) {
+                return;
+            }
+            if self.prefetch_queue.try_send((name.to_string(), query_type.clone())).is_err() {
+                trace(format_args!("prefetch queue is full, not refreshing {}", name));
+                self.prefetching.lock().unwrap().remove(&key);
+            }
+        }
+
+        /// Runs the queued prefetches until the queue is closed.
+        async fn run_prefetches(self: Arc<Self>, mut queue: mpsc::Receiver<(String, QueryType)>) {
+            let mut running = JoinSet::new();
             loop {
-                let mut buf =  [0u8;512];
-                let (_, client) = self.client_socket.recv_from(&mut buf)
-                    .await
-                    .expect("could recv packet from client");
-                let in_packet = DnsPacket::from_buf(&buf)
-                    .expect("could parse packet from client");
+                tokio::select! {
+                    Some(res) = running.join_next(), if !running.is_empty() => {
+                        log_task_result(res);
+                    }
+                    next = queue.recv() => match next {
+                        Some((name, query_type)) => {
+                            let server = Arc::clone(&self);
+                            running.spawn(async move { server.prefetch(&name, query_type).await });
+                        }
+                        None => break,
+                    },
+                }
+            }
+            while let Some(res) = running.join_next().await {
+                log_task_result(res);
+            }
+        }
+
+        /// Resolves `name` upstream, replacing its cached records.
+        async fn prefetch(&self, name: &str, query_type: QueryType) {
+            let key = (DomainName::new(name), query_type.clone());
+            trace(format_args!("prefetching {} {:?}", name, query_type));
+            let resolution = async {
+                let (buf, amt) = self.outbound_query(DnsPacket::query(1, name, query_type)).to_buf()?;
+                if self.config.forwarders.is_empty() {
+                    self.iterative_cache_resolve(name, &buf[..amt]).await
+                } else {
+                    self.forward(&buf[..amt]).await
+                }
+            };
+            match timeout(self.config.resolve_timeout, resolution).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => trace(format_args!("could not prefetch {}: {}", name, e)),
+                Err(_) => trace(format_args!("prefetching {} exceeded {:?}", name, self.config.resolve_timeout)),
+            }
+            self.prefetching.lock().unwrap().remove(&key);
+        }
+
+        /// Whether policy forbids answering `question`: blocklisted names and non-IN classes.
+        fn is_refused(&self, question: &Question) -> bool {
+            question.class != CLASS_IN || self.config.blocklist.iter()
+                .any(|blocked| is_subdomain_of(&question.name, blocked))
+        }
+
+        /// Transfers `secondary` from its primary and starts serving the new copy.
+        pub async fn transfer_secondary(&self, secondary: &SecondaryZone) -> io::Result<Arc<Zone>> {
+            let records = transfer_zone(secondary.primary, &secondary.origin, self.config.transfer_timeout).await?;
+            let zone = Arc::new(Zone::from_transfer(&secondary.origin, records)?);
+            println!("transferred zone {} serial {} from {}", zone.origin(), zone.serial(), secondary.primary);
+            self.zones.lock().unwrap().insert(DomainName::new(&secondary.origin), Arc::clone(&zone));
+            Ok(zone)
+        }
+
+        /// Transfers `secondary` again unless the SOA of its primary shows the copy
+        /// served has the current serial, which then counts as refreshed.
+        async fn refresh_secondary(&self, secondary: &SecondaryZone) -> io::Result<Arc<Zone>> {
+            let current = self.zones.lock().unwrap().get(secondary.origin.as_str()).cloned();
+            if let Some(zone) = current {
+                let query = DnsPacket::query(self.random_query_id(), &secondary.origin, QueryType::SOA);
+                let response = query_tcp(secondary.primary, &query, self.config.transfer_timeout).await?;
+                let serial = response.answers.iter()
+                    .find_map(|answer| match answer.record {
+                        Record::SOA { serial, .. } if names_equal(&answer.name, &secondary.origin) => Some(serial),
+                        _ => None,
+                    })
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "primary did not answer with the zone's SOA"))?;
+                if !serial_is_newer(zone.serial(), serial) {
+                    println!("zone {} serial {} is current", zone.origin(), zone.serial());
+                    zone.mark_refreshed();
+                    return Ok(zone);
+                }
+            }
+            self.transfer_secondary(secondary).await
+        }
+
+        /// Keeps `secondary` up to date, refreshing it whenever its SOA refresh
+        /// interval has passed or its primary sent a NOTIFY, and after the retry interval
+        /// when a refresh failed.
+        async fn maintain_secondary(&self, secondary: SecondaryZone) {
+            let refresh_signal = &self.refresh_signals[&DomainName::new(&secondary.origin)];
+            loop {
+                let wait = match self.refresh_secondary(&secondary).await {
+                    Ok(zone) => zone.refresh(),
+                    Err(e) => {
+                        println!("could not refresh zone {} from {}: {}", secondary.origin, secondary.primary, e);
+                        self.zones.lock().unwrap().get(secondary.origin.as_str())
+                            .map_or(FIRST_TRANSFER_RETRY, |zone| zone.retry())
+                    }
+                };
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = refresh_signal.notified() => {}
+                }
+            }
+        }
+
+        /// Answers a NOTIFY (RFC 1996) for the SOA of a secondary zone. Only the zone's
+        /// primary may send one, and it makes the zone be transferred again right away.
+        fn notify_response(&self, query: &DnsPacket, context: &mut QueryContext) -> DnsPacket {
+            let question = match query.question() {
+                Some(question) if question.query_type == QueryType::SOA => question,
+                _ => return self.error_response(query, ResponseCode::FORMERR),
+            };
+            let secondary = self.config.secondary_zones.iter()
+                .find(|secondary| names_equal(&secondary.origin, &question.name));
+            match secondary {
+                Some(secondary) if context.client == Some(secondary.primary.ip()) => {
+                    trace(format_args!("refreshing zone {} notified by {}", secondary.origin, secondary.primary));
+                    self.refresh_signals[&DomainName::new(&secondary.origin)].notify_one();
+                    let mut response = self.error_response(query, ResponseCode::NOERROR);
+                    response.header.set_authoritative_answer(true);
+                    response
+                }
+                _ => {
+                    trace(format_args!("ignoring notify for {} from {:?}", question.name, context.client));
+                    self.refused_response(query, context)
+                }
+            }
+        }
+
+        /// The deepest unexpired secondary zone containing `name`.
+        fn zone_for(&self, name: &str) -> Option<Arc<Zone>> {
+            let name = DomainName::new(name);
+            let zones = self.zones.lock().unwrap();
+            let labels: Vec<&str> = name.as_str().split('.').collect();
+            (0..labels.len())
+                .filter_map(|label_idx| zones.get(labels[label_idx..].join(".").as_str()))
+                .find(|zone| !zone.is_expired())
+                .cloned()
+        }
+
+        /// Answers authoritatively from `zone`, proving missing names and types with its SOA.
+        /// Names in child zones get a referral to the child's nameservers instead.
+        fn zone_response(&self, query: &DnsPacket, zone: &Zone, question: &Question) -> DnsPacket {
+            let (response_code, answers) = match zone.lookup(&question.name, &question.query_type) {
+                ZoneAnswer::Records(answers) => (ResponseCode::NOERROR, answers),
+                ZoneAnswer::NoData => (ResponseCode::NOERROR, Vec::new()),
+                ZoneAnswer::NxDomain => (ResponseCode::NXDOMAIN, Vec::new()),
+                ZoneAnswer::Referral { name_servers, glue } => {
+                    let mut response = self.local_response(query, ResponseCode::NOERROR, false);
+                    response.set_authorities(name_servers);
+                    response.set_additional(glue);
+                    return response;
+                }
+            };
+            let mut response = self.local_response(query, response_code, true);
+            if answers.is_empty() {
+                let mut soa = zone.soa().clone();
+                if let Record::SOA { minimum, .. } = soa.record {
+                    soa.ttl = soa.ttl.min(minimum);
+                }
+                soa.ttl = soa.ttl.min(self.config.synthesized_ttls.negative);
+                response.add_authority(soa);
+            }
+            response.set_answers(answers);
+            response
+        }
+
+        /// Points the client at the nameservers of a delegated zone, with glue
+        /// for those whose addresses are cached.
+        fn referral_response(&self, query: &DnsPacket, delegation: &Delegation) -> DnsPacket {
+            let ttl = self.config.synthesized_ttls.referral;
+            let mut response = self.local_response(query, ResponseCode::NOERROR, false);
+            for server in &delegation.name_servers {
+                response.add_authority(Answer {
+                    name: delegation.zone.clone(),
+                    query_type: QueryType::NS,
+                    class: CLASS_IN,
+                    ttl,
+                    len: 0,
+                    record: Record::NS(server.clone()),
+                });
+                for mut glue in self.cache.get(server, &QueryType::A).unwrap_or_default() {
+                    glue.ttl = ttl;
+                    response.add_additional(glue);
+                }
+            }
+            response
+        }
+
+        /// Answers an ANY query with a single HINFO record instead of every record set (RFC 8482).
+        fn minimal_any_response(&self, query: &DnsPacket, question: &Question) -> DnsPacket {
+            let mut response = self.local_response(query, ResponseCode::NOERROR, false);
+            response.add_answer(Answer {
+                name: question.name.clone(),
+                query_type: QueryType::HINFO,
+                class: question.class,
+                ttl: self.config.synthesized_ttls.local,
+                len: 0,
+                record: Record::HINFO { cpu: "RFC8482".to_string(), os: String::new() },
+            });
+            response
+        }
+
+        /// The most specific special-use domain containing `name`.
+        fn special_use(&self, name: &str) -> Option<&SpecialUseDomain> {
+            self.config.special_use_domains.iter()
+                .filter(|domain| is_subdomain_of(name, &domain.name))
+                .max_by_key(|domain| label_count(&domain.name))
+        }
+
+        /// Answers a name below a special-use domain authoritatively without recursing.
+        /// Negative answers carry a made up SOA of the domain so clients may cache them.
+        fn special_use_response(&self, query: &DnsPacket, question: &Question, domain: &SpecialUseDomain) -> DnsPacket {
+            let special_use = domain.handling;
+            let record = match (special_use, &question.query_type) {
+                (SpecialUse::Loopback, QueryType::A) => Some(Record::A(Ipv4Addr::LOCALHOST)),
+                (SpecialUse::Loopback, QueryType::AAAA) => Some(Record::AAAA(Ipv6Addr::LOCALHOST)),
+                (SpecialUse::LoopbackReverse, QueryType::PTR) => Some(Record::PTR("localhost".to_string())),
+                _ => None,
+            };
+            let response_code = match special_use {
+                SpecialUse::NxDomain => ResponseCode::NXDOMAIN,
+                _ => ResponseCode::NOERROR,
+            };
+            let mut response = self.local_response(query, response_code, true);
+            if let Some(record) = record {
+                response.add_answer(Answer {
+                    name: question.name.clone(),
+                    query_type: question.query_type.clone(),
+                    class: CLASS_IN,
+                    ttl: self.config.synthesized_ttls.local,
+                    len: 0,
+                    record,
+                });
+            } else {
+                let negative = self.config.synthesized_ttls.negative;
+                response.add_authority(Answer {
+                    name: domain.name.clone(),
+                    query_type: QueryType::SOA,
+                    class: CLASS_IN,
+                    ttl: negative,
+                    len: 0,
+                    record: Record::SOA {
+                        mname: "localhost".to_string(),
+                        rname: "nobody.invalid".to_string(),
+                        serial: 1,
+                        refresh: 3600,
+                        retry: 1200,
+                        expire: 604800,
+                        minimum: negative,
+                    },
+                });
+            }
+            response
+        }
+
+        /// Answers the conventional `version.bind` and `hostname.bind` probes
+        /// if configured and refuses every other CHAOS query.
+        fn chaos_response(&self, query: &DnsPacket, question: &Question, context: &mut QueryContext) -> DnsPacket {
+            let text = match question.query_type {
+                QueryType::TXT if names_equal(&question.name, "version.bind") => self.config.version_bind.as_ref(),
+                QueryType::TXT if names_equal(&question.name, "hostname.bind") => self.config.hostname_bind.as_ref(),
+                _ => None,
+            };
+            let text = match text {
+                Some(text) => text,
+                None => return self.refused_response(query, context),
+            };
+            let mut response = self.local_response(query, ResponseCode::NOERROR, true);
+            response.add_answer(Answer {
+                name: question.name.clone(),
+                query_type: QueryType::TXT,
+                class: CLASS_CH,
+                ttl: self.config.synthesized_ttls.local,
+                len: text.len() as u16 + 1,
+                record: Record::TXT(vec![text.clone()]),
+            });
+            response
+        }
+
+        /// Zone transfers are only for allowlisted secondaries and never over udp.
+        fn may_transfer(&self, context: &QueryContext) -> bool {
+            context.transport == Transport::Tcp
+                && context.client.is_some_and(|client| self.config.transfer_allowlist.contains(&client))
+        }
+
+        /// The configured refusal, marking the query as refused by policy.
+        fn refused_response(&self, query: &DnsPacket, context: &mut QueryContext) -> DnsPacket {
+            context.refused = true;
+            context.extended_error.get_or_insert_with(|| ExtendedError::new(ede::PROHIBITED, ""));
+            self.error_response(query, self.config.refused_response_code)
+        }
+
+        /// Every error response goes through here so the question is always echoed.
+        fn error_response(&self, query: &DnsPacket, response_code: ResponseCode) -> DnsPacket {
+            let mut response = DnsPacket::error_response(query, response_code);
+            response.header.set_recursion_available(!self.config.authoritative_only);
+            response
+        }
+
+        /// An empty answer to `query` from local data, echoing its questions.
+        fn local_response(&self, query: &DnsPacket, response_code: ResponseCode, authoritative: bool) -> DnsPacket {
+            let mut response = DnsPacket::new(Header::new(query.header.id, query.header.get_recursion_desired(),
+                                                          !self.config.authoritative_only, true, response_code));
+            response.header.set_authoritative_answer(authoritative);
+            response.set_questions(query.questions.clone());
+            response
+        }
+
+        pub async fn start(self: Arc<Self>) {
+            self.serve_until(std::future::pending()).await;
+        }
+
+        /// Serves clients until `shutdown` completes, then waits up to
+        /// `shutdown_timeout` for in-flight resolutions before aborting them.
+        pub async fn serve_until(self: Arc<Self>, shutdown: impl Future<Output = ()>) {
+            // one receive loop per client socket, all feeding the same queue
+            let (queries_tx, mut queries) = mpsc::channel(64);
+            let mut receivers = JoinSet::new();
+            for socket_idx in 0..self.client_sockets.len() {
+                let self_clone = Arc::clone(&self);
+                let queries_tx = queries_tx.clone();
+                receivers.spawn(async move {
+                    self_clone.receive_queries(socket_idx, queries_tx).await;
+                });
+            }
+            drop(queries_tx);
+            for listener_idx in 0..self.tcp_listeners.len() {
+                receivers.spawn(Arc::clone(&self).accept_tcp(listener_idx));
+            }
+            if self.debug_listener.is_some() {
+                let self_clone = Arc::clone(&self);
+                receivers.spawn(async move {
+                    self_clone.serve_debug_http().await;
+                });
+            }
+            if let Some(prefetches) = self.prefetch_receiver.lock().unwrap().take() {
+                receivers.spawn(Arc::clone(&self).run_prefetches(prefetches));
+            }
+            for secondary in self.config.secondary_zones.clone() {
                 let self_clone = Arc::clone(&self);
-                tokio::task::spawn(async move {
-                    self_clone.resolve_request(client, in_packet).await;
+                receivers.spawn(async move {
+                    self_clone.maintain_secondary(secondary).await;
                 });
             }
 
+            let mut tasks = JoinSet::new();
+            tokio::pin!(shutdown);
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    Some(res) = tasks.join_next(), if !tasks.is_empty() => {
+                        log_task_result(res);
+                    }
+                    Some((socket_idx, client, in_packet)) = queries.recv() => {
+                        let self_clone = Arc::clone(&self);
+                        tasks.spawn(async move {
+                            self_clone.resolve_request(socket_idx, client, in_packet).await;
+                        });
+                    }
+                }
+            }
+            receivers.abort_all();
+            println!("shutting down, waiting for {} resolutions", tasks.len());
+            drain_tasks(&mut tasks, self.config.shutdown_timeout).await;
+            for log in self.config.query_log.iter().chain(&self.config.trace_log) {
+                log.flush();
+            }
+        }
+
+        async fn receive_queries(&self, socket_idx: usize, queries: mpsc::Sender<(usize, SocketAddr, DnsPacket)>) {
+            let socket = &self.client_sockets[socket_idx];
+            // clients may send queries up to the payload size we advertise
+            let mut buf = vec![0u8; self.config.edns_udp_payload_size.max(512) as usize];
+            loop {
+                let (len, client) = match socket.recv_from(&mut buf).await {
+                    Ok(res) => res,
+                    Err(e) => {
+                        println!("could not receive from client: {}", e);
+                        continue;
+                    }
+                };
+                let in_packet = match DnsPacket::from_buf(&buf[..len]) {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        println!("dropping unparsable packet from {}: {}", client, e);
+                        continue;
+                    }
+                };
+                // clients only send queries, a response here is spoofed or reflected
+                if in_packet.header.get_query_response() {
+                    println!("dropping response packet {} from {}", in_packet.header.id, client);
+                    continue;
+                }
+                if queries.send((socket_idx, client, in_packet)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Logs a finished resolution task and returns false if it panicked or was cancelled.
+    fn log_task_result(res: Result<(), JoinError>) -> bool {
+        match res {
+            Ok(()) => true,
+            Err(e) => {
+                println!("resolution task failed: {}", e);
+                false
+            }
         }
     }
 
-}
+    /// Awaits all tasks for at most `deadline` and aborts the rest.
+    /// Returns the number of tasks that did not complete successfully.
+    pub async fn drain_tasks(tasks: &mut JoinSet<()>, deadline: Duration) -> usize {
+        let mut failed = 0;
+        let drained = timeout(deadline, async {
+            while let Some(res) = tasks.join_next().await {
+                if !log_task_result(res) {
+                    failed += 1;
+                }
+            }
+        }).await;
+        if drained.is_err() {
+            println!("aborting {} resolutions after shutdown deadline", tasks.len());
+            failed += tasks.len();
+            tasks.abort_all();
+        }
+        failed
+    }
 
+}
@@ -1,9 +1,15 @@
+pub mod borrowed;
 pub(crate) mod buffer;
 
 pub mod dns_packet {
     use std::{fmt, io};
-    use std::net::{Ipv4Addr, Ipv6Addr};
-    use crate::dns_server::dns_packet::buffer::buffer::{BufferBuilder, BufferParser};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+    use std::time::Duration;
+    use crate::dns_server::dns_packet::borrowed::borrowed::BorrowedPacket;
+    use crate::dns_server::dns_packet::buffer::buffer::{BufferBuilder, BufferParser, split_labels};
+    use crate::dns_server::names::names::{is_subdomain_of, names_equal};
+    use crate::dns_server::query_log::query_log::trace;
 
     pub mod flags {
         pub const QUERY_RESPONSE: u8 = 0b1000_0000;
@@ -13,10 +19,151 @@ pub mod dns_packet {
         pub const RECURSION_DESIRED: u8 = 0b0000_0001;
 
         pub const RECURSION_AVAILABLE: u8 = 0b1000_0000;
-        pub const RESERVED: u8 = 0b0111_0000;
+        /// must be zero, unlike AD and CD next to it
+        pub const Z: u8 = 0b0100_0000;
+        pub const AUTHENTIC_DATA: u8 = 0b0010_0000;
+        pub const CHECKING_DISABLED: u8 = 0b0001_0000;
         pub const RESPONSE_CODE: u8 = 0b0000_1111;
     }
 
+    /// smallest question on the wire, the root name followed by type and class
+    pub const MIN_QUESTION_LEN: usize = 5;
+    /// smallest record on the wire, the root name followed by the fixed fields
+    pub const MIN_RECORD_LEN: usize = 11;
+
+    pub const CLASS_IN: u16 = 1;
+    pub const CLASS_CH: u16 = 3;
+
+    /// DNSSEC OK bit in the ttl field of an OPT record
+    pub const EDNS_DO: u32 = 0x0000_8000;
+    /// extended response code for an unsupported EDNS version (RFC 6891)
+    pub const BADVERS: u16 = 16;
+
+    #[derive(Debug, PartialEq, Eq, Clone, Hash)]
+    pub struct EdnsOption {
+        pub code: u16,
+        pub data: Vec<u8>,
+    }
+
+    /// option code of EDNS Client Subnet (RFC 7871)
+    pub const EDNS_CLIENT_SUBNET: u16 = 8;
+    /// option code of edns-tcp-keepalive (RFC 7828)
+    pub const EDNS_TCP_KEEPALIVE: u16 = 11;
+    /// option code of EDNS Padding (RFC 7830)
+    pub const EDNS_PADDING: u16 = 12;
+    /// option code of Extended DNS Errors (RFC 8914)
+    pub const EDNS_EXTENDED_ERROR: u16 = 15;
+
+    /// Extended DNS Error info codes this server reports.
+    pub mod ede {
+        pub const OTHER: u16 = 0;
+        pub const PROHIBITED: u16 = 18;
+        pub const NO_REACHABLE_AUTHORITY: u16 = 22;
+        pub const NOT_SUPPORTED: u16 = 21;
+        pub const NETWORK_ERROR: u16 = 23;
+        pub const INVALID_DATA: u16 = 24;
+    }
+
+    /// An Extended DNS Error option explaining why a query failed.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct ExtendedError {
+        pub info_code: u16,
+        pub extra_text: String,
+    }
+
+    impl ExtendedError {
+        pub fn new(info_code: u16, extra_text: &str) -> ExtendedError {
+            ExtendedError { info_code, extra_text: extra_text.to_string() }
+        }
+
+        /// Explains a failed resolution, using the `DnsError` it carries if there is one.
+        pub fn from_io_error(e: &io::Error) -> ExtendedError {
+            if let Some(dns_error) = e.get_ref().and_then(|inner| inner.downcast_ref::<DnsError>()) {
+                return dns_error.extended_error();
+            }
+            let info_code = match e.kind() {
+                io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::AddrNotAvailable | io::ErrorKind::PermissionDenied => ede::NETWORK_ERROR,
+                io::ErrorKind::InvalidData => ede::INVALID_DATA,
+                _ => ede::NO_REACHABLE_AUTHORITY,
+            };
+            ExtendedError::new(info_code, &e.to_string())
+        }
+
+        pub fn from_option(option: &EdnsOption) -> io::Result<ExtendedError> {
+            if option.code != EDNS_EXTENDED_ERROR || option.data.len() < 2 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not an extended error option"));
+            }
+            Ok(ExtendedError {
+                info_code: u16::from_be_bytes([option.data[0], option.data[1]]),
+                extra_text: String::from_utf8_lossy(&option.data[2..]).into_owned(),
+            })
+        }
+
+        pub fn to_option(&self) -> EdnsOption {
+            let mut data = self.info_code.to_be_bytes().to_vec();
+            data.extend_from_slice(self.extra_text.as_bytes());
+            EdnsOption { code: EDNS_EXTENDED_ERROR, data }
+        }
+    }
+
+    /// The EDNS Client Subnet option, `address` holds only the bytes covered by the source prefix.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct ClientSubnet {
+        /// address family, 1 for ipv4 and 2 for ipv6
+        pub family: u16,
+        pub source_prefix_len: u8,
+        pub scope_prefix_len: u8,
+        pub address: Vec<u8>,
+    }
+
+    impl ClientSubnet {
+        pub fn new(addr: IpAddr, source_prefix_len: u8) -> io::Result<ClientSubnet> {
+            let (family, octets) = match addr {
+                IpAddr::V4(ip) => (1, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (2, ip.octets().to_vec()),
+            };
+            ClientSubnet::with_address(family, source_prefix_len, 0, octets)
+        }
+
+        pub fn from_option(option: &EdnsOption) -> io::Result<ClientSubnet> {
+            if option.code != EDNS_CLIENT_SUBNET || option.data.len() < 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "not a client subnet option"));
+            }
+            let family = u16::from_be_bytes([option.data[0], option.data[1]]);
+            ClientSubnet::with_address(family, option.data[2], option.data[3], option.data[4..].to_vec())
+        }
+
+        /// Truncates `address` to the bytes covered by the source prefix and zeroes the bits past it.
+        fn with_address(family: u16, source_prefix_len: u8, scope_prefix_len: u8, mut address: Vec<u8>) -> io::Result<ClientSubnet> {
+            let max_prefix_len = match family {
+                1 => 32,
+                2 => 128,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown client subnet family")),
+            };
+            if source_prefix_len > max_prefix_len || scope_prefix_len > max_prefix_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "client subnet prefix too long"));
+            }
+            let len = (source_prefix_len as usize).div_ceil(8);
+            if address.len() < len {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "client subnet address too short"));
+            }
+            address.truncate(len);
+            if !source_prefix_len.is_multiple_of(8) {
+                address[len - 1] &= 0xff << (8 - source_prefix_len % 8);
+            }
+            Ok(ClientSubnet { family, source_prefix_len, scope_prefix_len, address })
+        }
+
+        pub fn to_option(&self) -> EdnsOption {
+            let mut data = self.family.to_be_bytes().to_vec();
+            data.push(self.source_prefix_len);
+            data.push(self.scope_prefix_len);
+            data.extend_from_slice(&self.address);
+            EdnsOption { code: EDNS_CLIENT_SUBNET, data }
+        }
+    }
+
 
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub enum ResponseCode {
@@ -72,8 +219,22 @@ pub mod dns_packet {
         A,
         NS,
         CNAME,
+        SOA,
+        PTR,
+        HINFO,
         MX,
+        TXT,
         AAAA,
+        OPT,
+        DS,
+        RRSIG,
+        NSEC,
+        DNSKEY,
+        NSEC3,
+        IXFR,
+        AXFR,
+        ANY,
+        URI,
     }
     impl QueryType {
         pub fn from(num: u16) -> QueryType {
@@ -81,8 +242,22 @@ pub mod dns_packet {
                 1 => QueryType::A,
                 2 => QueryType::NS,
                 5 => QueryType::CNAME,
+                6 => QueryType::SOA,
+                12 => QueryType::PTR,
+                13 => QueryType::HINFO,
                 15 => QueryType::MX,
+                16 => QueryType::TXT,
                 28 => QueryType::AAAA,
+                41 => QueryType::OPT,
+                43 => QueryType::DS,
+                46 => QueryType::RRSIG,
+                47 => QueryType::NSEC,
+                48 => QueryType::DNSKEY,
+                50 => QueryType::NSEC3,
+                251 => QueryType::IXFR,
+                252 => QueryType::AXFR,
+                255 => QueryType::ANY,
+                256 => QueryType::URI,
                 _ => QueryType::UNKOWN(num),
             }
         }
@@ -91,32 +266,92 @@ pub mod dns_packet {
                 QueryType::A => 1,
                 QueryType::NS => 2,
                 QueryType::CNAME => 5,
+                QueryType::SOA => 6,
+                QueryType::PTR => 12,
+                QueryType::HINFO => 13,
                 QueryType::MX => 15,
+                QueryType::TXT => 16,
                 QueryType::AAAA => 28,
+                QueryType::OPT => 41,
+                QueryType::DS => 43,
+                QueryType::RRSIG => 46,
+                QueryType::NSEC => 47,
+                QueryType::DNSKEY => 48,
+                QueryType::NSEC3 => 50,
+                QueryType::IXFR => 251,
+                QueryType::AXFR => 252,
+                QueryType::ANY => 255,
+                QueryType::URI => 256,
                 QueryType::UNKOWN(x) => *x,
             }
         }
+        /// Whether this asks for a zone transfer rather than a record set.
+        pub fn is_zone_transfer(&self) -> bool {
+            matches!(self, QueryType::AXFR | QueryType::IXFR)
+        }
+        pub fn is_dnssec(&self) -> bool {
+            matches!(self, QueryType::DS | QueryType::RRSIG | QueryType::NSEC | QueryType::DNSKEY | QueryType::NSEC3)
+        }
+    }
+
+    impl fmt::Display for QueryType {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                QueryType::UNKOWN(x) => write!(f, "TYPE{}", x),
+                known => write!(f, "{:?}", known),
+            }
+        }
+    }
+
+    impl FromStr for QueryType {
+        type Err = io::Error;
+
+        /// Accepts mnemonics like `AAAA` as well as the generic `TYPE65` form, ignoring case.
+        fn from_str(s: &str) -> io::Result<QueryType> {
+            let upper = s.to_ascii_uppercase();
+            if let Some(num) = upper.strip_prefix("TYPE") {
+                return num.parse().map(QueryType::from)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid query type {}", s)));
+            }
+            [QueryType::A, QueryType::NS, QueryType::CNAME, QueryType::SOA, QueryType::PTR, QueryType::HINFO, QueryType::MX, QueryType::TXT, QueryType::AAAA,
+             QueryType::OPT, QueryType::DS, QueryType::RRSIG, QueryType::NSEC, QueryType::DNSKEY, QueryType::NSEC3, QueryType::IXFR,
+             QueryType::AXFR, QueryType::ANY, QueryType::URI]
+                .into_iter()
+                .find(|query_type| query_type.to_string() == upper)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unknown query type {}", s)))
+        }
     }
 
     #[derive(Copy, Clone, Debug, PartialEq, Eq)]
     pub enum OperationCode {
-        Query = 0,
-        IQuery = 1,
-        Status = 2,
-        Notify = 4,
-        Update = 5,
-        Unkown,
+        Query,
+        IQuery,
+        Status,
+        Notify,
+        Update,
+        /// any other opcode, keeping the original 4 bit value
+        Unknown(u8),
     }
 
     impl OperationCode {
-        fn from(num: u8) -> OperationCode {
+        pub fn from(num: u8) -> OperationCode {
             match num {
                 0 => OperationCode::Query,
                 1 => OperationCode::IQuery,
                 2 => OperationCode::Status,
                 4 => OperationCode::Notify,
                 5 => OperationCode::Update,
-                _ => OperationCode::Unkown
+                x => OperationCode::Unknown(x)
+            }
+        }
+        pub fn to_u8(&self) -> u8 {
+            match self {
+                OperationCode::Query => 0,
+                OperationCode::IQuery => 1,
+                OperationCode::Status => 2,
+                OperationCode::Notify => 4,
+                OperationCode::Update => 5,
+                OperationCode::Unknown(x) => *x & 0x0f,
             }
         }
     }
@@ -144,7 +379,8 @@ pub mod dns_packet {
                 additional_count: buf.read_u16()?,
             })
         }
-        pub fn new(id: u16, recursion: bool, is_response: bool, response_code: ResponseCode) -> Header {
+        pub fn new(id: u16, recursion_desired: bool, recursion_available: bool,
+                   is_response: bool, response_code: ResponseCode) -> Header {
             let mut result = Header {
                 id,
                 flags1: 0,
@@ -154,7 +390,8 @@ pub mod dns_packet {
                 authoritiy_count: 0,
                 additional_count: 0,
             };
-            result.set_recursion_desired(recursion);
+            result.set_recursion_desired(recursion_desired);
+            result.set_recursion_available(recursion_available);
             result.set_query_response(is_response);
             result.set_response_code(response_code);
             result
@@ -189,11 +426,17 @@ pub mod dns_packet {
         pub fn get_recursion_available(&self) -> bool {
             ((self.flags2 & flags::RECURSION_AVAILABLE) >> 7) !=0
         }
-        pub fn get_reserved(&self) -> u8 {
-            (self.flags2 & flags::RESERVED) >> 4
+        pub fn get_z(&self) -> bool {
+            (self.flags2 & flags::Z) != 0
+        }
+        pub fn get_authentic_data(&self) -> bool {
+            (self.flags2 & flags::AUTHENTIC_DATA) != 0
+        }
+        pub fn get_checking_disabled(&self) -> bool {
+            (self.flags2 & flags::CHECKING_DISABLED) != 0
         }
         pub fn get_response_code(&self) -> ResponseCode {
-            return ResponseCode::from(self.flags2 & flags::RESPONSE_CODE)
+            ResponseCode::from(self.flags2 & flags::RESPONSE_CODE)
         }
         pub fn set_query_response(&mut self, value: bool) {
             if value {
@@ -205,7 +448,7 @@ pub mod dns_packet {
 
         pub fn set_op_code(&mut self, value: OperationCode) {
             self.flags1 &= !flags::OP_CODE;
-            self.flags1 |= (value as u8) << 3;
+            self.flags1 |= value.to_u8() << 3;
         }
 
         pub fn set_authoritative_answer(&mut self, value: bool) {
@@ -240,9 +483,28 @@ pub mod dns_packet {
             }
         }
 
-        pub fn set_reserved(&mut self, value: u8) {
-            self.flags2 &= !flags::RESERVED;
-            self.flags2 |= value << 4;
+        pub fn set_z(&mut self, value: bool) {
+            if value {
+                self.flags2 |= flags::Z;
+            } else {
+                self.flags2 &= !flags::Z;
+            }
+        }
+
+        pub fn set_authentic_data(&mut self, value: bool) {
+            if value {
+                self.flags2 |= flags::AUTHENTIC_DATA;
+            } else {
+                self.flags2 &= !flags::AUTHENTIC_DATA;
+            }
+        }
+
+        pub fn set_checking_disabled(&mut self, value: bool) {
+            if value {
+                self.flags2 |= flags::CHECKING_DISABLED;
+            } else {
+                self.flags2 &= !flags::CHECKING_DISABLED;
+            }
         }
 
         pub fn set_response_code(&mut self, value: ResponseCode) {
@@ -265,7 +527,9 @@ pub mod dns_packet {
                 .field("recursion_desired", &self.get_recursion_desired())
                 .field("flags2", &format!("{:08b}", self.flags2))
                 .field("recursion_available", &self.get_recursion_available())
-                .field("reserved", &self.get_reserved())
+                .field("z", &self.get_z())
+                .field("authentic_data", &self.get_authentic_data())
+                .field("checking_disabled", &self.get_checking_disabled())
                 .field("response_code", &self.get_response_code())
                 .field("questions", &self.question_count)
                 .field("answers", &self.answer_count)
@@ -280,13 +544,40 @@ pub mod dns_packet {
         A(Ipv4Addr),
         NS(String),
         CNAME(String),
+        SOA {mname: String, rname: String, serial: u32, refresh: u32, retry: u32, expire: u32, minimum: u32},
+        PTR(String),
+        HINFO {cpu: String, os: String},
         MX {priority: u16, host:String},
+        TXT(Vec<String>),
         AAAA(Ipv6Addr),
+        OPT(Vec<EdnsOption>),
+        DS(Vec<u8>),
+        RRSIG(Vec<u8>),
+        /// the next owner name in the zone and the types present at this one
+        NSEC {next_domain: String, types: Vec<QueryType>},
+        DNSKEY(Vec<u8>),
+        NSEC3 {hash_algorithm: u8, flags: u8, iterations: u16, salt: Vec<u8>, next_hashed_owner: Vec<u8>, types: Vec<QueryType>},
+        URI {priority: u16, weight: u16, target: String},
         UNKOWN(u16),
     }
 
     impl Record {
+        /// Parses the rdata of `len` bytes. Types with a fixed size must match it exactly
+        /// and types that hold a name reject empty rdata instead of reading into the next record.
         pub fn from_buf(buf: &mut BufferParser, len: u16, query_type: QueryType) -> io::Result<Record> {
+            let expected_len = match query_type {
+                QueryType::A => Some(4),
+                QueryType::AAAA => Some(16),
+                _ => None,
+            };
+            if expected_len.is_some_and(|expected_len| expected_len != len) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{} record with rdata length {}", query_type, len)));
+            }
+            let holds_name = matches!(query_type, QueryType::CNAME | QueryType::NS | QueryType::SOA | QueryType::PTR | QueryType::MX);
+            if holds_name && len == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("empty {} record", query_type)));
+            }
             let result = match query_type {
                 QueryType::A => {
                     let raw_addr = buf.read_u32()?;
@@ -304,16 +595,94 @@ pub mod dns_packet {
                 QueryType::NS => {
                     Record::NS(buf.read_name()?)
                 }
+                QueryType::PTR => {
+                    Record::PTR(buf.read_name()?)
+                }
+                QueryType::SOA => {
+                    Record::SOA {
+                        mname: buf.read_name()?,
+                        rname: buf.read_name()?,
+                        serial: buf.read_u32()?,
+                        refresh: buf.read_u32()?,
+                        retry: buf.read_u32()?,
+                        expire: buf.read_u32()?,
+                        minimum: buf.read_u32()?,
+                    }
+                }
+                QueryType::HINFO => {
+                    Record::HINFO {
+                        cpu: buf.read_character_string()?,
+                        os: buf.read_character_string()?,
+                    }
+                }
                 QueryType::MX => {
                     Record::MX {
                         priority: buf.read_u16()?,
                         host: buf.read_name()?
                     }
                 }
+                QueryType::TXT => {
+                    let end = buf.get_pos() + len as usize;
+                    let mut strings = Vec::new();
+                    while buf.get_pos() < end {
+                        strings.push(buf.read_character_string()?);
+                    }
+                    Record::TXT(strings)
+                }
+                QueryType::OPT => {
+                    let end = buf.get_pos() + len as usize;
+                    let mut options = Vec::new();
+                    while buf.get_pos() < end {
+                        let code = buf.read_u16()?;
+                        let option_len = buf.read_u16()?;
+                        options.push(EdnsOption {
+                            code,
+                            data: buf.read_bytes(option_len as usize)?,
+                        });
+                    }
+                    Record::OPT(options)
+                }
+                QueryType::DS => Record::DS(buf.read_bytes(len as usize)?),
+                QueryType::RRSIG => Record::RRSIG(buf.read_bytes(len as usize)?),
+                QueryType::NSEC => {
+                    let end = buf.get_pos() + len as usize;
+                    Record::NSEC {
+                        next_domain: buf.read_name()?,
+                        types: read_type_bitmaps(buf, end)?,
+                    }
+                }
+                QueryType::DNSKEY => Record::DNSKEY(buf.read_bytes(len as usize)?),
+                QueryType::NSEC3 => {
+                    let end = buf.get_pos() + len as usize;
+                    let hash_algorithm = buf.read()?;
+                    let flags = buf.read()?;
+                    let iterations = buf.read_u16()?;
+                    let salt_len = buf.read()?;
+                    let salt = buf.read_bytes(salt_len as usize)?;
+                    let hash_len = buf.read()?;
+                    let next_hashed_owner = buf.read_bytes(hash_len as usize)?;
+                    Record::NSEC3 { hash_algorithm, flags, iterations, salt, next_hashed_owner, types: read_type_bitmaps(buf, end)? }
+                }
+                QueryType::URI => {
+                    if len < 4 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "URI record too short"));
+                    }
+                    let priority = buf.read_u16()?;
+                    let weight = buf.read_u16()?;
+                    // the target is raw rdata, not a name or character-string
+                    let target = String::from_utf8(buf.read_bytes(len as usize - 4)?)
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "URI target is not utf-8"))?;
+                    Record::URI { priority, weight, target }
+                }
                 QueryType::UNKOWN(x) => {
                     buf.seek(buf.get_pos() + len as usize);
                     Record::UNKOWN(x)
                 }
+                // transfer types and ANY only appear in questions, skip whatever data there is
+                QueryType::IXFR | QueryType::AXFR | QueryType::ANY => {
+                    buf.seek(buf.get_pos() + len as usize);
+                    Record::UNKOWN(query_type.to_u16())
+                }
             };
             Ok(result)
         }
@@ -324,22 +693,93 @@ pub mod dns_packet {
                     builder.write_u16(4)?;
                     builder.write_u32(u32::from(*addr))?;
                 }
-                Record::NS(name) | Record::CNAME(name) => {
+                Record::NS(name) | Record::CNAME(name) | Record::PTR(name) => {
                     let pos = builder.get_pos();
                     builder.write_u16(0)?;
                     builder.write_name(name)?;
                     builder.set_u16( (builder.get_pos() - (pos+2))as u16, pos)?;
                 }
+                Record::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+                    let pos = builder.get_pos();
+                    builder.write_u16(0)?;
+                    builder.write_name(mname)?;
+                    builder.write_name(rname)?;
+                    for value in [serial, refresh, retry, expire, minimum] {
+                        builder.write_u32(*value)?;
+                    }
+                    builder.set_u16( (builder.get_pos() - (pos+2)) as u16, pos)?;
+                }
+                Record::HINFO { cpu, os } => {
+                    let pos = builder.get_pos();
+                    builder.write_u16(0)?;
+                    builder.write_character_string(cpu)?;
+                    builder.write_character_string(os)?;
+                    builder.set_u16( (builder.get_pos() - (pos+2)) as u16, pos)?;
+                }
                 Record::MX { priority, host } => {
                     let pos = builder.get_pos();
+                    builder.write_u16(0)?;
                     builder.write_u16(*priority)?;
                     builder.write_name(host)?;
                     builder.set_u16( (builder.get_pos() - (pos+2)) as u16, pos)?;
                 }
+                Record::TXT(strings) => {
+                    let pos = builder.get_pos();
+                    builder.write_u16(0)?;
+                    for string in strings {
+                        for segment in txt_segments(string) {
+                            builder.write_character_string(segment)?;
+                        }
+                    }
+                    builder.set_u16( (builder.get_pos() - (pos+2)) as u16, pos)?;
+                }
                 Record::AAAA(addr) => {
                     builder.write_u16(16)?;
                     builder.write_u128(u128::from(*addr))?;
                 }
+                Record::OPT(options) => {
+                    let len: usize = options.iter().map(|option| 4 + option.data.len()).sum();
+                    builder.write_u16(len as u16)?;
+                    for option in options {
+                        builder.write_u16(option.code)?;
+                        builder.write_u16(option.data.len() as u16)?;
+                        builder.write_bytes(&option.data)?;
+                    }
+                }
+                Record::DS(data) | Record::RRSIG(data) | Record::DNSKEY(data) => {
+                    builder.write_u16(data.len() as u16)?;
+                    builder.write_bytes(data)?;
+                }
+                Record::NSEC { next_domain, types } => {
+                    let pos = builder.get_pos();
+                    builder.write_u16(0)?;
+                    // the next domain is never compressed (RFC 4034, section 4.1.1)
+                    builder.write_uncompressed_name(next_domain)?;
+                    write_type_bitmaps(builder, types)?;
+                    builder.set_u16( (builder.get_pos() - (pos+2)) as u16, pos)?;
+                }
+                Record::NSEC3 { hash_algorithm, flags, iterations, salt, next_hashed_owner, types } => {
+                    if salt.len() > 255 || next_hashed_owner.len() > 255 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidInput, "NSEC3 salt or hash too long"));
+                    }
+                    let pos = builder.get_pos();
+                    builder.write_u16(0)?;
+                    builder.write(*hash_algorithm)?;
+                    builder.write(*flags)?;
+                    builder.write_u16(*iterations)?;
+                    builder.write(salt.len() as u8)?;
+                    builder.write_bytes(salt)?;
+                    builder.write(next_hashed_owner.len() as u8)?;
+                    builder.write_bytes(next_hashed_owner)?;
+                    write_type_bitmaps(builder, types)?;
+                    builder.set_u16( (builder.get_pos() - (pos+2)) as u16, pos)?;
+                }
+                Record::URI { priority, weight, target } => {
+                    builder.write_u16((4 + target.len()) as u16)?;
+                    builder.write_u16(*priority)?;
+                    builder.write_u16(*weight)?;
+                    builder.write_bytes(target.as_bytes())?;
+                }
                 Record::UNKOWN(_) => {
                     // do nothing
                 }
@@ -349,6 +789,45 @@ pub mod dns_packet {
     }
 
 
+    impl fmt::Display for Record {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Record::A(addr) => write!(f, "{}", addr),
+                Record::AAAA(addr) => write!(f, "{}", addr),
+                Record::NS(name) | Record::CNAME(name) | Record::PTR(name) => write!(f, "{}.", name),
+                Record::SOA { mname, rname, serial, refresh, retry, expire, minimum } =>
+                    write!(f, "{}. {}. {} {} {} {} {}", mname, rname, serial, refresh, retry, expire, minimum),
+                Record::HINFO { cpu, os } => write!(f, "\"{}\" \"{}\"", cpu, os),
+                Record::MX { priority, host } => write!(f, "{} {}.", priority, host),
+                Record::TXT(strings) => write!(f, "{}", strings.iter()
+                    .map(|string| format!("\"{}\"", string))
+                    .collect::<Vec<_>>()
+                    .join(" ")),
+                Record::OPT(options) => write!(f, "; {} options", options.len()),
+                Record::DS(data) | Record::RRSIG(data) | Record::DNSKEY(data) => {
+                    write!(f, "\\# {} ", data.len())?;
+                    data.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+                }
+                Record::NSEC { next_domain, types } => {
+                    write!(f, "{}.", next_domain)?;
+                    types.iter().try_for_each(|query_type| write!(f, " {}", query_type))
+                }
+                Record::NSEC3 { hash_algorithm, flags, iterations, salt, next_hashed_owner, types } => {
+                    write!(f, "{} {} {} ", hash_algorithm, flags, iterations)?;
+                    if salt.is_empty() {
+                        write!(f, "-")?;
+                    }
+                    salt.iter().try_for_each(|byte| write!(f, "{:02x}", byte))?;
+                    write!(f, " ")?;
+                    next_hashed_owner.iter().try_for_each(|byte| write!(f, "{:02x}", byte))?;
+                    types.iter().try_for_each(|query_type| write!(f, " {}", query_type))
+                }
+                Record::URI { priority, weight, target } => write!(f, "{} {} \"{}\"", priority, weight, target),
+                Record::UNKOWN(_) => write!(f, "\\# 0"),
+            }
+        }
+    }
+
     #[derive(Debug, PartialEq, Eq, Clone, Hash)]
     pub struct Answer {
         pub name: String,
@@ -360,22 +839,46 @@ pub mod dns_packet {
     }
 
     impl Answer {
+        /// Parses a resource record. Its rdata length is authoritative: the parser always
+        /// ends up right after the rdata, skipping bytes the typed parser left unread,
+        /// and a typed parser reading past the rdata is an error.
         pub fn from_buf(buf: &mut BufferParser) -> io::Result<Answer> {
             let name_ = buf.read_name()?;
             let query_type_ = QueryType::from(buf.read_u16()?);
             let query_class_ = buf.read_u16()?;
             let ttl_ = buf.read_u32()?;
             let len_ = buf.read_u16()?;
+            let rdata_pos = buf.get_pos();
+            buf.get_range(rdata_pos, len_ as usize)?;
+            let record = Record::from_buf(buf, len_, query_type_.clone())?;
+            if buf.get_pos() > rdata_pos + len_ as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{} record longer than its rdata length {}", query_type_, len_)));
+            }
+            buf.seek(rdata_pos + len_ as usize);
             Ok(Answer {
                 name: name_,
-                query_type: query_type_.clone(),
+                query_type: query_type_,
                 class: query_class_,
                 ttl: ttl_,
                 len: len_,
-                record: Record::from_buf(buf ,len_, query_type_)?,
+                record,
             })
         }
 
+        /// Builds an EDNS OPT pseudo record, the class carries the udp payload size
+        /// and the ttl the extended flags.
+        pub fn opt(udp_payload_size: u16, dnssec_ok: bool) -> Answer {
+            Answer {
+                name: String::new(),
+                query_type: QueryType::OPT,
+                class: udp_payload_size,
+                ttl: if dnssec_ok { EDNS_DO } else { 0 },
+                len: 0,
+                record: Record::OPT(Vec::new()),
+            }
+        }
+
         pub fn write_to_buf(&self, builder: &mut BufferBuilder) -> io::Result<()> {
             builder.write_name(&self.name)?;
             builder.write_u16(self.query_type.to_u16())?;
@@ -386,6 +889,100 @@ pub mod dns_packet {
         }
     }
 
+    /// Fails fast when `count` entries of at least `min_len` bytes each cannot fit in
+    /// what is left of the packet, before a forged count makes the parser loop or allocate.
+    pub fn check_section_count(parser: &BufferParser, section: &str, count: u16, min_len: usize) -> io::Result<()> {
+        if count as usize * min_len > parser.remaining() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} count {} exceeds the {} bytes left in the packet", section, count, parser.remaining())));
+        }
+        Ok(())
+    }
+
+    /// Reads the type bitmaps of NSEC and NSEC3 records up to `end`: blocks of a window
+    /// number, a bitmap length of 1 to 32 and a bitmap in which bit n of the window
+    /// stands for type `window * 256 + n`. Windows must appear in increasing order.
+    fn read_type_bitmaps(buf: &mut BufferParser, end: usize) -> io::Result<Vec<QueryType>> {
+        let mut types = Vec::new();
+        let mut last_window = None;
+        while buf.get_pos() < end {
+            let window = buf.read()?;
+            let len = buf.read()?;
+            if last_window.is_some_and(|last| window <= last) || !(1..=32).contains(&len) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed type bitmap"));
+            }
+            last_window = Some(window);
+            for (byte_idx, byte) in buf.read_bytes(len as usize)?.into_iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) != 0 {
+                        types.push(QueryType::from((window as u16) << 8 | (byte_idx * 8 + bit) as u16));
+                    }
+                }
+            }
+        }
+        Ok(types)
+    }
+
+    /// Writes `types` as type bitmaps, sorted and without duplicates whatever their order.
+    fn write_type_bitmaps(builder: &mut BufferBuilder, types: &[QueryType]) -> io::Result<()> {
+        let mut numbers: Vec<u16> = types.iter().map(QueryType::to_u16).collect();
+        numbers.sort_unstable();
+        numbers.dedup();
+        for window in numbers.chunk_by(|a, b| a >> 8 == b >> 8) {
+            let mut bitmap = [0u8; 32];
+            for number in window {
+                let low = (number & 0xff) as usize;
+                bitmap[low / 8] |= 0x80 >> (low % 8);
+            }
+            let len = (window[window.len() - 1] & 0xff) as usize / 8 + 1;
+            builder.write((window[0] >> 8) as u8)?;
+            builder.write(len as u8)?;
+            builder.write_bytes(&bitmap[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Splits a TXT string into character-strings of at most 255 bytes. Splits fall on
+    /// character boundaries, so a segment holding multi-byte UTF-8 may be a little shorter.
+    /// Reading the record back yields the segments as separate strings.
+    fn txt_segments(string: &str) -> Vec<&str> {
+        let mut segments = Vec::new();
+        let mut rest = string;
+        while rest.len() > 255 {
+            let mut end = 255;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (segment, tail) = rest.split_at(end);
+            segments.push(segment);
+            rest = tail;
+        }
+        segments.push(rest);
+        segments
+    }
+
+    /// Writes the class mnemonic, `IN` or the generic `CLASS1234` form.
+    fn fmt_class(f: &mut fmt::Formatter<'_>, class: u16) -> fmt::Result {
+        if class == CLASS_IN {
+            write!(f, "IN")
+        } else if class == CLASS_CH {
+            write!(f, "CH")
+        } else {
+            write!(f, "CLASS{}", class)
+        }
+    }
+
+    impl fmt::Display for Answer {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if self.query_type == QueryType::OPT {
+                return write!(f, "; EDNS: udp: {}, flags: {:#06x}", self.class, self.ttl & 0xffff);
+            }
+            write!(f, "{}.\t{}\t", self.name, self.ttl)?;
+            fmt_class(f, self.class)?;
+            write!(f, "\t{}\t{}", self.query_type, self.record)
+        }
+    }
+
     #[derive(Debug, PartialEq, Eq, Clone)]
     pub struct Question {
         pub name: String,
@@ -409,6 +1006,70 @@ pub mod dns_packet {
         }
     }
 
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub enum DnsError {
+        CountMismatch { section: &'static str, declared: u16, actual: usize },
+        NameTooLong(String),
+        LabelTooLong(String),
+        /// an empty label or a broken escape
+        InvalidName(String),
+        UnserializableRecord(String, QueryType),
+    }
+
+    impl fmt::Display for DnsError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                DnsError::CountMismatch { section, declared, actual } =>
+                    write!(f, "{} count is {} but section holds {} entries", section, declared, actual),
+                DnsError::NameTooLong(name) => write!(f, "name {} exceeds 255 bytes", name),
+                DnsError::LabelTooLong(name) => write!(f, "name {} has a label over 63 bytes", name),
+                DnsError::InvalidName(name) => write!(f, "name {} is not a valid domain name", name),
+                DnsError::UnserializableRecord(name, query_type) =>
+                    write!(f, "record {} of type {:?} can not be serialized", name, query_type),
+            }
+        }
+    }
+
+    impl std::error::Error for DnsError {}
+
+    impl DnsError {
+        /// Bad names have no code of their own and are reported as Other with the reason.
+        pub fn extended_error(&self) -> ExtendedError {
+            let info_code = match self {
+                DnsError::CountMismatch { .. } => ede::INVALID_DATA,
+                DnsError::NameTooLong(_) | DnsError::LabelTooLong(_) | DnsError::InvalidName(_) => ede::OTHER,
+                DnsError::UnserializableRecord(..) => ede::NOT_SUPPORTED,
+            };
+            ExtendedError::new(info_code, &self.to_string())
+        }
+    }
+
+    impl From<DnsError> for io::Error {
+        fn from(e: DnsError) -> Self {
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        }
+    }
+
+    fn validate_name(name: &str) -> Result<(), DnsError> {
+        let labels = split_labels(name).map_err(|_| DnsError::InvalidName(name.to_string()))?;
+        // wire length: one length byte per label plus the terminating null byte
+        if labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1 > 255 {
+            return Err(DnsError::NameTooLong(name.to_string()));
+        }
+        if labels.iter().any(|label| label.len() > 63) {
+            return Err(DnsError::LabelTooLong(name.to_string()));
+        }
+        Ok(())
+    }
+
+    impl fmt::Display for Question {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, ";{}.\t\t", self.name)?;
+            fmt_class(f, self.class)?;
+            write!(f, "\t{}", self.query_type)
+        }
+    }
+
     #[derive(Debug, PartialEq, Eq, Clone)]
     pub struct DnsPacket {
         pub header: Header,
@@ -418,8 +1079,61 @@ pub mod dns_packet {
         pub additional: Vec<Answer>
     }
 
+    /// Formats the packet like the output of `dig`.
+    impl fmt::Display for DnsPacket {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, ";; ->>HEADER<<- opcode: {:?}, status: {:?}, id: {}",
+                     self.header.get_op_code(), self.header.get_response_code(), self.header.id)?;
+            let flags = [
+                ("qr", self.header.get_query_response()),
+                ("aa", self.header.get_authoritative_answer()),
+                ("tc", self.header.get_truncated_message()),
+                ("rd", self.header.get_recursion_desired()),
+                ("ra", self.header.get_recursion_available()),
+            ];
+            write!(f, ";; flags:")?;
+            for (name, _) in flags.iter().filter(|(_, set)| *set) {
+                write!(f, " {}", name)?;
+            }
+            writeln!(f, "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+                     self.questions.len(), self.answers.len(), self.authorities.len(), self.additional.len())?;
+            if !self.questions.is_empty() {
+                writeln!(f, "\n;; QUESTION SECTION:")?;
+                for question in &self.questions {
+                    writeln!(f, "{}", question)?;
+                }
+            }
+            for (section, records) in [("ANSWER", &self.answers), ("AUTHORITY", &self.authorities), ("ADDITIONAL", &self.additional)] {
+                if !records.is_empty() {
+                    writeln!(f, "\n;; {} SECTION:", section)?;
+                    for record in records {
+                        writeln!(f, "{}", record)?;
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
     impl DnsPacket {
-        pub fn from_buf(buf: &[u8]) -> io::Result<DnsPacket>
+        /// Parses a packet, tolerating an additional count that promises more
+        /// records than the buffer holds.
+        pub fn from_buf(buf: &[u8]) -> io::Result<DnsPacket> {
+            DnsPacket::parse(buf, false)
+        }
+
+        /// Parses a packet, failing if any section holds fewer records than its count.
+        pub fn from_buf_strict(buf: &[u8]) -> io::Result<DnsPacket> {
+            DnsPacket::parse(buf, true)
+        }
+
+        /// Parses without allocating names, for hot paths that only inspect a
+        /// few fields. `from_buf` stays the default.
+        pub fn parse_borrowed(buf: &[u8]) -> io::Result<BorrowedPacket<'_>> {
+            BorrowedPacket::parse(buf)
+        }
+
+        fn parse(buf: &[u8], strict: bool) -> io::Result<DnsPacket>
         {
             let mut parser = BufferParser::new(buf);
             let mut dns_packet = DnsPacket {
@@ -430,16 +1144,29 @@ pub mod dns_packet {
                 additional: Vec::new(),
             };
 
+            check_section_count(&parser, "question", dns_packet.header.question_count, MIN_QUESTION_LEN)?;
             for _ in 0..dns_packet.header.question_count {
                 dns_packet.questions.push(Question::from_buf(&mut parser)?);
             }
+            check_section_count(&parser, "answer", dns_packet.header.answer_count, MIN_RECORD_LEN)?;
             for _ in 0..dns_packet.header.answer_count {
                 dns_packet.answers.push(Answer::from_buf(&mut parser)?);
             }
+            check_section_count(&parser, "authority", dns_packet.header.authoritiy_count, MIN_RECORD_LEN)?;
             for _ in 0..dns_packet.header.authoritiy_count {
                 dns_packet.authorities.push(Answer::from_buf(&mut parser)?);
             }
+            // without strict parsing missing additional records are tolerated below
+            if strict {
+                check_section_count(&parser, "additional", dns_packet.header.additional_count, MIN_RECORD_LEN)?;
+            }
             for _ in 0..dns_packet.header.additional_count {
+                if !strict && parser.remaining() == 0 {
+                    trace(format_args!("warning: packet {} declares {} additional records but contains {}",
+                                       dns_packet.header.id, dns_packet.header.additional_count, dns_packet.additional.len()));
+                    dns_packet.header.additional_count = dns_packet.additional.len() as u16;
+                    break;
+                }
                 dns_packet.additional.push(Answer::from_buf(&mut parser)?);
             }
             Ok(dns_packet)
@@ -455,10 +1182,55 @@ pub mod dns_packet {
             }
         }
 
+        pub fn query(id: u16, name: &str, query_type: QueryType) -> DnsPacket {
+            let mut packet = DnsPacket::new(Header::new(id, true, false, false, ResponseCode::NOERROR));
+            packet.add_question(Question {
+                name: name.to_string(),
+                query_type,
+                class: CLASS_IN,
+            });
+            packet
+        }
+
+        /// Builds a REFUSED response for `query` echoing its questions.
+        pub fn refused_response(query: &DnsPacket) -> DnsPacket {
+            DnsPacket::error_response(query, ResponseCode::REFUSED)
+        }
+
+        /// An answerless response to `query` with `response_code`, echoing the
+        /// id, opcode, RD flag and question section.
+        pub fn error_response(query: &DnsPacket, response_code: ResponseCode) -> DnsPacket {
+            let mut header = Header::new(query.header.id, query.header.get_recursion_desired(),
+                                         true, true, response_code);
+            header.set_op_code(query.header.get_op_code());
+            let mut response = DnsPacket::new(header);
+            response.set_questions(query.questions.clone());
+            response
+        }
+
         pub fn add_question(&mut self, question: Question) {
             self.questions.push(question);
             self.header.question_count += 1;
         }
+        /// The first question, which is the one this server answers.
+        pub fn question(&self) -> Option<&Question> {
+            self.questions.first()
+        }
+
+        /// Drops questions repeating an earlier one, comparing names ignoring case,
+        /// and keeps the order of the rest.
+        pub fn dedup_questions(&mut self) {
+            let mut unique: Vec<Question> = Vec::with_capacity(self.questions.len());
+            for question in self.questions.drain(..) {
+                let repeated = unique.iter().any(|seen| names_equal(&seen.name, &question.name)
+                    && seen.query_type == question.query_type && seen.class == question.class);
+                if !repeated {
+                    unique.push(question);
+                }
+            }
+            self.set_questions(unique);
+        }
+
         pub fn set_questions(&mut self, questions: Vec<Question>) {
             self.questions = questions;
             self.header.question_count = self.questions.len() as u16;
@@ -475,11 +1247,317 @@ pub mod dns_packet {
             self.authorities.push(auth);
             self.header.authoritiy_count += 1;
         }
+        pub fn set_authorities(&mut self, authorities: Vec<Answer>) {
+            self.authorities = authorities;
+            self.header.authoritiy_count = self.authorities.len() as u16;
+        }
+        pub fn set_additional(&mut self, additional: Vec<Answer>) {
+            self.additional = additional;
+            self.header.additional_count = self.additional.len() as u16;
+        }
         pub fn add_additional(&mut self, additional: Answer) {
             self.additional.push(additional);
             self.header.additional_count += 1;
         }
 
+        /// The SOA record of a negative answer, an NXDOMAIN or a NOERROR without answers,
+        /// None for positive answers, referrals and errors.
+        pub fn negative_soa(&self) -> Option<&Answer> {
+            let negative = match self.header.get_response_code() {
+                ResponseCode::NXDOMAIN => true,
+                ResponseCode::NOERROR => self.answers.is_empty(),
+                _ => false,
+            };
+            if !negative {
+                return None;
+            }
+            self.authorities.iter().find(|authority| authority.query_type == QueryType::SOA)
+        }
+
+        /// The end of the CNAME chain in the answers when the chain does not reach
+        /// a record of the queried type, i.e. the name resolution has to continue at.
+        pub fn unresolved_cname(&self) -> Option<&str> {
+            let question = self.question()?;
+            if question.query_type == QueryType::CNAME {
+                return None;
+            }
+            let mut name = &question.name[..];
+            // every answer can extend the chain at most once, which also ends loops
+            for _ in 0..self.answers.len() {
+                let next = self.answers.iter().find_map(|answer| match &answer.record {
+                    Record::CNAME(target) if names_equal(&answer.name, name) => Some(&target[..]),
+                    _ => None,
+                });
+                match next {
+                    Some(target) => name = target,
+                    None => break,
+                }
+            }
+            let answered = self.answers.iter()
+                .any(|answer| names_equal(&answer.name, name) && answer.query_type == question.query_type);
+            if names_equal(name, &question.name) || answered {
+                None
+            } else {
+                Some(name)
+            }
+        }
+
+        /// Completes a CNAME chain whose target is answered only in the additional
+        /// section by moving those records into the answers. Returns whether it did.
+        /// Only a target within `bailiwick`, the zone the sender is authoritative for,
+        /// is trusted, anything else has to be resolved on its own.
+        pub fn answer_cname_from_additional(&mut self, bailiwick: &str) -> bool {
+            let (target, query_type) = match (self.unresolved_cname(), self.question()) {
+                (Some(target), Some(question)) => (target.to_string(), question.query_type.clone()),
+                _ => return false,
+            };
+            if !is_subdomain_of(&target, bailiwick) {
+                return false;
+            }
+            let (answers, additional): (Vec<Answer>, Vec<Answer>) = std::mem::take(&mut self.additional).into_iter()
+                .partition(|additional| names_equal(&additional.name, &target) && additional.query_type == query_type);
+            self.set_additional(additional);
+            if answers.is_empty() {
+                return false;
+            }
+            let mut all_answers = std::mem::take(&mut self.answers);
+            all_answers.extend(answers);
+            self.set_answers(all_answers);
+            true
+        }
+
+        /// Drops additional records that no answer or authority record refers to,
+        /// keeping the addresses of NS, MX and CNAME targets and the OPT record.
+        pub fn retain_relevant_additional(&mut self) {
+            let referenced: Vec<String> = self.answers.iter().chain(&self.authorities)
+                .filter_map(|answer| match &answer.record {
+                    Record::NS(name) | Record::CNAME(name) | Record::MX { host: name, .. } => Some(name.clone()),
+                    _ => None,
+                })
+                .collect();
+            let additional = std::mem::take(&mut self.additional).into_iter()
+                .filter(|additional| additional.query_type == QueryType::OPT
+                    || referenced.iter().any(|name| names_equal(name, &additional.name)))
+                .collect();
+            self.set_additional(additional);
+        }
+
+        /// Appends the records of `other` that are not present yet, deduplicated
+        /// by name, type and record data. The question section is left untouched.
+        pub fn merge_answers(&mut self, other: &DnsPacket) {
+            fn merge(target: &mut Vec<Answer>, records: &[Answer]) {
+                for record in records {
+                    let is_duplicate = target.iter().any(|existing|
+                        existing.name == record.name
+                            && existing.query_type == record.query_type
+                            && existing.record == record.record);
+                    if !is_duplicate {
+                        target.push(record.clone());
+                    }
+                }
+            }
+            merge(&mut self.answers, &other.answers);
+            merge(&mut self.authorities, &other.authorities);
+            merge(&mut self.additional, &other.additional);
+            self.header.answer_count = self.answers.len() as u16;
+            self.header.authoritiy_count = self.authorities.len() as u16;
+            self.header.additional_count = self.additional.len() as u16;
+        }
+
+        /// Checks the invariants a packet must hold before it is written to the wire.
+        pub fn validate(&self) -> Result<(), DnsError> {
+            let sections = [
+                ("question", self.header.question_count, self.questions.len()),
+                ("answer", self.header.answer_count, self.answers.len()),
+                ("authority", self.header.authoritiy_count, self.authorities.len()),
+                ("additional", self.header.additional_count, self.additional.len()),
+            ];
+            for (section, declared, actual) in sections {
+                if declared as usize != actual {
+                    return Err(DnsError::CountMismatch { section, declared, actual });
+                }
+            }
+            for question in &self.questions {
+                validate_name(&question.name)?;
+            }
+            for answer in self.answers.iter().chain(&self.authorities).chain(&self.additional) {
+                validate_name(&answer.name)?;
+                match &answer.record {
+                    Record::NS(name) | Record::CNAME(name) | Record::PTR(name) | Record::MX { host: name, .. } => validate_name(name)?,
+                    Record::UNKOWN(_) => {
+                        return Err(DnsError::UnserializableRecord(answer.name.clone(), answer.query_type.clone()));
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        }
+
+        pub fn edns(&self) -> Option<&Answer> {
+            self.additional.iter().find(|additional| additional.query_type == QueryType::OPT)
+        }
+
+        /// Replaces the OPT record, if any, by one advertising `udp_payload_size`.
+        pub fn set_edns(&mut self, udp_payload_size: u16, dnssec_ok: bool) {
+            self.additional.retain(|additional| additional.query_type != QueryType::OPT);
+            self.additional.push(Answer::opt(udp_payload_size, dnssec_ok));
+            self.header.additional_count = self.additional.len() as u16;
+        }
+
+        /// The client subnet option of the OPT record, if present and well formed.
+        pub fn client_subnet(&self) -> Option<ClientSubnet> {
+            match &self.edns()?.record {
+                Record::OPT(options) => options.iter()
+                    .find(|option| option.code == EDNS_CLIENT_SUBNET)
+                    .and_then(|option| ClientSubnet::from_option(option).ok()),
+                _ => None,
+            }
+        }
+
+        /// Adds `subnet` to the OPT record, replacing any previous client subnet.
+        /// Does nothing if the packet carries no OPT record.
+        pub fn set_client_subnet(&mut self, subnet: &ClientSubnet) {
+            let opt = self.additional.iter_mut().find(|additional| additional.query_type == QueryType::OPT);
+            if let Some(Answer { record: Record::OPT(options), .. }) = opt {
+                options.retain(|option| option.code != EDNS_CLIENT_SUBNET);
+                options.push(subnet.to_option());
+            }
+        }
+
+        /// The extended error option of the OPT record, if present and well formed.
+        pub fn extended_error(&self) -> Option<ExtendedError> {
+            match &self.edns()?.record {
+                Record::OPT(options) => options.iter()
+                    .find(|option| option.code == EDNS_EXTENDED_ERROR)
+                    .and_then(|option| ExtendedError::from_option(option).ok()),
+                _ => None,
+            }
+        }
+
+        /// Adds `error` to the OPT record, replacing any previous extended error.
+        /// Does nothing if the packet carries no OPT record.
+        pub fn set_extended_error(&mut self, error: &ExtendedError) {
+            let opt = self.additional.iter_mut().find(|additional| additional.query_type == QueryType::OPT);
+            if let Some(Answer { record: Record::OPT(options), .. }) = opt {
+                options.retain(|option| option.code != EDNS_EXTENDED_ERROR);
+                options.push(error.to_option());
+            }
+        }
+
+        /// Whether the OPT record carries a tcp keepalive option.
+        pub fn has_tcp_keepalive(&self) -> bool {
+            match self.edns().map(|opt| &opt.record) {
+                Some(Record::OPT(options)) => options.iter().any(|option| option.code == EDNS_TCP_KEEPALIVE),
+                _ => false,
+            }
+        }
+
+        /// The idle timeout of the tcp keepalive option, sent in units of 100 milliseconds.
+        pub fn tcp_keepalive(&self) -> Option<Duration> {
+            match &self.edns()?.record {
+                Record::OPT(options) => options.iter()
+                    .find(|option| option.code == EDNS_TCP_KEEPALIVE && option.data.len() == 2)
+                    .map(|option| Duration::from_millis(u16::from_be_bytes([option.data[0], option.data[1]]) as u64 * 100)),
+                _ => None,
+            }
+        }
+
+        /// Adds a tcp keepalive option announcing `idle_timeout`, replacing any previous one.
+        /// Does nothing if the packet carries no OPT record.
+        pub fn set_tcp_keepalive(&mut self, idle_timeout: Duration) {
+            let units = (idle_timeout.as_millis() / 100).min(u16::MAX as u128) as u16;
+            let opt = self.additional.iter_mut().find(|additional| additional.query_type == QueryType::OPT);
+            if let Some(Answer { record: Record::OPT(options), .. }) = opt {
+                options.retain(|option| option.code != EDNS_TCP_KEEPALIVE);
+                options.push(EdnsOption { code: EDNS_TCP_KEEPALIVE, data: units.to_be_bytes().to_vec() });
+            }
+        }
+
+        /// Whether the OPT record carries a padding option.
+        pub fn has_padding(&self) -> bool {
+            match self.edns().map(|opt| &opt.record) {
+                Some(Record::OPT(options)) => options.iter().any(|option| option.code == EDNS_PADDING),
+                _ => false,
+            }
+        }
+
+        /// Replaces any padding option by a zero filled one making the whole message a
+        /// multiple of `block` bytes long. Does nothing if the packet carries no OPT record.
+        pub fn pad_to_block(&mut self, block: u16) {
+            if self.edns().is_none() {
+                return;
+            }
+            self.set_padding(0);
+            let block = block.max(1) as usize;
+            let len = self.wire_len();
+            // a message padded beyond what fits a length prefix is left at its size
+            let padding = ((block - len % block) % block).min(u16::MAX as usize - len.min(u16::MAX as usize));
+            self.set_padding(padding);
+        }
+
+        fn remove_padding(&mut self) {
+            let opt = self.additional.iter_mut().find(|additional| additional.query_type == QueryType::OPT);
+            if let Some(Answer { record: Record::OPT(options), .. }) = opt {
+                options.retain(|option| option.code != EDNS_PADDING);
+            }
+        }
+
+        fn set_padding(&mut self, len: usize) {
+            let opt = self.additional.iter_mut().find(|additional| additional.query_type == QueryType::OPT);
+            if let Some(Answer { record: Record::OPT(options), .. }) = opt {
+                options.retain(|option| option.code != EDNS_PADDING);
+                options.push(EdnsOption { code: EDNS_PADDING, data: vec![0; len] });
+            }
+        }
+
+        pub fn dnssec_ok(&self) -> bool {
+            self.edns().is_some_and(|opt| opt.ttl & EDNS_DO != 0)
+        }
+
+        /// The EDNS version of the OPT record, carried in the second byte of its ttl.
+        pub fn edns_version(&self) -> Option<u8> {
+            self.edns().map(|opt| (opt.ttl >> 16) as u8)
+        }
+
+        /// The 12 bit response code, the upper 8 bits of which live in the ttl of the OPT record.
+        pub fn extended_response_code(&self) -> u16 {
+            let upper = self.edns().map_or(0, |opt| opt.ttl >> 24) as u16;
+            upper << 4 | self.header.get_response_code().to_u8() as u16
+        }
+
+        /// Splits `response_code` into the header and the OPT record. Codes above 15
+        /// need the OPT record, without one only the lower 4 bits are sent.
+        pub fn set_extended_response_code(&mut self, response_code: u16) {
+            self.header.set_response_code(ResponseCode::from((response_code & 0x0f) as u8));
+            let opt = self.additional.iter_mut().find(|additional| additional.query_type == QueryType::OPT);
+            if let Some(opt) = opt {
+                opt.ttl = (opt.ttl & 0x00ff_ffff) | ((response_code >> 4) as u32) << 24;
+            }
+        }
+
+        /// Removes RRSIG, DNSKEY, DS and NSEC records from all sections, except those
+        /// of the type the question asks for.
+        pub fn strip_dnssec_records(&mut self) {
+            let asked = self.question().map(|question| question.query_type.clone());
+            let strip = |record: &Answer| record.query_type.is_dnssec() && Some(&record.query_type) != asked.as_ref();
+            self.answers.retain(|answer| !strip(answer));
+            self.authorities.retain(|auth| !strip(auth));
+            self.additional.retain(|additional| !strip(additional));
+            self.header.answer_count = self.answers.len() as u16;
+            self.header.authoritiy_count = self.authorities.len() as u16;
+            self.header.additional_count = self.additional.len() as u16;
+        }
+
+        /// Sets the TTL of every record to `ttl`. OPT records are skipped, their TTL
+        /// field holds the extended rcode and flags.
+        pub fn set_all_ttls(&mut self, ttl: u32) {
+            self.answers.iter_mut()
+                .chain(self.authorities.iter_mut())
+                .chain(self.additional.iter_mut())
+                .filter(|answer| answer.query_type != QueryType::OPT)
+                .for_each(|answer| answer.ttl = ttl);
+        }
+
         pub fn write_to_buf(&self, builder: &mut BufferBuilder) -> io::Result<()> {
             self.header.write_to_buf(builder)?;
             for q in &self.questions {
@@ -497,6 +1575,75 @@ pub mod dns_packet {
             Ok(())
         }
 
+        /// Writes as many whole records as fit into `builder` and returns how many
+        /// were written, counting answers, authorities and additional in order.
+        /// The builder is left right after the last complete record and the header
+        /// counts are not adjusted. Running out of space within the header or the
+        /// questions, as well as any other error, is still fatal.
+        pub fn write_to_buf_partial(&self, builder: &mut BufferBuilder) -> io::Result<usize> {
+            self.header.write_to_buf(builder)?;
+            for q in &self.questions {
+                q.write_to_buf(builder)?
+            }
+            let mut written = 0;
+            for a in self.answers.iter().chain(&self.authorities).chain(&self.additional) {
+                let pos = builder.get_pos();
+                match a.write_to_buf(builder) {
+                    Ok(()) => written += 1,
+                    Err(e) if e.kind() == io::ErrorKind::WriteZero => {
+                        builder.seek(pos);
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(written)
+        }
+
+        /// The number of bytes `write_to_buf` writes for this packet, name compression
+        /// included. For packets `write_to_buf` rejects, such as ones with overlong
+        /// labels, this is the size written before the failure.
+        pub fn wire_len(&self) -> usize {
+            let mut builder = BufferBuilder::counting();
+            let _ = self.write_to_buf(&mut builder);
+            builder.get_pos()
+        }
+
+        /// Serializes the packet into a buffer of exactly its wire length.
+        pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+            let mut buf = vec![0u8; self.wire_len()];
+            let mut builder = BufferBuilder::new(&mut buf);
+            self.write_to_buf(&mut builder)?;
+            Ok(buf)
+        }
+
+        /// The udp payload size the sender of this packet accepts, 512 without EDNS.
+        pub fn udp_payload_size(&self) -> u16 {
+            self.edns().map_or(512, |opt| opt.class.max(512))
+        }
+
+        /// This packet if it fits in `max_size` bytes, otherwise a copy with TC set keeping
+        /// only the question and the OPT record without padding, telling the client to ask
+        /// again over tcp. The OPT record goes too if even that does not fit.
+        pub fn truncated_to(self, max_size: usize) -> DnsPacket {
+            if self.wire_len() <= max_size {
+                return self;
+            }
+            let mut truncated = self;
+            truncated.header.set_truncated_message(true);
+            truncated.set_answers(Vec::new());
+            truncated.set_authorities(Vec::new());
+            let opt = std::mem::take(&mut truncated.additional).into_iter()
+                .filter(|additional| additional.query_type == QueryType::OPT)
+                .collect();
+            truncated.set_additional(opt);
+            truncated.remove_padding();
+            if truncated.wire_len() > max_size {
+                truncated.set_additional(Vec::new());
+            }
+            truncated
+        }
+
         pub fn to_buf(&self) -> io::Result<([u8;512], usize)> {
             let mut buf = [0u8;512];
             let bytes_written;
@@ -509,7 +1656,7 @@ pub mod dns_packet {
             Ok((buf, bytes_written))
         }
 
-        pub fn get_ipv4_iterator_additional<'a>(&'a self) -> impl Iterator<Item = (&Ipv4Addr, &'a str)> {
+        pub fn get_ipv4_iterator_additional(&self) -> impl Iterator<Item = (&Ipv4Addr, &str)> {
             self.additional.iter()
                 .filter_map(|additional| match &additional.record {
                     Record::A(ip) => Some((ip, &additional.name[..])),
@@ -517,6 +1664,30 @@ pub mod dns_packet {
                 })
         }
 
+        /// Like `get_ipv4_iterator_additional` for both A and AAAA records, in section order.
+        pub fn get_address_iterator_additional(&self) -> impl Iterator<Item = (IpAddr, &str)> {
+            self.additional.iter()
+                .filter_map(|additional| match &additional.record {
+                    Record::A(ip) => Some((IpAddr::V4(*ip), &additional.name[..])),
+                    Record::AAAA(ip) => Some((IpAddr::V6(*ip), &additional.name[..])),
+                    _ => None
+                })
+        }
+
+        /// Collects the addresses of all A and AAAA answers, A records first,
+        /// each family in answer section order.
+        pub fn all_addresses(&self) -> Vec<IpAddr> {
+            let v4 = self.answers.iter().filter_map(|answer| match &answer.record {
+                Record::A(ip) => Some(IpAddr::V4(*ip)),
+                _ => None
+            });
+            let v6 = self.answers.iter().filter_map(|answer| match &answer.record {
+                Record::AAAA(ip) => Some(IpAddr::V6(*ip)),
+                _ => None
+            });
+            v4.chain(v6).collect()
+        }
+
         pub fn get_ipv4_iterator_answers(&self) -> impl Iterator<Item=&Ipv4Addr> {
             self.answers.iter()
                 .filter_map(|additional| match &additional.record {
@@ -525,36 +1696,57 @@ pub mod dns_packet {
                 })
         }
 
+        /// Returns the NS records of the closest zone enclosing `qname`
+        /// as (nameserver, zone) pairs.
         pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)>{
-            return  self.authorities.iter()
+            let closest_zone = self.authorities.iter()
+                .filter(|auth| matches!(auth.record, Record::NS(_)))
+                .map(|auth| &auth.name[..])
+                .filter(|auth_name| is_subdomain_of(qname, auth_name))
+                .max_by_key(|auth_name| auth_name.len());
+            self.authorities.iter()
                 .filter_map(|auth| match  &auth.record {
                     Record::NS(server) => Some((&server[..], &auth.name[..])),
                     _ => None
                 })
-                .filter(|(_, auth_name)| qname.ends_with(auth_name))
+                .filter(move |(_, auth_name)| closest_zone.is_some_and(|zone| names_equal(auth_name, zone)))
         }
 
        pub fn get_resolved_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = &'a Ipv4Addr> {
            self.get_unresolved_ns(qname)
                .flat_map(|(server, _)|
                    self.get_ipv4_iterator_additional()
-                       .filter( move |(_, additional_name)|
-                           if *additional_name == server {
-                               return true;
-                           } else {
-                               return false;
-                           }
-                       )
+                       .filter( move |(_, additional_name)| names_equal(additional_name, server))
                           .map(|(ip, _)| ip))
 
         }
 
+        /// The A and AAAA glue of the nameservers `get_unresolved_ns` returns, in the
+        /// order of the nameservers and, for each, of the additional section.
+        pub fn get_glue_addresses<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
+            self.get_unresolved_ns(qname)
+                .flat_map(|(server, _)|
+                    self.get_address_iterator_additional()
+                        .filter(move |(_, additional_name)| names_equal(additional_name, server))
+                        .map(|(ip, _)| ip))
+        }
+
+        /// TTL for caching a negative answer per RFC 2308: the smaller of the
+        /// authority SOA's own ttl and its MINIMUM field, None without an SOA.
+        pub fn negative_ttl(&self) -> Option<u32> {
+            self.authorities.iter()
+                .find_map(|auth| match &auth.record {
+                    Record::SOA { minimum, .. } => Some(auth.ttl.min(*minimum)),
+                    _ => None
+                })
+        }
+
         pub fn get_all_answers<'a>(&'a self, qname: &'a str) -> impl Iterator<Item=&'a Answer> {
             std::iter::once(self.answers.iter())
                 .chain(std::iter::once(self.authorities.iter()))
                 .chain(std::iter::once(self.additional.iter()))
-                .flat_map(|x| x)
-                .filter(|x | qname.ends_with(&x.name))
+                .flatten()
+                .filter(|x | is_subdomain_of(qname, &x.name))
         }
     }
 
@@ -0,0 +1,215 @@
+pub mod borrowed {
+    use std::{fmt, io};
+    use crate::dns_server::dns_packet::buffer::buffer::{BufferParser, JUMP_MASK, MAX_JUMPS, push_label};
+    use crate::dns_server::dns_packet::dns_packet::{Answer, check_section_count, DnsPacket, Header, MIN_QUESTION_LEN, MIN_RECORD_LEN, QueryType, Question, Record};
+    use crate::dns_server::query_log::query_log::trace;
+
+    /// A name inside a received packet, kept as its offset so nothing is allocated
+    /// until the name is needed as a `String`. Compression pointers are followed lazily.
+    #[derive(Copy, Clone, Debug)]
+    pub struct NameRef<'a> {
+        buf: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> NameRef<'a> {
+        /// Skips over the name at the parser's position, validating it.
+        fn read(buf: &'a [u8], parser: &mut BufferParser) -> io::Result<NameRef<'a>> {
+            let pos = parser.get_pos();
+            parser.skip_name()?;
+            Ok(NameRef { buf, pos })
+        }
+
+        /// The raw labels of the name, without the terminating root label.
+        pub fn labels(&self) -> Labels<'a> {
+            Labels { buf: self.buf, pos: self.pos, jumps: 0 }
+        }
+
+        /// Compares with a dotted name, ignoring case and a trailing dot.
+        pub fn matches(&self, name: &str) -> bool {
+            let name = name.strip_suffix('.').unwrap_or(name);
+            let mut labels = self.labels();
+            if !name.is_empty() {
+                for expected in name.split('.') {
+                    match labels.next() {
+                        Some(label) if label.eq_ignore_ascii_case(expected.as_bytes()) => {}
+                        _ => return false,
+                    }
+                }
+            }
+            labels.next().is_none()
+        }
+
+        /// Materializes the name the same way the owned parser does.
+        pub fn to_name(&self) -> io::Result<String> {
+            let mut parser = BufferParser::new(self.buf);
+            parser.seek(self.pos);
+            parser.read_name()
+        }
+    }
+
+    impl fmt::Display for NameRef<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut name = String::new();
+            for (idx, label) in self.labels().enumerate() {
+                if idx > 0 {
+                    name.push('.');
+                }
+                push_label(&mut name, label);
+            }
+            write!(f, "{}", name)
+        }
+    }
+
+    pub struct Labels<'a> {
+        buf: &'a [u8],
+        pos: usize,
+        jumps: i32,
+    }
+
+    impl<'a> Iterator for Labels<'a> {
+        type Item = &'a [u8];
+
+        fn next(&mut self) -> Option<&'a [u8]> {
+            loop {
+                let len = *self.buf.get(self.pos)?;
+                if (JUMP_MASK & len) == JUMP_MASK {
+                    // the name was validated on parse, this only guards against misuse
+                    self.jumps += 1;
+                    if self.jumps > MAX_JUMPS {
+                        return None;
+                    }
+                    let low = *self.buf.get(self.pos + 1)?;
+                    self.pos = u16::from_be_bytes([len & !JUMP_MASK, low]) as usize;
+                    continue;
+                }
+                if len == 0 {
+                    return None;
+                }
+                let start = self.pos + 1;
+                self.pos = start + len as usize;
+                return self.buf.get(start..self.pos);
+            }
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct BorrowedQuestion<'a> {
+        pub name: NameRef<'a>,
+        pub query_type: QueryType,
+        pub class: u16,
+    }
+
+    impl BorrowedQuestion<'_> {
+        pub fn to_owned(&self) -> io::Result<Question> {
+            Ok(Question { name: self.name.to_name()?, query_type: self.query_type.clone(), class: self.class })
+        }
+    }
+
+    /// A resource record whose rdata is only parsed by `record`.
+    #[derive(Clone, Debug)]
+    pub struct BorrowedAnswer<'a> {
+        pub name: NameRef<'a>,
+        pub query_type: QueryType,
+        pub class: u16,
+        pub ttl: u32,
+        pub len: u16,
+        buf: &'a [u8],
+        rdata_pos: usize,
+    }
+
+    impl<'a> BorrowedAnswer<'a> {
+        pub fn rdata(&self) -> &'a [u8] {
+            &self.buf[self.rdata_pos..self.rdata_pos + self.len as usize]
+        }
+
+        pub fn record(&self) -> io::Result<Record> {
+            let mut parser = BufferParser::new(self.buf);
+            parser.seek(self.rdata_pos);
+            Record::from_buf(&mut parser, self.len, self.query_type.clone())
+        }
+
+        pub fn to_owned(&self) -> io::Result<Answer> {
+            Ok(Answer {
+                name: self.name.to_name()?,
+                query_type: self.query_type.clone(),
+                class: self.class,
+                ttl: self.ttl,
+                len: self.len,
+                record: self.record()?,
+            })
+        }
+    }
+
+    /// A packet parsed without copying names or rdata out of the buffer it came from.
+    #[derive(Clone, Debug)]
+    pub struct BorrowedPacket<'a> {
+        pub header: Header,
+        pub questions: Vec<BorrowedQuestion<'a>>,
+        pub answers: Vec<BorrowedAnswer<'a>>,
+        pub authorities: Vec<BorrowedAnswer<'a>>,
+        pub additional: Vec<BorrowedAnswer<'a>>,
+    }
+
+    impl<'a> BorrowedPacket<'a> {
+        /// Parses like `DnsPacket::from_buf` but only checks that names and rdata
+        /// lie within `buf`, so malformed rdata surfaces in `record` or `to_owned`.
+        pub fn parse(buf: &'a [u8]) -> io::Result<BorrowedPacket<'a>> {
+            let mut parser = BufferParser::new(buf);
+            let mut header = Header::from_buf(&mut parser)?;
+            check_section_count(&parser, "question", header.question_count, MIN_QUESTION_LEN)?;
+            let mut questions = Vec::with_capacity(header.question_count as usize);
+            for _ in 0..header.question_count {
+                questions.push(BorrowedQuestion {
+                    name: NameRef::read(buf, &mut parser)?,
+                    query_type: QueryType::from(parser.read_u16()?),
+                    class: parser.read_u16()?,
+                });
+            }
+            let answers = read_section(buf, &mut parser, "answer", header.answer_count)?;
+            let authorities = read_section(buf, &mut parser, "authority", header.authoritiy_count)?;
+            // missing additional records are tolerated, so the declared count may not fit
+            let mut additional = Vec::with_capacity((header.additional_count as usize).min(parser.remaining() / MIN_RECORD_LEN));
+            for _ in 0..header.additional_count {
+                if parser.remaining() == 0 {
+                    trace(format_args!("warning: packet {} declares {} additional records but contains {}",
+                                       header.id, header.additional_count, additional.len()));
+                    header.additional_count = additional.len() as u16;
+                    break;
+                }
+                additional.push(read_answer(buf, &mut parser)?);
+            }
+            Ok(BorrowedPacket { header, questions, answers, authorities, additional })
+        }
+
+        pub fn to_owned(&self) -> io::Result<DnsPacket> {
+            let mut packet = DnsPacket::new(self.header);
+            packet.questions = self.questions.iter().map(|question| question.to_owned()).collect::<io::Result<_>>()?;
+            packet.answers = self.answers.iter().map(|answer| answer.to_owned()).collect::<io::Result<_>>()?;
+            packet.authorities = self.authorities.iter().map(|answer| answer.to_owned()).collect::<io::Result<_>>()?;
+            packet.additional = self.additional.iter().map(|answer| answer.to_owned()).collect::<io::Result<_>>()?;
+            Ok(packet)
+        }
+    }
+
+    fn read_section<'a>(buf: &'a [u8], parser: &mut BufferParser, section: &str, count: u16) -> io::Result<Vec<BorrowedAnswer<'a>>> {
+        check_section_count(parser, section, count, MIN_RECORD_LEN)?;
+        let mut answers = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            answers.push(read_answer(buf, parser)?);
+        }
+        Ok(answers)
+    }
+
+    fn read_answer<'a>(buf: &'a [u8], parser: &mut BufferParser) -> io::Result<BorrowedAnswer<'a>> {
+        let name = NameRef::read(buf, parser)?;
+        let query_type = QueryType::from(parser.read_u16()?);
+        let class = parser.read_u16()?;
+        let ttl = parser.read_u32()?;
+        let len = parser.read_u16()?;
+        let rdata_pos = parser.get_pos();
+        parser.get_range(rdata_pos, len as usize)?;
+        parser.seek(rdata_pos + len as usize);
+        Ok(BorrowedAnswer { name, query_type, class, ttl, len, buf, rdata_pos })
+    }
+}
@@ -1,11 +1,77 @@
 pub mod buffer {
-    const JUMP_MASK: u8 = 0b1100_0000;
-    const MAX_JUMPS: i32 = 5;
+    pub(crate) const JUMP_MASK: u8 = 0b1100_0000;
+    pub(crate) const MAX_JUMPS: i32 = 5;
+    /// highest offset a 14 bit compression pointer can address
+    const MAX_POINTER_OFFSET: usize = 0x3fff;
 
     use std::{io};
+    use std::collections::HashMap;
     use std::io::{Error, ErrorKind};
     use std::mem::size_of;
 
+    /// Appends `label` to `name` in presentation form. ASCII letters are lowercased,
+    /// dots and backslashes are escaped with a backslash and bytes that are neither
+    /// printable ASCII nor part of valid UTF-8 are written as `\DDD`, so
+    /// `split_labels` gets back the exact bytes.
+    pub fn push_label(name: &mut String, label: &[u8]) {
+        for chunk in label.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c {
+                    '.' | '\\' => {
+                        name.push('\\');
+                        name.push(c);
+                    }
+                    c if c.is_ascii_graphic() || !c.is_ascii() => name.push(c.to_ascii_lowercase()),
+                    c => name.push_str(&format!("\\{:03}", c as u8)),
+                }
+            }
+            for byte in chunk.invalid() {
+                name.push_str(&format!("\\{:03}", byte));
+            }
+        }
+    }
+
+    /// Splits a name in presentation form into the bytes of its labels, undoing the
+    /// escapes of `push_label`. The root name has no labels and a single trailing dot is
+    /// ignored, any other empty label is rejected.
+    pub fn split_labels(name: &str) -> io::Result<Vec<Vec<u8>>> {
+        if name == "." {
+            return Ok(Vec::new());
+        }
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut chars = name.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => {
+                    if label.is_empty() {
+                        return Err(Error::new(ErrorKind::InvalidInput, "Empty label"));
+                    }
+                    labels.push(std::mem::take(&mut label));
+                }
+                '\\' => {
+                    let escaped = chars.next()
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Name ends in an escape"))?;
+                    if escaped.is_ascii_digit() {
+                        let digits: String = std::iter::once(escaped)
+                            .chain((0..2).filter_map(|_| chars.next_if(char::is_ascii_digit)))
+                            .collect();
+                        let byte = digits.parse::<u8>().ok().filter(|_| digits.len() == 3)
+                            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid \\DDD escape"))?;
+                        label.push(byte);
+                    } else {
+                        label.extend_from_slice(escaped.encode_utf8(&mut [0; 4]).as_bytes());
+                    }
+                }
+                c => label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        if !label.is_empty() {
+            labels.push(label);
+        }
+        Ok(labels)
+    }
+
     pub struct BufferParser<'a> {
         buf_view: &'a[u8],
         position : usize
@@ -26,9 +92,13 @@ pub mod buffer {
             self.position
         }
 
+        pub fn remaining(&self) -> usize {
+            self.buf_view.len().saturating_sub(self.position)
+        }
+
         pub fn get(&self, pos: usize) -> io::Result<u8> {
             if pos >= self.buf_view.len() {
-                return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+                return Err(Error::new(ErrorKind::UnexpectedEof, "End of buffer"));
             }
             Ok(self.buf_view[pos])
         }
@@ -36,7 +106,7 @@ pub mod buffer {
         pub fn get_u16(&self, pos: usize) -> io::Result<u16> {
             let size_of_type = size_of::<u16>();
             if pos + size_of_type > self.buf_view.len() {
-                return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+                return Err(Error::new(ErrorKind::UnexpectedEof, "End of buffer"));
             }
             let slice = &self.buf_view[pos..pos + size_of_type];
             Ok(u16::from_be_bytes(slice.try_into().unwrap()))
@@ -45,7 +115,7 @@ pub mod buffer {
         pub fn get_u32(&self, pos: usize) -> io::Result<u32> {
             let size_of_type = size_of::<u32>();
             if pos + size_of_type > self.buf_view.len() {
-                return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+                return Err(Error::new(ErrorKind::UnexpectedEof, "End of buffer"));
             }
             let slice = &self.buf_view[pos..pos + size_of_type];
             Ok(u32::from_be_bytes(slice.try_into().unwrap()))
@@ -54,15 +124,15 @@ pub mod buffer {
         pub fn get_u128(&self, pos: usize) -> io::Result<u128> {
             let size_of_type = size_of::<u128>();
             if pos + size_of_type > self.buf_view.len() {
-                return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+                return Err(Error::new(ErrorKind::UnexpectedEof, "End of buffer"));
             }
             let slice = &self.buf_view[pos..pos + size_of_type];
             Ok(u128::from_be_bytes(slice.try_into().unwrap()))
         }
 
         pub fn get_range(&self, begin: usize, len: usize) -> io::Result<&[u8]> {
-            if begin + len >= self.buf_view.len() {
-                return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            if begin + len > self.buf_view.len() {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "End of buffer"));
             }
             Ok(&self.buf_view[begin..begin + len])
         }
@@ -90,6 +160,50 @@ pub mod buffer {
             Ok(result)
         }
 
+        pub fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+            let result = self.get_range(self.position, len)?.to_vec();
+            self.position += len;
+            Ok(result)
+        }
+
+        /// Reads a character-string, a length byte followed by that many bytes.
+        pub fn read_character_string(&mut self) -> io::Result<String> {
+            let len = self.read()?;
+            let bytes = self.read_bytes(len as usize)?;
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        }
+
+        /// Moves past the name at the current position without building it,
+        /// checking every label and pointer it consists of.
+        pub fn skip_name(&mut self) -> io::Result<()> {
+            let mut local_pos = self.position;
+            let mut jump_counter = 0;
+            loop {
+                if jump_counter > MAX_JUMPS {
+                    return Err(Error::new(ErrorKind::InvalidInput, "reached max amount of jumps"));
+                }
+                let len = self.get(local_pos)?;
+                if (JUMP_MASK & len) == JUMP_MASK {
+                    if jump_counter == 0 {
+                        self.seek(local_pos + 2);
+                    }
+                    local_pos = (self.get_u16(local_pos)? & !((JUMP_MASK as u16) << 8)) as usize;
+                    jump_counter += 1;
+                    continue;
+                }
+                local_pos += 1;
+                if len == 0 {
+                    break;
+                }
+                self.get_range(local_pos, len as usize)?;
+                local_pos += len as usize;
+            }
+            if jump_counter == 0 {
+                self.seek(local_pos);
+            }
+            Ok(())
+        }
+
         pub fn read_name(&mut self) -> io::Result<String> {
             let mut name = String::new();
             let mut local_pos = self.position;
@@ -120,8 +234,8 @@ pub mod buffer {
                         break;
                     }
 
-                    let str_buffer = self.get_range(local_pos, len as usize)?;
-                    name += &*String::from_utf8_lossy(str_buffer).to_lowercase();
+                    let label = self.get_range(local_pos, len as usize)?;
+                    push_label(&mut name, label);
                     name += ".";
 
                     local_pos += len as usize;
@@ -141,6 +255,10 @@ pub mod buffer {
     pub struct BufferBuilder<'a> {
         pub(crate) buf_view: &'a mut [u8],
         position: usize,
+        /// where each name suffix written so far starts, keyed by its lowercased wire form
+        name_offsets: HashMap<Vec<u8>, usize>,
+        /// only advance the position without storing bytes, see `counting`
+        counting: bool,
     }
 
     impl<'a> BufferBuilder<'a> {
@@ -148,75 +266,124 @@ pub mod buffer {
             BufferBuilder {
                 buf_view,
                 position: 0,
+                name_offsets: HashMap::new(),
+                counting: false,
             }
         }
 
+        /// A builder that stores nothing and never runs out of space. Writing to it
+        /// goes through the same compression logic, so `get_pos` afterwards is the
+        /// number of bytes a real builder would have written.
+        pub fn counting() -> BufferBuilder<'static> {
+            BufferBuilder {
+                buf_view: &mut [],
+                position: 0,
+                name_offsets: HashMap::new(),
+                counting: true,
+            }
+        }
+
+        /// Moves the write position. Names written at or after `pos` are forgotten
+        /// so later names never point into overwritten bytes.
         pub fn seek(&mut self, pos: usize) {
             self.position = pos;
+            self.name_offsets.retain(|_, offset| *offset < pos);
         }
 
         pub fn get_pos(&self) -> usize {
             self.position
         }
 
+        /// Fails with `ErrorKind::WriteZero` when `len` more bytes do not fit,
+        /// so running out of space can be told apart from malformed data.
         fn ensure_space(&self, len: usize) -> io::Result<()> {
-            if self.position + len > self.buf_view.len() {
-                return Err(Error::new(ErrorKind::InvalidInput, "End of buffer"));
+            if !self.counting && self.position + len > self.buf_view.len() {
+                return Err(Error::new(ErrorKind::WriteZero, "End of buffer"));
             }
             Ok(())
         }
 
         pub fn write(&mut self, val: u8) -> io::Result<()> {
-            self.ensure_space(1)?;
-            self.buf_view[self.position] = val;
-            self.position += 1;
-            Ok(())
+            self.write_bytes(&[val])
         }
 
         pub fn write_u16(&mut self, val: u16) -> io::Result<()> {
-            self.ensure_space(2)?;
-            let bytes = val.to_be_bytes();
-            self.buf_view[self.position..self.position + 2].copy_from_slice(&bytes);
-            self.position += 2;
-            Ok(())
+            self.write_bytes(&val.to_be_bytes())
         }
 
         pub fn set_u16(&mut self, val: u16, pos: usize) -> io::Result<()> {
-            self.ensure_space(2)?;
-            let bytes = val.to_be_bytes();
-            self.buf_view[pos..pos + 2].copy_from_slice(&bytes);
+            if self.counting {
+                return Ok(());
+            }
+            if pos + 2 > self.buf_view.len() {
+                return Err(Error::new(ErrorKind::WriteZero, "End of buffer"));
+            }
+            self.buf_view[pos..pos + 2].copy_from_slice(&val.to_be_bytes());
             Ok(())
         }
 
         pub fn write_u32(&mut self, val: u32) -> io::Result<()> {
-            self.ensure_space(4)?;
-            let bytes = val.to_be_bytes();
-            self.buf_view[self.position..self.position + 4].copy_from_slice(&bytes);
-            self.position += 4;
-            Ok(())
+            self.write_bytes(&val.to_be_bytes())
         }
 
         pub fn write_u128(&mut self, val: u128) -> io::Result<()> {
-            self.ensure_space(16)?;
-            let bytes = val.to_be_bytes();
-            self.buf_view[self.position..self.position + 16].copy_from_slice(&bytes);
-            self.position += 16;
+            self.write_bytes(&val.to_be_bytes())
+        }
+
+        pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+            self.ensure_space(bytes.len())?;
+            if !self.counting {
+                self.buf_view[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+            }
+            self.position += bytes.len();
             Ok(())
         }
 
-        pub fn write_name(&mut self, name: &str) -> io::Result<()> {
-            for label in name.split('.') {
-                let len = label.len();
-                if len > 63 {
+        /// Writes `text` as a character-string, at most 255 bytes long.
+        pub fn write_character_string(&mut self, text: &str) -> io::Result<()> {
+            if text.len() > 255 {
+                return Err(Error::new(ErrorKind::InvalidInput, "character-string too long"));
+            }
+            self.ensure_space(1 + text.len())?;
+            self.write(text.len() as u8)?;
+            self.write_bytes(text.as_bytes())
+        }
+
+        /// Writes `name` as literal labels, for rdata whose names must not be compressed.
+        /// The name is not remembered as a target for later compression pointers either.
+        pub fn write_uncompressed_name(&mut self, name: &str) -> io::Result<()> {
+            for label in split_labels(name)? {
+                if label.len() > 63 {
                     return Err(Error::new(ErrorKind::InvalidInput, "Label too long"));
                 }
-                self.write(len as u8)?;
-                self.ensure_space(len)?;
-                self.buf_view[self.position..self.position + len].copy_from_slice(label.as_bytes());
-                self.position += len;
+                self.write(label.len() as u8)?;
+                self.write_bytes(&label)?;
             }
-            self.write(0)?; // Write null byte to terminate the name
-            Ok(())
+            self.write(0)
+        }
+
+        /// Writes `name`, replacing the longest suffix written before by a compression
+        /// pointer. Only suffixes starting within the first 16383 bytes can be pointed to,
+        /// names further into large packets are written as literal labels.
+        pub fn write_name(&mut self, name: &str) -> io::Result<()> {
+            let labels = split_labels(name)?;
+            if labels.iter().any(|label| label.len() > 63) {
+                return Err(Error::new(ErrorKind::InvalidInput, "Label too long"));
+            }
+            for (idx, label) in labels.iter().enumerate() {
+                let suffix: Vec<u8> = labels[idx..].iter()
+                    .flat_map(|label| std::iter::once(label.len() as u8).chain(label.to_ascii_lowercase()))
+                    .collect();
+                if let Some(offset) = self.name_offsets.get(&suffix) {
+                    return self.write_u16(0xc000 | *offset as u16);
+                }
+                if self.position <= MAX_POINTER_OFFSET {
+                    self.name_offsets.insert(suffix, self.position);
+                }
+                self.write(label.len() as u8)?;
+                self.write_bytes(label)?;
+            }
+            self.write(0) // Write null byte to terminate the name
         }
     }
 }
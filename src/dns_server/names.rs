@@ -0,0 +1,30 @@
+pub mod names {
+    /// Compares two domain names ignoring ascii case and a trailing dot.
+    pub fn names_equal(a: &str, b: &str) -> bool {
+        a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.'))
+    }
+
+    /// Number of labels in `name`, not counting the root, so `example.com` has 2
+    /// and the root name, empty or `.`, has 0.
+    pub fn label_count(name: &str) -> u8 {
+        let name = name.trim_end_matches('.');
+        if name.is_empty() {
+            return 0;
+        }
+        name.split('.').count() as u8
+    }
+
+    /// Whether `child` equals `parent` or lies below it, comparing whole labels
+    /// and ignoring ascii case and trailing dots. The root contains every name.
+    pub fn is_subdomain_of(child: &str, parent: &str) -> bool {
+        let child = child.trim_end_matches('.').as_bytes();
+        let parent = parent.trim_end_matches('.').as_bytes();
+        if parent.is_empty() {
+            return true;
+        }
+        if child.len() < parent.len() || !child[child.len() - parent.len()..].eq_ignore_ascii_case(parent) {
+            return false;
+        }
+        child.len() == parent.len() || child[child.len() - parent.len() - 1] == b'.'
+    }
+}
@@ -0,0 +1,138 @@
+pub mod query_log {
+    use std::fmt;
+    use std::fs::{File, OpenOptions};
+    use std::future::Future;
+    use std::io::{self, Error, ErrorKind, Write};
+    use std::net::IpAddr;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::mpsc::{channel, Sender};
+    use std::thread;
+    use chrono::{DateTime, Local};
+    use serde::{Deserialize, Serialize};
+
+    /// One resolved query as written to the query log.
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct QueryLogEntry {
+        pub timestamp: DateTime<Local>,
+        /// internal id of the request, shared with its lines in the trace log
+        pub request_id: u64,
+        pub client: IpAddr,
+        pub qname: String,
+        pub qtype: String,
+        pub rcode: String,
+        pub answers: usize,
+        pub cache_hit: bool,
+        pub latency_ms: f64,
+    }
+
+    impl QueryLogEntry {
+        /// Serializes the entry as a single line JSON object, without the newline.
+        pub fn to_json(&self) -> String {
+            serde_json::to_string(self).unwrap_or_default()
+        }
+
+        /// Parses a line written by `to_json`.
+        pub fn from_json(line: &str) -> io::Result<QueryLogEntry> {
+            serde_json::from_str(line).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        }
+    }
+
+    enum LogMessage {
+        Line(String),
+        /// answered once every line sent before it is written out
+        Flush(Sender<()>),
+    }
+
+    /// Destination of the query log, one JSON object per line. Lines are written by a
+    /// thread of its own, so logging never blocks the resolution of a query.
+    pub struct QueryLog {
+        lines: Sender<LogMessage>,
+    }
+
+    impl QueryLog {
+        pub fn new(mut writer: impl Write + Send + 'static) -> Self {
+            let (lines, received) = channel();
+            thread::spawn(move || {
+                for message in received {
+                    match message {
+                        LogMessage::Line(line) => {
+                            if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+                                println!("could not write query log: {}", e);
+                            }
+                        }
+                        LogMessage::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            });
+            QueryLog { lines }
+        }
+
+        pub fn stdout() -> Self {
+            QueryLog::new(io::stdout())
+        }
+
+        /// Appends to the file at `path`, creating it if needed.
+        pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+            let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(QueryLog::new(file))
+        }
+
+        pub fn log(&self, entry: &QueryLogEntry) {
+            self.log_line(&entry.to_json());
+        }
+
+        pub fn log_line(&self, line: &str) {
+            let _ = self.lines.send(LogMessage::Line(line.to_string()));
+        }
+
+        /// Blocks until every line logged so far is written.
+        pub fn flush(&self) {
+            let (done, flushed) = channel();
+            if self.lines.send(LogMessage::Flush(done)).is_ok() {
+                let _ = flushed.recv();
+            }
+        }
+    }
+
+    impl fmt::Debug for QueryLog {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_struct("QueryLog").finish_non_exhaustive()
+        }
+    }
+
+    /// The client request a task is resolving, set for the duration of `traced`.
+    #[derive(Debug, Clone)]
+    pub struct RequestTrace {
+        pub id: u64,
+        /// where the lines are written, stdout when None
+        pub log: Option<Arc<QueryLog>>,
+    }
+
+    tokio::task_local! {
+        static CURRENT_REQUEST: RequestTrace;
+    }
+
+    /// Runs `future` as the resolution of the request `trace`, so every line
+    /// passed to `trace` meanwhile carries its id.
+    pub async fn traced<F: Future>(trace: RequestTrace, future: F) -> F::Output {
+        CURRENT_REQUEST.scope(trace, future).await
+    }
+
+    /// Writes `message` prefixed by the id of the request being resolved. Outside
+    /// of `traced` it is printed as is.
+    pub fn trace(message: fmt::Arguments) {
+        let written = CURRENT_REQUEST.try_with(|request| {
+            let line = format!("[{}] {}", request.id, message);
+            match &request.log {
+                Some(log) => log.log_line(&line),
+                None => println!("{}", line),
+            }
+        });
+        if written.is_err() {
+            println!("{}", message);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+pub mod rate_limit {
+    use std::collections::{BTreeSet, HashMap};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::sync::{Arc, Mutex};
+    use chrono::{DateTime, Local};
+    use crate::dns_cache::dns_cache::{Clock, SystemClock};
+    use crate::dns_server::dns_packet::dns_packet::{DnsPacket, QueryType, ResponseCode};
+
+    /// buckets tracked before the least recently used ones are forgotten
+    const MAX_BUCKETS: usize = 10_000;
+
+    /// How often the same response may go to the same client subnet before it is limited.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct RrlPolicy {
+        /// identical responses per second and subnet, 0 disables response rate limiting
+        pub responses_per_second: u32,
+        /// every slip-th limited response is sent truncated instead of dropped, 0 drops all
+        pub slip: u32,
+        /// how many leading bits of a client address make up its subnet
+        pub ipv4_prefix_len: u8,
+        pub ipv6_prefix_len: u8,
+    }
+
+    impl Default for RrlPolicy {
+        fn default() -> Self {
+            RrlPolicy {
+                responses_per_second: 0,
+                slip: 2,
+                ipv4_prefix_len: 24,
+                ipv6_prefix_len: 56,
+            }
+        }
+    }
+
+    /// What to do with a response after rate limiting.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum RrlAction {
+        Send,
+        /// send an empty truncated response so a real client retries over tcp
+        Slip,
+        Drop,
+    }
+
+    #[derive(Debug)]
+    struct Bucket {
+        tokens: f64,
+        updated: DateTime<Local>,
+        limited: u32,
+    }
+
+    /// client subnet and response signature
+    type BucketKey = (IpAddr, String);
+
+    #[derive(Debug, Default)]
+    struct Buckets {
+        by_key: HashMap<BucketKey, Bucket>,
+        /// every bucket ordered by its last update, the first ones are forgotten when full
+        by_update: BTreeSet<(DateTime<Local>, BucketKey)>,
+    }
+
+    /// Response rate limiting: a token bucket per client subnet and response
+    /// signature, so a spoofed victim only ever receives a trickle of answers.
+    #[derive(Debug)]
+    pub struct ResponseRateLimiter {
+        policy: RrlPolicy,
+        buckets: Mutex<Buckets>,
+        clock: Arc<dyn Clock>,
+    }
+
+    impl ResponseRateLimiter {
+        pub fn new(policy: RrlPolicy) -> Self {
+            ResponseRateLimiter::with_clock(policy, Arc::new(SystemClock))
+        }
+
+        pub fn with_clock(policy: RrlPolicy, clock: Arc<dyn Clock>) -> Self {
+            ResponseRateLimiter { policy, buckets: Mutex::default(), clock }
+        }
+
+        /// Takes a token for sending `response` to `client`, deciding between sending,
+        /// slipping and dropping once the subnet has used up its rate.
+        pub fn check(&self, client: IpAddr, response: &DnsPacket) -> RrlAction {
+            let rate = self.policy.responses_per_second;
+            if rate == 0 {
+                return RrlAction::Send;
+            }
+            let now = self.clock.now();
+            let key = (self.subnet(client), signature(response));
+            let mut buckets = self.buckets.lock().unwrap();
+            let Buckets { by_key, by_update } = &mut *buckets;
+            if !by_key.contains_key(&key) {
+                while by_key.len() >= MAX_BUCKETS {
+                    match by_update.pop_first() {
+                        Some((_, oldest)) => by_key.remove(&oldest),
+                        None => break,
+                    };
+                }
+            }
+            let bucket = by_key.entry(key.clone())
+                .or_insert(Bucket { tokens: rate as f64, updated: now, limited: 0 });
+            by_update.remove(&(bucket.updated, key.clone()));
+            let elapsed = (now - bucket.updated).num_milliseconds().max(0) as f64 / 1000.0;
+            bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+            bucket.updated = now;
+            by_update.insert((now, key));
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return RrlAction::Send;
+            }
+            bucket.limited += 1;
+            if self.policy.slip != 0 && bucket.limited.is_multiple_of(self.policy.slip) {
+                RrlAction::Slip
+            } else {
+                RrlAction::Drop
+            }
+        }
+
+        /// `client` with every bit past the configured prefix cleared.
+        fn subnet(&self, client: IpAddr) -> IpAddr {
+            match client {
+                IpAddr::V4(ip) => {
+                    let prefix_len = self.policy.ipv4_prefix_len.min(32) as u32;
+                    let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                    IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+                }
+                IpAddr::V6(ip) => {
+                    let prefix_len = self.policy.ipv6_prefix_len.min(128) as u32;
+                    let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                    IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+                }
+            }
+        }
+    }
+
+    /// Groups responses that are identical for an attacker: answers by name and type,
+    /// NXDOMAIN and NODATA by the zone proving them, errors by their response code.
+    fn signature(response: &DnsPacket) -> String {
+        let response_code = response.header.get_response_code();
+        let question = response.question();
+        match response_code {
+            ResponseCode::NOERROR | ResponseCode::NXDOMAIN if response.answers.is_empty() => {
+                let zone = response.authorities.iter()
+                    .find(|authority| authority.query_type == QueryType::SOA)
+                    .map(|soa| soa.name.to_ascii_lowercase())
+                    .or_else(|| question.map(|question| question.name.to_ascii_lowercase()))
+                    .unwrap_or_default();
+                format!("{:?} {}", response_code, zone)
+            }
+            ResponseCode::NOERROR => match question {
+                Some(question) => format!("{} {}", question.name.to_ascii_lowercase(), question.query_type),
+                None => String::new(),
+            },
+            response_code => format!("{:?}", response_code),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::Ipv4Addr;
+        use chrono::Duration;
+        use crate::dns_cache::dns_cache::MockClock;
+
+        #[test]
+        fn test_limited_responses_alternate_between_slip_and_drop() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let limiter = ResponseRateLimiter::with_clock(RrlPolicy {
+                responses_per_second: 2,
+                ..Default::default()
+            }, clock.clone());
+            let response = DnsPacket::query(1, "www.example.com", QueryType::A);
+            let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+            let neighbour = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 77));
+            let elsewhere = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+            assert_eq!(limiter.check(client, &response), RrlAction::Send);
+            assert_eq!(limiter.check(neighbour, &response), RrlAction::Send);
+            assert_eq!(limiter.check(client, &response), RrlAction::Drop);
+            assert_eq!(limiter.check(client, &response), RrlAction::Slip);
+            assert_eq!(limiter.check(elsewhere, &response), RrlAction::Send);
+            let other = DnsPacket::query(2, "mail.example.com", QueryType::A);
+            assert_eq!(limiter.check(client, &other), RrlAction::Send);
+
+            clock.advance(Duration::milliseconds(500));
+            assert_eq!(limiter.check(client, &response), RrlAction::Send);
+            assert_eq!(limiter.check(client, &response), RrlAction::Drop);
+        }
+
+        #[test]
+        fn test_least_recently_used_buckets_are_forgotten_when_full() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let limiter = ResponseRateLimiter::with_clock(RrlPolicy {
+                responses_per_second: 1,
+                slip: 0,
+                ..Default::default()
+            }, clock.clone());
+            let response = DnsPacket::query(1, "www.example.com", QueryType::A);
+            let client = |idx: usize| IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + ((idx as u32) << 8)));
+
+            assert_eq!(limiter.check(client(0), &response), RrlAction::Send);
+            assert_eq!(limiter.check(client(1), &response), RrlAction::Send);
+            clock.advance(Duration::milliseconds(1));
+            // client 0 is used again and so outlives client 1
+            assert_eq!(limiter.check(client(0), &response), RrlAction::Drop);
+            for idx in 2..=MAX_BUCKETS {
+                assert_eq!(limiter.check(client(idx), &response), RrlAction::Send);
+            }
+            assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), MAX_BUCKETS);
+            assert_eq!(limiter.check(client(0), &response), RrlAction::Drop);
+            // the forgotten bucket starts full again
+            assert_eq!(limiter.check(client(1), &response), RrlAction::Send);
+        }
+    }
+}
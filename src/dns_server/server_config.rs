@@ -0,0 +1,353 @@
+pub mod server_config {
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::dns_cache::dns_cache::CachePolicy;
+    use crate::dns_server::dns_packet::dns_packet::ResponseCode;
+    use crate::dns_server::query_log::query_log::QueryLog;
+    use crate::dns_server::rate_limit::rate_limit::RrlPolicy;
+    use crate::dns_server::upstream::upstream::{BreakerPolicy, TcpFallbackPolicy, UpstreamStrategy};
+
+    const ROOT_SERVER_STRS: [&str; 13] = ["198.41.0.4",
+                                        "199.9.14.201",
+                                        "192.33.4.12",
+                                        "199.7.91.13",
+                                        "192.203.230.10",
+                                        "192.5.5.241",
+                                        "192.112.36.4",
+                                        "198.97.190.53",
+                                        "192.36.148.17",
+                                        "192.58.128.30",
+                                        "193.0.14.129",
+                                        "199.7.83.42",
+                                        "202.12.27.33",
+                                        ];
+
+    /// Well-known /96 prefix of NAT64 gateways (RFC 6052).
+    pub const WELL_KNOWN_NAT64_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+
+    /// An address prefix like 10.0.0.0/8.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct AddressRange {
+        pub network: IpAddr,
+        pub prefix_len: u8,
+    }
+
+    impl AddressRange {
+        pub fn new(network: IpAddr, prefix_len: u8) -> io::Result<AddressRange> {
+            let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+            if prefix_len > max_prefix_len {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "prefix longer than the address"));
+            }
+            Ok(AddressRange { network, prefix_len })
+        }
+
+        pub fn contains(&self, ip: &IpAddr) -> bool {
+            match (self.network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) =>
+                    prefix_matches(&network.octets(), &ip.octets(), self.prefix_len),
+                (IpAddr::V6(network), IpAddr::V6(ip)) =>
+                    prefix_matches(&network.octets(), &ip.octets(), self.prefix_len),
+                _ => false,
+            }
+        }
+    }
+
+    fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+        let full_bytes = prefix_len as usize / 8;
+        if network[..full_bytes] != ip[..full_bytes] {
+            return false;
+        }
+        let rest = prefix_len % 8;
+        rest == 0 || (network[full_bytes] ^ ip[full_bytes]) & (0xff << (8 - rest)) == 0
+    }
+
+    impl FromStr for AddressRange {
+        type Err = io::Error;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "expected an address range like 10.0.0.0/8");
+            let (network, prefix_len) = s.split_once('/').ok_or_else(invalid)?;
+            let network = IpAddr::from_str(network).map_err(|_| invalid())?;
+            let prefix_len = prefix_len.parse().map_err(|_| invalid())?;
+            AddressRange::new(network, prefix_len)
+        }
+    }
+
+    /// RFC1918, loopback, link-local, shared (RFC 6598) and "this network" ranges plus
+    /// their ipv6 counterparts and ipv4-mapped addresses, which could carry any of them.
+    pub fn default_private_ranges() -> Vec<AddressRange> {
+        [
+            (IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8),
+            (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+            (IpAddr::V4(Ipv4Addr::new(100, 64, 0, 0)), 10),
+            (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+            (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+            (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+            (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+            (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+            (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+            (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+            (IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0xffff, 0, 0)), 96),
+        ].into_iter().map(|(network, prefix_len)| AddressRange { network, prefix_len }).collect()
+    }
+
+    /// How queries of type ANY are answered.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+    pub enum AnyQueries {
+        /// resolved like any other type
+        #[default]
+        Full,
+        /// a single synthesized HINFO record (RFC 8482), useless for amplification
+        Rfc8482,
+    }
+
+    /// How names below a special-use domain are answered instead of being resolved.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum SpecialUse {
+        /// A and AAAA queries get the loopback address, other types no data
+        Loopback,
+        /// PTR queries get `localhost`, other types no data
+        LoopbackReverse,
+        NxDomain,
+    }
+
+    /// A domain answered locally together with all its subdomains (RFC 6761).
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct SpecialUseDomain {
+        pub name: String,
+        pub handling: SpecialUse,
+    }
+
+    /// `localhost`, the loopback reverse zones and `invalid`.
+    pub fn default_special_use_domains() -> Vec<SpecialUseDomain> {
+        [
+            ("localhost", SpecialUse::Loopback),
+            ("127.in-addr.arpa", SpecialUse::LoopbackReverse),
+            ("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.ip6.arpa", SpecialUse::LoopbackReverse),
+            ("invalid", SpecialUse::NxDomain),
+        ].into_iter().map(|(name, handling)| SpecialUseDomain { name: name.to_string(), handling }).collect()
+    }
+
+    /// TTLs stamped on records the server makes up itself rather than relaying.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct SynthesizedTtls {
+        /// NS and glue records of referrals built from cached delegations
+        pub referral: u32,
+        /// fixed answers from the configuration such as `version.bind`
+        pub local: u32,
+        /// SOA records proving negative answers from special-use domains and, as an
+        /// upper bound, from served zones. Clients cache the negative answer as long
+        pub negative: u32,
+    }
+
+    impl Default for SynthesizedTtls {
+        fn default() -> Self {
+            SynthesizedTtls { referral: 300, local: 300, negative: 300 }
+        }
+    }
+
+    /// A zone copied from its primary by zone transfer and served authoritatively.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct SecondaryZone {
+        pub origin: String,
+        pub primary: SocketAddr,
+    }
+
+    /// Which address family of a referral's glue is tried first.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+    pub enum GluePreference {
+        #[default]
+        AsReceived,
+        Ipv4First,
+        Ipv6First,
+    }
+
+    impl GluePreference {
+        /// Stably moves the preferred family to the front.
+        pub fn order(&self, addresses: &mut [IpAddr]) {
+            match self {
+                GluePreference::AsReceived => {}
+                GluePreference::Ipv4First => addresses.sort_by_key(|ip| ip.is_ipv6()),
+                GluePreference::Ipv6First => addresses.sort_by_key(|ip| ip.is_ipv4()),
+            }
+        }
+    }
+
+    /// Which sections are left out of answers that already hold the records asked for.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+    pub enum MinimalResponses {
+        #[default]
+        Disabled,
+        /// drop the additional section
+        Additional,
+        /// drop the additional and authority sections
+        AdditionalAndAuthority,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct ServerConfig {
+        /// local address the socket for upstream queries is bound to, port 0 lets
+        /// the system pick one that is harder to guess than a fixed port
+        pub lookup_addr: String,
+        /// local address the socket for upstream queries over ipv6 is bound to
+        pub lookup_addr_v6: String,
+        /// port nameservers are queried on
+        pub upstream_port: u16,
+        pub root_server_ips: Vec<Ipv4Addr>,
+        /// how long a single upstream query may take
+        pub lookup_timeout: Duration,
+        /// how often an unanswered upstream query is resent to the same server
+        pub lookup_retries: u32,
+        /// pause before the first retry, doubled for every further one up to `lookup_timeout`
+        pub lookup_backoff: Duration,
+        /// which family of glue addresses is queried first when a referral has both
+        pub glue_preference: GluePreference,
+        /// how many nameservers of one zone are queried at once, the first answer wins
+        pub lookup_parallelism: usize,
+        /// how long resolving one client query may take in total before SERVFAIL is sent
+        pub resolve_timeout: Duration,
+        /// SO_RCVBUF of the client and lookup sockets in bytes, the system default when None
+        pub udp_recv_buffer: Option<usize>,
+        /// SO_SNDBUF of the client and lookup sockets in bytes, the system default when None
+        pub udp_send_buffer: Option<usize>,
+        /// also accept clients over tcp on every listen address
+        pub serve_tcp: bool,
+        /// how long an idle tcp connection is kept open, announced to clients using tcp keepalive
+        pub tcp_idle_timeout: Duration,
+        /// tcp connections served at once over all listeners, further clients are
+        /// closed right after being accepted
+        pub max_tcp_connections: usize,
+        /// how long shutdown waits for in-flight resolutions
+        pub shutdown_timeout: Duration,
+        pub cache_policy: CachePolicy,
+        /// how often identical udp responses go to one client subnet before they are
+        /// sent truncated or dropped, against reflection attacks
+        pub response_rate_limit: RrlPolicy,
+        /// names that are refused together with all their subdomains
+        pub blocklist: Vec<String>,
+        pub any_queries: AnyQueries,
+        /// names answered locally and never sent upstream, the most specific entry wins
+        pub special_use_domains: Vec<SpecialUseDomain>,
+        /// response code sent when a query is refused by policy
+        pub refused_response_code: ResponseCode,
+        /// how many names `warm_cache` resolves concurrently
+        pub warm_up_parallelism: usize,
+        /// upstream resolvers queries are forwarded to, recursion is used when empty
+        pub forwarders: Vec<IpAddr>,
+        pub upstream_strategy: UpstreamStrategy,
+        /// when forwarders and nameservers that keep failing are skipped
+        pub circuit_breaker: BreakerPolicy,
+        /// when nameservers and forwarders that keep timing out over udp are queried over tcp
+        pub tcp_fallback: TcpFallbackPolicy,
+        /// udp payload size advertised in our OPT records, at least 512
+        pub edns_udp_payload_size: u16,
+        /// block size responses to padded queries are padded to (RFC 7830, 468 as RFC 8467
+        /// recommends), never padded when None. Only queries from `tls_proxies` are
+        /// padded, in the clear padding just adds bytes
+        pub padding_block_size: Option<u16>,
+        /// tcp clients terminating TLS for their own clients, whose queries count as encrypted
+        pub tls_proxies: Vec<IpAddr>,
+        /// answer FORMERR to queries with the Z header bit set, off as some clients set it
+        pub reject_z_bit: bool,
+        /// answer only from local data, refer names below cached delegations to their
+        /// nameservers and refuse everything else, never recursing
+        pub authoritative_only: bool,
+        /// where a JSON line is written for every answered query, disabled when None
+        pub query_log: Option<Arc<QueryLog>>,
+        /// where the lines logged while resolving a client query are written, each
+        /// prefixed with the id of its request, stdout when None
+        pub trace_log: Option<Arc<QueryLog>>,
+        /// address of a plain http endpoint dumping the cache as JSON at `/cache`, for
+        /// debugging only and unauthenticated, so keep it on loopback. Disabled when None
+        pub debug_http_addr: Option<String>,
+        /// secondaries allowed to request zone transfers, only ever over tcp
+        pub transfer_allowlist: Vec<IpAddr>,
+        /// drop A and AAAA answers inside `private_ranges` from upstream data,
+        /// answering NODATA when nothing is left
+        pub rebinding_protection: bool,
+        pub private_ranges: Vec<AddressRange>,
+        /// /96 prefix AAAA records are synthesized in from A records for names without
+        /// AAAA records (DNS64, RFC 6147), usually `WELL_KNOWN_NAT64_PREFIX`. Disabled when None
+        pub dns64_prefix: Option<Ipv6Addr>,
+        /// answer to `version.bind CH TXT` of at most 255 bytes, refused when None
+        pub version_bind: Option<String>,
+        /// answer to `hostname.bind CH TXT`, refused when None
+        pub hostname_bind: Option<String>,
+        pub synthesized_ttls: SynthesizedTtls,
+        /// most records of the asked type sent in a udp answer, at least 1, the others
+        /// are left out. Unlimited when None
+        pub max_answers: Option<usize>,
+        /// set TC on answers cut down to `max_answers` so clients can get all over tcp
+        pub max_answers_truncates: bool,
+        /// TTL every record sent to clients is rewritten to, so all servers of an
+        /// anycast fleet answer alike. Disabled when None
+        pub fixed_ttl: Option<u32>,
+        /// referrals and negative answers are never minimized, they need their glue and SOA
+        pub minimal_responses: MinimalResponses,
+        /// zones transferred on startup and refreshed as their SOA asks
+        pub secondary_zones: Vec<SecondaryZone>,
+        /// how long a whole zone transfer may take
+        pub transfer_timeout: Duration,
+    }
+
+    impl Default for ServerConfig {
+        fn default() -> Self {
+            ServerConfig {
+                lookup_addr: "0.0.0.0:0".to_string(),
+                lookup_addr_v6: "[::]:0".to_string(),
+                upstream_port: 53,
+                root_server_ips: ROOT_SERVER_STRS
+                    .iter()
+                    .filter_map(|ip_str | Ipv4Addr::from_str(ip_str).ok())
+                    .collect(),
+                lookup_timeout: Duration::from_secs(1),
+                lookup_retries: 2,
+                lookup_backoff: Duration::from_millis(100),
+                glue_preference: GluePreference::default(),
+                lookup_parallelism: 1,
+                resolve_timeout: Duration::from_secs(5),
+                udp_recv_buffer: None,
+                udp_send_buffer: None,
+                serve_tcp: false,
+                tcp_idle_timeout: Duration::from_secs(10),
+                max_tcp_connections: 128,
+                shutdown_timeout: Duration::from_secs(5),
+                cache_policy: CachePolicy::default(),
+                response_rate_limit: RrlPolicy::default(),
+                blocklist: Vec::new(),
+                any_queries: AnyQueries::default(),
+                special_use_domains: default_special_use_domains(),
+                refused_response_code: ResponseCode::REFUSED,
+                warm_up_parallelism: 4,
+                forwarders: Vec::new(),
+                upstream_strategy: UpstreamStrategy::default(),
+                circuit_breaker: BreakerPolicy::default(),
+                tcp_fallback: TcpFallbackPolicy::default(),
+                edns_udp_payload_size: 1232,
+                padding_block_size: None,
+                tls_proxies: Vec::new(),
+                reject_z_bit: false,
+                authoritative_only: false,
+                query_log: None,
+                trace_log: None,
+                debug_http_addr: None,
+                transfer_allowlist: Vec::new(),
+                rebinding_protection: false,
+                private_ranges: default_private_ranges(),
+                dns64_prefix: None,
+                version_bind: None,
+                hostname_bind: None,
+                synthesized_ttls: SynthesizedTtls::default(),
+                fixed_ttl: None,
+                max_answers: None,
+                max_answers_truncates: false,
+                minimal_responses: MinimalResponses::default(),
+                secondary_zones: Vec::new(),
+                transfer_timeout: Duration::from_secs(30),
+            }
+        }
+    }
+}
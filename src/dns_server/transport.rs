@@ -0,0 +1,109 @@
+pub mod transport {
+    use std::io::{self, Error, ErrorKind};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use socket2::{Domain, Protocol, Socket, Type};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpStream, UdpSocket};
+    use tokio::time::timeout;
+    use crate::dns_server::dns_packet::dns_packet::{Answer, DnsPacket, QueryType, ResponseCode};
+
+    /// Binds a udp socket to `addr`, setting SO_RCVBUF and SO_SNDBUF to the given sizes
+    /// in bytes before binding. The operating system may round or cap them, None keeps its default.
+    pub async fn bind_udp(addr: &str, recv_buffer: Option<usize>, send_buffer: Option<usize>) -> io::Result<UdpSocket> {
+        if recv_buffer.is_none() && send_buffer.is_none() {
+            return UdpSocket::bind(addr).await;
+        }
+        let addr = tokio::net::lookup_host(addr).await?.next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "address resolved to nothing"))?;
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if let Some(size) = recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// Sends `query` to `server` in a single datagram and waits up to `deadline` for the matching response.
+    pub async fn query_udp(server: SocketAddr, query: &DnsPacket, deadline: Duration) -> io::Result<DnsPacket> {
+        let local_addr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(server).await?;
+        let (buf, amt) = query.to_buf()?;
+        socket.send(&buf[..amt]).await?;
+        timeout(deadline, async {
+            let mut in_buf = vec![0u8; 65535];
+            loop {
+                let len = socket.recv(&mut in_buf).await?;
+                let response = DnsPacket::from_buf(&in_buf[..len])?;
+                if response.header.id == query.header.id {
+                    return Ok(response);
+                }
+                println!("dropping response with unexpected id {}", response.header.id);
+            }
+        }).await.map_err(|_| Error::new(ErrorKind::TimedOut, "udp query timed out"))?
+    }
+
+    /// Sends `query` to `server` over TCP, each message prefixed by its two byte length.
+    pub async fn query_tcp(server: SocketAddr, query: &DnsPacket, deadline: Duration) -> io::Result<DnsPacket> {
+        let (buf, amt) = query.to_buf()?;
+        let response = DnsPacket::from_buf(&exchange_tcp(server, &buf[..amt], deadline).await?)?;
+        if response.header.id != query.header.id {
+            return Err(Error::new(ErrorKind::InvalidData, "tcp response id does not match the query"));
+        }
+        Ok(response)
+    }
+
+    /// Sends the already serialized `query` over TCP and returns the raw response message.
+    pub async fn exchange_tcp(server: SocketAddr, query: &[u8], deadline: Duration) -> io::Result<Vec<u8>> {
+        timeout(deadline, async {
+            let mut stream = TcpStream::connect(server).await?;
+            stream.write_all(&(query.len() as u16).to_be_bytes()).await?;
+            stream.write_all(query).await?;
+            let len = stream.read_u16().await?;
+            let mut in_buf = vec![0u8; len as usize];
+            stream.read_exact(&mut in_buf).await?;
+            Ok(in_buf)
+        }).await.map_err(|_| Error::new(ErrorKind::TimedOut, "tcp query timed out"))?
+    }
+
+    /// Requests an AXFR of `zone` from `server` and collects the records of all
+    /// messages until the closing SOA, which is included like the opening one.
+    pub async fn transfer_zone(server: SocketAddr, zone: &str, deadline: Duration) -> io::Result<Vec<Answer>> {
+        timeout(deadline, async {
+            let mut stream = TcpStream::connect(server).await?;
+            let query = DnsPacket::query(std::process::id() as u16, zone, QueryType::AXFR);
+            let (buf, amt) = query.to_buf()?;
+            stream.write_all(&(amt as u16).to_be_bytes()).await?;
+            stream.write_all(&buf[..amt]).await?;
+            let mut records: Vec<Answer> = Vec::new();
+            loop {
+                let len = stream.read_u16().await?;
+                let mut in_buf = vec![0u8; len as usize];
+                stream.read_exact(&mut in_buf).await?;
+                let message = DnsPacket::from_buf(&in_buf)?;
+                if message.header.id != query.header.id {
+                    return Err(Error::new(ErrorKind::InvalidData, "transfer message id does not match the query"));
+                }
+                if message.header.get_response_code() != ResponseCode::NOERROR {
+                    return Err(Error::new(ErrorKind::ConnectionRefused,
+                                          format!("transfer refused with {:?}", message.header.get_response_code())));
+                }
+                for answer in message.answers {
+                    if records.is_empty() && answer.query_type != QueryType::SOA {
+                        return Err(Error::new(ErrorKind::InvalidData, "transfer does not start with an SOA"));
+                    }
+                    let closing = !records.is_empty() && answer.query_type == QueryType::SOA;
+                    records.push(answer);
+                    if closing {
+                        return Ok(records);
+                    }
+                }
+            }
+        }).await.map_err(|_| Error::new(ErrorKind::TimedOut, "zone transfer timed out"))?
+    }
+}
@@ -0,0 +1,490 @@
+pub mod upstream {
+    use std::collections::{BTreeSet, HashMap};
+    use std::net::IpAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::time::Duration;
+    use chrono::{DateTime, Local};
+    use crate::dns_cache::dns_cache::{Clock, SystemClock};
+    use crate::dns_server::query_log::query_log::trace;
+
+    /// weight of the newest sample in the latency moving average
+    const EWMA_WEIGHT: f64 = 0.3;
+    /// how long what an upstream showed of its EDNS support is remembered
+    const EDNS_SUPPORT_TTL: Duration = Duration::from_secs(3600);
+    /// upstreams whose EDNS support is remembered at most, those learned first are forgotten beyond it
+    const MAX_EDNS_SUPPORT_ENTRIES: usize = 10_000;
+
+    /// How a forwarded query picks the upstream it is sent to first.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+    pub enum UpstreamStrategy {
+        First,
+        #[default]
+        RoundRobin,
+        Fastest,
+        Random,
+    }
+
+    #[derive(Debug)]
+    pub struct UpstreamSelector {
+        strategy: UpstreamStrategy,
+        count: usize,
+        next: AtomicUsize,
+        rng_state: AtomicU64,
+        /// moving average of the response time in milliseconds, None until measured
+        latencies: Mutex<Vec<Option<f64>>>,
+    }
+
+    impl UpstreamSelector {
+        pub fn new(strategy: UpstreamStrategy, count: usize) -> Self {
+            UpstreamSelector {
+                strategy,
+                count,
+                next: AtomicUsize::new(0),
+                rng_state: AtomicU64::new(0x2545_f491_4f6c_dd1d ^ std::process::id() as u64),
+                latencies: Mutex::new(vec![None; count]),
+            }
+        }
+
+        /// Returns the upstream indices in the order they should be tried.
+        pub fn order(&self) -> Vec<usize> {
+            let mut order: Vec<usize> = (0..self.count).collect();
+            if self.count == 0 {
+                return order;
+            }
+            match self.strategy {
+                UpstreamStrategy::First => {}
+                UpstreamStrategy::RoundRobin => {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % self.count;
+                    order.rotate_left(start);
+                }
+                UpstreamStrategy::Fastest => {
+                    // unmeasured upstreams go first so every upstream gets probed
+                    let latencies = self.latencies.lock().unwrap();
+                    order.sort_by(|a, b| latencies[*a].unwrap_or(0.0)
+                        .total_cmp(&latencies[*b].unwrap_or(0.0)));
+                }
+                UpstreamStrategy::Random => {
+                    let start = (self.next_random() % self.count as u64) as usize;
+                    order.rotate_left(start);
+                }
+            }
+            order
+        }
+
+        /// Records how long upstream `idx` took to answer, failures should pass the timeout.
+        pub fn record(&self, idx: usize, latency: Duration) {
+            let sample = latency.as_secs_f64() * 1000.0;
+            let mut latencies = self.latencies.lock().unwrap();
+            if let Some(latency) = latencies.get_mut(idx) {
+                *latency = Some(match *latency {
+                    Some(average) => average * (1.0 - EWMA_WEIGHT) + sample * EWMA_WEIGHT,
+                    None => sample,
+                });
+            }
+        }
+
+        fn next_random(&self) -> u64 {
+            // xorshift64, good enough to spread load
+            let mut x = self.rng_state.load(Ordering::Relaxed);
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.rng_state.store(x, Ordering::Relaxed);
+            x
+        }
+    }
+
+    /// When an upstream counts as down and for how long it is left alone.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct BreakerPolicy {
+        /// consecutive failures that open the circuit, 0 disables the breaker
+        pub failure_threshold: u32,
+        /// failures further apart than this start counting anew
+        pub window: Duration,
+        /// how long an open circuit skips the upstream before a single probe is let through
+        pub cooldown: Duration,
+    }
+
+    impl Default for BreakerPolicy {
+        fn default() -> Self {
+            BreakerPolicy {
+                failure_threshold: 5,
+                window: Duration::from_secs(30),
+                cooldown: Duration::from_secs(30),
+            }
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    enum BreakerState {
+        Closed { failures: u32, first_failure: DateTime<Local> },
+        Open { until: DateTime<Local> },
+        /// one probe was let through at `since` and has not reported back yet
+        HalfOpen { since: DateTime<Local> },
+    }
+
+    /// Tracks failures per upstream address and stops queries to those that keep failing.
+    #[derive(Debug)]
+    pub struct CircuitBreaker {
+        policy: BreakerPolicy,
+        states: Mutex<HashMap<IpAddr, BreakerState>>,
+        clock: Arc<dyn Clock>,
+    }
+
+    impl CircuitBreaker {
+        pub fn new(policy: BreakerPolicy) -> Self {
+            CircuitBreaker::with_clock(policy, Arc::new(SystemClock))
+        }
+
+        pub fn with_clock(policy: BreakerPolicy, clock: Arc<dyn Clock>) -> Self {
+            CircuitBreaker { policy, states: Mutex::new(HashMap::new()), clock }
+        }
+
+        /// Whether `addr` may be queried. Once the cooldown of an open circuit has
+        /// passed this lets exactly one probe through until it reports back.
+        pub fn allow(&self, addr: &IpAddr) -> bool {
+            let now = self.clock.now();
+            let mut states = self.states.lock().unwrap();
+            let allowed = match states.get(addr) {
+                None | Some(BreakerState::Closed { .. }) => return true,
+                Some(BreakerState::Open { until }) => *until <= now,
+                // a probe that never reported back, e.g. because it was cancelled, is replaced
+                Some(BreakerState::HalfOpen { since }) => *since + self.cooldown() <= now,
+            };
+            if allowed {
+                states.insert(*addr, BreakerState::HalfOpen { since: now });
+            }
+            allowed
+        }
+
+        pub fn record_success(&self, addr: &IpAddr) {
+            self.states.lock().unwrap().remove(addr);
+        }
+
+        pub fn record_failure(&self, addr: &IpAddr) {
+            if self.policy.failure_threshold == 0 {
+                return;
+            }
+            let now = self.clock.now();
+            let mut states = self.states.lock().unwrap();
+            let state = match states.get(addr) {
+                Some(BreakerState::Closed { failures, first_failure }) if *first_failure + self.window() > now =>
+                    BreakerState::Closed { failures: failures + 1, first_failure: *first_failure },
+                Some(BreakerState::Open { until }) => BreakerState::Open { until: *until },
+                Some(BreakerState::HalfOpen { .. }) => BreakerState::Open { until: now + self.cooldown() },
+                _ => BreakerState::Closed { failures: 1, first_failure: now },
+            };
+            let state = match state {
+                BreakerState::Closed { failures, .. } if failures >= self.policy.failure_threshold => {
+                    trace(format_args!("opening circuit for {} after {} failures", addr, failures));
+                    BreakerState::Open { until: now + self.cooldown() }
+                }
+                state => state,
+            };
+            states.insert(*addr, state);
+        }
+
+        fn window(&self) -> chrono::Duration {
+            chrono::Duration::milliseconds(self.policy.window.as_millis() as i64)
+        }
+
+        fn cooldown(&self) -> chrono::Duration {
+            chrono::Duration::milliseconds(self.policy.cooldown.as_millis() as i64)
+        }
+    }
+
+    /// When udp to an upstream counts as lossy and for how long tcp is used instead.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct TcpFallbackPolicy {
+        /// udp lookups timing out within `window` that switch the upstream to tcp, 0 never switches
+        pub udp_timeouts: u32,
+        pub window: Duration,
+        /// how long tcp is used before udp is given another chance
+        pub cooldown: Duration,
+    }
+
+    impl Default for TcpFallbackPolicy {
+        fn default() -> Self {
+            TcpFallbackPolicy {
+                udp_timeouts: 3,
+                window: Duration::from_secs(30),
+                cooldown: Duration::from_secs(300),
+            }
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    enum UdpState {
+        Losing { timeouts: u32, first_timeout: DateTime<Local> },
+        Tcp { until: DateTime<Local> },
+    }
+
+    /// Remembers the upstreams that keep losing udp datagrams, e.g. behind a broken
+    /// path MTU, and are queried over tcp for a while.
+    #[derive(Debug)]
+    pub struct TransportPreference {
+        policy: TcpFallbackPolicy,
+        states: Mutex<HashMap<IpAddr, UdpState>>,
+        clock: Arc<dyn Clock>,
+    }
+
+    impl TransportPreference {
+        pub fn new(policy: TcpFallbackPolicy) -> Self {
+            TransportPreference::with_clock(policy, Arc::new(SystemClock))
+        }
+
+        pub fn with_clock(policy: TcpFallbackPolicy, clock: Arc<dyn Clock>) -> Self {
+            TransportPreference { policy, states: Mutex::new(HashMap::new()), clock }
+        }
+
+        /// Whether `addr` is to be queried over tcp. The preference is forgotten once its
+        /// cooldown passed, and timeouts are once their window passed.
+        pub fn use_tcp(&self, addr: &IpAddr) -> bool {
+            let now = self.clock.now();
+            let mut states = self.states.lock().unwrap();
+            match states.get(addr) {
+                Some(UdpState::Tcp { until }) if *until > now => true,
+                Some(UdpState::Losing { first_timeout, .. }) if *first_timeout + self.window() > now => false,
+                Some(_) => {
+                    states.remove(addr);
+                    false
+                }
+                None => false,
+            }
+        }
+
+        fn window(&self) -> chrono::Duration {
+            chrono::Duration::milliseconds(self.policy.window.as_millis() as i64)
+        }
+
+        pub fn record_udp_answer(&self, addr: &IpAddr) {
+            let mut states = self.states.lock().unwrap();
+            if let Some(UdpState::Losing { .. }) = states.get(addr) {
+                states.remove(addr);
+            }
+        }
+
+        /// Counts a udp lookup to `addr` that timed out, returns true when this switched `addr` to tcp.
+        pub fn record_udp_timeout(&self, addr: &IpAddr) -> bool {
+            if self.policy.udp_timeouts == 0 {
+                return false;
+            }
+            let now = self.clock.now();
+            let mut states = self.states.lock().unwrap();
+            // expired timeouts and tcp preferences are replaced by a fresh count
+            let (timeouts, first_timeout) = match states.get(addr) {
+                Some(UdpState::Losing { timeouts, first_timeout }) if *first_timeout + self.window() > now =>
+                    (timeouts + 1, *first_timeout),
+                Some(UdpState::Tcp { until }) if *until > now => return false,
+                _ => (1, now),
+            };
+            if timeouts < self.policy.udp_timeouts {
+                states.insert(*addr, UdpState::Losing { timeouts, first_timeout });
+                return false;
+            }
+            trace(format_args!("switching {} to tcp after {} udp timeouts", addr, timeouts));
+            let cooldown = chrono::Duration::milliseconds(self.policy.cooldown.as_millis() as i64);
+            states.insert(*addr, UdpState::Tcp { until: now + cooldown });
+            true
+        }
+    }
+
+    /// What an upstream showed of its EDNS support.
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum EdnsSupport {
+        /// answered a query with an OPT record with one of its own
+        Supported,
+        /// answered a query with an OPT record without one, so it does answer plain queries
+        Ignored,
+        /// rejected a query with an OPT record and answered it without
+        Unsupported,
+    }
+
+    #[derive(Debug, Default)]
+    struct EdnsStates {
+        by_addr: HashMap<IpAddr, (EdnsSupport, DateTime<Local>)>,
+        /// every entry ordered by expiry, the first ones are dropped when full
+        by_expiry: BTreeSet<(DateTime<Local>, IpAddr)>,
+    }
+
+    impl EdnsStates {
+        fn remove(&mut self, addr: &IpAddr) {
+            if let Some((_, until)) = self.by_addr.remove(addr) {
+                self.by_expiry.remove(&(until, *addr));
+            }
+        }
+    }
+
+    /// Remembers for a while which upstreams support EDNS, so those that do not
+    /// are queried without OPT records right away.
+    #[derive(Debug)]
+    pub struct EdnsCapabilities {
+        states: Mutex<EdnsStates>,
+        clock: Arc<dyn Clock>,
+    }
+
+    impl Default for EdnsCapabilities {
+        fn default() -> Self {
+            EdnsCapabilities::with_clock(Arc::new(SystemClock))
+        }
+    }
+
+    impl EdnsCapabilities {
+        pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+            EdnsCapabilities { states: Mutex::default(), clock }
+        }
+
+        /// What `addr` showed of its EDNS support, None if unknown or forgotten.
+        pub fn get(&self, addr: &IpAddr) -> Option<EdnsSupport> {
+            let mut states = self.states.lock().unwrap();
+            match states.by_addr.get(addr) {
+                Some((support, until)) if *until > self.clock.now() => Some(*support),
+                Some(_) => {
+                    states.remove(addr);
+                    None
+                }
+                None => None,
+            }
+        }
+
+        pub fn record(&self, addr: &IpAddr, support: EdnsSupport) {
+            let until = self.clock.now() + chrono::Duration::milliseconds(EDNS_SUPPORT_TTL.as_millis() as i64);
+            let mut states = self.states.lock().unwrap();
+            states.remove(addr);
+            states.by_addr.insert(*addr, (support, until));
+            states.by_expiry.insert((until, *addr));
+            while states.by_addr.len() > MAX_EDNS_SUPPORT_ENTRIES {
+                match states.by_expiry.first().copied() {
+                    Some((_, oldest)) => states.remove(&oldest),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::dns_cache::dns_cache::MockClock;
+
+        #[test]
+        fn test_round_robin_rotates() {
+            let selector = UpstreamSelector::new(UpstreamStrategy::RoundRobin, 3);
+            assert_eq!(selector.order(), vec![0, 1, 2]);
+            assert_eq!(selector.order(), vec![1, 2, 0]);
+            assert_eq!(selector.order(), vec![2, 0, 1]);
+        }
+
+        #[test]
+        fn test_fastest_prefers_lowest_latency() {
+            let selector = UpstreamSelector::new(UpstreamStrategy::Fastest, 2);
+            selector.record(0, Duration::from_millis(80));
+            assert_eq!(selector.order(), vec![1, 0]);
+            selector.record(1, Duration::from_millis(5));
+            assert_eq!(selector.order(), vec![1, 0]);
+        }
+
+        #[test]
+        fn test_random_order_contains_every_upstream() {
+            let selector = UpstreamSelector::new(UpstreamStrategy::Random, 4);
+            let mut order = selector.order();
+            order.sort();
+            assert_eq!(order, vec![0, 1, 2, 3]);
+        }
+
+        #[test]
+        fn test_circuit_opens_and_probes_after_cooldown() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let breaker = CircuitBreaker::with_clock(BreakerPolicy {
+                failure_threshold: 2,
+                window: Duration::from_secs(10),
+                cooldown: Duration::from_secs(30),
+            }, clock.clone());
+            let addr = IpAddr::from([192, 0, 2, 1]);
+
+            breaker.record_failure(&addr);
+            assert!(breaker.allow(&addr));
+            breaker.record_failure(&addr);
+            assert!(!breaker.allow(&addr));
+
+            clock.advance(chrono::Duration::seconds(29));
+            assert!(!breaker.allow(&addr));
+            clock.advance(chrono::Duration::seconds(1));
+            // half-open, only one probe goes through
+            assert!(breaker.allow(&addr));
+            assert!(!breaker.allow(&addr));
+            breaker.record_failure(&addr);
+            assert!(!breaker.allow(&addr));
+
+            clock.advance(chrono::Duration::seconds(30));
+            assert!(breaker.allow(&addr));
+            breaker.record_success(&addr);
+            assert!(breaker.allow(&addr));
+            assert!(breaker.allow(&addr));
+        }
+
+        #[test]
+        fn test_failures_outside_window_do_not_open_circuit() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let breaker = CircuitBreaker::with_clock(BreakerPolicy {
+                failure_threshold: 2,
+                window: Duration::from_secs(10),
+                cooldown: Duration::from_secs(30),
+            }, clock.clone());
+            let addr = IpAddr::from([192, 0, 2, 2]);
+            breaker.record_failure(&addr);
+            clock.advance(chrono::Duration::seconds(11));
+            breaker.record_failure(&addr);
+            assert!(breaker.allow(&addr));
+        }
+
+        #[test]
+        fn test_udp_timeouts_switch_to_tcp_until_cooldown() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let transports = TransportPreference::with_clock(TcpFallbackPolicy {
+                udp_timeouts: 2,
+                window: Duration::from_secs(10),
+                cooldown: Duration::from_secs(60),
+            }, clock.clone());
+            let addr = IpAddr::from([192, 0, 2, 3]);
+            assert!(!transports.record_udp_timeout(&addr));
+            transports.record_udp_answer(&addr);
+            assert!(!transports.record_udp_timeout(&addr));
+            assert!(!transports.use_tcp(&addr));
+            assert!(transports.record_udp_timeout(&addr));
+            assert!(transports.use_tcp(&addr));
+
+            clock.advance(chrono::Duration::seconds(59));
+            assert!(transports.use_tcp(&addr));
+            clock.advance(chrono::Duration::seconds(1));
+            assert!(!transports.use_tcp(&addr));
+            assert!(!transports.record_udp_timeout(&addr));
+
+            // a timeout outside the window is forgotten
+            clock.advance(chrono::Duration::seconds(10));
+            assert!(!transports.use_tcp(&addr));
+            assert!(transports.states.lock().unwrap().is_empty());
+            assert!(!transports.record_udp_timeout(&addr));
+            clock.advance(chrono::Duration::seconds(10));
+            assert!(!transports.record_udp_timeout(&addr));
+        }
+
+        #[test]
+        fn test_edns_support_is_forgotten_after_its_ttl() {
+            let clock = Arc::new(MockClock::new(Local::now()));
+            let capabilities = EdnsCapabilities::with_clock(clock.clone());
+            let addr = IpAddr::from([192, 0, 2, 1]);
+            assert_eq!(capabilities.get(&addr), None);
+
+            capabilities.record(&addr, EdnsSupport::Unsupported);
+            assert_eq!(capabilities.get(&addr), Some(EdnsSupport::Unsupported));
+            clock.advance(chrono::Duration::seconds(3599));
+            capabilities.record(&IpAddr::from([192, 0, 2, 2]), EdnsSupport::Supported);
+            assert_eq!(capabilities.get(&addr), Some(EdnsSupport::Unsupported));
+            clock.advance(chrono::Duration::seconds(1));
+            assert_eq!(capabilities.get(&addr), None);
+            assert_eq!(capabilities.get(&IpAddr::from([192, 0, 2, 2])), Some(EdnsSupport::Supported));
+        }
+    }
+}
@@ -0,0 +1,185 @@
+pub mod zone {
+    use std::collections::{HashMap, HashSet};
+    use std::io::{self, Error, ErrorKind};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+    use crate::dns_cache::dns_cache::DomainName;
+    use crate::dns_server::dns_packet::dns_packet::{Answer, QueryType, Record};
+    use crate::dns_server::names::names::{is_subdomain_of, label_count, names_equal};
+
+    /// What a zone holds for a name and type.
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum ZoneAnswer {
+        /// the records of the asked type, or the CNAME the name is an alias for
+        Records(Vec<Answer>),
+        /// the name exists but has no records of the asked type, or it has none at
+        /// all but names below it do
+        NoData,
+        NxDomain,
+        /// the name lies in a child zone delegated to `name_servers`, with the
+        /// addresses of those inside this zone as glue
+        Referral { name_servers: Vec<Answer>, glue: Vec<Answer> },
+    }
+
+    /// Whether `serial` is newer than `current` in serial number arithmetic (RFC 1982).
+    pub fn serial_is_newer(current: u32, serial: u32) -> bool {
+        serial != current && serial.wrapping_sub(current) < 1 << 31
+    }
+
+    /// A complete copy of a zone, as received through a zone transfer.
+    #[derive(Debug)]
+    pub struct Zone {
+        origin: String,
+        soa: Answer,
+        records: HashMap<DomainName, Vec<Answer>>,
+        /// names without records of their own that have names with records below them
+        empty_non_terminals: HashSet<DomainName>,
+        /// names below the origin with NS records, where child zones begin
+        cuts: HashSet<DomainName>,
+        /// when the copy was last known to be current, transferred or confirmed by the primary's SOA
+        refreshed_at: Mutex<Instant>,
+    }
+
+    impl Zone {
+        /// Builds the zone from the records of an AXFR, which start and end with the SOA.
+        /// Records outside `origin` are ignored.
+        pub fn from_transfer(origin: &str, mut records: Vec<Answer>) -> io::Result<Zone> {
+            let soa = match records.first() {
+                Some(soa) if soa.query_type == QueryType::SOA && names_equal(&soa.name, origin) => soa.clone(),
+                _ => return Err(Error::new(ErrorKind::InvalidData, "transfer does not start with the zone's SOA")),
+            };
+            if records.len() > 1 && records.last().is_some_and(|last| last.query_type == QueryType::SOA) {
+                records.pop();
+            }
+            let mut by_name: HashMap<DomainName, Vec<Answer>> = HashMap::new();
+            for record in records {
+                if !is_subdomain_of(&record.name, origin) {
+                    println!("ignoring {} outside of zone {}", record.name, origin);
+                    continue;
+                }
+                by_name.entry(DomainName::new(&record.name)).or_default().push(record);
+            }
+            let origin = DomainName::new(origin);
+            let empty_non_terminals = by_name.keys()
+                .flat_map(|name| ancestors(name.as_str(), origin.as_str()))
+                .map(|ancestor| DomainName::new(&ancestor))
+                .filter(|ancestor| !by_name.contains_key(ancestor))
+                .collect();
+            let cuts = by_name.iter()
+                .filter(|(name, records)| **name != origin && records.iter().any(|record| record.query_type == QueryType::NS))
+                .map(|(name, _)| name.clone())
+                .collect();
+            Ok(Zone {
+                origin: origin.to_string(),
+                soa,
+                records: by_name,
+                empty_non_terminals,
+                cuts,
+                refreshed_at: Mutex::new(Instant::now()),
+            })
+        }
+
+        pub fn origin(&self) -> &str {
+            &self.origin
+        }
+
+        pub fn soa(&self) -> &Answer {
+            &self.soa
+        }
+
+        pub fn serial(&self) -> u32 {
+            self.soa_field(|serial, _, _, _| serial)
+        }
+
+        /// How long until the primary should be asked for a newer copy.
+        pub fn refresh(&self) -> Duration {
+            Duration::from_secs(self.soa_field(|_, refresh, _, _| refresh) as u64)
+        }
+
+        /// How long to wait before asking again after a failed refresh.
+        pub fn retry(&self) -> Duration {
+            Duration::from_secs(self.soa_field(|_, _, retry, _| retry) as u64)
+        }
+
+        /// Whether the copy is too old to be served, as no refresh succeeded within the SOA expire time.
+        pub fn is_expired(&self) -> bool {
+            self.refreshed_at.lock().unwrap().elapsed() > Duration::from_secs(self.soa_field(|_, _, _, expire| expire) as u64)
+        }
+
+        /// Restarts the expire timer, as the primary still serves the same serial.
+        pub fn mark_refreshed(&self) {
+            *self.refreshed_at.lock().unwrap() = Instant::now();
+        }
+
+        fn soa_field(&self, field: impl Fn(u32, u32, u32, u32) -> u32) -> u32 {
+            match self.soa.record {
+                Record::SOA { serial, refresh, retry, expire, .. } => field(serial, refresh, retry, expire),
+                _ => unreachable!("zones are only built with an SOA"),
+            }
+        }
+
+        pub fn contains(&self, name: &str) -> bool {
+            is_subdomain_of(name, &self.origin)
+        }
+
+        pub fn lookup(&self, name: &str, query_type: &QueryType) -> ZoneAnswer {
+            let name = DomainName::new(name);
+            if let Some(cut) = self.cut_above(name.as_str(), query_type) {
+                return self.referral(&cut);
+            }
+            let records = match self.records.get(&name) {
+                Some(records) => records,
+                None if self.empty_non_terminals.contains(&name) => return ZoneAnswer::NoData,
+                None => return ZoneAnswer::NxDomain,
+            };
+            let matching: Vec<Answer> = records.iter()
+                .filter(|record| record.query_type == *query_type)
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                return ZoneAnswer::Records(matching);
+            }
+            match records.iter().find(|record| record.query_type == QueryType::CNAME) {
+                Some(cname) => ZoneAnswer::Records(vec![cname.clone()]),
+                None => ZoneAnswer::NoData,
+            }
+        }
+
+        /// The topmost child zone cut at or above `name`. DS records of a child zone
+        /// belong to the parent, so they are answered from here.
+        fn cut_above(&self, name: &str, query_type: &QueryType) -> Option<DomainName> {
+            let mut candidates = ancestors(name, &self.origin);
+            candidates.reverse();
+            if *query_type != QueryType::DS && !names_equal(name, &self.origin) {
+                candidates.push(name.to_string());
+            }
+            candidates.into_iter()
+                .map(|candidate| DomainName::new(&candidate))
+                .find(|candidate| self.cuts.contains(candidate))
+        }
+
+        fn referral(&self, cut: &DomainName) -> ZoneAnswer {
+            let name_servers: Vec<Answer> = self.records[cut].iter()
+                .filter(|record| record.query_type == QueryType::NS)
+                .cloned()
+                .collect();
+            let glue = name_servers.iter()
+                .filter_map(|ns| match &ns.record {
+                    Record::NS(server) => self.records.get(DomainName::new(server).as_str()),
+                    _ => None,
+                })
+                .flatten()
+                .filter(|record| matches!(record.query_type, QueryType::A | QueryType::AAAA))
+                .cloned()
+                .collect();
+            ZoneAnswer::Referral { name_servers, glue }
+        }
+    }
+
+    /// The names between `name` and `origin`, both excluded, from the closest to `name` upwards.
+    fn ancestors(name: &str, origin: &str) -> Vec<String> {
+        let labels: Vec<&str> = name.split('.').collect();
+        let below_origin = labels.len().saturating_sub(label_count(origin) as usize);
+        (1..below_origin).map(|label_idx| labels[label_idx..].join(".")).collect()
+    }
+}
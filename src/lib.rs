@@ -0,0 +1,6 @@
+#![allow(clippy::module_inception)]
+
+pub mod dns_server;
+pub mod dns_cache;
+pub mod dig;
+pub mod test;
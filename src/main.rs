@@ -1,17 +1,15 @@
-extern crate core;
-
 use std::io;
 use std::sync::Arc;
-use crate::dns_server::dns_server::DnsServer;
-
-
-pub mod dns_server;
-pub mod test;
-mod dns_cache;
+use untitled::dns_server::dns_server::DnsServer;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let server = Arc::new(DnsServer::new("127.0.0.1:2053").await?);
-    server.start().await;
+    let server = Arc::new(DnsServer::new(&["127.0.0.1:2053"]).await?);
+    if let Err(e) = server.prime().await {
+        println!("resolving from the root hints: {}", e);
+    }
+    server.serve_until(async {
+        let _ = tokio::signal::ctrl_c().await;
+    }).await;
     Ok(())
 }
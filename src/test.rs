@@ -1,11 +1,24 @@
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::io::ErrorKind;
-    use std::net::Ipv4Addr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
     use std::str::FromStr;
+    use crate::dns_cache::dns_cache::CachePolicy;
     use crate::dns_server::dns_packet::buffer::buffer::{BufferParser, BufferBuilder};
-    use crate::dns_server::dns_packet::dns_packet::{Answer, DnsPacket, Header, OperationCode, QueryType, Question, Record, ResponseCode};
-    use crate::dns_server::dns_server::DnsServer;
+    use crate::dns_server::dns_packet::dns_packet::{Answer, BADVERS, CLASS_CH, CLASS_IN, ClientSubnet, DnsError, DnsPacket, ede, EDNS_PADDING, EDNS_TCP_KEEPALIVE, EdnsOption, ExtendedError, Header, OperationCode, QueryType, Question, Record, ResponseCode};
+    use crate::dns_server::dns_server::{drain_tasks, DnsServer, ResolutionOutcome};
+    use crate::dns_server::names::names::{is_subdomain_of, label_count, names_equal};
+    use crate::dns_server::query_log::query_log::{QueryLog, QueryLogEntry};
+    use crate::dns_server::rate_limit::rate_limit::RrlPolicy;
+    use crate::dns_server::server_config::server_config::{AddressRange, AnyQueries, GluePreference, MinimalResponses, SecondaryZone, ServerConfig, SpecialUse, SpecialUseDomain, SynthesizedTtls};
+    use crate::dns_server::transport::transport::{bind_udp, query_tcp, query_udp};
+    use crate::dns_server::upstream::upstream::{BreakerPolicy, TcpFallbackPolicy, UpstreamStrategy};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+    use crate::dig::dig::{dig, DigOptions};
 
     #[test]
     fn test_buffer_parser_basic() {
@@ -117,18 +130,162 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_write_name_ignores_a_trailing_dot() {
+        let mut data = [0u8; 32];
+        let written = {
+            let mut write_buffer = BufferBuilder::new(&mut data);
+            write_buffer.write_name("example.com.").unwrap();
+            write_buffer.write_name(".").unwrap();
+            write_buffer.get_pos()
+        };
+        assert_eq!(&data[..written], b"\x07example\x03com\x00\x00");
+
+        let mut read_buffer = BufferParser::new(&data);
+        assert_eq!(read_buffer.read_name().unwrap(), "example.com");
+        assert_eq!(read_buffer.read_name().unwrap(), "");
+
+        for name in ["example..com", ".example.com", "example.com..", ".."] {
+            let mut data = [0u8; 32];
+            let err = BufferBuilder::new(&mut data).write_name(name).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_non_utf8_labels_round_trip_byte_exact() {
+        let buf = vec![0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                       5, b'a', 0xff, b'.', b'\\', b' ', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01];
+        let packet = DnsPacket::from_buf(&buf).unwrap();
+        assert_eq!(packet.questions[0].name, r"a\255\.\\\032.com");
+        assert_eq!(DnsPacket::parse_borrowed(&buf).unwrap().questions[0].name.to_string(), packet.questions[0].name);
+
+        let (out, amt) = packet.to_buf().unwrap();
+        assert_eq!(&out[..amt], &buf[..]);
+        assert!(packet.validate().is_ok());
+        // escapes can also be written by hand
+        let mut data = [0u8; 16];
+        let mut builder = BufferBuilder::new(&mut data);
+        builder.write_name(r"\065\.b.").unwrap();
+        let written = builder.get_pos();
+        assert_eq!(&data[..written], &[3, b'A', b'.', b'b', 0]);
+        let mut builder = BufferBuilder::new(&mut data);
+        assert_eq!(builder.write_name(r"a\25").unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(builder.write_name("a..b").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_names_are_compressed_within_pointer_range() {
+        let mut data = vec![0u8; 0x5000];
+        let mut write_buffer = BufferBuilder::new(&mut data);
+        write_buffer.write_name("www.example.com").unwrap();
+        let mail_pos = write_buffer.get_pos();
+        write_buffer.write_name("mail.Example.com").unwrap();
+        assert_eq!(write_buffer.get_pos(), mail_pos + 5 + 2);
+
+        write_buffer.seek(0x4000);
+        let late_pos = write_buffer.get_pos();
+        write_buffer.write_name("late.example.com").unwrap();
+        let again_pos = write_buffer.get_pos();
+        write_buffer.write_name("late.example.com").unwrap();
+        let end = write_buffer.get_pos();
+        drop(write_buffer);
+
+        // the first copy of late.example.com lies past the window, so it is spelled out
+        // again and only example.com, written at the start, is pointed to
+        assert_eq!(data[again_pos], 4);
+        assert_eq!(&data[again_pos + 5..end], &[0xc0, 4]);
+        let mut read_buffer = BufferParser::new(&data);
+        read_buffer.seek(mail_pos);
+        assert_eq!(read_buffer.read_name().unwrap(), "mail.example.com");
+        read_buffer.seek(late_pos);
+        assert_eq!(read_buffer.read_name().unwrap(), "late.example.com");
+        assert_eq!(read_buffer.read_name().unwrap(), "late.example.com");
+        assert_eq!(read_buffer.get_pos(), end);
+    }
+
+    #[test]
+    fn test_large_packet_round_trips_past_pointer_range() {
+        let mut packet = mock_response("www.example.com", QueryType::A);
+        for i in 0..1200u32 {
+            let name = format!("host{}.zone{}.example.com", i, i % 7);
+            packet.add_answer(a_record(&name, Ipv4Addr::from(i)));
+        }
+        let mut data = vec![0u8; 65535];
+        let len = {
+            let mut builder = BufferBuilder::new(&mut data);
+            packet.write_to_buf(&mut builder).unwrap();
+            builder.get_pos()
+        };
+        assert!(len > 0x4000);
+        assert_eq!(packet.wire_len(), len);
+        let parsed = DnsPacket::from_buf(&data[..len]).unwrap();
+        assert_eq!(parsed.answers, packet.answers);
+    }
+
+    #[test]
+    fn test_wire_len_matches_bytes_written() {
+        let mut packet = mock_response("www.example.com", QueryType::A);
+        packet.add_answer(a_record("www.example.com", Ipv4Addr::new(192, 0, 2, 1)));
+        packet.add_answer(a_record("www.example.com", Ipv4Addr::new(192, 0, 2, 2)));
+        packet.add_authority(ns_record("example.com", "ns1.example.com"));
+        packet.add_authority(soa_record("example.com", 7));
+        packet.add_additional(a_record("ns1.example.com", Ipv4Addr::new(192, 0, 2, 53)));
+        packet.set_edns(1232, false);
+
+        let (_, written) = packet.to_buf().unwrap();
+        assert_eq!(packet.wire_len(), written);
+    }
+
+    #[test]
+    fn test_read_write_character_string() {
+        let long = "x".repeat(255);
+        let mut data = [0u8; 300];
+        let written;
+        {
+            let mut write_buffer = BufferBuilder::new(&mut data);
+            write_buffer.write_character_string("").unwrap();
+            write_buffer.write_character_string("v=spf1 -all").unwrap();
+            write_buffer.write_character_string(&long).unwrap();
+            assert!(write_buffer.write_character_string(&"x".repeat(256)).is_err());
+            written = write_buffer.get_pos();
+        }
+        assert_eq!(written, 1 + 12 + 256);
+        assert_eq!(data[0], 0);
+
+        let mut read_buffer = BufferParser::new(&data[..written]);
+        assert_eq!(read_buffer.read_character_string().unwrap(), "");
+        assert_eq!(read_buffer.read_character_string().unwrap(), "v=spf1 -all");
+        assert_eq!(read_buffer.read_character_string().unwrap(), long);
+        assert!(read_buffer.read_character_string().is_err());
+    }
+
     #[test]
     fn test_header_creation() {
-        let header = Header::new(42, true, true, ResponseCode::NXDOMAIN);
+        let header = Header::new(42, true, false, true, ResponseCode::NXDOMAIN);
         assert_eq!(header.id, 42);
-        assert_eq!(header.get_query_response(), true);
-        assert_eq!(header.get_recursion_desired(), true);
+        assert!(header.get_query_response());
+        assert!(header.get_recursion_desired());
+        assert!(!header.get_recursion_available());
         assert_eq!(header.get_response_code(), ResponseCode::NXDOMAIN);
     }
 
+    #[test]
+    fn test_query_and_response_header_recursion_bits() {
+        let query = Header::new(1, true, false, false, ResponseCode::NOERROR);
+        assert!(query.get_recursion_desired());
+        assert!(!query.get_recursion_available());
+        assert!(!query.get_query_response());
+
+        let response = Header::new(1, false, true, true, ResponseCode::NOERROR);
+        assert!(!response.get_recursion_desired());
+        assert!(response.get_recursion_available());
+        assert!(response.get_query_response());
+    }
+
     #[test]
     fn test_header_flags() {
-        let mut header = Header::new(42, false, false, ResponseCode::NOERROR);
+        let mut header = Header::new(42, false, false, false, ResponseCode::NOERROR);
         header.set_query_response(true);
         header.set_op_code(OperationCode::Update);
         header.set_authoritative_answer(true);
@@ -137,15 +294,50 @@ mod tests {
         header.set_recursion_available(true);
         header.set_response_code(ResponseCode::SERVFAIL);
 
-        assert_eq!(header.get_query_response(), true);
+        assert!(header.get_query_response());
         assert_eq!(header.get_op_code(), OperationCode::Update);
-        assert_eq!(header.get_authoritative_answer(), true);
-        assert_eq!(header.get_truncated_message(), true);
-        assert_eq!(header.get_recursion_desired(), true);
-        assert_eq!(header.get_recursion_available(), true);
+        assert!(header.get_authoritative_answer());
+        assert!(header.get_truncated_message());
+        assert!(header.get_recursion_desired());
+        assert!(header.get_recursion_available());
         assert_eq!(header.get_response_code(), ResponseCode::SERVFAIL);
     }
 
+    #[tokio::test]
+    async fn test_z_bit_gets_formerr_in_strict_mode() {
+        let mut header = Header::new(51, true, false, false, ResponseCode::NOERROR);
+        header.set_authentic_data(true);
+        header.set_checking_disabled(true);
+        assert!(!header.get_z());
+        header.set_z(true);
+        assert_eq!(header.flags2, 0b0111_0000);
+        header.set_z(false);
+        assert!(header.get_authentic_data() && header.get_checking_disabled());
+
+        let server = test_server(ServerConfig {
+            version_bind: Some("untitled 0.1".to_string()),
+            reject_z_bit: true,
+            ..test_config()
+        }).await;
+        let mut query = DnsPacket::query(51, "version.bind", QueryType::TXT);
+        query.questions[0].class = CLASS_CH;
+        // AD and CD share the byte with Z and are fine
+        query.header.set_authentic_data(true);
+        query.header.set_checking_disabled(true);
+        let response = server.build_response(query.clone()).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+
+        let (mut buf, amt) = query.to_buf().unwrap();
+        buf[3] |= 0b0100_0000;
+        let query = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert!(query.header.get_z());
+        let response = server.build_response(query).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::FORMERR);
+        assert_eq!(response.header.id, 51);
+        assert!(response.answers.is_empty());
+    }
+
     #[test]
     fn test_parse_header_invalid_size() {
         let data = [0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x02];
@@ -154,12 +346,12 @@ mod tests {
         let result = Header::from_buf(&mut parser);
 
         assert!(result.is_err());
-        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
     }
 
     #[test]
     fn test_header_read_write() {
-        let header = Header::new(42, true, true, ResponseCode::NXDOMAIN);
+        let header = Header::new(42, true, false, true, ResponseCode::NXDOMAIN);
         let mut  buf = [0u8;512];
         let mut builder = BufferBuilder::new(&mut buf);
         header.write_to_buf(&mut builder).unwrap();
@@ -178,12 +370,12 @@ mod tests {
 
     #[test]
     fn packet_create_and_query() {
-        let header = Header::new(42, true, false, ResponseCode::NOERROR);
+        let header = Header::new(42, true, false, false, ResponseCode::NOERROR);
         let mut packet = DnsPacket::new(header);
         let question = Question {
             name: "exmaple.com".to_string(),
             query_type: QueryType::A,
-            class: 1,
+            class: CLASS_IN,
         };
         packet.add_question(question.clone());
         assert_eq!(packet.questions.len(), 1);
@@ -193,12 +385,12 @@ mod tests {
 
     #[test]
     fn test_add_answer() {
-        let header = Header::new(42, true, false, ResponseCode::NOERROR);
+        let header = Header::new(42, true, false, false, ResponseCode::NOERROR);
         let mut packet = DnsPacket::new(header);
         let answer = Answer {
             name: "example.com".to_string(),
             query_type: QueryType::A,
-            class: 1,
+            class: CLASS_IN,
             ttl: 100,
             len: 4,
             record: Record::A(Ipv4Addr::new(93, 184, 216, 34)),
@@ -209,14 +401,26 @@ mod tests {
         assert_eq!(packet.answers[0], answer);
     }
 
+    #[test]
+    fn test_query_uses_class_in() {
+        let packet = DnsPacket::query(7, "ns1.example.com", QueryType::A);
+        let (buf, bytes_written) = packet.to_buf().unwrap();
+        let parsed_packet = DnsPacket::from_buf(&buf[..bytes_written]).unwrap();
+
+        assert_eq!(parsed_packet.questions.len(), 1);
+        assert_eq!(parsed_packet.questions[0].class, CLASS_IN);
+        assert_eq!(parsed_packet.questions[0].name, "ns1.example.com");
+        assert_eq!(parsed_packet.questions[0].query_type, QueryType::A);
+    }
+
     #[test]
     fn build_and_parse_packet() {
-        let header = Header::new(42, true, false, ResponseCode::NOERROR);
+        let header = Header::new(42, true, false, false, ResponseCode::NOERROR);
         let mut packet = DnsPacket::new(header);
         let question = Question {
             name: "example.com".to_string(),
             query_type: QueryType::A,
-            class: 1,
+            class: CLASS_IN,
         };
         packet.add_question(question.clone());
 
@@ -243,12 +447,17 @@ mod tests {
 
     #[tokio::test]
     async fn test_query_built_packet() {
-        let header = Header::new(42, true, false, ResponseCode::NOERROR);
+        let ns = Ipv4Addr::new(127, 0, 70, 1);
+        let (port, _) = MockUpstream::new(ns)
+            .answer("google.com", vec![a_record("google.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .serve_all(vec![]).await;
+
+        let header = Header::new(42, true, false, false, ResponseCode::NOERROR);
         let mut packet = DnsPacket::new(header);
         let question = Question {
             name: "google.com".to_string(),
             query_type: QueryType::A,
-            class: 1,
+            class: CLASS_IN,
         };
         packet.add_question(question);
 
@@ -259,12 +468,3148 @@ mod tests {
             packet.write_to_buf(&mut builder).unwrap();
             bytes_written = builder.get_pos();
         }
-        let ns = Ipv4Addr::from_str("198.41.0.4").unwrap();
-        let mut server = DnsServer::new("127.0.0.1:2053").await.unwrap();
-        let mut out_buf = [0u8; 512];
-        let packet = server.lookup(&ns, &buf[..bytes_written]).await.unwrap();
+        let server = mock_resolver(ns, port).await;
+        let packet = server.lookup_v4(&ns, &buf[..bytes_written]).await.unwrap();
 
         assert_eq!(packet.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(packet.header.id, 42);
+        assert_eq!(packet.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+    }
+
+    fn a_record(name: &str, ip: Ipv4Addr) -> Answer {
+        Answer {
+            name: name.to_string(),
+            query_type: QueryType::A,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 4,
+            record: Record::A(ip),
+        }
+    }
+
+    fn ns_record(zone: &str, server: &str) -> Answer {
+        Answer {
+            name: zone.to_string(),
+            query_type: QueryType::NS,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::NS(server.to_string()),
+        }
+    }
+
+    fn mock_response(qname: &str, query_type: QueryType) -> DnsPacket {
+        let mut packet = DnsPacket::new(Header::new(0, false, false, true, ResponseCode::NOERROR));
+        packet.add_question(Question {
+            name: qname.to_string(),
+            query_type,
+            class: CLASS_IN,
+        });
+        packet
+    }
+
+    fn mock_referral(qname: &str, zone: &str, name_servers: &[(&str, Option<Ipv4Addr>)]) -> DnsPacket {
+        let mut packet = mock_response(qname, QueryType::A);
+        for (server, glue) in name_servers {
+            packet.add_authority(ns_record(zone, server));
+            if let Some(ip) = glue {
+                packet.add_additional(a_record(server, *ip));
+            }
+        }
+        packet
+    }
+
+    /// A fake nameserver on one loopback address, answering canned responses
+    /// keyed by the name and type of the first question and ignoring other queries.
+    struct MockUpstream {
+        ip: Ipv4Addr,
+        responses: HashMap<(String, QueryType), DnsPacket>,
+    }
+
+    impl MockUpstream {
+        fn new(ip: Ipv4Addr) -> MockUpstream {
+            MockUpstream { ip, responses: HashMap::new() }
+        }
+
+        /// Answers `qname` with `records`, asked for with the type of the first record.
+        fn answer(self, qname: &str, records: Vec<Answer>) -> MockUpstream {
+            let mut response = mock_response(qname, records[0].query_type.clone());
+            response.set_answers(records);
+            self.respond(response)
+        }
+
+        /// Refers A queries for `qname` to the nameservers of `zone`, with glue where given.
+        fn delegate(self, qname: &str, zone: &str, name_servers: &[(&str, Option<Ipv4Addr>)]) -> MockUpstream {
+            self.respond(mock_referral(qname, zone, name_servers))
+        }
+
+        fn nxdomain(self, qname: &str, query_type: QueryType, zone: &str) -> MockUpstream {
+            let mut response = mock_response(qname, query_type);
+            response.header.set_response_code(ResponseCode::NXDOMAIN);
+            response.header.set_authoritative_answer(true);
+            response.add_authority(soa_record(zone, 1));
+            self.respond(response)
+        }
+
+        fn respond(mut self, response: DnsPacket) -> MockUpstream {
+            let question = response.question().expect("canned responses need a question");
+            self.responses.insert((question.name.clone(), question.query_type.clone()), response);
+            self
+        }
+
+        /// Serves this and `others` on one shared port, returning it together
+        /// with the queries each of them received, in the given order.
+        async fn serve_all(self, others: Vec<MockUpstream>) -> (u16, Vec<Arc<Mutex<Vec<DnsPacket>>>>) {
+            let upstreams: Vec<MockUpstream> = std::iter::once(self).chain(others).collect();
+            let ips: Vec<Ipv4Addr> = upstreams.iter().map(|upstream| upstream.ip).collect();
+            let (port, sockets) = bind_mock_servers(&ips).await;
+            let received = upstreams.into_iter().zip(sockets)
+                .map(|(upstream, socket)| serve_mock(socket, upstream.responses.into_values().collect()))
+                .collect();
+            (port, received)
+        }
+
+        /// Serves this mock alone, returning its port and the queries it received.
+        async fn serve(self) -> (u16, Arc<Mutex<Vec<DnsPacket>>>) {
+            let (port, mut received) = self.serve_all(Vec::new()).await;
+            (port, received.remove(0))
+        }
+    }
+
+    /// Binds one socket per ip, all sharing the same port so the resolver can
+    /// reach every mock through a single `upstream_port`.
+    async fn bind_mock_servers<T: Into<IpAddr> + Copy>(ips: &[T]) -> (u16, Vec<UdpSocket>) {
+        'retry: loop {
+            let first = UdpSocket::bind((ips[0].into(), 0)).await.unwrap();
+            let port = first.local_addr().unwrap().port();
+            let mut sockets = vec![first];
+            for ip in &ips[1..] {
+                match UdpSocket::bind(((*ip).into(), port)).await {
+                    Ok(socket) => sockets.push(socket),
+                    Err(_) => continue 'retry,
+                }
+            }
+            return (port, sockets);
+        }
+    }
+
+    /// Answers every query whose first question matches one of `responses`
+    /// and silently drops everything else.
+    fn serve_mock(socket: UdpSocket, responses: Vec<DnsPacket>) -> Arc<Mutex<Vec<DnsPacket>>> {
+        serve_mock_with_delay(socket, responses, Duration::ZERO)
+    }
+
+    /// Like `serve_mock` but waits `delay` before answering. Returns the received queries.
+    fn serve_mock_with_delay(socket: UdpSocket, responses: Vec<DnsPacket>, delay: Duration) -> Arc<Mutex<Vec<DnsPacket>>> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = Arc::clone(&received);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (amt, client) = match socket.recv_from(&mut buf).await {
+                    Ok(res) => res,
+                    Err(_) => return,
+                };
+                let query = match DnsPacket::from_buf(&buf[..amt]) {
+                    Ok(query) => query,
+                    Err(_) => continue,
+                };
+                received_clone.lock().unwrap().push(query.clone());
+                let matching = responses.iter().find(|response| {
+                    match (response.questions.first(), query.questions.first()) {
+                        (Some(r), Some(q)) => r.name == q.name && r.query_type == q.query_type,
+                        _ => false,
+                    }
+                });
+                if let Some(response) = matching {
+                    tokio::time::sleep(delay).await;
+                    let mut response = response.clone();
+                    response.header.id = query.header.id;
+                    let (out, out_len) = response.to_buf().unwrap();
+                    let _ = socket.send_to(&out[..out_len], client).await;
+                }
+            }
+        });
+        received
+    }
+
+    /// Binds the lookup socket to loopback and leaves everything else at its default.
+    fn test_config() -> ServerConfig {
+        ServerConfig {
+            lookup_addr: "127.0.0.1:0".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Resolves from the mock root at `root`, giving up on silent nameservers quickly.
+    fn resolver_config(root: Ipv4Addr, upstream_port: u16) -> ServerConfig {
+        ServerConfig {
+            upstream_port,
+            root_server_ips: vec![root],
+            lookup_timeout: Duration::from_millis(100),
+            ..test_config()
+        }
+    }
+
+    async fn test_server(config: ServerConfig) -> DnsServer {
+        DnsServer::with_config(&["127.0.0.1:0"], config).await.unwrap()
+    }
+
+    async fn mock_resolver(root: Ipv4Addr, upstream_port: u16) -> DnsServer {
+        test_server(resolver_config(root, upstream_port)).await
+    }
+
+    #[tokio::test]
+    async fn test_delegation_falls_back_through_all_nameservers() {
+        let root = Ipv4Addr::new(127, 0, 18, 1);
+        let ns1 = Ipv4Addr::new(127, 0, 18, 11);
+        let ns2 = Ipv4Addr::new(127, 0, 18, 12);
+        let ns3 = Ipv4Addr::new(127, 0, 18, 13);
+        // ns1 and ns2 are bound but never answer
+        let (port, mut sockets) = bind_mock_servers(&[root, ns1, ns2, ns3]).await;
+
+        let referral = mock_referral("www.example.com", "example.com", &[
+            ("ns1.example.com", Some(ns1)),
+            ("ns2.example.com", Some(ns2)),
+            ("ns3.example.com", Some(ns3)),
+        ]);
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 3)));
+
+        serve_mock(sockets.remove(3), vec![answer]);
+        serve_mock(sockets.remove(2), vec![]);
+        serve_mock(sockets.remove(1), vec![]);
+        serve_mock(sockets.remove(0), vec![referral]);
+
+        let server = mock_resolver(root, port).await;
+        let query = DnsPacket::query(1, "www.example.com", QueryType::A);
+        let (buf, amt) = query.to_buf().unwrap();
+        let packet = server.iterative_cache_resolve("www.example.com", &buf[..amt]).await.unwrap();
+
+        assert_eq!(packet.answers[0].record, Record::A(Ipv4Addr::new(10, 0, 0, 3)));
+    }
+
+    #[test]
+    fn test_get_unresolved_ns_uses_closest_zone() {
+        let mut packet = mock_response("www.example.com", QueryType::A);
+        packet.add_authority(ns_record("com", "a.gtld-servers.net"));
+        packet.add_authority(ns_record("example.com", "ns1.example.com"));
+        packet.add_authority(ns_record("example.com", "ns2.example.com"));
+        packet.add_authority(ns_record("example.com", "ns3.example.com"));
+
+        let servers: Vec<&str> = packet.get_unresolved_ns("www.example.com")
+            .map(|(server, _)| server)
+            .collect();
+        assert_eq!(servers, vec!["ns1.example.com", "ns2.example.com", "ns3.example.com"]);
+    }
+
+    #[test]
+    fn test_glue_matching_ignores_case_and_trailing_dot() {
+        let mut packet = mock_response("www.Example.com", QueryType::A);
+        packet.add_authority(ns_record("example.com.", "NS1.Example.com."));
+        packet.add_authority(ns_record("example.com", "ns2.example.com"));
+        packet.add_additional(a_record("ns1.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        packet.add_additional(a_record("NS2.EXAMPLE.COM.", Ipv4Addr::new(10, 0, 0, 2)));
+
+        let servers: Vec<&str> = packet.get_unresolved_ns("www.Example.com")
+            .map(|(server, _)| server)
+            .collect();
+        assert_eq!(servers, vec!["NS1.Example.com.", "ns2.example.com"]);
+        let glue: Vec<&Ipv4Addr> = packet.get_resolved_ns("www.Example.com").collect();
+        assert_eq!(glue, vec![&Ipv4Addr::new(10, 0, 0, 1), &Ipv4Addr::new(10, 0, 0, 2)]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_tasks_surfaces_panics() {
+        let mut tasks = tokio::task::JoinSet::new();
+        tasks.spawn(async {});
+        tasks.spawn(async { panic!("resolution blew up") });
+
+        assert_eq!(drain_tasks(&mut tasks, Duration::from_secs(1)).await, 1);
+        assert!(tasks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drain_tasks_aborts_after_deadline() {
+        let mut tasks = tokio::task::JoinSet::new();
+        tasks.spawn(tokio::time::sleep(Duration::from_secs(60)));
+
+        assert_eq!(drain_tasks(&mut tasks, Duration::from_millis(50)).await, 1);
+    }
+
+    #[test]
+    fn test_opt_record_round_trip() {
+        let mut packet = DnsPacket::query(5, "example.com", QueryType::A);
+        packet.add_additional(Answer::opt(1232, true));
+        let (buf, bytes_written) = packet.to_buf().unwrap();
+        let parsed_packet = DnsPacket::from_buf(&buf[..bytes_written]).unwrap();
+
+        assert_eq!(parsed_packet.edns().unwrap().class, 1232);
+        assert!(parsed_packet.dnssec_ok());
+        assert!(!DnsPacket::query(5, "example.com", QueryType::A).dnssec_ok());
+    }
+
+    async fn resolve_with_dnssec_ok(root: Ipv4Addr, dnssec_ok: bool) -> DnsPacket {
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut answer = mock_response("signed.example.com", QueryType::A);
+        answer.add_answer(a_record("signed.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        answer.add_answer(Answer {
+            name: "signed.example.com".to_string(),
+            query_type: QueryType::RRSIG,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 4,
+            record: Record::RRSIG(vec![0, 1, 2, 3]),
+        });
+        serve_mock(sockets.remove(0), vec![answer]);
+
+        let server = mock_resolver(root, port).await;
+        let mut query = DnsPacket::query(9, "signed.example.com", QueryType::A);
+        query.add_additional(Answer::opt(1232, dnssec_ok));
+        server.build_response(query).await
+    }
+
+    #[tokio::test]
+    async fn test_dnssec_records_only_for_do_clients() {
+        let with_do = resolve_with_dnssec_ok(Ipv4Addr::new(127, 0, 20, 1), true).await;
+        let without_do = resolve_with_dnssec_ok(Ipv4Addr::new(127, 0, 20, 2), false).await;
+
+        assert!(with_do.answers.iter().any(|answer| answer.query_type == QueryType::RRSIG));
+        assert!(without_do.answers.iter().all(|answer| answer.query_type != QueryType::RRSIG));
+        assert_eq!(without_do.answers.len(), 1);
+        assert_eq!(without_do.header.answer_count, 1);
+
+        // records of the asked type are kept, only the signatures over them go
+        let mut ds = mock_response("example.com", QueryType::DS);
+        ds.add_answer(Answer {
+            name: "example.com".to_string(),
+            query_type: QueryType::DS,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 4,
+            record: Record::DS(vec![0, 1, 2, 3]),
+        });
+        ds.add_answer(Answer {
+            name: "example.com".to_string(),
+            query_type: QueryType::RRSIG,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 4,
+            record: Record::RRSIG(vec![0, 1, 2, 3]),
+        });
+        ds.strip_dnssec_records();
+        assert_eq!(ds.answers.len(), 1);
+        assert_eq!(ds.answers[0].query_type, QueryType::DS);
+    }
+
+    #[test]
+    fn test_merge_answers_dedups_and_fixes_counts() {
+        let mut first = mock_response("www.example.com", QueryType::A);
+        first.add_answer(Answer {
+            name: "www.example.com".to_string(),
+            query_type: QueryType::CNAME,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::CNAME("web.example.com".to_string()),
+        });
+        first.add_authority(ns_record("example.com", "ns1.example.com"));
+
+        let mut second = mock_response("web.example.com", QueryType::A);
+        second.add_answer(a_record("web.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        second.add_authority(ns_record("example.com", "ns1.example.com"));
+        second.add_additional(a_record("ns1.example.com", Ipv4Addr::new(10, 0, 0, 53)));
+
+        first.merge_answers(&second);
+        first.merge_answers(&second);
+
+        assert_eq!(first.questions[0].name, "www.example.com");
+        assert_eq!(first.header.question_count, 1);
+        assert_eq!(first.answers.len(), 2);
+        assert_eq!(first.header.answer_count, 2);
+        assert_eq!(first.authorities.len(), 1);
+        assert_eq!(first.header.authoritiy_count, 1);
+        assert_eq!(first.additional.len(), 1);
+        assert_eq!(first.header.additional_count, 1);
+    }
+
+    #[test]
+    fn test_parse_tolerates_overpromised_additional_count() {
+        let mut packet = mock_response("www.example.com", QueryType::A);
+        packet.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        packet.add_additional(a_record("ns1.example.com", Ipv4Addr::new(10, 0, 0, 53)));
+        packet.header.additional_count += 1;
+        let (buf, bytes_written) = packet.to_buf().unwrap();
+
+        let parsed_packet = DnsPacket::from_buf(&buf[..bytes_written]).unwrap();
+        assert_eq!(parsed_packet.answers, packet.answers);
+        assert_eq!(parsed_packet.additional, packet.additional);
+        assert_eq!(parsed_packet.header.additional_count, 1);
+
+        assert!(DnsPacket::from_buf_strict(&buf[..bytes_written]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_blocklisted_name_is_refused() {
+        let server = test_server(ServerConfig {
+            blocklist: vec!["ads.example.com".to_string()],
+            ..test_config()
+        }).await;
+
+        let query = DnsPacket::query(77, "tracker.ads.example.com", QueryType::A);
+        let response = server.build_response(query.clone()).await;
+
+        assert_eq!(response.header.id, 77);
+        assert!(response.header.get_query_response());
+        assert!(response.header.get_recursion_available());
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+        assert_eq!(response.questions, query.questions);
+        assert_eq!(response.header.question_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_refused_response_code_is_configurable() {
+        let server = test_server(ServerConfig {
+            blocklist: vec!["ads.example.com".to_string()],
+            refused_response_code: ResponseCode::NXDOMAIN,
+            ..test_config()
+        }).await;
+
+        let response = server.build_response(DnsPacket::query(1, "ads.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NXDOMAIN);
+        assert_eq!(response.questions[0].name, "ads.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_lookup_over_ipv6() {
+        let (port, mut sockets) = bind_mock_servers(&[Ipv6Addr::LOCALHOST]).await;
+        let mut answer = mock_response("v6.example.com", QueryType::AAAA);
+        answer.add_answer(Answer {
+            name: "v6.example.com".to_string(),
+            query_type: QueryType::AAAA,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 16,
+            record: Record::AAAA(Ipv6Addr::from_str("2001:db8::1").unwrap()),
+        });
+        serve_mock(sockets.remove(0), vec![answer.clone()]);
+
+        let server = test_server(ServerConfig {
+            lookup_addr_v6: "[::1]:0".to_string(),
+            upstream_port: port,
+            lookup_timeout: Duration::from_millis(500),
+            ..test_config()
+        }).await;
+        let (buf, amt) = DnsPacket::query(3, "v6.example.com", QueryType::AAAA).to_buf().unwrap();
+        let packet = server.lookup(&IpAddr::V6(Ipv6Addr::LOCALHOST), &buf[..amt]).await.unwrap();
+
+        assert_eq!(packet.header.id, 3);
+        assert_eq!(packet.answers, answer.answers);
+    }
+
+    #[tokio::test]
+    async fn test_warm_cache_populates_entries() {
+        let root = Ipv4Addr::new(127, 0, 26, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut first = mock_response("one.example.com", QueryType::A);
+        first.add_answer(a_record("one.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        let mut second = mock_response("two.example.com", QueryType::A);
+        second.add_answer(a_record("two.example.com", Ipv4Addr::new(10, 0, 0, 2)));
+        serve_mock(sockets.remove(0), vec![first, second]);
+
+        let server = Arc::new(mock_resolver(root, port).await);
+        server.warm_cache(&[
+            ("one.example.com".to_string(), QueryType::A),
+            ("two.example.com".to_string(), QueryType::A),
+            ("missing.example.com".to_string(), QueryType::A),
+        ]).await;
+
+        let cached = server.cache().get("one.example.com", &QueryType::A).unwrap();
+        assert_eq!(cached[0].record, Record::A(Ipv4Addr::new(10, 0, 0, 1)));
+        let cached = server.cache().get("two.example.com", &QueryType::A).unwrap();
+        assert_eq!(cached[0].record, Record::A(Ipv4Addr::new(10, 0, 0, 2)));
+        assert!(server.cache().get("missing.example.com", &QueryType::A).is_none());
+    }
+
+    #[test]
+    fn test_uri_record_round_trip() {
+        let mut packet = mock_response("_http._tcp.example.com", QueryType::URI);
+        packet.add_answer(Answer {
+            name: "_http._tcp.example.com".to_string(),
+            query_type: QueryType::URI,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 24,
+            record: Record::URI {
+                priority: 10,
+                weight: 1,
+                target: "https://example.com/".to_string(),
+            },
+        });
+        let (buf, bytes_written) = packet.to_buf().unwrap();
+        let parsed_packet = DnsPacket::from_buf(&buf[..bytes_written]).unwrap();
+
+        assert_eq!(parsed_packet.answers, packet.answers);
+    }
+
+    #[test]
+    fn test_nsec_record_round_trip() {
+        let mut packet = mock_response("alpha.example.com", QueryType::NSEC);
+        packet.add_answer(Answer {
+            name: "alpha.example.com".to_string(),
+            query_type: QueryType::NSEC,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 26,
+            record: Record::NSEC {
+                next_domain: "host.example.com".to_string(),
+                types: vec![QueryType::A, QueryType::RRSIG],
+            },
+        });
+        let (buf, bytes_written) = packet.to_buf().unwrap();
+        // window 0 holds bit 1 (A) and bit 46 (RRSIG) in a six byte bitmap
+        let rdata = &buf[bytes_written - 26..bytes_written];
+        assert_eq!(&rdata[18..], &[0, 6, 0x40, 0, 0, 0, 0, 0x02]);
+        let parsed_packet = DnsPacket::from_buf(&buf[..bytes_written]).unwrap();
+
+        assert_eq!(parsed_packet.answers, packet.answers);
+    }
+
+    #[test]
+    fn test_nsec3_record_round_trip() {
+        let mut packet = mock_response("example.com", QueryType::NSEC3);
+        packet.add_answer(Answer {
+            name: "example.com".to_string(),
+            query_type: QueryType::NSEC3,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 36,
+            record: Record::NSEC3 {
+                hash_algorithm: 1,
+                flags: 1,
+                iterations: 10,
+                salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                next_hashed_owner: vec![0x11; 20],
+                types: vec![QueryType::NS, QueryType::SOA, QueryType::URI],
+            },
+        });
+        let (buf, bytes_written) = packet.to_buf().unwrap();
+        let parsed_packet = DnsPacket::from_buf(&buf[..bytes_written]).unwrap();
+
+        assert_eq!(parsed_packet.answers, packet.answers);
+    }
+
+    #[test]
+    fn test_type_bitmap_windows_must_increase() {
+        // header with one NSEC answer whose two blocks both claim window 0
+        let mut buf = vec![0x00, 0x01, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+                           7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x2f, 0x00, 0x01];
+        buf.extend_from_slice(&[0xc0, 0x0c, 0x00, 0x2f, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x07,
+                                0, 0, 1, 0x40, 0, 1, 0x40]);
+        assert_eq!(DnsPacket::from_buf(&buf).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_inflated_section_counts_fail_fast() {
+        // header declaring 65535 questions followed by a single one
+        let buf = vec![0x00, 0x01, 0x01, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                       7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x01, 0x00, 0x01];
+        let err = DnsPacket::from_buf(&buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "question count 65535 exceeds the 17 bytes left in the packet");
+        assert_eq!(DnsPacket::parse_borrowed(&buf).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut inflated_answers = buf.clone();
+        inflated_answers[4] = 0;
+        inflated_answers[5] = 1;
+        inflated_answers[6] = 0x01;
+        let err = DnsPacket::from_buf(&inflated_answers).unwrap_err();
+        assert_eq!(err.to_string(), "answer count 256 exceeds the 0 bytes left in the packet");
+
+        // missing additional records stay tolerated outside of strict parsing
+        let mut inflated_additional = buf;
+        inflated_additional[4] = 0;
+        inflated_additional[5] = 1;
+        inflated_additional[11] = 3;
+        assert!(DnsPacket::from_buf(&inflated_additional).is_ok());
+        assert!(DnsPacket::parse_borrowed(&inflated_additional).is_ok());
+        assert_eq!(DnsPacket::from_buf_strict(&inflated_additional).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_zero_length_rdata() {
+        // header with two answers, each named with a pointer to the question
+        let mut buf = vec![0x00, 0x01, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+                           7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x10, 0x00, 0x01];
+        let empty_txt = [0xc0, 0x0c, 0x00, 0x10, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x00];
+        let a = [0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 10, 0, 0, 1];
+        buf.extend_from_slice(&empty_txt);
+        buf.extend_from_slice(&a);
+        let packet = DnsPacket::from_buf(&buf).unwrap();
+        assert_eq!(packet.answers[0].record, Record::TXT(Vec::new()));
+        assert_eq!(packet.answers[1].record, Record::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+        // an A record declaring no rdata must not swallow the record after it
+        let mut empty_a = buf.clone();
+        empty_a[32] = 0x01;
+        let err = DnsPacket::from_buf(&empty_a).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let mut short_aaaa = buf.clone();
+        short_aaaa[32] = 0x1c;
+        short_aaaa[40] = 4;
+        short_aaaa.extend_from_slice(&[0, 0, 0, 1]);
+        assert_eq!(DnsPacket::from_buf(&short_aaaa).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        let mut empty_cname = buf;
+        empty_cname[32] = 0x05;
+        assert_eq!(DnsPacket::from_buf(&empty_cname).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_rdata_length_is_authoritative() {
+        // header with two answers, each named with a pointer to the question
+        let mut buf = vec![0x00, 0x01, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+                           7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0, 0x00, 0x05, 0x00, 0x01];
+        // a CNAME to a pointer at example.com, followed by three bytes of padding
+        let padded_cname = [0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x05, 0xc0, 0x0c, 0xff, 0xff, 0xff];
+        let a = [0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04, 10, 0, 0, 1];
+        buf.extend_from_slice(&padded_cname);
+        buf.extend_from_slice(&a);
+        let packet = DnsPacket::from_buf(&buf).unwrap();
+        assert_eq!(packet.answers[0].record, Record::CNAME("example.com".to_string()));
+        assert_eq!(packet.answers[1].record, Record::A(Ipv4Addr::new(10, 0, 0, 1)));
+
+        // a name running past the declared rdata length is rejected
+        let mut overlong = buf.clone();
+        overlong[40] = 1;
+        assert_eq!(DnsPacket::from_buf(&overlong).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        // rdata reaching past the end of the packet is rejected too
+        let mut truncated = buf;
+        truncated.truncate(truncated.len() - 2);
+        assert_eq!(DnsPacket::from_buf(&truncated).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_mx_record_round_trip() {
+        let mut packet = mock_response("example.com", QueryType::MX);
+        packet.add_answer(Answer {
+            name: "example.com".to_string(),
+            query_type: QueryType::MX,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::MX { priority: 10, host: "mail.example.com".to_string() },
+        });
+        let (buf, amt) = packet.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.answers[0].record, packet.answers[0].record);
+    }
+
+    #[tokio::test]
+    async fn test_fastest_forwarder_strategy_converges() {
+        let slow = Ipv4Addr::new(127, 0, 29, 1);
+        let fast = Ipv4Addr::new(127, 0, 29, 2);
+        let (port, mut sockets) = bind_mock_servers(&[slow, fast]).await;
+        let responses = |ip: Ipv4Addr| (0..6).map(|i| {
+            let name = format!("q{}.example.com", i);
+            let mut response = mock_response(&name, QueryType::A);
+            response.add_answer(a_record(&name, ip));
+            response
+        }).collect::<Vec<_>>();
+        serve_mock(sockets.remove(1), responses(fast));
+        serve_mock_with_delay(sockets.remove(0), responses(slow), Duration::from_millis(60));
+
+        let server = test_server(ServerConfig {
+            upstream_port: port,
+            lookup_timeout: Duration::from_millis(500),
+            forwarders: vec![IpAddr::V4(slow), IpAddr::V4(fast)],
+            upstream_strategy: UpstreamStrategy::Fastest,
+            ..test_config()
+        }).await;
+
+        let mut answered_by = Vec::new();
+        for i in 0..6 {
+            let query = DnsPacket::query(i, &format!("q{}.example.com", i), QueryType::A);
+            let response = server.build_response(query).await;
+            answered_by.push(response.answers[0].record.clone());
+        }
+        assert!(answered_by[2..].iter().all(|record| *record == Record::A(fast)));
+    }
+
+    #[tokio::test]
+    async fn test_failing_forwarder_is_passed_over() {
+        let broken = Ipv4Addr::new(127, 0, 91, 1);
+        let working = Ipv4Addr::new(127, 0, 91, 2);
+        let names: Vec<String> = (0..4).map(|i| format!("q{}.example.com", i)).collect();
+        let servfail = |name: &str| {
+            let mut response = mock_response(name, QueryType::A);
+            response.header.set_response_code(ResponseCode::SERVFAIL);
+            response
+        };
+        let broken_upstream = names.iter()
+            .fold(MockUpstream::new(broken), |upstream, name| upstream.respond(servfail(name)))
+            .respond(servfail("down.example.com"));
+        let working_upstream = names.iter()
+            .fold(MockUpstream::new(working), |upstream, name| upstream.answer(name, vec![a_record(name, working)]));
+        let (port, received) = broken_upstream.serve_all(vec![working_upstream]).await;
+        let server = test_server(ServerConfig {
+            upstream_port: port,
+            lookup_timeout: Duration::from_millis(100),
+            forwarders: vec![IpAddr::V4(broken), IpAddr::V4(working)],
+            upstream_strategy: UpstreamStrategy::Fastest,
+            ..test_config()
+        }).await;
+
+        for (id, name) in names.iter().enumerate() {
+            let response = server.build_response(DnsPacket::query(id as u16, name, QueryType::A)).await;
+            assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+            assert_eq!(response.all_addresses(), vec![IpAddr::V4(working)]);
+        }
+        // its quick failures did not make the broken forwarder the fastest
+        assert!(received[0].lock().unwrap().len() < names.len());
+
+        // when every forwarder fails, the failure is passed on
+        let response = server.build_response(DnsPacket::query(9, "down.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::SERVFAIL);
+    }
+
+    #[test]
+    fn test_validate_rejects_count_mismatch() {
+        let mut packet = mock_response("www.example.com", QueryType::A);
+        packet.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(packet.validate(), Ok(()));
+
+        packet.header.answer_count = 2;
+        assert_eq!(packet.validate(), Err(DnsError::CountMismatch { section: "answer", declared: 2, actual: 1 }));
+    }
+
+    #[test]
+    fn test_validate_rejects_overlong_names() {
+        let long_name = vec!["a".repeat(60); 5].join(".");
+        let packet = DnsPacket::query(1, &long_name, QueryType::A);
+        assert_eq!(packet.validate(), Err(DnsError::NameTooLong(long_name)));
+
+        let long_label = format!("{}.com", "a".repeat(64));
+        let packet = DnsPacket::query(1, &long_label, QueryType::A);
+        assert_eq!(packet.validate(), Err(DnsError::LabelTooLong(long_label)));
+    }
+
+    #[tokio::test]
+    async fn test_edns_payload_size_is_advertised() {
+        let root = Ipv4Addr::new(127, 0, 33, 1);
+        let (port, received) = MockUpstream::new(root)
+            .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .serve().await;
+
+        let server = test_server(ServerConfig {
+            edns_udp_payload_size: 1400,
+            ..resolver_config(root, port)
+        }).await;
+        let mut query = DnsPacket::query(4, "www.example.com", QueryType::A);
+        query.add_additional(Answer::opt(4096, false));
+        let response = server.build_response(query).await;
+
+        assert_eq!(received.lock().unwrap()[0].edns().unwrap().class, 1400);
+        assert_eq!(response.edns().unwrap().class, 1400);
+        assert_eq!(response.header.id, 4);
+    }
+
+    #[tokio::test]
+    async fn test_edns_payload_size_is_validated() {
+        let server = DnsServer::with_config(&["127.0.0.1:0"], ServerConfig {
+            edns_udp_payload_size: 100,
+            ..test_config()
+        }).await;
+        assert_eq!(server.err().unwrap().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_edns_version_gets_badvers() {
+        let server = test_server(ServerConfig {
+            version_bind: Some("untitled 0.1".to_string()),
+            ..test_config()
+        }).await;
+        let mut query = DnsPacket::query(50, "version.bind", QueryType::TXT);
+        query.questions[0].class = CLASS_CH;
+        query.set_edns(1232, false);
+        query.additional[0].ttl |= 1 << 16;
+        assert_eq!(query.edns_version(), Some(1));
+
+        let response = server.build_response(query.clone()).await;
+        let (buf, amt) = response.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        // BADVERS is 16: 1 in the upper byte of the OPT ttl and 0 in the header
+        assert_eq!(parsed.extended_response_code(), BADVERS);
+        assert_eq!(parsed.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(parsed.edns().unwrap().ttl >> 24, 1);
+        assert_eq!(parsed.edns_version(), Some(0));
+        assert!(parsed.answers.is_empty());
+
+        query.additional[0].ttl = 0;
+        let response = server.build_response(query).await;
+        assert_eq!(response.extended_response_code(), 0);
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[test]
+    fn test_all_addresses_returns_both_families() {
+        let v6 = Ipv6Addr::from_str("2001:db8::1").unwrap();
+        let mut packet = mock_response("dual.example.com", QueryType::A);
+        packet.add_answer(Answer {
+            name: "dual.example.com".to_string(),
+            query_type: QueryType::AAAA,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 16,
+            record: Record::AAAA(v6),
+        });
+        packet.add_answer(a_record("dual.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        packet.add_answer(a_record("dual.example.com", Ipv4Addr::new(10, 0, 0, 2)));
+
+        assert_eq!(packet.all_addresses(), vec![
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+            IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+            IpAddr::V6(v6),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_resolved_response_uses_client_header() {
+        let root = Ipv4Addr::new(127, 0, 35, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.header.set_authoritative_answer(true);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        answer.add_additional(Answer::opt(4096, false));
+        serve_mock(sockets.remove(0), vec![answer]);
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(4242, "www.example.com", QueryType::A)).await;
+
+        assert_eq!(response.header.id, 4242);
+        assert!(response.header.get_query_response());
+        assert!(response.header.get_recursion_available());
+        assert!(!response.header.get_authoritative_answer());
+        assert_eq!(response.questions[0].name, "www.example.com");
+        assert_eq!(response.answers.len(), 1);
+        assert!(response.edns().is_none());
+        assert_eq!(response.validate(), Ok(()));
     }
 
+    #[tokio::test]
+    async fn test_serves_clients_on_every_listen_address() {
+        let root = Ipv4Addr::new(127, 0, 36, 1);
+        let (port, _) = MockUpstream::new(root)
+            .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .serve().await;
+
+        let server = Arc::new(DnsServer::with_config(&["127.0.0.1:0", "[::1]:0"], resolver_config(root, port)).await.unwrap());
+        let listen_addrs = server.local_addrs().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        for (id, listen_addr) in listen_addrs.into_iter().enumerate() {
+            let client = match listen_addr {
+                SocketAddr::V4(_) => UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                SocketAddr::V6(_) => UdpSocket::bind("[::1]:0").await.unwrap(),
+            };
+            let (buf, amt) = DnsPacket::query(id as u16, "www.example.com", QueryType::A).to_buf().unwrap();
+            client.send_to(&buf[..amt], listen_addr).await.unwrap();
+            let mut in_buf = [0u8; 512];
+            let (len, from) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+                .await.unwrap().unwrap();
+            assert_eq!(from, listen_addr);
+            let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+            assert_eq!(response.header.id, id as u16);
+            assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        }
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache_hits_about_to_expire_are_prefetched() {
+        let root = Ipv4Addr::new(127, 0, 89, 1);
+        let (port, received) = MockUpstream::new(root)
+            .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .serve().await;
+        let server = Arc::new(test_server(ServerConfig {
+            // every cached answer is within the threshold of its 300s ttl
+            cache_policy: CachePolicy { prefetch_threshold: 400, ..CachePolicy::default() },
+            ..resolver_config(root, port)
+        }).await);
+        let query = DnsPacket::query(90, "www.example.com", QueryType::A);
+        server.build_response(query.clone()).await;
+        let response = server.build_response(query.clone()).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        // the hit is answered from the cache, the refresh waits for the server to run
+        server.build_response(query).await;
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+        for _ in 0..50 {
+            if received.lock().unwrap().len() > 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        // both hits queued the same name, which is refreshed once
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(received.lock().unwrap().len(), 2);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_endpoint_dumps_the_cache() {
+        let server = Arc::new(test_server(ServerConfig {
+            debug_http_addr: Some("127.0.0.1:0".to_string()),
+            ..test_config()
+        }).await);
+        assert!(test_server(test_config()).await.local_debug_addr().is_none());
+        server.cache().insert(vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))]);
+        let debug_addr = server.local_debug_addr().unwrap().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        let get = |path: &'static str| async move {
+            let mut stream = tokio::net::TcpStream::connect(debug_addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/cache").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(body, server.cache().entries_json());
+        assert!(body.contains("www.example.com"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_upstream_query_ids_are_unpredictable() {
+        let root = Ipv4Addr::new(127, 0, 90, 1);
+        let names: Vec<String> = (0..8).map(|i| format!("q{}.example.com", i)).collect();
+        let upstream = names.iter().fold(MockUpstream::new(root), |upstream, name| {
+            upstream.answer(name, vec![a_record(name, Ipv4Addr::new(10, 0, 0, 1))])
+        });
+        let (port, received) = upstream.serve().await;
+        let server = mock_resolver(root, port).await;
+        for name in &names {
+            let (buf, amt) = DnsPacket::query(1, name, QueryType::A).to_buf().unwrap();
+            server.lookup_v4(&root, &buf[..amt]).await.unwrap();
+        }
+
+        let ids: Vec<u16> = received.lock().unwrap().iter().map(|query| query.header.id).collect();
+        assert_eq!(ids.len(), names.len());
+        // neither the client's id nor a counter
+        assert!(ids.iter().any(|id| *id != 1));
+        assert!(ids.windows(2).any(|pair| pair[1] != pair[0].wrapping_add(1)));
+    }
+
+    #[test]
+    fn test_dig_options_from_args() {
+        let args = ["@127.0.0.1:2053", "WWW.Example.com.", "aaaa", "+tcp"].map(String::from);
+        let options = DigOptions::from_args(args).unwrap();
+        assert_eq!(options, DigOptions {
+            server: "127.0.0.1:2053".parse().unwrap(),
+            name: "www.example.com".to_string(),
+            query_type: QueryType::AAAA,
+            tcp: true,
+        });
+
+        let options = DigOptions::from_args(["@::1", "example.com"].map(String::from)).unwrap();
+        assert_eq!(options.server, "[::1]:53".parse().unwrap());
+        assert_eq!(options.query_type, QueryType::A);
+        assert!(!options.tcp);
+
+        assert!(DigOptions::from_args(Vec::new()).is_err());
+        assert!(DigOptions::from_args(["example.com", "BOGUS"].map(String::from)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dig_queries_mock_server_over_udp_and_tcp() {
+        let server_ip = Ipv4Addr::new(127, 0, 37, 1);
+        let (port, mut sockets) = bind_mock_servers(&[server_ip]).await;
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        serve_mock(sockets.remove(0), vec![answer.clone()]);
+
+        let server = SocketAddr::new(IpAddr::V4(server_ip), port);
+        let tcp_listener = tokio::net::TcpListener::bind(server).await.unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp_listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0u8; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            let query = DnsPacket::from_buf(&buf).unwrap();
+            answer.header.id = query.header.id;
+            let (out, out_len) = answer.to_buf().unwrap();
+            stream.write_all(&(out_len as u16).to_be_bytes()).await.unwrap();
+            stream.write_all(&out[..out_len]).await.unwrap();
+        });
+
+        for tcp in [false, true] {
+            let options = DigOptions { server, name: "www.example.com".to_string(), query_type: QueryType::A, tcp };
+            let response = dig(&options).await.unwrap();
+            assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+            let printed = response.to_string();
+            assert!(printed.contains("status: NOERROR"));
+            assert!(printed.contains(";www.example.com.\t\tIN\tA"));
+            assert!(printed.contains("www.example.com.\t300\tIN\tA\t10.0.0.1"));
+        }
+    }
+
+    #[test]
+    fn test_negative_ttl_from_soa() {
+        let mut packet = DnsPacket::new(Header::new(7, true, true, true, ResponseCode::NXDOMAIN));
+        packet.add_question(Question { name: "missing.example.com".to_string(), query_type: QueryType::A, class: CLASS_IN });
+        assert_eq!(packet.negative_ttl(), None);
+
+        packet.add_authority(Answer {
+            name: "example.com".to_string(),
+            query_type: QueryType::SOA,
+            class: CLASS_IN,
+            ttl: 900,
+            len: 0,
+            record: Record::SOA {
+                mname: "ns1.example.com".to_string(),
+                rname: "hostmaster.example.com".to_string(),
+                serial: 2023040101,
+                refresh: 7200,
+                retry: 3600,
+                expire: 1209600,
+                minimum: 300,
+            },
+        });
+        let (buf, amt) = packet.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.authorities[0].record, packet.authorities[0].record);
+        assert_eq!(parsed.negative_ttl(), Some(300));
+
+        packet.authorities[0].ttl = 60;
+        assert_eq!(packet.negative_ttl(), Some(60));
+    }
+
+    #[test]
+    fn test_suffix_matching_respects_label_boundaries() {
+        let mut packet = mock_response("notexample.com", QueryType::A);
+        packet.add_authority(ns_record("com", "a.gtld-servers.net"));
+        packet.add_authority(ns_record("example.com", "ns1.example.com"));
+        packet.add_additional(a_record("Example.COM", Ipv4Addr::new(10, 0, 0, 1)));
+
+        let zones: Vec<&str> = packet.get_unresolved_ns("notexample.com")
+            .map(|(_, zone)| zone)
+            .collect();
+        assert_eq!(zones, vec!["com"]);
+        let matching: Vec<&str> = packet.get_all_answers("notexample.com")
+            .map(|answer| &answer.name[..])
+            .collect();
+        assert_eq!(matching, vec!["com"]);
+        assert_eq!(packet.get_all_answers("www.example.com").count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_authoritative_only_refuses_without_recursing() {
+        let root = Ipv4Addr::new(127, 0, 42, 1);
+        let (port, received) = MockUpstream::new(root)
+            .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .serve().await;
+
+        let server = test_server(ServerConfig {
+            authoritative_only: true,
+            ..resolver_config(root, port)
+        }).await;
+        server.cache().insert(vec![a_record("local.example.com", Ipv4Addr::new(10, 0, 0, 2))]);
+
+        let response = server.build_response(DnsPacket::query(5, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+        assert!(!response.header.get_recursion_available());
+        assert!(response.answers.is_empty());
+
+        let response = server.build_response(DnsPacket::query(6, "local.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(!response.header.get_recursion_available());
+        assert_eq!(response.answers.len(), 1);
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cached_cut_without_addresses_resolves_its_nameservers() {
+        let root = Ipv4Addr::new(127, 0, 87, 1);
+        let example_org_ns = Ipv4Addr::new(127, 0, 87, 2);
+        let (port, received) = MockUpstream::new(root)
+            .answer("ns.example.net", vec![a_record("ns.example.net", example_org_ns)])
+            .serve_all(vec![
+                MockUpstream::new(example_org_ns)
+                    .answer("www.example.org", vec![a_record("www.example.org", Ipv4Addr::new(10, 0, 0, 1))]),
+            ]).await;
+        let server = mock_resolver(root, port).await;
+        server.cache().insert_all(&mock_referral("mail.example.org", "example.org", &[("ns.example.net", None)]), "");
+
+        let (buf, amt) = DnsPacket::query(13, "www.example.org", QueryType::A).to_buf().unwrap();
+        let packet = server.iterative_cache_resolve("www.example.org", &buf[..amt]).await.unwrap();
+
+        assert_eq!(packet.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        // iteration starts at the cut, the root is only asked for the nameserver
+        let root_names: Vec<String> = received[0].lock().unwrap().iter()
+            .map(|query| query.questions[0].name.clone())
+            .collect();
+        assert_eq!(root_names, vec!["ns.example.net"]);
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_resolution_reuses_cached_delegation() {
+        let root = Ipv4Addr::new(127, 0, 44, 1);
+        let example_com_ns = Ipv4Addr::new(127, 0, 44, 2);
+        let example_org_ns = Ipv4Addr::new(127, 0, 44, 3);
+        let (port, mut sockets) = bind_mock_servers(&[root, example_com_ns, example_org_ns]).await;
+        let root_received = serve_mock(sockets.remove(0), vec![
+            mock_referral("www.example.org", "example.org", &[("ns2.example.com", None)]),
+        ]);
+        let mut ns_answer = mock_response("ns2.example.com", QueryType::A);
+        ns_answer.add_answer(a_record("ns2.example.com", example_org_ns));
+        let com_received = serve_mock(sockets.remove(0), vec![ns_answer]);
+        let mut answer = mock_response("www.example.org", QueryType::A);
+        answer.add_answer(a_record("www.example.org", Ipv4Addr::new(10, 0, 0, 1)));
+        serve_mock(sockets.remove(0), vec![answer]);
+
+        let server = mock_resolver(root, port).await;
+        // the example.com cut is known from an earlier referral
+        server.cache().insert_all(&mock_referral("www.example.com", "example.com", &[("ns1.example.com", Some(example_com_ns))]), "");
+        let (buf, amt) = DnsPacket::query(9, "www.example.org", QueryType::A).to_buf().unwrap();
+        let packet = server.iterative_cache_resolve("www.example.org", &buf[..amt]).await.unwrap();
+
+        assert_eq!(packet.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        let root_names: Vec<String> = root_received.lock().unwrap().iter()
+            .map(|query| query.questions[0].name.clone())
+            .collect();
+        assert_eq!(root_names, vec!["www.example.org"]);
+        assert_eq!(com_received.lock().unwrap()[0].questions[0].name, "ns2.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_listed_twice_is_resolved_once() {
+        let root = Ipv4Addr::new(127, 0, 59, 1);
+        let silent_ns = Ipv4Addr::new(127, 0, 59, 2);
+        let (port, mut sockets) = bind_mock_servers(&[root, silent_ns]).await;
+        let mut ns_answer = mock_response("ns.shared.net", QueryType::A);
+        ns_answer.add_answer(a_record("ns.shared.net", silent_ns));
+        let root_received = serve_mock(sockets.remove(0), vec![
+            mock_referral("www.example.com", "example.com", &[("ns.shared.net", None), ("ns.shared.net", None)]),
+            ns_answer,
+        ]);
+        serve_mock(sockets.remove(0), vec![]);
+
+        let server = mock_resolver(root, port).await;
+        let (buf, amt) = DnsPacket::query(12, "www.example.com", QueryType::A).to_buf().unwrap();
+        assert!(server.iterative_cache_resolve("www.example.com", &buf[..amt]).await.is_err());
+
+        let ns_queries = root_received.lock().unwrap().iter()
+            .filter(|query| query.questions[0].name == "ns.shared.net")
+            .count();
+        assert_eq!(ns_queries, 1);
+    }
+
+    #[tokio::test]
+    async fn test_unglued_self_referral_terminates() {
+        let root = Ipv4Addr::new(127, 0, 44, 11);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        serve_mock(sockets.remove(0), vec![
+            mock_referral("www.loop.test", "loop.test", &[("ns.loop.test", None)]),
+            mock_referral("ns.loop.test", "loop.test", &[("ns.loop.test", None)]),
+        ]);
+
+        let server = mock_resolver(root, port).await;
+        let (buf, amt) = DnsPacket::query(10, "www.loop.test", QueryType::A).to_buf().unwrap();
+        let resolved = tokio::time::timeout(Duration::from_secs(5),
+            server.iterative_cache_resolve("www.loop.test", &buf[..amt])).await;
+        assert!(resolved.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_opcode_round_trips_and_is_not_implemented() {
+        let mut query = DnsPacket::query(11, "www.example.com", QueryType::A);
+        query.header.set_op_code(OperationCode::from(15));
+        let (buf, amt) = query.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.header.get_op_code(), OperationCode::Unknown(15));
+        assert_eq!(parsed.header.get_op_code().to_u8(), 15);
+
+        let server = test_server(test_config()).await;
+        let response = server.build_response(parsed).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOTIMP);
+        assert_eq!(response.header.get_op_code(), OperationCode::Unknown(15));
+        assert_eq!(response.header.id, 11);
+    }
+
+    #[tokio::test]
+    async fn test_question_less_query_gets_formerr() {
+        let server = Arc::new(test_server(test_config()).await);
+        let listen_addr = server.local_addrs().unwrap()[0];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = DnsPacket::new(Header::new(12, true, false, false, ResponseCode::NOERROR));
+        assert_eq!(query.question(), None);
+        let (buf, amt) = query.to_buf().unwrap();
+        let mut in_buf = [0u8; 512];
+        for _ in 0..2 {
+            client.send_to(&buf[..amt], listen_addr).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+                .await.unwrap().unwrap();
+            let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+            assert_eq!(response.header.id, 12);
+            assert_eq!(response.header.get_response_code(), ResponseCode::FORMERR);
+        }
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_repeated_responses_to_one_subnet_are_slipped() {
+        let server = Arc::new(test_server(ServerConfig {
+            version_bind: Some("untitled 0.1".to_string()),
+            response_rate_limit: RrlPolicy { responses_per_second: 2, slip: 1, ..Default::default() },
+            ..test_config()
+        }).await);
+        let listen_addr = server.local_addrs().unwrap()[0];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = DnsPacket::query(48, "version.bind", QueryType::TXT);
+        query.questions[0].class = CLASS_CH;
+        let (buf, amt) = query.to_buf().unwrap();
+        let mut in_buf = [0u8; 512];
+        let mut responses = Vec::new();
+        for _ in 0..4 {
+            client.send_to(&buf[..amt], listen_addr).await.unwrap();
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+                .await.unwrap().unwrap();
+            responses.push(DnsPacket::from_buf(&in_buf[..len]).unwrap());
+        }
+        for response in &responses[..2] {
+            assert!(!response.header.get_truncated_message());
+            assert_eq!(response.answers.len(), 1);
+        }
+        for response in &responses[2..] {
+            assert!(response.header.get_truncated_message());
+            assert!(response.answers.is_empty());
+            assert_eq!(response.questions, query.questions);
+        }
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lookup_retries_after_lost_datagram() {
+        let root = Ipv4Addr::new(127, 0, 48, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let socket = sockets.remove(0);
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        let (served, serving) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            // lose the first query
+            let mut buf = [0u8; 512];
+            socket.recv_from(&mut buf).await.unwrap();
+            let _ = served.send(serve_mock(socket, vec![answer]));
+        });
+
+        let server = mock_resolver(root, port).await;
+        let (buf, amt) = DnsPacket::query(13, "www.example.com", QueryType::A).to_buf().unwrap();
+        let packet = server.lookup_v4(&root, &buf[..amt]).await.unwrap();
+
+        assert_eq!(packet.header.id, 13);
+        assert_eq!(packet.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert_eq!(serving.await.unwrap().lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_gives_up_after_retries() {
+        let silent = Ipv4Addr::new(127, 0, 48, 11);
+        let (port, received) = MockUpstream::new(silent).serve().await;
+
+        let server = test_server(ServerConfig {
+            upstream_port: port,
+            lookup_timeout: Duration::from_millis(50),
+            lookup_retries: 2,
+            lookup_backoff: Duration::from_millis(10),
+            ..test_config()
+        }).await;
+        let (buf, amt) = DnsPacket::query(14, "www.example.com", QueryType::A).to_buf().unwrap();
+        let started = std::time::Instant::now();
+        let res = server.lookup_v4(&silent, &buf[..amt]).await;
+
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_millis(250));
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_client_subnet_round_trips_and_is_forwarded() {
+        let subnet = ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 77)), 24).unwrap();
+        assert_eq!(subnet, ClientSubnet { family: 1, source_prefix_len: 24, scope_prefix_len: 0, address: vec![192, 0, 2] });
+        assert_eq!(subnet.to_option().data, vec![0, 1, 24, 0, 192, 0, 2]);
+        assert_eq!(ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 77)), 20).unwrap().address, vec![192, 0, 0]);
+
+        let mut query = DnsPacket::query(15, "www.example.com", QueryType::A);
+        query.set_edns(1232, false);
+        query.set_client_subnet(&subnet);
+        let (buf, amt) = query.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.client_subnet(), Some(subnet.clone()));
+
+        let forwarder = Ipv4Addr::new(127, 0, 50, 1);
+        let (port, mut sockets) = bind_mock_servers(&[forwarder]).await;
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        answer.set_edns(1232, false);
+        answer.set_client_subnet(&ClientSubnet { scope_prefix_len: 16, ..subnet.clone() });
+        let received = serve_mock(sockets.remove(0), vec![answer]);
+
+        let server = test_server(ServerConfig {
+            upstream_port: port,
+            lookup_timeout: Duration::from_millis(100),
+            forwarders: vec![IpAddr::V4(forwarder)],
+            ..test_config()
+        }).await;
+        let response = server.build_response(parsed).await;
+
+        assert_eq!(received.lock().unwrap()[0].client_subnet(), Some(subnet.clone()));
+        assert_eq!(response.client_subnet(), Some(ClientSubnet { scope_prefix_len: 16, ..subnet }));
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolution_starts_at_cached_delegation() {
+        let root = Ipv4Addr::new(127, 0, 51, 1);
+        let zone_ns = Ipv4Addr::new(127, 0, 51, 2);
+        let (port, mut sockets) = bind_mock_servers(&[root, zone_ns]).await;
+        let root_received = serve_mock(sockets.remove(0), vec![
+            mock_referral("a.b.example.com", "b.example.com", &[("ns.b.example.com", Some(zone_ns))]),
+        ]);
+        let answers = ["a.b.example.com", "c.b.example.com"].iter().map(|name| {
+            let mut answer = mock_response(name, QueryType::A);
+            answer.add_answer(a_record(name, Ipv4Addr::new(10, 0, 0, 1)));
+            answer
+        }).collect();
+        serve_mock(sockets.remove(0), answers);
+
+        let server = mock_resolver(root, port).await;
+        for name in ["a.b.example.com", "c.b.example.com"] {
+            let (buf, amt) = DnsPacket::query(16, name, QueryType::A).to_buf().unwrap();
+            let packet = server.iterative_cache_resolve(name, &buf[..amt]).await.unwrap();
+            assert_eq!(packet.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        }
+
+        assert_eq!(root_received.lock().unwrap().len(), 1);
+        let delegation = server.cache().closest_delegation("c.b.example.com").unwrap();
+        assert_eq!(delegation.zone, "b.example.com");
+        assert_eq!(delegation.name_servers, vec!["ns.b.example.com"]);
+        assert_eq!(delegation.addresses, vec![IpAddr::V4(zone_ns)]);
+        let sibling_cut = server.cache().zone_cuts().deepest_cut("d.b.example.com").unwrap();
+        assert_eq!(sibling_cut, delegation);
+    }
+
+    #[tokio::test]
+    async fn test_cname_only_answer_is_followed() {
+        let root = Ipv4Addr::new(127, 0, 53, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let cname = Answer {
+            name: "www.example.com".to_string(),
+            query_type: QueryType::CNAME,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::CNAME("web.example.net".to_string()),
+        };
+        let mut alias = mock_response("www.example.com", QueryType::A);
+        alias.add_answer(cname.clone());
+        let mut target = mock_response("web.example.net", QueryType::A);
+        target.add_answer(a_record("web.example.net", Ipv4Addr::new(10, 0, 0, 1)));
+        serve_mock(sockets.remove(0), vec![alias.clone(), target]);
+
+        assert_eq!(alias.unresolved_cname(), Some("web.example.net"));
+        let server = mock_resolver(root, port).await;
+        let (buf, amt) = DnsPacket::query(17, "www.example.com", QueryType::A).to_buf().unwrap();
+        let packet = server.iterative_cache_resolve("www.example.com", &buf[..amt]).await.unwrap();
+
+        let records: Vec<&Record> = packet.answers.iter().map(|answer| &answer.record).collect();
+        assert_eq!(records, vec![&cname.record, &Record::A(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert_eq!(packet.header.answer_count, 2);
+        assert_eq!(packet.unresolved_cname(), None);
+    }
+
+    #[test]
+    fn test_partial_write_reports_records_that_fit() {
+        let mut packet = mock_response("www.example.com", QueryType::A);
+        for i in 0..4 {
+            packet.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, i)));
+        }
+        // header 12, question 21, each answer 16 bytes with its name compressed
+        let mut buf = [0u8; 12 + 21 + 2 * 16 + 10];
+        let (written, pos) = {
+            let mut builder = BufferBuilder::new(&mut buf);
+            let written = packet.write_to_buf_partial(&mut builder).unwrap();
+            (written, builder.get_pos())
+        };
+        assert_eq!(written, 2);
+        assert_eq!(pos, 12 + 21 + 2 * 16);
+
+        let mut buf = [0u8; 20];
+        let mut builder = BufferBuilder::new(&mut buf);
+        assert_eq!(packet.write_to_buf_partial(&mut builder).unwrap_err().kind(), ErrorKind::WriteZero);
+
+        let mut buf = [0u8; 512];
+        let mut builder = BufferBuilder::new(&mut buf);
+        assert_eq!(packet.write_to_buf_partial(&mut builder).unwrap(), 4);
+    }
+
+    /// Query log writer that keeps everything written in memory.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_log_writes_json_lines() {
+        let output = SharedBuffer::default();
+        let query_log = Arc::new(QueryLog::new(output.clone()));
+        let server = test_server(ServerConfig {
+            query_log: Some(Arc::clone(&query_log)),
+            ..test_config()
+        }).await;
+        server.cache().insert(vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))]);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        server.resolve_request(0, client_addr, DnsPacket::query(18, "www.example.com", QueryType::A)).await;
+        query_log.flush();
+
+        let written = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let entry = QueryLogEntry::from_json(lines[0]).unwrap();
+        assert_eq!(entry.client, client_addr.ip());
+        assert_eq!(entry.qname, "www.example.com");
+        assert_eq!(entry.qtype, "A");
+        assert_eq!(entry.rcode, "NOERROR");
+        assert_eq!(entry.answers, 1);
+        assert!(entry.cache_hit);
+        assert!(entry.latency_ms >= 0.0);
+        assert_eq!(QueryLogEntry::from_json(&entry.to_json()).unwrap().to_json(), entry.to_json());
+
+        let escaped = QueryLogEntry { qname: "we\"ird\\name\u{1}".to_string(), ..entry };
+        assert_eq!(QueryLogEntry::from_json(&escaped.to_json()).unwrap().qname, escaped.qname);
+    }
+
+    #[tokio::test]
+    async fn test_trace_lines_of_one_request_share_its_id() {
+        let root = Ipv4Addr::new(127, 0, 81, 1);
+        let example = Ipv4Addr::new(127, 0, 81, 2);
+        // the nameserver of example.com also sends a record it has no say over
+        let mut two = mock_response("two.example.com", QueryType::A);
+        two.add_answer(a_record("two.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        two.add_additional(a_record("www.example.org", Ipv4Addr::new(10, 0, 0, 66)));
+        let (port, _) = MockUpstream::new(root)
+            .answer("one.example.com", vec![a_record("one.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .delegate("two.example.com", "example.com", &[("ns1.example.com", Some(example))])
+            .serve_all(vec![MockUpstream::new(example).respond(two)])
+            .await;
+        let trace_output = SharedBuffer::default();
+        let query_output = SharedBuffer::default();
+        let trace_log = Arc::new(QueryLog::new(trace_output.clone()));
+        let query_log = Arc::new(QueryLog::new(query_output.clone()));
+        let server = test_server(ServerConfig {
+            trace_log: Some(Arc::clone(&trace_log)),
+            query_log: Some(Arc::clone(&query_log)),
+            ..resolver_config(root, port)
+        }).await;
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        tokio::join!(
+            server.resolve_request(0, client_addr, DnsPacket::query(52, "one.example.com", QueryType::A)),
+            server.resolve_request(0, client_addr, DnsPacket::query(53, "two.example.com", QueryType::A)),
+        );
+        trace_log.flush();
+        query_log.flush();
+
+        let written = String::from_utf8(trace_output.0.lock().unwrap().clone()).unwrap();
+        let mut lines_by_id: HashMap<u64, Vec<&str>> = HashMap::new();
+        for line in written.lines() {
+            let (id, message) = line.strip_prefix('[').and_then(|line| line.split_once("] ")).unwrap();
+            lines_by_id.entry(id.parse().unwrap()).or_default().push(message);
+        }
+        assert_eq!(lines_by_id.len(), 2);
+        let written = String::from_utf8(query_output.0.lock().unwrap().clone()).unwrap();
+        for line in written.lines() {
+            let entry = QueryLogEntry::from_json(line).unwrap();
+            let lines = &lines_by_id[&entry.request_id];
+            assert!(lines.first().unwrap().starts_with(&format!("received query {} for {}",
+                if entry.qname == "one.example.com" { 52 } else { 53 }, entry.qname)), "{:?}", lines);
+            assert!(lines.iter().any(|line| line.starts_with("sending lookup")), "{:?}", lines);
+            assert!(lines.last().unwrap().starts_with("answered"), "{:?}", lines);
+            // what the cache logs while resolving belongs to the request too
+            let discarded = lines.iter().any(|line| line.starts_with("not caching 1 records outside of"));
+            assert_eq!(discarded, entry.qname == "two.example.com", "{:?}", lines);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns64_synthesizes_aaaa_from_a_records() {
+        let root = Ipv4Addr::new(127, 0, 82, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut v4_only_a = mock_response("v4only.example.com", QueryType::A);
+        v4_only_a.add_answer(a_record("v4only.example.com", Ipv4Addr::new(192, 0, 2, 33)));
+        let mut v4_only_aaaa = mock_response("v4only.example.com", QueryType::AAAA);
+        v4_only_aaaa.add_authority(soa_record("example.com", 1));
+        let mut dual_a = mock_response("dual.example.com", QueryType::A);
+        dual_a.add_answer(a_record("dual.example.com", Ipv4Addr::new(192, 0, 2, 34)));
+        let mut dual_aaaa = mock_response("dual.example.com", QueryType::AAAA);
+        dual_aaaa.add_answer(Answer {
+            name: "dual.example.com".to_string(),
+            query_type: QueryType::AAAA,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 16,
+            record: Record::AAAA(Ipv6Addr::from_str("2001:db8::34").unwrap()),
+        });
+        let mut mapped_a = mock_response("mapped.example.com", QueryType::A);
+        mapped_a.add_answer(a_record("mapped.example.com", Ipv4Addr::new(192, 0, 2, 35)));
+        let mut mapped_aaaa = mock_response("mapped.example.com", QueryType::AAAA);
+        mapped_aaaa.add_answer(Answer {
+            name: "mapped.example.com".to_string(),
+            query_type: QueryType::AAAA,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 16,
+            record: Record::AAAA(Ipv4Addr::new(192, 0, 2, 35).to_ipv6_mapped()),
+        });
+        serve_mock(sockets.remove(0), vec![v4_only_a, v4_only_aaaa, dual_a, dual_aaaa, mapped_a, mapped_aaaa]);
+        let prefix = Ipv6Addr::from_str("2001:db8:64::").unwrap();
+        let server = test_server(ServerConfig {
+            dns64_prefix: Some(prefix),
+            ..resolver_config(root, port)
+        }).await;
+
+        let response = server.build_response(DnsPacket::query(54, "v4only.example.com", QueryType::AAAA)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(response.questions[0].query_type, QueryType::AAAA);
+        assert_eq!(response.all_addresses(), vec![IpAddr::V6(Ipv6Addr::from_str("2001:db8:64::c000:221").unwrap())]);
+        assert_eq!(response.answers[0].query_type, QueryType::AAAA);
+        // capped by the SOA minimum of the NODATA answer instead of the A record's 300s
+        assert_eq!(response.answers[0].ttl, 60);
+        assert!(response.authorities.is_empty());
+
+        // real AAAA records are never replaced
+        let response = server.build_response(DnsPacket::query(55, "dual.example.com", QueryType::AAAA)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V6(Ipv6Addr::from_str("2001:db8::34").unwrap())]);
+
+        // IPv4-mapped ones are, and without a SOA the ttl is capped at 600s
+        let response = server.build_response(DnsPacket::query(56, "mapped.example.com", QueryType::AAAA)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V6(Ipv6Addr::from_str("2001:db8:64::c000:223").unwrap())]);
+        assert_eq!(response.answers[0].ttl, 300);
+
+        // validating resolvers downstream get the real answer
+        let mut query = DnsPacket::query(57, "v4only.example.com", QueryType::AAAA);
+        query.set_edns(1232, true);
+        query.header.set_checking_disabled(true);
+        let response = server.build_response(query.clone()).await;
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        // CD alone is not enough to opt out
+        query.set_edns(1232, false);
+        let response = server.build_response(query).await;
+        assert_eq!(response.answers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dns64_lookup_shares_the_query_deadline() {
+        let root = Ipv4Addr::new(127, 0, 92, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut slow_a = mock_response("slow.example.com", QueryType::A);
+        slow_a.add_answer(a_record("slow.example.com", Ipv4Addr::new(192, 0, 2, 36)));
+        let mut slow_aaaa = mock_response("slow.example.com", QueryType::AAAA);
+        slow_aaaa.add_authority(soa_record("example.com", 1));
+        let received = serve_mock_with_delay(sockets.remove(0), vec![slow_a, slow_aaaa], Duration::from_millis(200));
+        let server = test_server(ServerConfig {
+            dns64_prefix: Some(Ipv6Addr::from_str("2001:db8:64::").unwrap()),
+            lookup_timeout: Duration::from_secs(1),
+            resolve_timeout: Duration::from_millis(300),
+            ..resolver_config(root, port)
+        }).await;
+
+        // the AAAA lookup uses up most of the deadline, the A lookup gets only what is left
+        let started = std::time::Instant::now();
+        let response = server.build_response(DnsPacket::query(58, "slow.example.com", QueryType::AAAA)).await;
+        assert!(started.elapsed() < Duration::from_millis(390), "{:?}", started.elapsed());
+        assert!(response.answers.is_empty());
+        assert!(received.lock().unwrap().iter()
+            .any(|query| query.questions[0].query_type == QueryType::A));
+    }
+
+    #[tokio::test]
+    async fn test_error_responses_echo_the_question() {
+        let root = Ipv4Addr::new(127, 0, 56, 1);
+        let (port, _) = MockUpstream::new(root).serve().await;
+        let server = mock_resolver(root, port).await;
+
+        let response = server.build_response(DnsPacket::query(19, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::SERVFAIL);
+        assert_eq!(response.header.id, 19);
+        assert_eq!(response.header.question_count, 1);
+        assert_eq!(response.questions, DnsPacket::query(19, "www.example.com", QueryType::A).questions);
+
+        let response = server.build_response(DnsPacket::query(20, "www.example.com", QueryType::UNKOWN(999))).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOTIMP);
+        assert_eq!(response.header.question_count, 1);
+        assert_eq!(response.questions[0].query_type, QueryType::UNKOWN(999));
+    }
+
+    #[tokio::test]
+    async fn test_udp_zone_transfer_is_refused() {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let server = test_server(ServerConfig {
+            transfer_allowlist: vec![client_addr.ip()],
+            ..test_config()
+        }).await;
+
+        for query_type in [QueryType::AXFR, QueryType::IXFR] {
+            let query = DnsPacket::query(21, "example.com", query_type.clone());
+            let (buf, amt) = query.to_buf().unwrap();
+            assert_eq!(DnsPacket::from_buf(&buf[..amt]).unwrap().questions[0].query_type, query_type);
+
+            server.resolve_request(0, client_addr, query).await;
+            let mut in_buf = [0u8; 512];
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+                .await.unwrap().unwrap();
+            let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+            assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+            assert_eq!(response.questions[0].query_type, query_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rebinding_protection_drops_private_answers() {
+        let root = Ipv4Addr::new(127, 0, 59, 11);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let public = Ipv4Addr::new(93, 184, 216, 34);
+        let mut mixed = mock_response("www.example.com", QueryType::A);
+        mixed.add_answer(a_record("www.example.com", Ipv4Addr::LOCALHOST));
+        mixed.add_answer(a_record("www.example.com", public));
+        let mut rebind = mock_response("rebind.example.com", QueryType::A);
+        rebind.add_answer(a_record("rebind.example.com", Ipv4Addr::LOCALHOST));
+        serve_mock(sockets.remove(0), vec![mixed, rebind]);
+
+        let server = test_server(ServerConfig {
+            rebinding_protection: true,
+            ..resolver_config(root, port)
+        }).await;
+
+        let response = server.build_response(DnsPacket::query(22, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(public)]);
+        assert_eq!(response.header.answer_count, 1);
+
+        let response = server.build_response(DnsPacket::query(23, "rebind.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(response.answers.is_empty());
+        // the cached copy is filtered as well
+        let response = server.build_response(DnsPacket::query(24, "rebind.example.com", QueryType::A)).await;
+        assert!(response.answers.is_empty());
+
+        // an ipv4-mapped loopback address is just as private
+        server.cache().insert(vec![Answer {
+            name: "mapped.example.com".to_string(),
+            query_type: QueryType::AAAA,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 16,
+            record: Record::AAAA(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+        }]);
+        let response = server.build_response(DnsPacket::query(25, "mapped.example.com", QueryType::AAAA)).await;
+        assert!(response.answers.is_empty());
+
+        // local answers are not filtered
+        let response = server.build_response(DnsPacket::query(26, "localhost", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    }
+
+    #[test]
+    fn test_address_range_contains() {
+        let range = AddressRange::from_str("172.16.0.0/12").unwrap();
+        assert!(range.contains(&IpAddr::V4(Ipv4Addr::new(172, 31, 255, 1))));
+        assert!(!range.contains(&IpAddr::V4(Ipv4Addr::new(172, 32, 0, 1))));
+        assert!(!range.contains(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        let link_local = AddressRange::from_str("fe80::/10").unwrap();
+        assert!(link_local.contains(&IpAddr::V6(Ipv6Addr::new(0xfebf, 0, 0, 0, 0, 0, 0, 1))));
+        assert!(AddressRange::from_str("10.0.0.0/33").is_err());
+        assert!(AddressRange::from_str("10.0.0.0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_version_bind_is_answered_only_when_configured() {
+        let mut query = DnsPacket::query(25, "version.bind", QueryType::TXT);
+        query.questions[0].class = CLASS_CH;
+
+        let server = test_server(test_config()).await;
+        let response = server.build_response(query.clone()).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+        assert!(response.answers.is_empty());
+
+        let server = test_server(ServerConfig {
+            version_bind: Some("untitled 0.1".to_string()),
+            ..test_config()
+        }).await;
+        let response = server.build_response(query).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        let (buf, amt) = response.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.answers[0].class, CLASS_CH);
+        assert_eq!(parsed.answers[0].record, Record::TXT(vec!["untitled 0.1".to_string()]));
+
+        let mut hostname = DnsPacket::query(26, "hostname.bind", QueryType::TXT);
+        hostname.questions[0].class = CLASS_CH;
+        let response = server.build_response(hostname).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+    }
+
+    #[test]
+    fn test_borrowed_parse_agrees_with_owned_parse() {
+        let mut buf = vec![
+            0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00,
+            3, b'w', b'w', b'w', 7, b'E', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            0x00, 0x01, 0x00, 0x01,
+            // www.example.com CNAME cdn.example.com, both compressed
+            0xc0, 0x0c, 0x00, 0x05, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x06,
+            3, b'c', b'd', b'n', 0xc0, 0x10,
+            // cdn.example.com A 1.2.3.4, pointing into the CNAME rdata
+            0xc0, 0x2d, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x2c, 0x00, 0x04,
+            1, 2, 3, 4,
+        ];
+        let owned = DnsPacket::from_buf(&buf).unwrap();
+        let borrowed = DnsPacket::parse_borrowed(&buf).unwrap();
+        assert_eq!(borrowed.to_owned().unwrap(), owned);
+        assert!(borrowed.questions[0].name.matches("www.example.com."));
+        assert!(!borrowed.questions[0].name.matches("example.com"));
+        assert_eq!(borrowed.answers[1].name.to_string(), "cdn.example.com");
+        assert_eq!(borrowed.answers[1].rdata(), &[1, 2, 3, 4]);
+        assert_eq!(owned.answers[0].record, Record::CNAME("cdn.example.com".to_string()));
+
+        let mut built = mock_response("www.example.com", QueryType::A);
+        for i in 0..10 {
+            built.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, i)));
+        }
+        built.add_authority(ns_record("example.com", "ns1.example.com"));
+        let (built_buf, amt) = built.to_buf().unwrap();
+        let borrowed = DnsPacket::parse_borrowed(&built_buf[..amt]).unwrap();
+        assert_eq!(borrowed.to_owned().unwrap(), DnsPacket::from_buf(&built_buf[..amt]).unwrap());
+
+        // a pointer to itself is rejected by both parsers
+        buf[33] = 0xc0;
+        buf[34] = 33;
+        assert!(DnsPacket::from_buf(&buf).is_err());
+        assert!(DnsPacket::parse_borrowed(&buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_synthesized_referral_uses_configured_ttl() {
+        let server = test_server(ServerConfig {
+            authoritative_only: true,
+            synthesized_ttls: SynthesizedTtls { referral: 120, ..Default::default() },
+            ..test_config()
+        }).await;
+        server.cache().insert_all(&mock_referral("sub.example.com", "sub.example.com",
+                                                 &[("ns1.sub.example.com", Some(Ipv4Addr::new(10, 0, 0, 53)))]), "");
+
+        let response = server.build_response(DnsPacket::query(27, "www.sub.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].record, Record::NS("ns1.sub.example.com".to_string()));
+        assert_eq!(response.authorities[0].ttl, 120);
+        assert_eq!(response.additional[0].record, Record::A(Ipv4Addr::new(10, 0, 0, 53)));
+        assert_eq!(response.additional[0].ttl, 120);
+
+        let response = server.build_response(DnsPacket::query(28, "www.example.org", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+    }
+
+    #[tokio::test]
+    async fn test_slow_resolution_hits_overall_deadline() {
+        let root = Ipv4Addr::new(127, 0, 61, 1);
+        let ns1 = Ipv4Addr::new(127, 0, 61, 2);
+        let ns2 = Ipv4Addr::new(127, 0, 61, 3);
+        let (port, mut sockets) = bind_mock_servers(&[root, ns1, ns2]).await;
+        let hop_delay = Duration::from_millis(200);
+        serve_mock_with_delay(sockets.remove(0), vec![
+            mock_referral("www.example.com", "com", &[("ns1.com", Some(ns1))]),
+        ], hop_delay);
+        serve_mock_with_delay(sockets.remove(0), vec![
+            mock_referral("www.example.com", "example.com", &[("ns2.example.com", Some(ns2))]),
+        ], hop_delay);
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        serve_mock_with_delay(sockets.remove(0), vec![answer], hop_delay);
+
+        let config = ServerConfig {
+            lookup_timeout: Duration::from_secs(1),
+            resolve_timeout: Duration::from_millis(350),
+            ..resolver_config(root, port)
+        };
+        let server = test_server(config.clone()).await;
+        let started = std::time::Instant::now();
+        let response = server.build_response(DnsPacket::query(29, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::SERVFAIL);
+        assert!(started.elapsed() < Duration::from_millis(550));
+
+        // every single hop is well within lookup_timeout, so the chain resolves given time
+        let patient = test_server(ServerConfig {
+            resolve_timeout: Duration::from_secs(5),
+            ..config
+        }).await;
+        let response = patient.build_response(DnsPacket::query(30, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+    }
+
+    #[tokio::test]
+    async fn test_response_packets_from_clients_are_dropped() {
+        let server = Arc::new(test_server(test_config()).await);
+        let listen_addr = server.local_addrs().unwrap()[0];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut in_buf = [0u8; 512];
+        let mut reflected = DnsPacket::query(31, "version.bind", QueryType::TXT);
+        reflected.questions[0].class = CLASS_CH;
+        reflected.header.set_query_response(true);
+        let (buf, amt) = reflected.to_buf().unwrap();
+        client.send_to(&buf[..amt], listen_addr).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(300), client.recv_from(&mut in_buf)).await.is_err());
+
+        // the same packet as a query is answered
+        reflected.header.set_query_response(false);
+        let (buf, amt) = reflected.to_buf().unwrap();
+        client.send_to(&buf[..amt], listen_addr).await.unwrap();
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+            .await.unwrap().unwrap();
+        let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+        assert_eq!(response.header.id, 31);
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    fn soa_record(zone: &str, serial: u32) -> Answer {
+        Answer {
+            name: zone.to_string(),
+            query_type: QueryType::SOA,
+            class: CLASS_IN,
+            ttl: 3600,
+            len: 0,
+            record: Record::SOA {
+                mname: format!("ns1.{}", zone),
+                rname: format!("hostmaster.{}", zone),
+                serial,
+                refresh: 3600,
+                retry: 600,
+                expire: 86400,
+                minimum: 60,
+            },
+        }
+    }
+
+    /// Accepts one connection and answers its AXFR query with `messages`, each
+    /// holding a slice of the zone's records.
+    async fn serve_mock_primary(messages: Vec<Vec<Answer>>) -> SocketAddr {
+        serve_mock_primary_transfers(vec![messages]).await.0
+    }
+
+    /// Like `serve_mock_primary`, serving one connection per transfer in `transfers`.
+    /// SOA queries in between get the SOA of the next transfer. Returns the types
+    /// of the queries received.
+    async fn serve_mock_primary_transfers(transfers: Vec<Vec<Vec<Answer>>>) -> (SocketAddr, Arc<Mutex<Vec<QueryType>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = Arc::clone(&received);
+        tokio::spawn(async move {
+            let mut transfers = transfers.into_iter().peekable();
+            while let Some(next) = transfers.peek() {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut buf = vec![0u8; len as usize];
+                stream.read_exact(&mut buf).await.unwrap();
+                let query = DnsPacket::from_buf(&buf).unwrap();
+                let query_type = query.questions[0].query_type.clone();
+                received_clone.lock().unwrap().push(query_type.clone());
+                let messages = match query_type {
+                    QueryType::SOA => vec![vec![next[0][0].clone()]],
+                    QueryType::AXFR => transfers.next().unwrap(),
+                    query_type => panic!("unexpected {:?} query", query_type),
+                };
+                for records in messages {
+                    let mut message = DnsPacket::new(Header::new(query.header.id, false, false, true, ResponseCode::NOERROR));
+                    message.set_questions(query.questions.clone());
+                    message.set_answers(records);
+                    let (out, out_len) = message.to_buf().unwrap();
+                    stream.write_all(&(out_len as u16).to_be_bytes()).await.unwrap();
+                    stream.write_all(&out[..out_len]).await.unwrap();
+                }
+            }
+        });
+        (addr, received)
+    }
+
+    #[tokio::test]
+    async fn test_secondary_zone_is_transferred_and_served() {
+        let primary = serve_mock_primary(vec![
+            vec![soa_record("example.com", 2024010101), ns_record("example.com", "ns1.example.com"),
+                 a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))],
+            vec![a_record("ns1.example.com", Ipv4Addr::new(10, 0, 0, 53)),
+                 a_record("host.lab.example.com", Ipv4Addr::new(10, 0, 0, 2)),
+                 ns_record("sub.example.com", "ns1.sub.example.com"),
+                 a_record("ns1.sub.example.com", Ipv4Addr::new(10, 0, 1, 53)),
+                 soa_record("example.com", 2024010101)],
+        ]).await;
+        let secondary = SecondaryZone { origin: "example.com".to_string(), primary };
+        let server = test_server(ServerConfig {
+            authoritative_only: true,
+            secondary_zones: vec![secondary.clone()],
+            synthesized_ttls: SynthesizedTtls { negative: 30, ..Default::default() },
+            ..test_config()
+        }).await;
+
+        let zone = server.transfer_secondary(&secondary).await.unwrap();
+        assert_eq!(zone.serial(), 2024010101);
+        assert_eq!(zone.refresh(), Duration::from_secs(3600));
+
+        let response = server.build_response(DnsPacket::query(32, "WWW.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(response.header.get_authoritative_answer());
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+
+        let response = server.build_response(DnsPacket::query(33, "www.example.com", QueryType::MX)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(response.answers.is_empty());
+        // the zone's negative ttl of 60 is capped by the configured one
+        assert_eq!(response.negative_ttl(), Some(30));
+
+        let response = server.build_response(DnsPacket::query(34, "missing.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NXDOMAIN);
+        assert_eq!(response.authorities[0].query_type, QueryType::SOA);
+
+        // a name with no records but names below it exists
+        let response = server.build_response(DnsPacket::query(36, "lab.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities[0].query_type, QueryType::SOA);
+
+        // names in a delegated child zone are referred to its nameservers
+        let response = server.build_response(DnsPacket::query(37, "www.sub.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(!response.header.get_authoritative_answer());
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].record, Record::NS("ns1.sub.example.com".to_string()));
+        assert_eq!(response.additional.len(), 1);
+        assert_eq!(response.additional[0].record, Record::A(Ipv4Addr::new(10, 0, 1, 53)));
+        // the DS of the child is the parent's to answer
+        let response = server.build_response(DnsPacket::query(38, "sub.example.com", QueryType::DS)).await;
+        assert!(response.header.get_authoritative_answer());
+
+        let response = server.build_response(DnsPacket::query(35, "www.example.org", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+    }
+
+    #[tokio::test]
+    async fn test_notify_from_primary_refreshes_secondary_zone() {
+        let zone_with = |serial: u32, ip: Ipv4Addr| vec![vec![soa_record("example.com", serial),
+            ns_record("example.com", "ns1.example.com"), a_record("www.example.com", ip), soa_record("example.com", serial)]];
+        let (primary, received) = serve_mock_primary_transfers(vec![
+            zone_with(1, Ipv4Addr::new(10, 0, 0, 1)),
+            zone_with(2, Ipv4Addr::new(10, 0, 0, 2)),
+            // not transferred as the serial did not change
+            zone_with(2, Ipv4Addr::new(10, 0, 0, 3)),
+        ]).await;
+        let server = Arc::new(test_server(ServerConfig {
+            authoritative_only: true,
+            secondary_zones: vec![SecondaryZone { origin: "example.com".to_string(), primary }],
+            ..test_config()
+        }).await);
+        let listen_addr = server.local_addrs().unwrap()[0];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        let served_address = || async {
+            server.build_response(DnsPacket::query(1, "www.example.com", QueryType::A)).await.all_addresses()
+        };
+        let first = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while served_address().await != vec![first] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        // only the primary may notify, a query not from the network has no sender
+        let mut notify = DnsPacket::query(40, "example.com", QueryType::SOA);
+        notify.header.set_op_code(OperationCode::Notify);
+        let response = server.build_response(notify.clone()).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (buf, amt) = notify.to_buf().unwrap();
+        client.send_to(&buf[..amt], listen_addr).await.unwrap();
+        let mut in_buf = [0u8; 512];
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+            .await.unwrap().unwrap();
+        let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(response.header.get_op_code(), OperationCode::Notify);
+        assert!(response.header.get_authoritative_answer());
+
+        let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while served_address().await != vec![second] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        // a notify for the serial already served is answered by comparing SOA serials only
+        client.send_to(&buf[..amt], listen_addr).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf)).await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while received.lock().unwrap().len() < 4 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![QueryType::AXFR, QueryType::SOA, QueryType::AXFR, QueryType::SOA]);
+        assert_eq!(served_address().await, vec![second]);
+
+        let mut update = DnsPacket::query(41, "example.com", QueryType::SOA);
+        update.header.set_op_code(OperationCode::Update);
+        assert_eq!(server.build_response(update).await.header.get_response_code(), ResponseCode::REFUSED);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_open_circuit_skips_failing_nameserver() {
+        let root = Ipv4Addr::new(127, 0, 62, 1);
+        let silent_ns = Ipv4Addr::new(127, 0, 62, 2);
+        let good_ns = Ipv4Addr::new(127, 0, 62, 3);
+        let (port, mut sockets) = bind_mock_servers(&[root, silent_ns, good_ns]).await;
+        let name_servers = [("ns1.example.com", Some(silent_ns)), ("ns2.example.com", Some(good_ns))];
+        serve_mock(sockets.remove(0), vec![
+            mock_referral("www.example.com", "example.com", &name_servers),
+            mock_referral("mail.example.com", "example.com", &name_servers),
+        ]);
+        let silent_received = serve_mock(sockets.remove(0), vec![]);
+        let mut www = mock_response("www.example.com", QueryType::A);
+        www.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        let mut mail = mock_response("mail.example.com", QueryType::A);
+        mail.add_answer(a_record("mail.example.com", Ipv4Addr::new(10, 0, 0, 2)));
+        serve_mock(sockets.remove(0), vec![www, mail]);
+
+        let server = test_server(ServerConfig {
+            lookup_retries: 0,
+            circuit_breaker: BreakerPolicy { failure_threshold: 1, ..Default::default() },
+            ..resolver_config(root, port)
+        }).await;
+
+        for (id, name) in [(36, "www.example.com"), (37, "mail.example.com")] {
+            let (buf, amt) = DnsPacket::query(id, name, QueryType::A).to_buf().unwrap();
+            // start at the root every time instead of the cached delegation
+            let packet = server.recursive_lookup(&buf[..amt], std::iter::once(IpAddr::V4(root))).await.unwrap();
+            assert_eq!(packet.answers.len(), 1);
+        }
+        assert_eq!(silent_received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resolution_timeout_reports_no_reachable_authority() {
+        let root = Ipv4Addr::new(127, 0, 63, 1);
+        let (port, _) = MockUpstream::new(root).serve().await;
+        let server = test_server(ServerConfig {
+            resolve_timeout: Duration::from_millis(200),
+            ..resolver_config(root, port)
+        }).await;
+
+        let mut query = DnsPacket::query(38, "www.example.com", QueryType::A);
+        query.set_edns(1232, false);
+        let response = server.build_response(query).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::SERVFAIL);
+        let (buf, amt) = response.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        let error = parsed.extended_error().unwrap();
+        assert_eq!(error.info_code, ede::NO_REACHABLE_AUTHORITY);
+        assert_eq!(error.extra_text, "resolution deadline exceeded");
+
+        // clients without EDNS get a plain SERVFAIL
+        let response = server.build_response(DnsPacket::query(39, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::SERVFAIL);
+        assert_eq!(response.extended_error(), None);
+    }
+
+    #[test]
+    fn test_dns_errors_map_to_their_own_codes() {
+        let e: std::io::Error = DnsError::LabelTooLong("x".repeat(64)).into();
+        let error = ExtendedError::from_io_error(&e);
+        assert_eq!(error.info_code, ede::OTHER);
+        assert_eq!(ExtendedError::from_option(&error.to_option()).unwrap(), error);
+
+        let e: std::io::Error = DnsError::CountMismatch { section: "answer", declared: 2, actual: 1 }.into();
+        assert_eq!(ExtendedError::from_io_error(&e).info_code, ede::INVALID_DATA);
+        let e = DnsError::UnserializableRecord("example.com".to_string(), QueryType::UNKOWN(99));
+        assert_eq!(e.extended_error().info_code, ede::NOT_SUPPORTED);
+        assert_eq!(e.extended_error().extra_text, e.to_string());
+    }
+
+    #[tokio::test]
+    async fn test_parallel_lookups_use_the_fastest_nameserver() {
+        let root = Ipv4Addr::new(127, 0, 64, 1);
+        let slow_ns = Ipv4Addr::new(127, 0, 64, 2);
+        let fast_ns = Ipv4Addr::new(127, 0, 64, 3);
+        let (port, mut sockets) = bind_mock_servers(&[root, slow_ns, fast_ns]).await;
+        serve_mock(sockets.remove(0), vec![
+            mock_referral("www.example.com", "example.com", &[("ns1.example.com", Some(slow_ns)), ("ns2.example.com", Some(fast_ns))]),
+        ]);
+        let mut slow_answer = mock_response("www.example.com", QueryType::A);
+        slow_answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        serve_mock_with_delay(sockets.remove(0), vec![slow_answer], Duration::from_millis(500));
+        let mut fast_answer = mock_response("www.example.com", QueryType::A);
+        fast_answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 2)));
+        serve_mock(sockets.remove(0), vec![fast_answer]);
+
+        let server = test_server(ServerConfig {
+            lookup_timeout: Duration::from_secs(1),
+            lookup_parallelism: 2,
+            ..resolver_config(root, port)
+        }).await;
+        let started = std::time::Instant::now();
+        let response = server.build_response(DnsPacket::query(40, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]);
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn test_server_from_pre_bound_sockets() {
+        let root = Ipv4Addr::new(127, 0, 65, 1);
+        let (port, _) = MockUpstream::new(root)
+            .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .serve().await;
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listen_addr = client_socket.local_addr().unwrap();
+        assert_ne!(listen_addr.port(), 0);
+        let lookup_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = Arc::new(DnsServer::from_sockets(vec![client_socket], lookup_socket, None, ServerConfig {
+            upstream_port: port,
+            root_server_ips: vec![root],
+            lookup_timeout: Duration::from_millis(100),
+            ..Default::default()
+        }).unwrap());
+        assert_eq!(server.local_addrs().unwrap(), vec![listen_addr]);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (buf, amt) = DnsPacket::query(41, "www.example.com", QueryType::A).to_buf().unwrap();
+        client.send_to(&buf[..amt], listen_addr).await.unwrap();
+        let mut in_buf = [0u8; 512];
+        let (len, from) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+            .await.unwrap().unwrap();
+        assert_eq!(from, listen_addr);
+        let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+        assert_eq!(response.header.id, 41);
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[test]
+    fn test_label_count() {
+        assert_eq!(label_count(""), 0);
+        assert_eq!(label_count("."), 0);
+        assert_eq!(label_count("com"), 1);
+        assert_eq!(label_count("example.com"), 2);
+        assert_eq!(label_count("www.example.com."), 3);
+    }
+
+    #[test]
+    fn test_is_subdomain_of() {
+        assert!(is_subdomain_of("example.com", "example.com"));
+        assert!(is_subdomain_of("www.Example.COM.", "example.com"));
+        assert!(is_subdomain_of("a.b.example.com", "example.com."));
+        assert!(is_subdomain_of("example.com", ""));
+        assert!(is_subdomain_of("example.com", "."));
+        assert!(!is_subdomain_of("notexample.com", "example.com"));
+        assert!(!is_subdomain_of("example.com", "www.example.com"));
+        assert!(!is_subdomain_of("", "com"));
+        assert!(names_equal("Example.com.", "example.COM"));
+    }
+
+    #[tokio::test]
+    async fn test_minimal_responses_drop_the_additional_section() {
+        let root = Ipv4Addr::new(127, 0, 66, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        answer.add_authority(ns_record("example.com", "ns1.example.com"));
+        answer.add_additional(a_record("ns1.example.com", Ipv4Addr::new(10, 0, 0, 53)));
+        serve_mock(sockets.remove(0), vec![answer]);
+
+        let server = test_server(ServerConfig {
+            minimal_responses: MinimalResponses::Additional,
+            ..resolver_config(root, port)
+        }).await;
+        let response = server.build_response(DnsPacket::query(42, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert!(response.additional.is_empty());
+        assert_eq!(response.header.additional_count, 0);
+        assert_eq!(response.authorities.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_rejects_responses_without_the_question() {
+        let upstream = Ipv4Addr::new(127, 0, 67, 1);
+        let (port, mut sockets) = bind_mock_servers(&[upstream]).await;
+        let socket = sockets.remove(0);
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (amt, client) = match socket.recv_from(&mut buf).await {
+                    Ok(res) => res,
+                    Err(_) => return,
+                };
+                let query = DnsPacket::from_buf(&buf[..amt]).unwrap();
+                // answers with the right id but echoes no question
+                let mut response = DnsPacket::new(Header::new(query.header.id, false, false, true, ResponseCode::NOERROR));
+                response.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 6, 6, 6)));
+                let (out, out_len) = response.to_buf().unwrap();
+                let _ = socket.send_to(&out[..out_len], client).await;
+            }
+        });
+
+        let server = mock_resolver(upstream, port).await;
+        let (buf, amt) = DnsPacket::query(43, "www.example.com", QueryType::A).to_buf().unwrap();
+        let err = server.lookup(&IpAddr::V4(upstream), &buf[..amt]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_long_txt_strings_are_split_into_segments() {
+        let mut packet = mock_response("example.com", QueryType::TXT);
+        packet.add_answer(Answer {
+            name: "example.com".to_string(),
+            query_type: QueryType::TXT,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::TXT(vec!["k".repeat(300)]),
+        });
+        let (buf, amt) = packet.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.answers[0].record, Record::TXT(vec!["k".repeat(255), "k".repeat(45)]));
+
+        // a two byte character straddling the 255 byte limit moves to the next segment
+        let mut packet = mock_response("example.com", QueryType::TXT);
+        packet.add_answer(Answer { record: Record::TXT(vec![format!("{}é", "k".repeat(254))]), ..parsed.answers[0].clone() });
+        let (buf, amt) = packet.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.answers[0].record, Record::TXT(vec!["k".repeat(254), "é".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn test_iterative_queries_clear_recursion_desired() {
+        let root = Ipv4Addr::new(127, 0, 68, 1);
+        let forwarder = Ipv4Addr::new(127, 0, 68, 2);
+        let (port, mut sockets) = bind_mock_servers(&[root, forwarder]).await;
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        let authoritative_queries = serve_mock(sockets.remove(0), vec![answer.clone()]);
+        let forwarded_queries = serve_mock(sockets.remove(0), vec![answer]);
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(44, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert!(response.header.get_recursion_desired());
+        let sent = authoritative_queries.lock().unwrap().clone();
+        assert!(!sent.is_empty());
+        assert!(sent.iter().all(|query| !query.header.get_recursion_desired()));
+
+        let server = test_server(ServerConfig {
+            forwarders: vec![IpAddr::V4(forwarder)],
+            ..resolver_config(root, port)
+        }).await;
+        server.build_response(DnsPacket::query(45, "www.example.com", QueryType::A)).await;
+        let sent = forwarded_queries.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].header.get_recursion_desired());
+
+        // iterating on its own, a server with forwarders still clears RD
+        authoritative_queries.lock().unwrap().clear();
+        server.trace_delegation("www.example.com").await;
+        let sent = authoritative_queries.lock().unwrap().clone();
+        assert!(!sent.is_empty());
+        assert!(sent.iter().all(|query| !query.header.get_recursion_desired()));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_questions_are_resolved_once() {
+        let root = Ipv4Addr::new(127, 0, 75, 1);
+        let (port, received) = MockUpstream::new(root)
+            .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+            .serve().await;
+
+        let server = mock_resolver(root, port).await;
+        let mut query = DnsPacket::query(47, "www.example.com", QueryType::A);
+        let mut questions = query.questions.clone();
+        questions.push(Question { name: "WWW.example.com".to_string(), ..questions[0].clone() });
+        query.set_questions(questions);
+        let response = server.build_response(query).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.answers.len(), 1);
+        let sent = received.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].questions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cname_target_answered_from_the_additional_section() {
+        let root = Ipv4Addr::new(127, 0, 69, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(Answer {
+            name: "www.example.com".to_string(),
+            query_type: QueryType::CNAME,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::CNAME("web.example.com".to_string()),
+        });
+        answer.add_additional(a_record("web.example.com", Ipv4Addr::new(10, 0, 0, 9)));
+        answer.add_additional(a_record("unrelated.example.org", Ipv4Addr::new(10, 6, 6, 6)));
+        // web.example.com is never asked for, the root only knows www
+        let received = serve_mock(sockets.remove(0), vec![answer]);
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(46, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9))]);
+        assert!(response.additional.is_empty());
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cname_target_outside_the_zone_is_not_taken_from_the_additional_section() {
+        let root = Ipv4Addr::new(127, 0, 86, 1);
+        let example = Ipv4Addr::new(127, 0, 86, 2);
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(Answer {
+            name: "www.example.com".to_string(),
+            query_type: QueryType::CNAME,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 0,
+            record: Record::CNAME("web.example.org".to_string()),
+        });
+        // example.com has no say over example.org
+        answer.add_additional(a_record("web.example.org", Ipv4Addr::new(10, 6, 6, 6)));
+        let (port, _) = MockUpstream::new(root)
+            .delegate("www.example.com", "example.com", &[("ns1.example.com", Some(example))])
+            .answer("web.example.org", vec![a_record("web.example.org", Ipv4Addr::new(10, 0, 0, 9))])
+            .serve_all(vec![MockUpstream::new(example).respond(answer)]).await;
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(49, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 9))]);
+        assert!(server.cache().get("web.example.org", &QueryType::A).unwrap().iter()
+            .all(|answer| answer.record == Record::A(Ipv4Addr::new(10, 0, 0, 9))));
+    }
+
+    #[tokio::test]
+    async fn test_mock_upstreams_resolve_a_two_level_delegation() {
+        let root = Ipv4Addr::new(127, 0, 70, 11);
+        let com = Ipv4Addr::new(127, 0, 70, 12);
+        let example = Ipv4Addr::new(127, 0, 70, 13);
+        let (port, received) = MockUpstream::new(root)
+            .delegate("www.example.com", "com", &[("a.gtld-servers.net", Some(com))])
+            .serve_all(vec![
+                MockUpstream::new(com)
+                    .delegate("www.example.com", "example.com", &[("ns1.example.com", Some(example))]),
+                MockUpstream::new(example)
+                    .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])
+                    .nxdomain("missing.example.com", QueryType::A, "example.com"),
+            ]).await;
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(47, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert!(received.iter().all(|queries| queries.lock().unwrap().len() == 1));
+
+        let (buf, amt) = DnsPacket::query(48, "missing.example.com", QueryType::A).to_buf().unwrap();
+        let response = server.lookup_v4(&example, &buf[..amt]).await.unwrap();
+        assert_eq!(response.header.get_response_code(), ResponseCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].record, soa_record("example.com", 1).record);
+    }
+
+    #[test]
+    fn test_truncation_drops_padding_to_fit() {
+        let mut response = mock_response("www.example.com", QueryType::A);
+        for i in 0..40 {
+            response.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, i)));
+        }
+        response.set_edns(1232, false);
+        response.pad_to_block(468);
+        assert!(response.wire_len() > 512);
+
+        let truncated = response.truncated_to(512);
+        assert!(truncated.header.get_truncated_message());
+        assert!(truncated.edns().is_some());
+        assert!(!truncated.has_padding());
+        assert!(truncated.wire_len() <= 512);
+
+        // padding alone can push a response over the limit
+        let mut response = mock_response("www.example.com", QueryType::A);
+        response.set_edns(1232, false);
+        response.pad_to_block(1024);
+        assert_eq!(response.wire_len(), 1024);
+        let truncated = response.truncated_to(512);
+        assert!(!truncated.has_padding());
+        assert!(truncated.wire_len() <= 512);
+    }
+
+    #[tokio::test]
+    async fn test_truncated_udp_response_is_retried_over_tcp() {
+        let upstream = Ipv4Addr::new(127, 0, 71, 1);
+        let (port, mut sockets) = bind_mock_servers(&[upstream]).await;
+        let socket = sockets.remove(0);
+        let listener = tokio::net::TcpListener::bind((upstream, port)).await.unwrap();
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 2)));
+        let udp_answer = answer.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            let (amt, client) = socket.recv_from(&mut buf).await.unwrap();
+            let query = DnsPacket::from_buf(&buf[..amt]).unwrap();
+            let mut response = udp_answer;
+            response.header.id = query.header.id;
+            let (out, out_len) = response.to_buf().unwrap();
+            // cut off in the middle of the second answer
+            socket.send_to(&out[..out_len - 3], client).await.unwrap();
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0u8; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            let query = DnsPacket::from_buf(&buf).unwrap();
+            let mut response = answer;
+            response.header.id = query.header.id;
+            let (out, out_len) = response.to_buf().unwrap();
+            stream.write_all(&(out_len as u16).to_be_bytes()).await.unwrap();
+            stream.write_all(&out[..out_len]).await.unwrap();
+        });
+
+        let server = mock_resolver(upstream, port).await;
+        let (buf, amt) = DnsPacket::query(49, "www.example.com", QueryType::A).to_buf().unwrap();
+        let response = server.lookup(&IpAddr::V4(upstream), &buf[..amt]).await.unwrap();
+        assert_eq!(response.header.id, 49);
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_falls_back_to_plain_queries_without_edns_support() {
+        let upstream = Ipv4Addr::new(127, 0, 80, 1);
+        let (port, mut sockets) = bind_mock_servers(&[upstream]).await;
+        let socket = sockets.remove(0);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = Arc::clone(&received);
+        // an old server that FORMERRs anything with an OPT record
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (amt, client) = socket.recv_from(&mut buf).await.unwrap();
+                let query = DnsPacket::from_buf(&buf[..amt]).unwrap();
+                received_clone.lock().unwrap().push(query.edns().is_some());
+                let response = if query.edns().is_some() {
+                    DnsPacket::error_response(&query, ResponseCode::FORMERR)
+                } else {
+                    let mut response = mock_response("www.example.com", QueryType::A);
+                    response.header.id = query.header.id;
+                    response.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+                    response
+                };
+                let (out, out_len) = response.to_buf().unwrap();
+                socket.send_to(&out[..out_len], client).await.unwrap();
+            }
+        });
+
+        let server = mock_resolver(upstream, port).await;
+        let mut query = DnsPacket::query(51, "www.example.com", QueryType::A);
+        query.set_edns(1232, false);
+        let (buf, amt) = query.to_buf().unwrap();
+        for _ in 0..2 {
+            let response = server.lookup(&IpAddr::V4(upstream), &buf[..amt]).await.unwrap();
+            assert_eq!(response.header.id, 51);
+            assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+            assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        }
+        // once known to lack edns the server is no longer sent OPT records
+        assert_eq!(*received.lock().unwrap(), vec![true, false, false]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_timing_out_on_first_contact_is_not_retried_without_edns() {
+        let upstream = Ipv4Addr::new(127, 0, 88, 1);
+        let (port, received) = MockUpstream::new(upstream).serve_all(vec![]).await;
+        let server = mock_resolver(upstream, port).await;
+        let mut query = DnsPacket::query(52, "www.example.com", QueryType::A);
+        query.set_edns(1232, false);
+        let (buf, amt) = query.to_buf().unwrap();
+
+        let err = server.lookup(&IpAddr::V4(upstream), &buf[..amt]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        // a silent server may just be down, so it is not asked again without edns
+        assert!(received[0].lock().unwrap().iter().all(|query| query.edns().is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_keepalive_is_announced_and_idle_connections_close() {
+        let server = Arc::new(test_server(ServerConfig {
+            serve_tcp: true,
+            tcp_idle_timeout: Duration::from_millis(300),
+            ..test_config()
+        }).await);
+        server.cache().insert(vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))]);
+        let tcp_addr = server.local_tcp_addrs().unwrap()[0];
+        assert_eq!(tcp_addr, server.local_addrs().unwrap()[0]);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        let mut stream = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+        for id in [50, 51] {
+            let mut query = DnsPacket::query(id, "www.example.com", QueryType::A);
+            query.set_edns(1232, false);
+            if let Some(Answer { record: Record::OPT(options), .. }) = query.additional.last_mut() {
+                options.push(EdnsOption { code: EDNS_TCP_KEEPALIVE, data: Vec::new() });
+            }
+            let (buf, amt) = query.to_buf().unwrap();
+            stream.write_all(&(amt as u16).to_be_bytes()).await.unwrap();
+            stream.write_all(&buf[..amt]).await.unwrap();
+            let len = tokio::time::timeout(Duration::from_secs(2), stream.read_u16()).await.unwrap().unwrap();
+            let mut in_buf = vec![0u8; len as usize];
+            stream.read_exact(&mut in_buf).await.unwrap();
+            let response = DnsPacket::from_buf(&in_buf).unwrap();
+            assert_eq!(response.header.id, id);
+            assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+            assert_eq!(response.tcp_keepalive(), Some(Duration::from_millis(300)));
+        }
+
+        // left idle past the announced timeout, the server closes the connection
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest)).await.unwrap();
+        assert_eq!(read.unwrap(), 0);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_tcp_clients_are_limited_and_timed_out() {
+        let server = Arc::new(test_server(ServerConfig {
+            serve_tcp: true,
+            tcp_idle_timeout: Duration::from_millis(300),
+            max_tcp_connections: 1,
+            ..test_config()
+        }).await);
+        let tcp_addr = server.local_tcp_addrs().unwrap()[0];
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+
+        // announces a query but never sends all of it
+        let mut slow = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+        slow.write_all(&[0, 40, 0, 1]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the only connection slot is taken, so this one is closed right away
+        let mut rejected = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_millis(200), rejected.read_to_end(&mut rest)).await.unwrap();
+        assert_eq!(read.unwrap(), 0);
+
+        let read = tokio::time::timeout(Duration::from_secs(2), slow.read_to_end(&mut rest)).await.unwrap();
+        assert_eq!(read.unwrap(), 0);
+        // and the slot is free again
+        let mut stream = tokio::net::TcpStream::connect(tcp_addr).await.unwrap();
+        let (buf, amt) = DnsPacket::query(53, "localhost", QueryType::A).to_buf().unwrap();
+        stream.write_all(&(amt as u16).to_be_bytes()).await.unwrap();
+        stream.write_all(&buf[..amt]).await.unwrap();
+        let len = tokio::time::timeout(Duration::from_secs(2), stream.read_u16()).await.unwrap().unwrap();
+        let mut in_buf = vec![0u8; len as usize];
+        stream.read_exact(&mut in_buf).await.unwrap();
+        assert_eq!(DnsPacket::from_buf(&in_buf).unwrap().header.id, 53);
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_glueless_in_bailiwick_nameservers_are_not_resolved_through_their_own_zone() {
+        let root = Ipv4Addr::new(127, 0, 72, 1);
+        let other_ns = Ipv4Addr::new(127, 0, 72, 2);
+        let (port, received) = MockUpstream::new(root)
+            .delegate("www.example.com", "example.com", &[("ns1.example.com", None), ("ns.other.net", None)])
+            .answer("ns.other.net", vec![a_record("ns.other.net", other_ns)])
+            .serve_all(vec![
+                MockUpstream::new(other_ns)
+                    .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))]),
+            ]).await;
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(52, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        let asked: Vec<String> = received[0].lock().unwrap().iter()
+            .map(|query| query.questions[0].name.clone())
+            .collect();
+        assert_eq!(asked, vec!["www.example.com", "ns.other.net"]);
+    }
+
+    #[tokio::test]
+    async fn test_out_of_bailiwick_records_are_not_cached() {
+        let root = Ipv4Addr::new(127, 0, 73, 1);
+        let example_ns = Ipv4Addr::new(127, 0, 73, 2);
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        answer.add_additional(a_record("www.bank.com", Ipv4Addr::new(10, 6, 6, 6)));
+        answer.add_additional(a_record("ns1.example.com", example_ns));
+        let (port, _) = MockUpstream::new(root)
+            .delegate("www.example.com", "example.com", &[("ns1.example.com", Some(example_ns))])
+            .serve_all(vec![MockUpstream::new(example_ns).respond(answer)]).await;
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(53, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert!(server.cache().get("www.example.com", &QueryType::A).is_some());
+        assert!(server.cache().get("ns1.example.com", &QueryType::A).is_some());
+        assert_eq!(server.cache().get("www.bank.com", &QueryType::A), None);
+    }
+
+    #[tokio::test]
+    async fn test_glue_preference_orders_address_families() {
+        let root = IpAddr::V4(Ipv4Addr::new(127, 0, 74, 1));
+        let ns_v4 = Ipv4Addr::new(127, 0, 74, 2);
+        let (port, mut sockets) = bind_mock_servers(&[root, IpAddr::V4(ns_v4), IpAddr::V6(Ipv6Addr::LOCALHOST)]).await;
+        let mut referral = mock_referral("www.example.com", "example.com", &[("ns1.example.com", Some(ns_v4))]);
+        referral.add_additional(Answer {
+            name: "ns1.example.com".to_string(),
+            query_type: QueryType::AAAA,
+            class: CLASS_IN,
+            ttl: 300,
+            len: 16,
+            record: Record::AAAA(Ipv6Addr::LOCALHOST),
+        });
+        serve_mock(sockets.remove(0), vec![referral]);
+        let mut v4_answer = mock_response("www.example.com", QueryType::A);
+        v4_answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 4)));
+        serve_mock(sockets.remove(0), vec![v4_answer]);
+        let mut v6_answer = mock_response("www.example.com", QueryType::A);
+        v6_answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 6)));
+        serve_mock(sockets.remove(0), vec![v6_answer]);
+
+        for (glue_preference, expected) in [
+            (GluePreference::AsReceived, Ipv4Addr::new(10, 0, 0, 4)),
+            (GluePreference::Ipv6First, Ipv4Addr::new(10, 0, 0, 6)),
+            (GluePreference::Ipv4First, Ipv4Addr::new(10, 0, 0, 4)),
+        ] {
+            let server = test_server(ServerConfig {
+                lookup_addr_v6: "[::1]:0".to_string(),
+                upstream_port: port,
+                root_server_ips: vec![Ipv4Addr::new(127, 0, 74, 1)],
+                lookup_timeout: Duration::from_millis(100),
+                glue_preference,
+                ..test_config()
+            }).await;
+            let response = server.build_response(DnsPacket::query(54, "www.example.com", QueryType::A)).await;
+            assert_eq!(response.all_addresses(), vec![IpAddr::V4(expected)], "{:?}", glue_preference);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priming_replaces_the_root_hints() {
+        let hint = Ipv4Addr::new(127, 0, 76, 1);
+        let primed = Ipv4Addr::new(127, 0, 76, 2);
+        let mut priming = mock_response("", QueryType::NS);
+        priming.header.set_authoritative_answer(true);
+        priming.add_answer(ns_record("", "a.root-servers.test"));
+        priming.add_additional(a_record("a.root-servers.test", primed));
+        let (port, received) = MockUpstream::new(hint)
+            .respond(priming)
+            .serve_all(vec![MockUpstream::new(primed)
+                .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))])])
+            .await;
+
+        let server = mock_resolver(hint, port).await;
+        server.prime().await.unwrap();
+        let root = server.cache().delegation("").unwrap();
+        assert_eq!(root.name_servers, vec!["a.root-servers.test".to_string()]);
+        assert_eq!(root.addresses, vec![IpAddr::V4(primed)]);
+
+        let response = server.build_response(DnsPacket::query(55, "www.example.com", QueryType::A)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        // only the priming query went to the hint
+        assert_eq!(received[0].lock().unwrap().len(), 1);
+
+        // a hint that does not answer leaves the hints in place
+        let server = mock_resolver(Ipv4Addr::new(127, 0, 76, 3), port).await;
+        assert!(server.prime().await.is_err());
+        assert!(server.cache().delegation("").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_special_use_domains_are_answered_locally() {
+        let root = Ipv4Addr::new(127, 0, 77, 1);
+        let (port, received) = MockUpstream::new(root).serve().await;
+
+        let server = mock_resolver(root, port).await;
+        let response = server.build_response(DnsPacket::query(56, "localhost", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(response.header.get_authoritative_answer());
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+        let response = server.build_response(DnsPacket::query(57, "app.LOCALHOST", QueryType::AAAA)).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+        let response = server.build_response(DnsPacket::query(58, "localhost", QueryType::MX)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert!(response.answers.is_empty());
+        // negative answers carry a SOA so clients may cache them
+        assert_eq!(response.authorities[0].name, "localhost");
+        assert_eq!(response.authorities[0].ttl, 300);
+        let response = server.build_response(DnsPacket::query(59, "1.0.0.127.in-addr.arpa", QueryType::PTR)).await;
+        assert_eq!(response.answers[0].record, Record::PTR("localhost".to_string()));
+        let response = server.build_response(DnsPacket::query(60, "something.invalid", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NXDOMAIN);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities[0].query_type, QueryType::SOA);
+        assert_eq!(response.authorities[0].name, "invalid");
+        assert!(received.lock().unwrap().is_empty());
+
+        // overriding the table sends localhost upstream like any other name
+        let server = test_server(ServerConfig {
+            special_use_domains: vec![SpecialUseDomain { name: "internal".to_string(), handling: SpecialUse::NxDomain }],
+            synthesized_ttls: SynthesizedTtls { negative: 30, ..Default::default() },
+            ..resolver_config(root, port)
+        }).await;
+        let response = server.build_response(DnsPacket::query(61, "db.internal", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NXDOMAIN);
+        assert_eq!(response.authorities[0].ttl, 30);
+        assert!(received.lock().unwrap().is_empty());
+        server.build_response(DnsPacket::query(62, "localhost", QueryType::A)).await;
+        assert!(!received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_any_queries_get_a_single_hinfo_in_rfc8482_mode() {
+        let root = Ipv4Addr::new(127, 0, 78, 1);
+        let (port, received) = MockUpstream::new(root).serve().await;
+
+        let server = test_server(ServerConfig {
+            any_queries: AnyQueries::Rfc8482,
+            ..resolver_config(root, port)
+        }).await;
+        let response = server.build_response(DnsPacket::query(63, "example.com", QueryType::ANY)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::NOERROR);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].query_type, QueryType::HINFO);
+        assert_eq!(response.answers[0].record, Record::HINFO { cpu: "RFC8482".to_string(), os: String::new() });
+        assert!(received.lock().unwrap().is_empty());
+
+        let (buf, amt) = response.to_buf().unwrap();
+        let parsed = DnsPacket::from_buf(&buf[..amt]).unwrap();
+        assert_eq!(parsed.answers[0].record, response.answers[0].record);
+        assert_eq!("any".parse::<QueryType>().unwrap(), QueryType::ANY);
+
+        // in the default mode ANY is resolved upstream like any other type
+        let server = mock_resolver(root, port).await;
+        server.build_response(DnsPacket::query(64, "example.com", QueryType::ANY)).await;
+        let sent = received.lock().unwrap().clone();
+        assert_eq!(sent[0].question().unwrap().query_type, QueryType::ANY);
+    }
+
+    #[tokio::test]
+    async fn test_caching_depends_on_the_response_code() {
+        let root = Ipv4Addr::new(127, 0, 79, 1);
+        let mut servfail = mock_response("broken.example.com", QueryType::A);
+        servfail.header.set_response_code(ResponseCode::SERVFAIL);
+        servfail.add_answer(a_record("broken.example.com", Ipv4Addr::new(10, 6, 6, 6)));
+        servfail.add_authority(ns_record("example.com", "ns1.example.com"));
+        let (port, received) = MockUpstream::new(root)
+            .respond(servfail)
+            .nxdomain("missing.example.com", QueryType::A, "example.com")
+            .serve_all(Vec::new())
+            .await;
+
+        let server = test_server(ServerConfig {
+            cache_policy: CachePolicy { negative_ttl: 300, ..CachePolicy::default() },
+            ..resolver_config(root, port)
+        }).await;
+        let response = server.build_response(DnsPacket::query(65, "broken.example.com", QueryType::A)).await;
+        assert_eq!(response.header.get_response_code(), ResponseCode::SERVFAIL);
+        assert!(server.cache().entries().is_empty());
+        assert!(!server.cache().is_negative("broken.example.com", &QueryType::A));
+
+        for id in [66, 67] {
+            let response = server.build_response(DnsPacket::query(id, "missing.example.com", QueryType::A)).await;
+            assert_eq!(response.header.get_response_code(), ResponseCode::NXDOMAIN);
+            assert_eq!(response.authorities[0].record, soa_record("example.com", 1).record);
+        }
+        // the SOA minimum of 60 seconds bounds how long the answer is cached
+        let negative = server.cache().get_negative("missing.example.com", &QueryType::A).unwrap();
+        assert!(negative.expires_in <= chrono::Local::now() + chrono::Duration::seconds(60));
+        let negative = server.cache().get_negative("missing.example.com", &QueryType::AAAA).unwrap();
+        assert_eq!(negative.response_code, ResponseCode::NXDOMAIN);
+        let asked: Vec<String> = received[0].lock().unwrap().iter()
+            .map(|query| query.question().unwrap().name.clone())
+            .collect();
+        assert_eq!(asked.iter().filter(|name| *name == "missing.example.com").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_socket_buffer_sizes_are_applied() {
+        let size = 48 * 1024;
+        let socket = bind_udp("127.0.0.1:0", Some(size), Some(size)).await.unwrap();
+        let socket_ref = socket2::SockRef::from(&socket);
+        // linux reports twice the requested size to account for its bookkeeping
+        let recv_buffer = socket_ref.recv_buffer_size().unwrap();
+        assert!((size..=2 * size).contains(&recv_buffer), "{}", recv_buffer);
+        let send_buffer = socket_ref.send_buffer_size().unwrap();
+        assert!((size..=2 * size).contains(&send_buffer), "{}", send_buffer);
+        assert_ne!(socket.local_addr().unwrap().port(), 0);
+
+        let server = test_server(ServerConfig {
+            udp_recv_buffer: Some(size),
+            udp_send_buffer: Some(size),
+            ..test_config()
+        }).await;
+        assert_ne!(server.local_addrs().unwrap()[0].port(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_local_addr_reports_the_bound_port() {
+        let server = test_server(test_config()).await;
+        let lookup_addr = server.lookup_local_addr().unwrap();
+        assert_ne!(lookup_addr.port(), 0);
+        assert_ne!(lookup_addr, server.local_addrs().unwrap()[0]);
+
+        // the reported address is the one held by the server, not a fresh free port
+        let err = UdpSocket::bind(lookup_addr).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&[0u8; 12], lookup_addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dropped_and_refused_outcomes() {
+        let server = test_server(ServerConfig {
+            blocklist: vec!["blocked.example".to_string()],
+            ..test_config()
+        }).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let query = DnsPacket::query(71, "www.example.com", QueryType::A);
+        let mut in_buf = [0u8; 512];
+
+        assert_eq!(server.deliver(0, client_addr, &query, ResolutionOutcome::Drop).await, None);
+        assert!(tokio::time::timeout(Duration::from_millis(200), client.recv_from(&mut in_buf)).await.is_err());
+
+        // a refusal keeps what the client asked for in its OPT record
+        let mut query = DnsPacket::query(72, "ads.blocked.example", QueryType::A);
+        query.set_edns(1232, false);
+        query.set_client_subnet(&ClientSubnet::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 24).unwrap());
+        server.resolve_request(0, client_addr, query).await;
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+            .await.unwrap().unwrap();
+        let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+        assert_eq!(response.header.id, 72);
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+        assert_eq!(response.question().unwrap().name, "ads.blocked.example");
+        assert!(response.answers.is_empty());
+        assert_eq!(response.client_subnet().unwrap().scope_prefix_len, 0);
+        assert_eq!(response.extended_error().unwrap().info_code, ede::PROHIBITED);
+
+        // so do refused CHAOS queries
+        let mut query = DnsPacket::query(73, "version.server", QueryType::TXT);
+        query.questions[0].class = CLASS_CH;
+        query.set_edns(1232, false);
+        server.resolve_request(0, client_addr, query).await;
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+            .await.unwrap().unwrap();
+        let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+        assert_eq!(response.header.get_response_code(), ResponseCode::REFUSED);
+        assert!(response.edns().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_set_all_ttls_leaves_the_opt_record_alone() {
+        let mut packet = mock_response("www.example.com", QueryType::A);
+        packet.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        packet.add_authority(ns_record("example.com", "ns1.example.com"));
+        packet.add_additional(a_record("ns1.example.com", Ipv4Addr::new(10, 0, 0, 53)));
+        packet.set_edns(1232, true);
+        packet.set_extended_response_code(BADVERS);
+        let opt_ttl = packet.edns().unwrap().ttl;
+        packet.set_all_ttls(30);
+        let ttls: Vec<u32> = packet.answers.iter().chain(&packet.authorities).chain(&packet.additional)
+            .filter(|answer| answer.query_type != QueryType::OPT)
+            .map(|answer| answer.ttl)
+            .collect();
+        assert_eq!(ttls, vec![30, 30, 30]);
+        assert_eq!(packet.edns().unwrap().ttl, opt_ttl);
+        assert!(packet.dnssec_ok());
+        assert_eq!(packet.extended_response_code(), BADVERS);
+
+        let root = Ipv4Addr::new(127, 0, 83, 1);
+        let (port, mut sockets) = bind_mock_servers(&[root]).await;
+        let mut answer = mock_response("www.example.com", QueryType::A);
+        answer.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+        answer.add_authority(ns_record("example.com", "ns1.example.com"));
+        serve_mock(sockets.remove(0), vec![answer]);
+        let server = test_server(ServerConfig {
+            fixed_ttl: Some(60),
+            ..resolver_config(root, port)
+        }).await;
+        let mut query = DnsPacket::query(72, "www.example.com", QueryType::A);
+        query.set_edns(1232, false);
+        let response = server.build_response(query).await;
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert!(response.answers.iter().chain(&response.authorities).all(|answer| answer.ttl == 60));
+        assert_ne!(response.edns().unwrap().ttl, 60);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_losing_udp_is_switched_to_tcp() {
+        let upstream = Ipv4Addr::new(127, 0, 84, 1);
+        // the udp socket is bound but never read, every datagram to it is lost
+        let (port, _sockets) = bind_mock_servers(&[upstream]).await;
+        let listener = tokio::net::TcpListener::bind((upstream, port)).await.unwrap();
+        let tcp_queries = Arc::new(Mutex::new(0));
+        let tcp_queries_clone = Arc::clone(&tcp_queries);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let len = stream.read_u16().await.unwrap();
+                let mut buf = vec![0u8; len as usize];
+                stream.read_exact(&mut buf).await.unwrap();
+                let query = DnsPacket::from_buf(&buf).unwrap();
+                *tcp_queries_clone.lock().unwrap() += 1;
+                let mut response = mock_response("www.example.com", QueryType::A);
+                response.header.id = query.header.id;
+                response.add_answer(a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1)));
+                let (out, out_len) = response.to_buf().unwrap();
+                stream.write_all(&(out_len as u16).to_be_bytes()).await.unwrap();
+                stream.write_all(&out[..out_len]).await.unwrap();
+            }
+        });
+
+        let server = test_server(ServerConfig {
+            upstream_port: port,
+            lookup_timeout: Duration::from_millis(100),
+            lookup_retries: 0,
+            tcp_fallback: TcpFallbackPolicy {
+                udp_timeouts: 2,
+                window: Duration::from_secs(10),
+                cooldown: Duration::from_secs(60),
+            },
+            ..test_config()
+        }).await;
+        let (buf, amt) = DnsPacket::query(73, "www.example.com", QueryType::A).to_buf().unwrap();
+        let addr = IpAddr::V4(upstream);
+        let err = server.lookup(&addr, &buf[..amt]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(*tcp_queries.lock().unwrap(), 0);
+
+        // the second timeout reaches the threshold, the lookup is retried over tcp
+        let response = server.lookup(&addr, &buf[..amt]).await.unwrap();
+        assert_eq!(response.header.id, 73);
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert_eq!(*tcp_queries.lock().unwrap(), 1);
+
+        // from now on tcp is used without waiting for udp
+        let started = std::time::Instant::now();
+        let response = server.lookup(&addr, &buf[..amt]).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(response.all_addresses(), vec![IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))]);
+        assert_eq!(*tcp_queries.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_padded_query_gets_padded_response() {
+        let server = Arc::new(test_server(ServerConfig {
+            version_bind: Some("untitled 0.1".to_string()),
+            serve_tcp: true,
+            padding_block_size: Some(468),
+            tls_proxies: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            ..test_config()
+        }).await);
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(Arc::clone(&server).serve_until(async { let _ = stopped.await; }));
+        let tcp_addr = server.local_tcp_addrs().unwrap()[0];
+        let mut query = DnsPacket::query(74, "version.bind", QueryType::TXT);
+        query.questions[0].class = CLASS_CH;
+        query.set_edns(1232, false);
+        // queries are padded to 128 bytes as RFC 8467 recommends
+        query.pad_to_block(128);
+        assert!(query.has_padding());
+        assert_eq!(query.wire_len(), 128);
+
+        let response = query_tcp(tcp_addr, &query, Duration::from_secs(2)).await.unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.wire_len(), 468);
+        match &response.edns().unwrap().record {
+            Record::OPT(options) => {
+                let padding = options.iter().find(|option| option.code == EDNS_PADDING).unwrap();
+                assert!(padding.data.iter().all(|byte| *byte == 0));
+            }
+            record => panic!("unexpected record {:?}", record),
+        }
+
+        // cleartext udp is never padded
+        let response = query_udp(server.local_addrs().unwrap()[0], &query, Duration::from_secs(2)).await.unwrap();
+        assert_eq!(response.answers.len(), 1);
+        assert!(!response.has_padding());
+        // and neither are queries that did not ask for it
+        let mut unpadded = DnsPacket::query(75, "version.bind", QueryType::TXT);
+        unpadded.questions[0].class = CLASS_CH;
+        unpadded.set_edns(1232, false);
+        let response = query_tcp(tcp_addr, &unpadded, Duration::from_secs(2)).await.unwrap();
+        assert!(!response.has_padding());
+
+        stop.send(()).unwrap();
+        serving.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trace_delegation_returns_the_chain_of_zone_cuts() {
+        let root = Ipv4Addr::new(127, 0, 85, 1);
+        let com = Ipv4Addr::new(127, 0, 85, 2);
+        let example = Ipv4Addr::new(127, 0, 85, 3);
+        let (port, _) = MockUpstream::new(root)
+            .delegate("www.example.com", "com", &[("a.gtld-servers.net", Some(com))])
+            .serve_all(vec![
+                MockUpstream::new(com)
+                    .delegate("www.example.com", "example.com", &[("ns1.example.com", Some(example))]),
+                MockUpstream::new(example)
+                    .answer("www.example.com", vec![a_record("www.example.com", Ipv4Addr::new(10, 0, 0, 1))]),
+            ]).await;
+        let server = mock_resolver(root, port).await;
+
+        assert_eq!(server.trace_delegation("www.example.com").await, vec![
+            (String::new(), vec![root]),
+            ("com".to_string(), vec![com]),
+            ("example.com".to_string(), vec![example]),
+        ]);
+        // a name below a broken delegation shows how far resolution got
+        assert_eq!(server.trace_delegation("missing.org").await, vec![(String::new(), vec![root])]);
+        // cuts cached by earlier lookups are not reported when this walk never reached them
+        assert_eq!(server.trace_delegation("ftp.example.com").await, vec![(String::new(), vec![root])]);
+    }
+
+    #[tokio::test]
+    async fn test_answer_set_is_capped_for_clients() {
+        let server = test_server(ServerConfig {
+            max_answers: Some(10),
+            max_answers_truncates: true,
+            ..test_config()
+        }).await;
+        let records: Vec<Answer> = (1..=50)
+            .map(|host| a_record("many.example.com", Ipv4Addr::new(10, 0, 0, host)))
+            .collect();
+        server.cache().insert(records);
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let mut in_buf = [0u8; 512];
+        let mut seen = std::collections::HashSet::new();
+        for id in 0..3 {
+            server.resolve_request(0, client_addr, DnsPacket::query(76 + id, "many.example.com", QueryType::A)).await;
+            let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+                .await.unwrap().unwrap();
+            let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+            assert_eq!(response.header.id, 76 + id);
+            assert_eq!(response.answers.len(), 10);
+            assert_eq!(response.header.answer_count, 10);
+            assert!(response.header.get_truncated_message());
+            seen.extend(response.all_addresses());
+        }
+        // the cache rotates the records, so repeated queries see different ones
+        assert!(seen.len() > 10);
+
+        // building a response is not capped, only what is sent to udp clients
+        let response = server.build_response(DnsPacket::query(79, "many.example.com", QueryType::A)).await;
+        assert_eq!(response.answers.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn test_large_udp_answers_use_the_edns_payload_size() {
+        let server = test_server(test_config()).await;
+        let records: Vec<Answer> = (1..=40)
+            .map(|host| a_record("big.example.com", Ipv4Addr::new(10, 0, 0, host)))
+            .collect();
+        server.cache().insert(records);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let mut in_buf = [0u8; 4096];
+
+        let mut query = DnsPacket::query(80, "big.example.com", QueryType::A);
+        query.set_edns(4096, false);
+        server.resolve_request(0, client_addr, query).await;
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+            .await.unwrap().unwrap();
+        assert!(len > 512 && len <= 1232, "{}", len);
+        let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+        assert_eq!(response.answers.len(), 40);
+        assert!(!response.header.get_truncated_message());
+
+        // without EDNS the answer does not fit 512 bytes and the client is sent to tcp
+        server.resolve_request(0, client_addr, DnsPacket::query(81, "big.example.com", QueryType::A)).await;
+        let (len, _) = tokio::time::timeout(Duration::from_secs(2), client.recv_from(&mut in_buf))
+            .await.unwrap().unwrap();
+        assert!(len <= 512);
+        let response = DnsPacket::from_buf(&in_buf[..len]).unwrap();
+        assert!(response.header.get_truncated_message());
+        assert_eq!(response.header.id, 81);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.question().unwrap().name, "big.example.com");
+    }
 }